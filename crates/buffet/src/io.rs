@@ -1,4 +1,4 @@
-use crate::{BufResult, IoBufMut, Piece, PieceList};
+use crate::{BufMut, BufResult, IoBufMut, Piece, PieceList, Roll};
 
 mod pipe;
 pub use pipe::*;
@@ -105,11 +105,52 @@ pub trait WriteOwned {
         Ok(())
     }
 
+    /// Write `len` bytes of `file`, starting at `offset`. Fails with
+    /// [std::io::ErrorKind::UnexpectedEof] if the file is shorter than that.
+    ///
+    /// The default implementation reads the file into pool buffers and writes
+    /// those: implementors that can move bytes from a file descriptor to
+    /// another without copying them through userspace should override it.
+    async fn write_file_all(
+        &mut self,
+        file: &std::fs::File,
+        offset: u64,
+        len: u64,
+    ) -> std::io::Result<()> {
+        let mut offset = offset;
+        let mut remain = len;
+        while remain > 0 {
+            let mut buf = BufMut::alloc().map_err(std::io::Error::other)?;
+            let read_len = std::cmp::min(remain, buf.len() as u64) as usize;
+            let n = read_file_at(file, &mut buf[..read_len], offset)?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            let roll: Roll = buf.freeze().into();
+            self.write_all_owned(roll.slice(..n)).await?;
+            offset += n as u64;
+            remain -= n as u64;
+        }
+        Ok(())
+    }
+
     /// Shuts down the write end of this socket. This flushes
     /// any data that may not have been send.
     async fn shutdown(&mut self) -> std::io::Result<()>;
 }
 
+/// Reads from `file` at `offset`, without moving its cursor
+#[cfg(unix)]
+pub fn read_file_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// Reads from `file` at `offset`, without moving its cursor
+#[cfg(windows)]
+pub fn read_file_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
 #[cfg(all(test, not(feature = "miri")))]
 mod tests {
     use std::{cell::RefCell, rc::Rc};
@@ -192,6 +233,45 @@ mod tests {
             assert_eq!(&writer.bytes.borrow()[..], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
         });
     }
+
+    #[test]
+    fn test_write_file_all() {
+        struct Writer {
+            bytes: Vec<u8>,
+        }
+
+        impl WriteOwned for Writer {
+            async fn write_owned(&mut self, buf: impl Into<Piece>) -> BufResult<usize, Piece> {
+                let buf = buf.into();
+                self.bytes.extend_from_slice(&buf[..]);
+                (Ok(buf.len()), buf)
+            }
+
+            async fn shutdown(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let contents: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let path =
+            std::env::temp_dir().join(format!("buffet-test-write-file-all-{}", std::process::id()));
+        std::fs::write(&path, &contents).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        crate::start(async move {
+            let mut writer = Writer { bytes: vec![] };
+            writer.write_file_all(&file, 100, 9_000).await.unwrap();
+            assert_eq!(&writer.bytes[..], &contents[100..9_100]);
+
+            let mut writer = Writer { bytes: vec![] };
+            let err = writer
+                .write_file_all(&file, 9_000, 2_000)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        });
+    }
 }
 
 pub trait IntoHalves: 'static {
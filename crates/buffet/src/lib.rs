@@ -14,6 +14,9 @@ pub use io::*;
 
 pub mod net;
 
+mod multi;
+pub use multi::*;
+
 #[cfg(all(target_os = "linux", feature = "uring"))]
 mod uring;
 
@@ -0,0 +1,315 @@
+//! Running a task on several threads, each with its own current-thread
+//! runtime, io_uring instance and buffer pool ("thread-per-core").
+//!
+//! Nothing is shared between workers: to serve a single port from all of them,
+//! have each worker bind its own listener to it — on unix,
+//! [crate::net::TcpListener] sets `SO_REUSEPORT`, so the kernel spreads
+//! incoming connections across them.
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use tokio::sync::Notify;
+
+pub struct MultiConf {
+    /// How many worker threads to start
+    pub threads: usize,
+
+    /// Whether to pin each worker thread to a CPU core. Workers are spread
+    /// over the cores the process is allowed to run on, wrapping around if
+    /// there are more workers than cores. Ignored outside of Linux.
+    pub pin_threads: bool,
+}
+
+impl Default for MultiConf {
+    fn default() -> Self {
+        Self {
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            pin_threads: false,
+        }
+    }
+}
+
+/// A signal shared by all workers started by [start_multi]: once triggered,
+/// every worker is expected to wind down. It's triggered automatically if a
+/// worker panics.
+#[derive(Clone, Default)]
+pub struct MultiShutdown(Arc<MultiShutdownState>);
+
+#[derive(Default)]
+struct MultiShutdownState {
+    triggered: AtomicBool,
+    notify: Notify,
+}
+
+impl MultiShutdown {
+    /// Wakes up every worker waiting on this signal, now and in the future.
+    /// Can be called from any thread.
+    pub fn trigger(&self) {
+        self.0.triggered.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Returns once the signal is triggered
+    pub async fn wait(&self) {
+        loop {
+            // register before checking, so we can't miss a notification
+            let notified = self.0.notify.notified();
+            if self.is_triggered() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// What a worker started by [start_multi] is told about itself
+pub struct Worker {
+    /// From 0 to `threads - 1`
+    pub index: usize,
+
+    /// Triggered when all workers should shut down
+    pub shutdown: MultiShutdown,
+}
+
+/// Worker threads started by [start_multi]
+pub struct Workers<T> {
+    shutdown: MultiShutdown,
+    threads: Vec<JoinHandle<T>>,
+}
+
+impl<T> Workers<T> {
+    /// Returns the shutdown signal shared by all workers
+    pub fn shutdown(&self) -> &MultiShutdown {
+        &self.shutdown
+    }
+
+    /// Waits for all workers to finish, and returns what they returned, in
+    /// worker order. If a worker panicked, the panic is propagated once all
+    /// other workers have finished.
+    pub fn join(self) -> Vec<T> {
+        let mut panic = None;
+        let mut outputs = Vec::with_capacity(self.threads.len());
+        for thread in self.threads {
+            match thread.join() {
+                Ok(output) => outputs.push(output),
+                Err(e) => {
+                    panic.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(panic) = panic {
+            std::panic::resume_unwind(panic);
+        }
+        outputs
+    }
+}
+
+/// Starts `threads` worker threads, each running the future returned by
+/// `factory` on its own runtime, like [crate::start] does.
+pub fn start_multi<F, Fut>(threads: usize, factory: F) -> std::io::Result<Workers<Fut::Output>>
+where
+    F: Fn(Worker) -> Fut + Send + Sync + 'static,
+    Fut: Future + 'static,
+    Fut::Output: Send + 'static,
+{
+    start_multi_with_conf(
+        MultiConf {
+            threads,
+            ..Default::default()
+        },
+        factory,
+    )
+}
+
+/// Like [start_multi], with more options.
+pub fn start_multi_with_conf<F, Fut>(
+    conf: MultiConf,
+    factory: F,
+) -> std::io::Result<Workers<Fut::Output>>
+where
+    F: Fn(Worker) -> Fut + Send + Sync + 'static,
+    Fut: Future + 'static,
+    Fut::Output: Send + 'static,
+{
+    let factory = Arc::new(factory);
+    let shutdown = MultiShutdown::default();
+    let cpus = if conf.pin_threads {
+        allowed_cpus()
+    } else {
+        Vec::new()
+    };
+
+    let mut threads = Vec::with_capacity(conf.threads);
+    for index in 0..conf.threads {
+        let factory = factory.clone();
+        let worker_shutdown = shutdown.clone();
+        let cpu = (!cpus.is_empty()).then(|| cpus[index % cpus.len()]);
+
+        let res = std::thread::Builder::new()
+            .name(format!("buffet-worker-{index}"))
+            .spawn(move || {
+                if let Some(cpu) = cpu {
+                    if let Err(e) = pin_current_thread(cpu) {
+                        tracing::warn!("could not pin worker {index} to cpu {cpu}: {e}");
+                    }
+                }
+
+                let _guard = ShutdownOnPanic(worker_shutdown.clone());
+                crate::start(factory(Worker {
+                    index,
+                    shutdown: worker_shutdown,
+                }))
+            });
+        match res {
+            Ok(thread) => threads.push(thread),
+            Err(e) => {
+                // don't leave the workers we did start running
+                shutdown.trigger();
+                for thread in threads {
+                    _ = thread.join();
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(Workers { shutdown, threads })
+}
+
+/// Shuts every worker down if the one that owns it panics
+struct ShutdownOnPanic(MultiShutdown);
+
+impl Drop for ShutdownOnPanic {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.trigger();
+        }
+    }
+}
+
+/// Returns the CPUs the current thread may run on
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> Vec<usize> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) };
+    if res != 0 {
+        tracing::warn!(
+            "could not get cpu affinity, not pinning workers: {}",
+            std::io::Error::last_os_error()
+        );
+        return Vec::new();
+    }
+    (0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cpus() -> Vec<usize> {
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpu: usize) -> std::io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    let res = unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_cpu: usize) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, not(feature = "miri")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_multi() {
+        use std::{sync::mpsc, time::Duration};
+
+        // find a free port
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let (bound_tx, bound_rx) = mpsc::channel();
+        let (accepted_tx, accepted_rx) = mpsc::channel();
+        let (exited_tx, exited_rx) = mpsc::channel();
+        let workers = start_multi_with_conf(
+            MultiConf {
+                threads: 2,
+                pin_threads: true,
+            },
+            move |worker| {
+                let bound_tx = bound_tx.clone();
+                let accepted_tx = accepted_tx.clone();
+                let exited_tx = exited_tx.clone();
+                async move {
+                    // every worker listens on the same port
+                    let listener = crate::net::TcpListener::bind(addr).await.unwrap();
+                    bound_tx.send(()).unwrap();
+
+                    // no accept is left in flight when the worker returns:
+                    // workers only notice the shutdown once they accept
+                    // another connection.
+                    let mut conns = Vec::new();
+                    loop {
+                        let (conn, _) = listener.accept().await.unwrap();
+                        if worker.shutdown.is_triggered() {
+                            break;
+                        }
+                        conns.push(conn);
+                        accepted_tx.send(()).unwrap();
+                    }
+                    exited_tx.send(()).unwrap();
+                    (worker.index, conns.len())
+                }
+            },
+        )
+        .unwrap();
+
+        for _ in 0..2 {
+            bound_rx.recv().unwrap();
+        }
+        let _conns: Vec<_> = (0..8)
+            .map(|_| std::net::TcpStream::connect(addr).unwrap())
+            .collect();
+        for _ in 0..8 {
+            accepted_rx.recv().unwrap();
+        }
+
+        // which worker gets a connection is up to the kernel: keep
+        // connecting until both have noticed the shutdown.
+        workers.shutdown().trigger();
+        let mut wake_ups = Vec::new();
+        let mut exited = 0;
+        while exited < 2 {
+            wake_ups.push(std::net::TcpStream::connect(addr).unwrap());
+            while exited_rx.recv_timeout(Duration::from_millis(10)).is_ok() {
+                exited += 1;
+            }
+        }
+
+        let outputs = workers.join();
+        assert_eq!(outputs.iter().map(|o| o.0).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(outputs.iter().map(|o| o.1).sum::<usize>(), 8);
+    }
+}
@@ -12,6 +12,11 @@ mod net_noring;
 #[cfg(not(all(target_os = "linux", feature = "uring")))]
 pub use net_noring::*;
 
+/// How many connections may wait to be accepted on a listener created with
+/// `TcpListener::bind`, past which the kernel refuses new ones. Use
+/// `TcpListener::bind_with_backlog` to pick another value.
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 256;
+
 impl IntoHalves for tokio::net::TcpStream {
     type Read = tokio::net::tcp::OwnedReadHalf;
     type Write = tokio::net::tcp::OwnedWriteHalf;
@@ -20,3 +25,38 @@ impl IntoHalves for tokio::net::TcpStream {
         self.into_split()
     }
 }
+
+/// Returns true if an idle connection looks usable: the peer hasn't closed it,
+/// and hasn't sent anything either (which, between two HTTP/1.1 requests, would
+/// be out of protocol). Never blocks, and never consumes data.
+#[cfg(unix)]
+pub fn is_idle_connection_usable(r: &TcpReadHalf) -> bool {
+    use std::os::fd::AsRawFd;
+
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    let fd = r.as_raw_fd();
+    #[cfg(not(all(target_os = "linux", feature = "uring")))]
+    let fd = r.as_ref().as_raw_fd();
+
+    let mut byte = 0u8;
+    let ret = unsafe {
+        libc::recv(
+            fd,
+            &mut byte as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    if ret >= 0 {
+        // either the peer closed the connection (0), or it sent something
+        return false;
+    }
+    std::io::Error::last_os_error().kind() == std::io::ErrorKind::WouldBlock
+}
+
+/// Returns true if an idle connection looks usable. There's no way to tell
+/// without reading from it on this platform, so this always returns true.
+#[cfg(not(unix))]
+pub fn is_idle_connection_usable(_r: &TcpReadHalf) -> bool {
+    true
+}
@@ -1,5 +1,5 @@
 use std::net::SocketAddr;
-use tokio::net::{TcpListener as TokListener, TcpStream as TokStream};
+use tokio::net::{TcpListener as TokListener, TcpSocket, TcpStream as TokStream};
 
 pub type TcpStream = TokStream;
 
@@ -12,7 +12,23 @@ pub struct TcpListener {
 
 impl TcpListener {
     pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
-        let tok = TokListener::bind(addr).await?;
+        Self::bind_with_backlog(addr, super::DEFAULT_LISTEN_BACKLOG).await
+    }
+
+    /// Like [TcpListener::bind], with at most `backlog` connections waiting
+    /// to be accepted
+    pub async fn bind_with_backlog(addr: SocketAddr, backlog: u32) -> std::io::Result<Self> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        // lets several threads listen on the same port, cf. `crate::start_multi`
+        #[cfg(unix)]
+        socket.set_reuseport(true)?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        let tok = socket.listen(backlog)?;
         Ok(Self { tok })
     }
 
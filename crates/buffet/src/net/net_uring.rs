@@ -5,7 +5,7 @@ use std::{
     rc::Rc,
 };
 
-use io_uring::opcode::{Accept, Read, Write};
+use io_uring::opcode::{Accept, Read, Splice, Write};
 use nix::errno::Errno;
 
 use crate::{
@@ -41,6 +41,18 @@ impl TcpStream {
         cqe.error_for_errno()?;
         Ok(Self { fd })
     }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        let socket = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(self.fd) });
+        let addr = socket.local_addr()?;
+        Ok(addr.as_socket().unwrap())
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        let socket = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(self.fd) });
+        let addr = socket.peer_addr()?;
+        Ok(addr.as_socket().unwrap())
+    }
 }
 
 impl Drop for TcpStream {
@@ -61,6 +73,12 @@ impl TcpListener {
     // note: this is only async to match tokio's API
     // TODO: investigate why tokio's TcpListener::bind is async
     pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        Self::bind_with_backlog(addr, super::DEFAULT_LISTEN_BACKLOG).await
+    }
+
+    /// Like [TcpListener::bind], with at most `backlog` connections waiting
+    /// to be accepted
+    pub async fn bind_with_backlog(addr: SocketAddr, backlog: u32) -> std::io::Result<Self> {
         let addr: socket2::SockAddr = addr.into();
         let socket = socket2::Socket::new(addr.domain(), socket2::Type::STREAM, None)?;
 
@@ -71,8 +89,7 @@ impl TcpListener {
         socket.set_reuse_address(true)?;
         socket.bind(&addr)?;
 
-        // FIXME: magic values
-        socket.listen(256)?;
+        socket.listen(backlog.try_into().unwrap_or(i32::MAX))?;
 
         let fd = socket.as_raw_fd();
         std::mem::forget(socket);
@@ -121,6 +138,12 @@ impl TcpListener {
 // the underlying fd, in-flight operations etc.
 pub struct TcpReadHalf(Rc<TcpStream>);
 
+impl AsRawFd for TcpReadHalf {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.fd
+    }
+}
+
 impl ReadOwned for TcpReadHalf {
     async fn read_owned<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
         let sqe = Read::new(
@@ -159,6 +182,60 @@ impl WriteOwned for TcpWriteHalf {
 
     // TODO: implement writev
 
+    /// Moves bytes from `file` to the socket through a pipe with `splice`,
+    /// so they never get copied to userspace.
+    async fn write_file_all(
+        &mut self,
+        file: &std::fs::File,
+        offset: u64,
+        len: u64,
+    ) -> std::io::Result<()> {
+        let pipe = SplicePipe::new()?;
+        let mut offset = offset;
+        let mut remain = len;
+        while remain > 0 {
+            let splice_len = std::cmp::min(remain, SplicePipe::CAPACITY as u64) as u32;
+            let sqe = Splice::new(
+                io_uring::types::Fd(file.as_raw_fd()),
+                offset.try_into().expect("u64 -> i64"),
+                io_uring::types::Fd(pipe.write_fd),
+                -1,
+                splice_len,
+            )
+            .build();
+            let n = get_ring().push(sqe).await.error_for_errno()? as u32;
+            if n == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+
+            // whatever made it into the pipe has to make it out before we can
+            // splice more in.
+            let mut in_pipe = n;
+            while in_pipe > 0 {
+                let sqe = Splice::new(
+                    io_uring::types::Fd(pipe.read_fd),
+                    -1,
+                    io_uring::types::Fd(self.0.fd),
+                    -1,
+                    in_pipe,
+                )
+                .build();
+                let written = get_ring().push(sqe).await.error_for_errno()? as u32;
+                if written == 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "write zero",
+                    ));
+                }
+                in_pipe -= written;
+            }
+
+            offset += n as u64;
+            remain -= n as u64;
+        }
+        Ok(())
+    }
+
     async fn shutdown(&mut self) -> std::io::Result<()> {
         tracing::debug!("requesting shutdown");
         let sqe =
@@ -185,6 +262,40 @@ impl FromRawFd for TcpStream {
     }
 }
 
+/// A pipe used as the intermediate buffer for `splice`: at least one end of
+/// a `splice` must be a pipe.
+struct SplicePipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl SplicePipe {
+    /// The default capacity of a pipe on Linux: splicing more than this from a
+    /// file only ever moves this much.
+    const CAPACITY: usize = 64 * 1024;
+
+    fn new() -> std::io::Result<Self> {
+        let mut fds = [0 as RawFd; 2];
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            read_fd: fds[0],
+            write_fd: fds[1],
+        })
+    }
+}
+
+impl Drop for SplicePipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
 trait CqueueExt {
     fn error_for_errno(&self) -> Result<i32, Errno>;
 }
@@ -250,4 +361,38 @@ mod tests {
         }
         crate::start(async move { test_accept_inner().await });
     }
+
+    #[test]
+    fn test_write_file_all() {
+        let contents: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("buffet-test-splice-{}", std::process::id()));
+        std::fs::write(&path, &contents).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        crate::start(async move {
+            let listener = super::TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let client = std::thread::spawn(move || {
+                use std::io::Read;
+
+                let mut sock = std::net::TcpStream::connect(addr).unwrap();
+                let mut received = vec![];
+                sock.read_to_end(&mut received).unwrap();
+                received
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let (_r, mut w) = stream.into_halves();
+            w.write_file_all(&file, 10, 150_000).await.unwrap();
+            w.shutdown().await.unwrap();
+
+            let received = client.join().unwrap();
+            assert_eq!(received.len(), 150_000);
+            assert!(received == contents[10..150_010]);
+        });
+    }
 }
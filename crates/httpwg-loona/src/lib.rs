@@ -1,12 +1,10 @@
 use b_x::{BxForResults, BX};
-use std::{cell::RefCell, rc::Rc};
 use tokio::{process::Command, sync::oneshot};
 
-use buffet::{IntoHalves, RollMut};
 use loona::{
     http::{self, StatusCode},
-    Body, BodyChunk, Encoder, ExpectResponseHeaders, Responder, Response, ResponseDone,
-    ServerDriver,
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Protocol, Responder, Response, ResponseDone,
+    Server, ServerDriver,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -74,69 +72,20 @@ pub fn do_main(port: u16, proto: Proto, mode: Mode) {
             }
         };
         let loop_fut = async move {
-            let num_conns = Rc::new(RefCell::new(0));
-            loop {
-                let num_conns = num_conns.clone();
-                tracing::debug!("Accepting...");
-                let before_accept = std::time::Instant::now();
-                let (stream, addr) = ln.accept().await.unwrap();
-
-                *num_conns.borrow_mut() += 1;
+            let protocol = match proto {
+                Proto::H1 => Protocol::H1,
+                Proto::H2 => Protocol::H2,
+            };
+            let mut server = Server::new(Default::default(), move |addr| {
                 tracing::debug!(
                     ?addr,
-                    "Accepted connection in {:?} ({:?} since start), total conns = {}",
-                    before_accept.elapsed(),
-                    server_start.elapsed(),
-                    num_conns.borrow()
+                    "Accepted connection ({:?} since start)",
+                    server_start.elapsed()
                 );
-
-                let conn_fut = async move {
-                    struct DecrementOnDrop(Rc<RefCell<usize>>);
-                    impl Drop for DecrementOnDrop {
-                        fn drop(&mut self) {
-                            let mut num_conns = self.0.borrow_mut();
-                            *num_conns -= 1;
-                        }
-                    }
-                    let _guard = DecrementOnDrop(num_conns);
-
-                    let client_buf = RollMut::alloc().unwrap();
-                    let io = stream.into_halves();
-
-                    match proto {
-                        Proto::H1 => {
-                            let driver = TestDriver;
-                            let server_conf = Rc::new(loona::h1::ServerConf {
-                                ..Default::default()
-                            });
-
-                            if let Err(e) =
-                                loona::h1::serve(io, server_conf, client_buf, driver).await
-                            {
-                                tracing::warn!("http/1 server error: {e:?}");
-                            }
-                            tracing::debug!("http/1 server done");
-                        }
-                        Proto::H2 => {
-                            let driver = Rc::new(TestDriver);
-                            let server_conf = Rc::new(loona::h2::ServerConf {
-                                ..Default::default()
-                            });
-
-                            if let Err(e) =
-                                loona::h2::serve(io, server_conf, client_buf, driver).await
-                            {
-                                tracing::warn!("http/2 server error: {e:?}");
-                            }
-                            tracing::debug!("http/2 server done");
-                        }
-                    }
-                };
-
-                let before_spawn = std::time::Instant::now();
-                buffet::spawn(conn_fut);
-                tracing::debug!("spawned connection in {:?}", before_spawn.elapsed());
-            }
+                TestDriver
+            });
+            server.add_listener(ln, protocol);
+            server.run().await
         };
 
         tokio::select! {
@@ -266,6 +266,28 @@ impl<'a> Encoder<'a> {
         Ok(())
     }
 
+    /// Encodes the given headers as literals that neither use nor update the
+    /// dynamic table (HPACK spec, section 6.2.2).
+    ///
+    /// The resulting header block doesn't depend on the state of the paired
+    /// decoder, and doesn't change it: it may be sent before or after blocks
+    /// encoded with `encode_into`, regardless of the order they were encoded
+    /// in.
+    pub fn encode_without_indexing_into<'b, I, W>(
+        &mut self,
+        headers: I,
+        writer: &mut W,
+    ) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (&'b [u8], &'b [u8])>,
+        W: io::Write,
+    {
+        for header in headers {
+            self.encode_literal(&header, false, writer)?;
+        }
+        Ok(())
+    }
+
     /// Encodes a header as a literal (i.e. both the name and the value are
     /// encoded as a string literal) and places the result in the given buffer
     /// `buf`.
@@ -429,6 +451,25 @@ mod tests {
         );
     }
 
+    /// Tests that headers encoded without indexing are neither looked up in
+    /// nor added to the dynamic table.
+    #[test]
+    fn test_encode_without_indexing() {
+        let mut encoder: Encoder = Encoder::new();
+        let headers = vec![(b"custom-key".to_vec(), b"custom-value".to_vec())];
+        let _ = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+
+        let mut result = Vec::new();
+        encoder
+            .encode_without_indexing_into(headers.iter().map(|h| (&h.0[..], &h.1[..])), &mut result)
+            .unwrap();
+
+        // A literal, not the index the first encoding added.
+        assert_eq!(result[0], 0x00);
+        assert!(is_decodable(&result, &headers));
+        assert_eq!(encoder.header_table.dynamic_table.to_vec(), headers);
+    }
+
     /// Tests that when a header name is indexed, but the value isn't, the
     /// header is represented by an index (for the name) and a literal (for
     /// the value).
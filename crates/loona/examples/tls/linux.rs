@@ -11,8 +11,8 @@ use http::Version;
 use ktls::CorkStream;
 use loona::{
     buffet::{net::TcpStream, IntoHalves, RollMut},
-    h1, h2, Body, Encoder, ExpectResponseHeaders, Method, Request, Responder, ResponseDone,
-    ServerDriver,
+    h1, h2, Body, ConnectionInfo, Encoder, ExpectResponseHeaders, Method, Request, Responder,
+    ResponseDone, ServerDriver, TlsInfo,
 };
 use rustls::{pki_types::PrivatePkcs8KeyDer, ServerConfig};
 use tokio::net::TcpListener;
@@ -143,17 +143,21 @@ async fn handle_plaintext_conn(
     info!("Accepted connection from {remote_addr}");
     let buf = RollMut::alloc()?;
 
+    let mut conn = ConnectionInfo::new();
+    conn.peer_addr = Some(remote_addr);
+    conn.local_addr = stream.local_addr().ok();
+
     let stream = stream.to_uring_tcp_stream()?;
     let driver = SDriver {};
 
     match proto {
         Proto::H1(h1_conf) => {
             info!("Using HTTP/1.1");
-            loona::h1::serve(stream.into_halves(), h1_conf, buf, driver).await?;
+            loona::h1::serve(stream.into_halves(), h1_conf, buf, driver, conn).await?;
         }
         Proto::H2(h2_conf) => {
             info!("Using HTTP/2");
-            loona::h2::serve(stream.into_halves(), h2_conf, buf, Rc::new(driver)).await?;
+            loona::h2::serve(stream.into_halves(), h2_conf, buf, Rc::new(driver), conn).await?;
         }
     }
 
@@ -168,6 +172,10 @@ async fn handle_tls_conn(
     h2_conf: Rc<h2::ServerConf>,
 ) -> b_x::Result<()> {
     info!("Accepted connection from {remote_addr}");
+    let mut conn = ConnectionInfo::new();
+    conn.peer_addr = Some(remote_addr);
+    conn.local_addr = stream.local_addr().ok();
+
     let stream = CorkStream::new(stream);
     let stream = acceptor.accept(stream).await?;
 
@@ -176,6 +184,10 @@ async fn handle_tls_conn(
         .alpn_protocol()
         .and_then(|p| std::str::from_utf8(p).ok().map(|s| s.to_string()));
     debug!(?alpn_proto, "Performed TLS handshake");
+    conn.tls = Some(TlsInfo {
+        alpn_protocol: sc.alpn_protocol().map(|p| p.to_vec()),
+        server_name: sc.server_name().map(|s| s.to_owned()),
+    });
 
     let stream = ktls::config_ktls_server(stream).await.bx()?;
 
@@ -194,11 +206,11 @@ async fn handle_tls_conn(
     match alpn_proto.as_deref() {
         Some("h2") => {
             info!("Using HTTP/2");
-            loona::h2::serve(stream.into_halves(), h2_conf, buf, Rc::new(driver)).await?;
+            loona::h2::serve(stream.into_halves(), h2_conf, buf, Rc::new(driver), conn).await?;
         }
         Some("http/1.1") | None => {
             info!("Using HTTP/1.1");
-            loona::h1::serve(stream.into_halves(), h1_conf, buf, driver).await?;
+            loona::h1::serve(stream.into_halves(), h1_conf, buf, driver, conn).await?;
         }
         Some(other) => {
             b_x::bail!("Unsupported ALPN protocol: {}", other)
@@ -235,8 +247,14 @@ where
 
         req.version = Version::HTTP_11;
         req.headers.insert("host", "httpbingo.org".into());
-        let (transport, respond) =
-            h1::request(transport.into_halves(), req, req_body, driver).await?;
+        let (transport, respond) = h1::request(
+            transport.into_halves(),
+            &h1::ClientConf::default(),
+            req,
+            req_body,
+            driver,
+        )
+        .await?;
 
         // don't re-use transport for now
         drop(transport);
@@ -343,10 +361,17 @@ async fn sample_http_request() -> b_x::Result<()> {
         method: Method::Get,
         uri: "http://httpbingo.org/image/jpeg".parse().unwrap(),
         version: Version::HTTP_11,
-        headers: Default::default(),
+        ..Default::default()
     };
 
-    let (transport, _) = h1::request(transport.into_halves(), req, &mut (), driver).await?;
+    let (transport, _) = h1::request(
+        transport.into_halves(),
+        &h1::ClientConf::default(),
+        req,
+        &mut (),
+        driver,
+    )
+    .await?;
     // don't re-use transport for now
     drop(transport);
 
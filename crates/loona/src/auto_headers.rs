@@ -0,0 +1,254 @@
+//! Headers servers add to responses on their own: `date` and `server`.
+
+use std::{
+    cell::RefCell,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use buffet::{Piece, RollMut};
+use http::header;
+
+use crate::Headers;
+
+/// What to add to final responses, unless the handler already set it
+#[derive(Clone, Default)]
+pub(crate) struct AutoHeaders {
+    pub(crate) date: bool,
+    pub(crate) server: Option<Piece>,
+}
+
+impl AutoHeaders {
+    pub(crate) fn apply(&self, headers: &mut Headers) {
+        if self.date && !headers.contains_key(header::DATE) {
+            headers.insert(header::DATE, http_date_now());
+        }
+        if let Some(server) = &self.server {
+            if !headers.contains_key(header::SERVER) {
+                headers.insert(header::SERVER, server.clone());
+            }
+        }
+    }
+}
+
+/// Length of an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+const HTTP_DATE_LEN: usize = 29;
+
+struct DateCache {
+    secs: u64,
+    date: Piece,
+    // the formatted dates are carved out of this buffer
+    scratch: Option<RollMut>,
+}
+
+thread_local! {
+    static DATE_CACHE: RefCell<Option<DateCache>> = const { RefCell::new(None) };
+}
+
+/// Returns the current date, formatted for the `date` header. It's formatted at
+/// most once per second per thread.
+pub(crate) fn http_date_now() -> Piece {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    DATE_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if let Some(cache) = cache.as_ref().filter(|c| c.secs == secs) {
+            return cache.date.clone();
+        }
+
+        let mut scratch = cache.take().and_then(|c| c.scratch);
+        let formatted = format_http_date(secs);
+        let date = match put_in_scratch(&mut scratch, &formatted) {
+            Some(date) => date,
+            // the buffer pool isn't available (or exhausted), heap-allocate
+            None => formatted.to_vec().into(),
+        };
+        *cache = Some(DateCache {
+            secs,
+            date: date.clone(),
+            scratch,
+        });
+        date
+    })
+}
+
+fn put_in_scratch(scratch: &mut Option<RollMut>, bytes: &[u8]) -> Option<Piece> {
+    if scratch.as_ref().map_or(true, |s| s.cap() < bytes.len()) {
+        *scratch = Some(RollMut::alloc().ok()?);
+    }
+    let scratch = scratch.as_mut()?;
+    scratch.put(bytes).ok()?;
+    Some(scratch.take_all().into())
+}
+
+const MONTHS: [&[u8; 3]; 12] = [
+    b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec",
+];
+
+/// Formats a unix timestamp as an IMF-fixdate, cf. RFC 9110, section 5.6.7
+pub(crate) fn format_http_date(secs: u64) -> [u8; HTTP_DATE_LEN] {
+    const WEEKDAYS: [&[u8; 3]; 7] = [b"Sun", b"Mon", b"Tue", b"Wed", b"Thu", b"Fri", b"Sat"];
+
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    // 1970-01-01 was a Thursday
+    let weekday = (days + 4) % 7;
+    let (year, month, day) = civil_from_days(days);
+
+    let mut out = *b"Thu, 01 Jan 1970 00:00:00 GMT";
+    out[0..3].copy_from_slice(WEEKDAYS[weekday as usize]);
+    put_2_digits(&mut out[5..7], day);
+    out[8..11].copy_from_slice(MONTHS[month as usize - 1]);
+    put_2_digits(&mut out[12..14], year / 100);
+    put_2_digits(&mut out[14..16], year % 100);
+    put_2_digits(&mut out[17..19], secs_of_day / 3600);
+    put_2_digits(&mut out[20..22], secs_of_day / 60 % 60);
+    put_2_digits(&mut out[23..25], secs_of_day % 60);
+    out
+}
+
+fn put_2_digits(out: &mut [u8], n: u64) {
+    out[0] = b'0' + (n / 10 % 10) as u8;
+    out[1] = b'0' + (n % 10) as u8;
+}
+
+/// Turns days since the unix epoch into a (year, month, day) date, cf.
+/// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Parses an HTTP-date into a unix timestamp. All three formats are accepted,
+/// as recipients must, cf. RFC 9110, section 5.6.7:
+///
+///   - `Sun, 06 Nov 1994 08:49:37 GMT` (IMF-fixdate)
+///   - `Sunday, 06-Nov-94 08:49:37 GMT` (obsolete RFC 850 format)
+///   - `Sun Nov  6 08:49:37 1994` (ANSI C's asctime() format)
+pub(crate) fn parse_http_date(input: &[u8]) -> Option<u64> {
+    let input = std::str::from_utf8(input).ok()?;
+
+    let (day, month, year, time) = match input.split_once(", ") {
+        Some((_weekday, rest)) => match rest.split(' ').collect::<Vec<_>>()[..] {
+            [day, month, year, time, "GMT"] if year.len() == 4 => {
+                (day, month, parse_digits(year)?, time)
+            }
+            [date, time, "GMT"] => match date.split('-').collect::<Vec<_>>()[..] {
+                [day, month, year] if year.len() == 2 => {
+                    // two-digit years that would be more than 50 years in the
+                    // future are in the past, close enough.
+                    let year = parse_digits(year)?;
+                    (
+                        day,
+                        month,
+                        if year < 70 { 2000 + year } else { 1900 + year },
+                        time,
+                    )
+                }
+                _ => return None,
+            },
+            _ => return None,
+        },
+        None => match input
+            .split(' ')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()[..]
+        {
+            [_weekday, month, day, time, year] if year.len() == 4 => {
+                (day, month, parse_digits(year)?, time)
+            }
+            _ => return None,
+        },
+    };
+
+    let month = MONTHS.iter().position(|m| m[..] == *month.as_bytes())? as u64 + 1;
+    let day = parse_digits(day).filter(|d| (1..=31).contains(d))?;
+    let (hours, minutes, seconds) = match time.split(':').collect::<Vec<_>>()[..] {
+        [h, m, s] if h.len() == 2 && m.len() == 2 && s.len() == 2 => {
+            (parse_digits(h)?, parse_digits(m)?, parse_digits(s)?)
+        }
+        _ => return None,
+    };
+    if year < 1970 || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+fn parse_digits(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Turns a (year, month, day) date into days since the unix epoch, cf.
+/// <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+/// Only valid for years since 1970.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_http_date, parse_http_date};
+
+    #[test]
+    fn test_parse_http_date() {
+        for input in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(
+                parse_http_date(input.as_bytes()),
+                Some(784111777),
+                "{input}"
+            );
+        }
+        for secs in [0, 951782400, 1735689599] {
+            assert_eq!(parse_http_date(&format_http_date(secs)), Some(secs));
+        }
+        for input in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Nov 1994 8:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sun, 06 Nov +994 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(input.as_bytes()), None, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_format_http_date() {
+        for (secs, expected) in [
+            (0, "Thu, 01 Jan 1970 00:00:00 GMT"),
+            (784111777, "Sun, 06 Nov 1994 08:49:37 GMT"),
+            (951782400, "Tue, 29 Feb 2000 00:00:00 GMT"),
+            (1735689599, "Tue, 31 Dec 2024 23:59:59 GMT"),
+        ] {
+            assert_eq!(
+                std::str::from_utf8(&format_http_date(secs)).unwrap(),
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,576 @@
+//! Ready-made [Body] implementations, and adapters for existing ones.
+
+use std::{collections::VecDeque, fmt, fs::File, pin::Pin, rc::Rc};
+
+use buffet::{bufpool::BufMut, Piece, PieceList, Roll};
+use futures_util::{Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::{error::NeverError, Body, BodyChunk, Headers};
+
+/// A body that's entirely in memory, with a known length
+pub struct Full {
+    pieces: VecDeque<Piece>,
+    content_len: u64,
+    trailers: Option<Box<Headers>>,
+}
+
+impl Full {
+    pub fn new(pieces: impl Into<PieceList>) -> Self {
+        let pieces: PieceList = pieces.into();
+        let content_len = pieces.len() as u64;
+        Self {
+            pieces: pieces.into_vec_deque(),
+            content_len,
+            trailers: None,
+        }
+    }
+
+    /// Ends the body with the given trailers
+    pub fn with_trailers(mut self, trailers: Box<Headers>) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+}
+
+impl From<Piece> for Full {
+    fn from(piece: Piece) -> Self {
+        Self::new(PieceList::single(piece))
+    }
+}
+
+impl From<PieceList> for Full {
+    fn from(pieces: PieceList) -> Self {
+        Self::new(pieces)
+    }
+}
+
+impl fmt::Debug for Full {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Full")
+            .field("content_len", &self.content_len)
+            .field("pieces_left", &self.pieces.len())
+            .finish()
+    }
+}
+
+impl Body for Full {
+    type Error = NeverError;
+
+    fn content_len(&self) -> Option<u64> {
+        Some(self.content_len)
+    }
+
+    fn eof(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        Ok(match self.pieces.pop_front() {
+            Some(piece) => BodyChunk::Chunk(piece),
+            None => BodyChunk::Done {
+                trailers: self.trailers.take(),
+            },
+        })
+    }
+}
+
+/// A region of an open file, cf. [Body::take_file]
+#[derive(Debug, Clone)]
+pub struct FileRegion {
+    pub file: Rc<File>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A body read from a file. Over plaintext HTTP/1.1, with the io_uring
+/// backend, it goes from the file to the socket with `splice`, without being
+/// copied through userspace. Everywhere else, it's read into pool buffers.
+#[derive(Debug)]
+pub struct FileBody {
+    content_len: u64,
+    // `None` once the encoder took it
+    region: Option<FileRegion>,
+}
+
+impl FileBody {
+    /// The whole file
+    pub fn new(file: File) -> std::io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self::with_range(file, 0, len))
+    }
+
+    /// `len` bytes of the file, starting at `offset`. If the file turns out to
+    /// be shorter than that, reading the body fails.
+    pub fn with_range(file: impl Into<Rc<File>>, offset: u64, len: u64) -> Self {
+        Self {
+            content_len: len,
+            region: Some(FileRegion {
+                file: file.into(),
+                offset,
+                len,
+            }),
+        }
+    }
+}
+
+impl Body for FileBody {
+    type Error = std::io::Error;
+
+    fn content_len(&self) -> Option<u64> {
+        Some(self.content_len)
+    }
+
+    fn eof(&self) -> bool {
+        self.region.as_ref().map_or(true, |region| region.len == 0)
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        let region = match &mut self.region {
+            Some(region) if region.len > 0 => region,
+            _ => return Ok(BodyChunk::Done { trailers: None }),
+        };
+
+        let mut buf = BufMut::alloc().map_err(std::io::Error::other)?;
+        let read_len = std::cmp::min(region.len, buf.len() as u64) as usize;
+        let n = buffet::read_file_at(&region.file, &mut buf[..read_len], region.offset)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        region.offset += n as u64;
+        region.len -= n as u64;
+
+        let roll: Roll = buf.freeze().into();
+        Ok(BodyChunk::Chunk(roll.slice(..n).into()))
+    }
+
+    fn take_file(&mut self) -> Option<FileRegion> {
+        self.region.take()
+    }
+}
+
+enum ChannelMessage {
+    Chunk(Piece),
+    Done(Option<Box<Headers>>),
+}
+
+/// Returns a body that yields whatever is sent through the returned
+/// [BodySender], e.g. from another task. At most `buffer` chunks are held
+/// before [BodySender::send] waits for the body to be read.
+///
+/// Panics if `buffer` is 0.
+pub fn channel(buffer: usize) -> (BodySender, ChannelBody) {
+    let (tx, rx) = mpsc::channel(buffer);
+    (
+        BodySender { tx },
+        ChannelBody {
+            rx,
+            content_len: None,
+            eof: false,
+        },
+    )
+}
+
+/// Feeds a [ChannelBody]. The body must be ended with [BodySender::finish]:
+/// if the sender is dropped before that, the body errors out.
+pub struct BodySender {
+    tx: mpsc::Sender<ChannelMessage>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("the body was dropped")]
+pub struct BodySendError;
+
+impl BodySender {
+    pub async fn send(&self, chunk: impl Into<Piece>) -> Result<(), BodySendError> {
+        self.tx
+            .send(ChannelMessage::Chunk(chunk.into()))
+            .await
+            .map_err(|_| BodySendError)
+    }
+
+    /// Ends the body, optionally with trailers
+    pub async fn finish(self, trailers: Option<Box<Headers>>) -> Result<(), BodySendError> {
+        self.tx
+            .send(ChannelMessage::Done(trailers))
+            .await
+            .map_err(|_| BodySendError)
+    }
+}
+
+/// The receiving end of [channel]
+pub struct ChannelBody {
+    rx: mpsc::Receiver<ChannelMessage>,
+    content_len: Option<u64>,
+    eof: bool,
+}
+
+impl ChannelBody {
+    /// Announces the length of the body, e.g. so it's sent with
+    /// `content-length` rather than chunked transfer-encoding
+    pub fn with_content_len(mut self, content_len: u64) -> Self {
+        self.content_len = Some(content_len);
+        self
+    }
+}
+
+impl fmt::Debug for ChannelBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelBody")
+            .field("content_len", &self.content_len)
+            .field("eof", &self.eof)
+            .finish()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ChannelBodyError {
+    #[error("the body sender was dropped before finishing the body")]
+    SenderDropped,
+}
+
+impl Body for ChannelBody {
+    type Error = ChannelBodyError;
+
+    fn content_len(&self) -> Option<u64> {
+        self.content_len
+    }
+
+    fn eof(&self) -> bool {
+        self.eof
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        if self.eof {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+        match self.rx.recv().await {
+            Some(ChannelMessage::Chunk(chunk)) => Ok(BodyChunk::Chunk(chunk)),
+            Some(ChannelMessage::Done(trailers)) => {
+                self.eof = true;
+                Ok(BodyChunk::Done { trailers })
+            }
+            None => Err(ChannelBodyError::SenderDropped),
+        }
+    }
+}
+
+/// Turns a stream of pieces into a body of unknown length
+pub struct StreamBody<S> {
+    stream: Pin<Box<S>>,
+    eof: bool,
+}
+
+impl<S> StreamBody<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: Box::pin(stream),
+            eof: false,
+        }
+    }
+}
+
+impl<S> fmt::Debug for StreamBody<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamBody")
+            .field("eof", &self.eof)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, E> Body for StreamBody<S>
+where
+    S: Stream<Item = Result<Piece, E>>,
+    E: std::error::Error + 'static,
+{
+    type Error = E;
+
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.eof
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        if self.eof {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+        match self.stream.next().await {
+            Some(chunk) => Ok(BodyChunk::Chunk(chunk?)),
+            None => {
+                self.eof = true;
+                Ok(BodyChunk::Done { trailers: None })
+            }
+        }
+    }
+}
+
+/// Adapters available on every [Body]
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait BodyExt: Body {
+    /// Errors out once more than `limit` bytes were read, cf. [Limited]
+    fn limited(self, limit: u64) -> Limited<Self> {
+        Limited::new(self, limit)
+    }
+
+    /// Passes every chunk through `f`. The resulting body has an unknown
+    /// length, since `f` may change the length of chunks.
+    fn map_chunks<F>(self, f: F) -> MapChunks<Self, F>
+    where
+        F: FnMut(Piece) -> Piece,
+    {
+        MapChunks { inner: self, f }
+    }
+
+    /// Reads the whole body into memory. Errors out if it's longer than
+    /// `max_len` bytes.
+    async fn collect(&mut self, max_len: u64) -> Result<Collected, CollectError<Self::Error>> {
+        if let Some(content_len) = self.content_len() {
+            if content_len > max_len {
+                return Err(CollectError::TooLarge { max_len });
+            }
+        }
+
+        let mut body = PieceList::default();
+        let mut len = 0;
+        loop {
+            match self.next_chunk().await.map_err(CollectError::Body)? {
+                BodyChunk::Chunk(chunk) => {
+                    len += chunk.len() as u64;
+                    if len > max_len {
+                        return Err(CollectError::TooLarge { max_len });
+                    }
+                    body.push_back(chunk);
+                }
+                BodyChunk::Done { trailers } => return Ok(Collected { body, trailers }),
+            }
+        }
+    }
+}
+
+impl<B: Body> BodyExt for B {}
+
+/// A body read by [BodyExt::collect]
+pub struct Collected {
+    pub body: PieceList,
+    pub trailers: Option<Box<Headers>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum CollectError<BodyError> {
+    #[error("body is larger than {max_len} bytes")]
+    TooLarge { max_len: u64 },
+
+    #[error("body error: {0}")]
+    Body(BodyError),
+}
+
+/// Errors out once the inner body yielded more than a given number of bytes,
+/// or right away if it announced a longer length.
+#[derive(Debug)]
+pub struct Limited<B> {
+    inner: B,
+    limit: u64,
+    read: u64,
+}
+
+impl<B: Body> Limited<B> {
+    pub fn new(inner: B, limit: u64) -> Self {
+        Self {
+            inner,
+            limit,
+            read: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum LimitedError<BodyError> {
+    #[error("body is larger than the limit of {limit} bytes")]
+    TooLarge { limit: u64 },
+
+    #[error("body error: {0}")]
+    Body(BodyError),
+}
+
+impl<B: Body> Body for Limited<B> {
+    type Error = LimitedError<B::Error>;
+
+    fn content_len(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        let too_large = LimitedError::TooLarge { limit: self.limit };
+        if self.inner.content_len().is_some_and(|len| len > self.limit) {
+            return Err(too_large);
+        }
+
+        let chunk = self.inner.next_chunk().await.map_err(LimitedError::Body)?;
+        if let BodyChunk::Chunk(chunk) = &chunk {
+            self.read += chunk.len() as u64;
+            if self.read > self.limit {
+                return Err(too_large);
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+/// Cf. [BodyExt::map_chunks]
+pub struct MapChunks<B, F> {
+    inner: B,
+    f: F,
+}
+
+impl<B: fmt::Debug, F> fmt::Debug for MapChunks<B, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MapChunks")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B, F> Body for MapChunks<B, F>
+where
+    B: Body,
+    F: FnMut(Piece) -> Piece,
+{
+    type Error = B::Error;
+
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        Ok(match self.inner.next_chunk().await? {
+            BodyChunk::Chunk(chunk) => BodyChunk::Chunk((self.f)(chunk)),
+            done => done,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_full_and_collect() {
+        let mut pieces = PieceList::default();
+        pieces.push_back(&b"hello "[..]);
+        pieces.push_back(&b"world"[..]);
+        let mut body = Full::from(pieces);
+        assert_eq!(body.content_len(), Some(11));
+
+        let collected = body.collect(11).await.unwrap();
+        assert_eq!(collected.body.len(), 11);
+        assert!(body.eof());
+
+        let mut body = Full::from(Piece::from(&b"hello world"[..]));
+        assert!(matches!(
+            body.collect(10).await,
+            Err(CollectError::TooLarge { max_len: 10 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_channel() {
+        let (tx, mut body) = channel(1);
+        let send = async move {
+            tx.send(&b"hello"[..]).await.unwrap();
+            tx.send(&b" world"[..]).await.unwrap();
+            tx.finish(None).await.unwrap();
+        };
+        let (collected, _) = tokio::join!(body.collect(1024), send);
+        let collected = collected.unwrap();
+        let bytes: Vec<u8> = collected
+            .body
+            .into_vec_deque()
+            .iter()
+            .flat_map(|piece| piece.to_vec())
+            .collect();
+        assert_eq!(bytes, b"hello world");
+
+        // the sender went away without finishing the body
+        let (tx, mut body) = channel(1);
+        drop(tx);
+        assert!(matches!(
+            body.next_chunk().await,
+            Err(ChannelBodyError::SenderDropped)
+        ));
+    }
+
+    #[test]
+    fn test_file_body() {
+        let path =
+            std::env::temp_dir().join(format!("loona-test-file-body-{}", std::process::id()));
+        std::fs::write(&path, b"hello world").unwrap();
+        let file = Rc::new(File::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        buffet::start(async move {
+            let mut body = FileBody::with_range(file.clone(), 6, 5);
+            assert_eq!(body.content_len(), Some(5));
+            let collected = body.collect(1024).await.unwrap();
+            assert!(collected.body.into_vec_deque()[0] == b"world");
+            assert!(body.eof());
+
+            // the encoder can take the file instead
+            let mut body = FileBody::with_range(file.clone(), 0, 5);
+            let region = body.take_file().unwrap();
+            assert_eq!((region.offset, region.len), (0, 5));
+            assert!(matches!(
+                body.next_chunk().await,
+                Ok(BodyChunk::Done { trailers: None })
+            ));
+
+            // the file is shorter than announced
+            let mut body = FileBody::with_range(file, 6, 10);
+            assert!(matches!(
+                body.collect(1024).await,
+                Err(CollectError::Body(_))
+            ));
+        });
+    }
+
+    #[tokio::test]
+    async fn test_limited_and_map_chunks() {
+        let chunks: Vec<Result<Piece, NeverError>> =
+            vec![Ok((&b"abc"[..]).into()), Ok((&b"def"[..]).into())];
+        let mut body = StreamBody::new(futures_util::stream::iter(chunks))
+            .map_chunks(|chunk| chunk.to_ascii_uppercase().into())
+            .limited(4);
+        assert!(matches!(
+            body.next_chunk().await,
+            Ok(BodyChunk::Chunk(chunk)) if &chunk[..] == b"ABC"
+        ));
+        assert!(matches!(
+            body.next_chunk().await,
+            Err(LimitedError::TooLarge { limit: 4 })
+        ));
+
+        // bodies that announce a length past the limit fail right away
+        let mut body = Full::from(Piece::from(&b"abcdef"[..])).limited(4);
+        assert!(matches!(
+            body.next_chunk().await,
+            Err(LimitedError::TooLarge { limit: 4 })
+        ));
+    }
+}
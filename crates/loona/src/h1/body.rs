@@ -1,21 +1,34 @@
 use std::fmt;
 
+use http::header;
 use tracing::debug;
 
-use crate::{util::read_and_parse, Body, BodyChunk, BodyError};
+use crate::{
+    body::FileRegion, types::from_digits, util::read_and_parse, Body, BodyChunk, BodyError,
+    Headers, HeadersExt,
+};
 use buffet::{Piece, PieceList, ReadOwned, RollMut, WriteOwned};
 
-/// An HTTP/1.1 body, either chunked or content-length.
+use super::encode::encode_headers;
+
+/// Max length of the trailer section of a chunked body
+const MAX_TRAILERS_LEN: usize = 16 * 1024;
+
+/// An HTTP/1.1 body: chunked, content-length, or delimited by the connection
+/// closing.
 pub(crate) struct H1Body<T> {
     transport_r: T,
     buf: Option<RollMut>,
     state: Decoder,
+    max_len: Option<u64>,
+    read: u64,
 }
 
 #[derive(Debug)]
 enum Decoder {
     Chunked(ChunkedDecoder),
     ContentLength(ContentLengthDecoder),
+    CloseDelimited(CloseDelimitedDecoder),
 }
 
 #[derive(Debug)]
@@ -33,10 +46,35 @@ struct ContentLengthDecoder {
     read: u64,
 }
 
+#[derive(Debug)]
+struct CloseDelimitedDecoder {
+    // We've read EOF from the transport
+    done: bool,
+}
+
 #[derive(Debug)]
 pub(crate) enum H1BodyKind {
     Chunked,
     ContentLength(u64),
+    // Only valid for responses, cf. RFC 9112, section 6.3
+    CloseDelimited,
+}
+
+/// Parses the `content-length` header(s): a list of identical values is fine,
+/// anything else isn't, cf. RFC 9112, section 6.3
+pub(crate) fn content_length(headers: &Headers) -> Result<Option<u64>, &'static str> {
+    if !headers.contains_key(header::CONTENT_LENGTH) {
+        return Ok(None);
+    }
+
+    let mut len = None;
+    for part in headers.list(header::CONTENT_LENGTH) {
+        let part_len = from_digits(part).ok_or("invalid content-length")?;
+        if *len.get_or_insert(part_len) != part_len {
+            return Err("conflicting content-length values");
+        }
+    }
+    len.map(Some).ok_or("invalid content-length")
 }
 
 impl<T> fmt::Debug for H1Body<T> {
@@ -54,14 +92,25 @@ impl<T: ReadOwned> H1Body<T> {
             H1BodyKind::ContentLength(len) => {
                 Decoder::ContentLength(ContentLengthDecoder { len, read: 0 })
             }
+            H1BodyKind::CloseDelimited => {
+                Decoder::CloseDelimited(CloseDelimitedDecoder { done: false })
+            }
         };
         H1Body {
             transport_r,
             buf: Some(buf),
             state,
+            max_len: None,
+            read: 0,
         }
     }
 
+    /// Errors out once more than `max_len` bytes of body were read
+    pub(crate) fn with_max_len(mut self, max_len: Option<u64>) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
     /// Returns the inner buffer and transport, but only if the body has been
     /// fully read.
     pub(crate) fn into_inner(self) -> Option<(RollMut, T)> {
@@ -80,6 +129,7 @@ impl<OurReadOwned: ReadOwned> Body for H1Body<OurReadOwned> {
         match &self.state {
             Decoder::Chunked(_) => None,
             Decoder::ContentLength(state) => Some(state.len),
+            Decoder::CloseDelimited(_) => None,
         }
     }
 
@@ -88,18 +138,33 @@ impl<OurReadOwned: ReadOwned> Body for H1Body<OurReadOwned> {
             return Ok(BodyChunk::Done { trailers: None });
         }
 
-        match &mut self.state {
+        let chunk = match &mut self.state {
             Decoder::Chunked(state) => state.next_chunk(&mut self.buf, &mut self.transport_r).await,
             Decoder::ContentLength(state) => {
                 state.next_chunk(&mut self.buf, &mut self.transport_r).await
             }
+            Decoder::CloseDelimited(state) => {
+                state.next_chunk(&mut self.buf, &mut self.transport_r).await
+            }
+        }?;
+
+        if let (BodyChunk::Chunk(chunk), Some(limit)) = (&chunk, self.max_len) {
+            self.read += chunk.len() as u64;
+            if self.read > limit {
+                // the rest of the body is never read, so the connection can't
+                // be reused
+                self.buf = None;
+                return Err(BodyError::BodyTooLarge { limit });
+            }
         }
+        Ok(chunk)
     }
 
     fn eof(&self) -> bool {
         match &self.state {
             Decoder::Chunked(state) => state.eof(),
             Decoder::ContentLength(state) => state.eof(),
+            Decoder::CloseDelimited(state) => state.done,
         }
     }
 }
@@ -142,6 +207,41 @@ impl ContentLengthDecoder {
     }
 }
 
+impl CloseDelimitedDecoder {
+    async fn next_chunk(
+        &mut self,
+        buf_slot: &mut Option<RollMut>,
+        transport: &mut impl ReadOwned,
+    ) -> Result<BodyChunk, BodyError> {
+        if self.done {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        let mut buf = buf_slot
+            .take()
+            .ok_or(BodyError::CalledNextChunkAfterError)?;
+
+        if buf.is_empty() {
+            buf.reserve()?;
+
+            let res;
+            (res, buf) = buf.read_into(usize::MAX, transport).await;
+            res.map_err(BodyError::ErrorWhileReadingChunkData)?;
+        }
+
+        let chunk = buf.take_at_most(usize::MAX);
+        buf_slot.replace(buf);
+        match chunk {
+            Some(chunk) => Ok(BodyChunk::Chunk(chunk.into())),
+            None => {
+                debug!("close-delimited body done");
+                self.done = true;
+                Ok(BodyChunk::Done { trailers: None })
+            }
+        }
+    }
+}
+
 impl ChunkedDecoder {
     async fn next_chunk(
         &mut self,
@@ -174,13 +274,14 @@ impl ChunkedDecoder {
                 buf = next_buf;
 
                 if chunk_size == 0 {
-                    // that's the final chunk, look for the final CRLF
-                    let (next_buf, _) = read_and_parse(
-                        "Http1BodyChunkFinalTerminator",
-                        super::parse::crlf,
+                    // that's the final chunk: it's followed by trailers, if
+                    // any, then by the final CRLF
+                    let (next_buf, trailers) = read_and_parse(
+                        "Http1BodyTrailers",
+                        super::parse::headers_and_crlf,
                         transport,
                         buf,
-                        2,
+                        MAX_TRAILERS_LEN,
                     )
                     .await
                     .map_err(BodyError::InvalidChunkTerminator)?
@@ -189,8 +290,8 @@ impl ChunkedDecoder {
                     *self = ChunkedDecoder::Done;
                     buf_slot.replace(buf);
 
-                    // TODO: trailers
-                    return Ok(BodyChunk::Done { trailers: None });
+                    let trailers = (!trailers.is_empty()).then(|| Box::new(trailers));
+                    return Ok(BodyChunk::Done { trailers });
                 }
 
                 *self = ChunkedDecoder::ReadingChunk { remain: chunk_size }
@@ -284,10 +385,13 @@ where
             .map_err(WriteBodyError::InnerBodyError)?
         {
             BodyChunk::Chunk(chunk) => write_h1_body_chunk(transport, chunk, mode).await?,
-            BodyChunk::Done { .. } => {
+            BodyChunk::Done { trailers } => {
                 // TODO: check that we've sent what we announced in terms of
                 // content length
-                write_h1_body_end(transport, mode).await?;
+                match trailers {
+                    Some(trailers) => write_h1_trailers(transport, trailers, mode).await?,
+                    None => write_h1_body_end(transport, mode).await?,
+                }
                 break;
             }
         }
@@ -301,6 +405,11 @@ pub(crate) async fn write_h1_body_chunk(
     chunk: Piece,
     mode: BodyWriteMode,
 ) -> Result<(), BodyError> {
+    if chunk.is_empty() {
+        // for chunked transfer-encoding, an empty chunk would end the body
+        return Ok(());
+    }
+
     match mode {
         BodyWriteMode::Chunked => {
             transport
@@ -326,6 +435,44 @@ pub(crate) async fn write_h1_body_chunk(
     Ok(())
 }
 
+pub(crate) async fn write_h1_body_file(
+    transport: &mut impl WriteOwned,
+    region: FileRegion,
+    mode: BodyWriteMode,
+) -> Result<(), BodyError> {
+    if region.len == 0 {
+        // for chunked transfer-encoding, an empty chunk would end the body
+        return Ok(());
+    }
+
+    match mode {
+        BodyWriteMode::Chunked => {
+            transport
+                .write_all_owned(format!("{:x}\r\n", region.len).into_bytes())
+                .await
+                .map_err(BodyError::WriteError)?;
+            transport
+                .write_file_all(&region.file, region.offset, region.len)
+                .await
+                .map_err(BodyError::WriteError)?;
+            transport
+                .write_all_owned("\r\n")
+                .await
+                .map_err(BodyError::WriteError)?;
+        }
+        BodyWriteMode::ContentLength(_) => {
+            transport
+                .write_file_all(&region.file, region.offset, region.len)
+                .await
+                .map_err(BodyError::WriteError)?;
+        }
+        BodyWriteMode::Empty => {
+            return Err(BodyError::CalledWriteBodyChunkWhenNoBodyWasExpected);
+        }
+    }
+    Ok(())
+}
+
 pub(crate) async fn write_h1_body_end(
     transport: &mut impl WriteOwned,
     mode: BodyWriteMode,
@@ -347,3 +494,29 @@ pub(crate) async fn write_h1_body_end(
     }
     Ok(())
 }
+
+/// Ends the body with a trailer section. Only chunked bodies can carry
+/// trailers: for other modes, they're dropped and the body ends as usual.
+pub(crate) async fn write_h1_trailers(
+    transport: &mut impl WriteOwned,
+    trailers: Box<Headers>,
+    mode: BodyWriteMode,
+) -> Result<(), BodyError> {
+    if mode != BodyWriteMode::Chunked {
+        debug!(
+            ?mode,
+            "can't send trailers without chunked transfer-encoding, dropping them"
+        );
+        return write_h1_body_end(transport, mode).await;
+    }
+
+    let mut list = PieceList::default();
+    list.push_back("0\r\n");
+    encode_headers(*trailers, &mut list)?;
+    list.push_back("\r\n");
+    transport
+        .writev_all_owned(list)
+        .await
+        .map_err(BodyError::WriteError)?;
+    Ok(())
+}
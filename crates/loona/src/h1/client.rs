@@ -1,34 +1,47 @@
+use std::time::Duration;
+
 use b_x::BX;
-use http::header;
+use http::{header, StatusCode, Version};
+use tokio::sync::oneshot;
 use tracing::debug;
 
 use crate::{
     types::Request,
     util::{read_and_parse, ReadAndParseError},
-    Body, HeadersExt, Response,
+    Body, HeadersExt, Method, Response,
 };
 use buffet::{
     PieceList, RollMut, {ReadOwned, WriteOwned},
 };
 
 use super::{
-    body::{write_h1_body, BodyWriteMode, H1Body, H1BodyKind},
-    encode::encode_request,
+    body::{content_length, write_h1_body, BodyWriteMode, H1Body, H1BodyKind, WriteBodyError},
+    encode::{encode_request, H1EncoderError, RequestTargetForm},
 };
 
-pub struct ClientConf {}
+pub use crate::ClientDriver;
+
+pub struct ClientConf {
+    /// Max length of the status line + HTTP headers of a response
+    pub max_http_header_len: usize,
 
-#[allow(async_fn_in_trait)] // we never require Send
-pub trait ClientDriver {
-    type Return;
-    type Error: std::error::Error + 'static;
+    /// How long to hold back the request body when sending
+    /// `expect: 100-continue`, if the server doesn't respond at all
+    pub expect_continue_timeout: Duration,
 
-    async fn on_informational_response(&mut self, res: Response) -> Result<(), Self::Error>;
-    async fn on_final_response(
-        self,
-        res: Response,
-        body: &mut impl Body,
-    ) -> Result<Self::Return, Self::Error>;
+    /// Send request targets in absolute form (`GET http://example.org/ HTTP/1.1`),
+    /// as forward proxies expect, rather than in origin form (`GET / HTTP/1.1`)
+    pub absolute_form: bool,
+}
+
+impl Default for ClientConf {
+    fn default() -> Self {
+        Self {
+            max_http_header_len: 64 * 1024,
+            expect_continue_timeout: Duration::from_secs(1),
+            absolute_form: false,
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -37,15 +50,27 @@ pub enum Http1ClientError<DriverError> {
     #[error("An error occurred with the client driver: {0}")]
     DriverError(#[source] DriverError),
 
+    #[error("Could not connect to the server")]
+    Connect(#[source] std::io::Error),
+
     #[error("Could not write the request headers")]
     WhileWritingRequestHeaders(#[source] std::io::Error),
 
+    #[error("Could not encode the request headers")]
+    EncodeRequestHeaders(#[source] H1EncoderError),
+
+    #[error("Could not write the request body")]
+    WhileWritingRequestBody(#[source] WriteBodyError<BX>),
+
     #[error("Could not read / receive the response headers")]
     ErrorReadingResponseHeaders(#[from] ReadAndParseError),
 
     #[error("Server went away before sending response headers")]
     ServerWentAwayBeforeSendingResponseHeaders,
 
+    #[error("Invalid response framing: {0}")]
+    InvalidResponseFraming(&'static str),
+
     #[error("Allocation failed")]
     Alloc(#[from] buffet::bufpool::Error),
 }
@@ -65,6 +90,7 @@ where
 /// close or the request body wasn't fully drained
 pub async fn request<R, W, D>(
     (mut transport_r, mut transport_w): (R, W),
+    conf: &ClientConf,
     mut req: Request,
     body: &mut impl Body,
     driver: D,
@@ -74,106 +100,310 @@ where
     W: WriteOwned,
     D: ClientDriver,
 {
+    // the body decides how it's framed, whatever the headers we were given say
     let mode = match body.content_len() {
-        Some(0) => BodyWriteMode::Empty,
+        Some(0) => {
+            req.headers.remove(header::TRANSFER_ENCODING);
+            BodyWriteMode::Empty
+        }
         Some(len) => {
             // TODO: we can probably save a heap allocation here - we could format
             // directly to a `RollMut`, without going through `format!` machinery
+            req.headers.remove(header::TRANSFER_ENCODING);
             req.headers
                 .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
             BodyWriteMode::ContentLength(len)
         }
-        None => BodyWriteMode::Chunked,
+        None => {
+            req.headers.remove(header::CONTENT_LENGTH);
+            req.headers
+                .insert(header::TRANSFER_ENCODING, "chunked".into());
+            BodyWriteMode::Chunked
+        }
     };
 
+    // cf. RFC 9110, section 10.1.1: a client that sends `expect: 100-continue`
+    // isn't required to wait for a 100 (Continue) response, but it should
+    // give the server a chance to reject the request before sending the body.
+    let (continue_tx, continue_rx) =
+        if !matches!(mode, BodyWriteMode::Empty) && req.headers.expects_100_continue() {
+            let (tx, rx) = oneshot::channel::<()>();
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+
+    // the request method changes how the response is framed
+    let method = req.method.clone();
+
     let mut buf = RollMut::alloc()?;
 
     let mut list = PieceList::default();
-    encode_request(req, &mut list, &mut buf)
-        .map_err(Http1ClientError::WhileWritingRequestHeaders)?;
+    let target_form = if conf.absolute_form {
+        RequestTargetForm::Absolute
+    } else {
+        RequestTargetForm::Origin
+    };
+    encode_request(req, target_form, &mut list, &mut buf)
+        .map_err(Http1ClientError::EncodeRequestHeaders)?;
     transport_w
         .writev_all_owned(list)
         .await
         .map_err(Http1ClientError::WhileWritingRequestHeaders)?;
 
-    // TODO: handle `expect: 100-continue` (don't start sending body until we get a
-    // 100 response)
-
     let send_body_fut = {
         async move {
-            match write_h1_body(&mut transport_w, body, mode).await {
-                Err(err) => {
-                    // TODO: find way to report this error to the driver without
-                    // spawning, without ref-counting the driver, etc.
-                    panic!("error writing request body: {err}");
-                }
-                Ok(_) => {
-                    debug!("done writing request body");
-                    Ok::<_, Http1ClientError<D::Error>>(transport_w)
+            if let Some(continue_rx) = continue_rx {
+                match tokio::time::timeout(conf.expect_continue_timeout, continue_rx).await {
+                    Ok(_) => debug!("got a response, sending request body"),
+                    Err(_) => {
+                        debug!("server didn't respond to expect: 100-continue, sending request body anyway")
+                    }
                 }
             }
+
+            write_h1_body(&mut transport_w, body, mode)
+                .await
+                .map_err(|e| match e {
+                    WriteBodyError::InnerBodyError(e) => {
+                        WriteBodyError::InnerBodyError(BX::from_err(e))
+                    }
+                    WriteBodyError::BodyError(e) => WriteBodyError::BodyError(e),
+                })?;
+            debug!("done writing request body");
+            Ok(transport_w)
         }
     };
 
     let recv_res_fut = {
         async move {
-            let (buf, res) = read_and_parse(
-                "Http1Response",
-                super::parse::response,
-                &mut transport_r,
-                buf,
-                // TODO: make this configurable
-                64 * 1024,
-            )
-            .await
-            .map_err(Http1ClientError::ErrorReadingResponseHeaders)?
-            .ok_or(Http1ClientError::ServerWentAwayBeforeSendingResponseHeaders)?;
-            debug!("client received response");
-            res.debug_print();
-
-            if res.status.is_informational() {
-                todo!("handle informational responses");
-            }
+            let mut driver = driver;
+            let mut continue_tx = continue_tx;
+            let mut buf = buf;
 
-            let chunked = res.headers.is_chunked_transfer_encoding();
+            let res = loop {
+                let res;
+                (buf, res) = read_and_parse(
+                    "Http1Response",
+                    super::parse::response,
+                    &mut transport_r,
+                    buf,
+                    conf.max_http_header_len,
+                )
+                .await
+                .map_err(Http1ClientError::ErrorReadingResponseHeaders)?
+                .ok_or(Http1ClientError::ServerWentAwayBeforeSendingResponseHeaders)?;
+                debug!("client received response");
+                res.debug_print();
 
-            // TODO: handle 204/304 separately
-            let content_len = res.headers.content_length().unwrap_or_default();
+                if res.status == StatusCode::CONTINUE {
+                    if let Some(tx) = continue_tx.take() {
+                        _ = tx.send(());
+                    }
+                }
 
-            let mut res_body = H1Body::new(
-                transport_r,
-                buf,
-                if chunked {
-                    // TODO: even with chunked transfer-encoding, we can announce
-                    // a content length - we should probably detect errors there?
-                    H1BodyKind::Chunked
-                } else {
-                    H1BodyKind::ContentLength(content_len)
-                },
-            );
+                // 101 (Switching Protocols) is the last response on this
+                // connection, there's no other response coming after it.
+                if res.status.is_informational() && res.status != StatusCode::SWITCHING_PROTOCOLS {
+                    driver
+                        .on_informational_response(res)
+                        .await
+                        .map_err(Http1ClientError::DriverError)?;
+                    continue;
+                }
 
-            let conn_close = res.headers.is_connection_close();
+                break res;
+            };
+
+            // a final response also means we can stop holding back the body
+            if let Some(tx) = continue_tx.take() {
+                _ = tx.send(());
+            }
+
+            let (kind, reusable) = response_framing(&method, &res)
+                .map_err(Http1ClientError::InvalidResponseFraming)?;
+            debug!(?kind, %reusable, "response framing");
+
+            let mut res_body = H1Body::new(transport_r, buf, kind);
 
             let ret = driver
                 .on_final_response(res, &mut res_body)
                 .await
                 .map_err(Http1ClientError::DriverError)?;
 
-            let transport_r = match (conn_close, res_body.into_inner()) {
-                // can only re-use the body if conn_close is false and the body was fully draided
-                (false, Some((_buf, transport_r))) => Some(transport_r),
+            let transport_r = match (reusable, res_body.into_inner()) {
+                // can only re-use the connection if the server didn't ask us
+                // to close it, and the body was fully drained
+                (true, Some((_buf, transport_r))) => Some(transport_r),
                 _ => None,
             };
 
-            Ok((transport_r, ret))
+            Ok::<_, Http1ClientError<D::Error>>((transport_r, ret))
+        }
+    };
+
+    let mut send_body_fut = std::pin::pin!(send_body_fut);
+    let mut recv_res_fut = std::pin::pin!(recv_res_fut);
+
+    // `Some(Err(_))` if we couldn't write the request body: the server may have
+    // responded early and closed the connection, so we still try to read the
+    // response, and only report that error if that fails too.
+    let mut send_res: Option<Result<W, WriteBodyError<BX>>> = None;
+    let (transport_r, ret) = loop {
+        tokio::select! {
+            res = &mut send_body_fut, if send_res.is_none() => {
+                match res {
+                    Err(WriteBodyError::InnerBodyError(e)) => {
+                        // the server is waiting on a body we can't produce
+                        return Err(Http1ClientError::WhileWritingRequestBody(
+                            WriteBodyError::InnerBodyError(e),
+                        ));
+                    }
+                    Err(e) => {
+                        debug!("could not write request body, still waiting for a response: {e}");
+                        send_res = Some(Err(e));
+                    }
+                    Ok(transport_w) => send_res = Some(Ok(transport_w)),
+                }
+            }
+            res = &mut recv_res_fut => {
+                match (res, send_res.take()) {
+                    (Ok(res), s) => {
+                        send_res = s;
+                        break res;
+                    }
+                    (Err(_), Some(Err(e))) => {
+                        return Err(Http1ClientError::WhileWritingRequestBody(e));
+                    }
+                    (Err(e), _) => return Err(e),
+                }
+            }
         }
     };
 
-    // TODO: cancel sending the body if we get a response early?
-    let (send_res, recv_res) = tokio::try_join!(send_body_fut, recv_res_fut)?;
-    let transport_w = send_res;
-    let (transport_r, ret) = recv_res;
+    let transport_w = match send_res {
+        Some(Ok(transport_w)) => Some(transport_w),
+        Some(Err(_)) => None,
+        None => {
+            if transport_r.is_some() {
+                // the connection can be re-used, as long as we finish sending
+                // the request body.
+                Some(
+                    send_body_fut
+                        .await
+                        .map_err(Http1ClientError::WhileWritingRequestBody)?,
+                )
+            } else {
+                debug!("got the whole response before sending the whole request body, cancelling");
+                None
+            }
+        }
+    };
 
-    let transport = transport_r.map(|transport_r| (transport_r, transport_w));
+    let transport = transport_r.zip(transport_w);
     Ok((transport, ret))
 }
+
+/// Determines how the response body is delimited, and whether the connection
+/// can carry another request afterwards, cf. RFC 9112, section 6.3
+fn response_framing(method: &Method, res: &Response) -> Result<(H1BodyKind, bool), &'static str> {
+    let headers = &res.headers;
+
+    let mut reusable = !headers.is_connection_close();
+    if res.version == Version::HTTP_10 {
+        // HTTP/1.0 connections are only persistent if explicitly asked for
+        reusable = reusable && headers.has_token(header::CONNECTION, "keep-alive");
+    }
+
+    // responses to HEAD requests, and 1xx, 204 and 304 responses never have a
+    // body, whatever their headers say.
+    if *method == Method::Head
+        || res.status.is_informational()
+        || res.status == StatusCode::NO_CONTENT
+        || res.status == StatusCode::NOT_MODIFIED
+    {
+        // after a 101, the connection speaks another protocol
+        let reusable = reusable && res.status != StatusCode::SWITCHING_PROTOCOLS;
+        return Ok((H1BodyKind::ContentLength(0), reusable));
+    }
+
+    // after a 2xx response to CONNECT, the connection is a tunnel
+    if *method == Method::Connect && res.status.is_success() {
+        return Ok((H1BodyKind::ContentLength(0), false));
+    }
+
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        // transfer-encoding overrides content-length, but having both might
+        // be an attempt at response splitting: don't trust this connection
+        // any further. HTTP/1.0 doesn't have transfer-encoding at all.
+        if headers.contains_key(header::CONTENT_LENGTH) || res.version == Version::HTTP_10 {
+            reusable = false;
+        }
+
+        return if headers.is_chunked_transfer_encoding() {
+            Ok((H1BodyKind::Chunked, reusable))
+        } else {
+            // the body is whatever we read until the server closes the connection
+            Ok((H1BodyKind::CloseDelimited, false))
+        };
+    }
+
+    if let Some(len) = content_length(headers)? {
+        return Ok((H1BodyKind::ContentLength(len), reusable));
+    }
+
+    // no framing information: read until the server closes the connection
+    Ok((H1BodyKind::CloseDelimited, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(version: Version, headers: &[(&'static str, &'static str)]) -> Response {
+        let mut res = Response {
+            version,
+            ..Default::default()
+        };
+        for &(name, value) in headers {
+            res.headers
+                .append(header::HeaderName::from_static(name), value.into());
+        }
+        res
+    }
+
+    #[test]
+    fn test_response_framing() {
+        let framing = |res: &Response| response_framing(&Method::Get, res);
+
+        // HTTP/1.0 is only persistent if `keep-alive` is listed
+        for (connection, reusable) in [
+            (None, false),
+            (Some("keep-alive"), true),
+            (Some("Keep-Alive"), true),
+            (Some("foo, keep-alive"), true),
+            (Some("keep-alive, close"), false),
+        ] {
+            let mut headers = vec![("content-length", "0")];
+            headers.extend(connection.map(|value| ("connection", value)));
+            let res = response(Version::HTTP_10, &headers);
+            assert_eq!(framing(&res).unwrap().1, reusable, "{connection:?}");
+        }
+
+        let res = response(Version::HTTP_11, &[("content-length", "5, 5")]);
+        assert!(matches!(
+            framing(&res),
+            Ok((H1BodyKind::ContentLength(5), true))
+        ));
+
+        for invalid in [
+            &[("content-length", "5, 6")][..],
+            &[("content-length", "5"), ("content-length", "6")],
+            &[("content-length", "-5")],
+            &[("content-length", "")],
+        ] {
+            let res = response(Version::HTTP_11, invalid);
+            assert!(framing(&res).is_err(), "{invalid:?}");
+        }
+    }
+}
@@ -1,44 +1,117 @@
 use std::io::Write;
 
-use http::{header, StatusCode, Version};
+use http::{header, uri::Authority, StatusCode, Version};
 
 use crate::{
+    auto_headers::AutoHeaders,
+    body::FileRegion,
+    is_valid_field_value,
     types::{Headers, Request, Response},
-    BodyError, Encoder, HeadersExt,
+    BodyError, Encoder, HeadersExt, InvalidHeaderValue, Method,
 };
 use buffet::{Piece, PieceList, RollMut, WriteOwned};
 
-use super::body::{write_h1_body_chunk, write_h1_body_end, BodyWriteMode};
+use super::{
+    body::{
+        write_h1_body_chunk, write_h1_body_end, write_h1_body_file, write_h1_trailers,
+        BodyWriteMode,
+    },
+    parse::is_token,
+};
+
+/// The form of the request target, cf. RFC 9112, section 3.2
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RequestTargetForm {
+    /// `/path?query`, what origin servers expect
+    Origin,
+
+    /// `http://example.org/path?query`, what forward proxies expect
+    Absolute,
+}
 
 pub(crate) fn encode_request(
     req: Request,
+    target_form: RequestTargetForm,
     list: &mut PieceList,
     out_scratch: &mut RollMut,
-) -> Result<(), std::io::Error> {
-    list.push_back(req.method.into_chunk());
+) -> Result<(), H1EncoderError> {
+    let Request {
+        method,
+        uri,
+        version,
+        mut headers,
+        extensions: _,
+    } = req;
+
+    // the method goes on the request line as-is, cf. RFC 9110, section 9.1
+    if !is_token(method.as_str().as_bytes()) {
+        return Err(H1EncoderError::InvalidMethod(method));
+    }
+
+    let is_connect = method == Method::Connect;
+    list.push_back(method.into_chunk());
     list.push_back(" ");
 
     assert_eq!(out_scratch.len(), 0);
-    out_scratch.write_all(req.uri.path().as_bytes())?;
+    let path_and_query = uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .filter(|pq| !pq.is_empty())
+        .unwrap_or("/");
+    match uri.authority() {
+        // CONNECT only ever uses the authority form
+        Some(authority) if is_connect => {
+            out_scratch.write_all(authority.as_str().as_bytes())?;
+        }
+        Some(authority) if target_form == RequestTargetForm::Absolute => {
+            write!(
+                out_scratch,
+                "{}://{}{}",
+                uri.scheme_str().unwrap_or("http"),
+                strip_userinfo(authority),
+                path_and_query
+            )?;
+        }
+        // origin form, or asterisk form for `OPTIONS * HTTP/1.1`
+        _ => {
+            out_scratch.write_all(path_and_query.as_bytes())?;
+        }
+    }
     list.push_back(out_scratch.take_all());
 
-    match req.version {
+    match version {
         Version::HTTP_10 => list.push_back(" HTTP/1.0\r\n"),
         Version::HTTP_11 => list.push_back(" HTTP/1.1\r\n"),
         _ => panic!(
             "passed unsupported HTTP version to HTTP/1.1 request encoder {:?}",
-            req.version
+            version
         ),
     }
 
-    // TODO: if `host` isn't set, set from request uri? which should
-    // take precedence here?
-    encode_headers(req.headers, list)?;
+    // an explicit `host` header wins (a proxy might want to keep the one it
+    // received), otherwise it comes from the URI, cf. RFC 9110, section 7.2
+    if !headers.contains_key(header::HOST) {
+        if let Some(authority) = uri.authority() {
+            let host = strip_userinfo(authority).to_owned();
+            headers.insert(header::HOST, host.into_bytes().into());
+        }
+    }
+
+    encode_headers(headers, list)?;
     list.push_back("\r\n");
     Ok(())
 }
 
-fn encode_response(res: Response, list: &mut PieceList) -> Result<(), std::io::Error> {
+/// Senders must not generate the userinfo subcomponent of "http" URIs, cf.
+/// RFC 9110, section 4.2.4
+fn strip_userinfo(authority: &Authority) -> &str {
+    match authority.as_str().rsplit_once('@') {
+        Some((_userinfo, host)) => host,
+        None => authority.as_str(),
+    }
+}
+
+fn encode_response(res: Response, list: &mut PieceList) -> Result<(), InvalidHeaderValue> {
     match res.version {
         Version::HTTP_10 => list.push_back(&b"HTTP/1.0 "[..]),
         Version::HTTP_11 => list.push_back(&b"HTTP/1.1 "[..]),
@@ -57,9 +130,22 @@ fn encode_response(res: Response, list: &mut PieceList) -> Result<(), std::io::E
     Ok(())
 }
 
-pub(crate) fn encode_headers(headers: Headers, list: &mut PieceList) -> Result<(), std::io::Error> {
+/// Fails if any of the values would break out of its field line, cf.
+/// [crate::is_valid_field_value]
+pub(crate) fn encode_headers(
+    headers: Headers,
+    list: &mut PieceList,
+) -> Result<(), InvalidHeaderValue> {
     let mut last_header_name = None;
     for (name, value) in headers {
+        if !is_valid_field_value(&value) {
+            let name = match name {
+                Some(name) => name,
+                None => last_header_name.expect("HeaderMap's IntoIter violated its contract"),
+            };
+            return Err(InvalidHeaderValue { name });
+        }
+
         match name {
             Some(name) => {
                 last_header_name = Some(name.clone());
@@ -155,6 +241,9 @@ where
 {
     pub(crate) transport_w: OurWriteOwned,
     mode: BodyWriteMode,
+    pub(crate) auto_headers: AutoHeaders,
+    // responding to a HEAD request
+    omit_body: bool,
 }
 
 impl<OurWriteOwned> H1Encoder<OurWriteOwned>
@@ -165,6 +254,8 @@ where
         Self {
             transport_w,
             mode: BodyWriteMode::Empty,
+            auto_headers: Default::default(),
+            omit_body: false,
         }
     }
 }
@@ -181,6 +272,10 @@ pub enum H1EncoderError {
     },
     #[error("Body error: {0}")]
     BodyError(#[from] BodyError),
+    #[error("{0}")]
+    InvalidHeaderValue(#[from] InvalidHeaderValue),
+    #[error("Invalid method: {0:?}")]
+    InvalidMethod(Method),
 }
 
 impl AsRef<dyn std::error::Error> for H1EncoderError {
@@ -196,7 +291,13 @@ where
     type Error = H1EncoderError;
 
     async fn write_response(&mut self, mut res: Response) -> Result<(), Self::Error> {
-        if !res.status.is_informational() && !res.means_empty_body() {
+        if !res.means_empty_body() {
+            if !self.omit_body && res.headers.contains_key(header::TRAILER) {
+                // trailers can only be sent with chunked transfer-encoding.
+                // the responder still checks the body against the
+                // content-length it saw.
+                res.headers.remove(header::CONTENT_LENGTH);
+            }
             self.mode = match res.headers.content_length() {
                 Some(0) => BodyWriteMode::Empty,
                 Some(length) => BodyWriteMode::ContentLength(length),
@@ -206,6 +307,20 @@ where
                     BodyWriteMode::Chunked
                 }
             };
+            if self.omit_body {
+                // the headers say what a GET would have gotten, but there's
+                // no body on the wire
+                self.mode = BodyWriteMode::Empty;
+            }
+        } else if res.status == StatusCode::RESET_CONTENT
+            && !res.headers.contains_key(header::CONTENT_LENGTH)
+        {
+            // otherwise the client would read until the connection closes,
+            // cf. https://httpwg.org/specs/rfc9110.html#status.205
+            res.headers.insert(header::CONTENT_LENGTH, "0".into());
+        }
+        if !res.status.is_informational() {
+            self.auto_headers.apply(&mut res.headers);
         }
 
         let mut list = PieceList::default();
@@ -234,15 +349,27 @@ where
             .map_err(H1EncoderError::from)
     }
 
-    async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error> {
-        let mut list = PieceList::default();
-        encode_headers(*trailers, &mut list)?;
+    fn omit_body(&mut self) {
+        self.omit_body = true;
+    }
 
-        self.transport_w
-            .writev_all_owned(list)
+    fn can_write_trailers(&self) -> bool {
+        self.mode == BodyWriteMode::Chunked
+    }
+
+    fn can_write_body_file(&self) -> bool {
+        self.mode != BodyWriteMode::Empty
+    }
+
+    async fn write_body_file(&mut self, region: FileRegion) -> Result<(), Self::Error> {
+        write_h1_body_file(&mut self.transport_w, region, self.mode)
             .await
-            .map_err(H1EncoderError::from)?;
+            .map_err(H1EncoderError::from)
+    }
 
-        Ok(())
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error> {
+        write_h1_trailers(&mut self.transport_w, trailers, self.mode)
+            .await
+            .map_err(H1EncoderError::from)
     }
 }
@@ -4,10 +4,14 @@
 mod client;
 pub use client::*;
 
+mod pool;
+pub use pool::*;
+
 mod server;
 pub use server::*;
 
 pub(crate) mod body;
+pub use body::WriteBodyError;
 pub(crate) mod parse;
 
 pub mod encode;
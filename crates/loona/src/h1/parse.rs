@@ -13,6 +13,7 @@ use nom::{
 
 use crate::{
     types::{Headers, Request, Response},
+    util::ows_trimmed_range,
     Method,
 };
 use buffet::{PieceStr, Roll, RollStr};
@@ -43,6 +44,7 @@ pub fn request(i: Roll) -> IResult<Roll, Request> {
         uri: path.parse().unwrap(),
         version,
         headers,
+        extensions: Default::default(),
     };
     Ok((i, request))
 }
@@ -61,6 +63,12 @@ pub fn token(i: Roll) -> IResult<Roll, RollStr> {
     Ok((i, token))
 }
 
+/// Returns true if all of `s` is a [token], e.g. for methods that didn't come
+/// through this parser
+pub(crate) fn is_token(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().all(|&c| is_tchar(c))
+}
+
 /// cf. <https://httpwg.org/specs/rfc9110.html#rule.token.separators>
 fn is_tchar(c: u8) -> bool {
     c.is_ascii_graphic() && !is_delimiter(c)
@@ -158,6 +166,10 @@ fn header(i: Roll) -> IResult<Roll, (HeaderName, Roll)> {
     })(i)?;
     let (i, value) = preceded(space1, take_until_and_consume(CRLF))(i)?;
 
+    // whitespace around the value isn't part of it, cf. RFC 9112, section 5
+    let range = ows_trimmed_range(&value[..]);
+    let value = value.slice(range);
+
     Ok((i, (name, value)))
 }
 
@@ -0,0 +1,163 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use buffet::{
+    net::{self, TcpReadHalf, TcpStream, TcpWriteHalf},
+    IntoHalves,
+};
+use tracing::debug;
+
+use crate::{types::Request, Body};
+
+use super::{request, ClientConf, ClientDriver, Http1ClientError};
+
+type Transport = (TcpReadHalf, TcpWriteHalf);
+
+pub struct PoolConf {
+    /// How many idle connections to keep around for a single authority
+    pub max_idle_per_authority: usize,
+
+    /// How long a connection may stay idle before we stop re-using it
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConf {
+    fn default() -> Self {
+        Self {
+            max_idle_per_authority: 8,
+            idle_timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+struct IdleConn {
+    transport: Transport,
+    idle_since: Instant,
+}
+
+/// A pool of idle HTTP/1.1 client connections, keyed by authority (e.g.
+/// `example.org:80`).
+///
+/// Pools aren't `Send`: use [Pool::thread_local] to get the one shared by
+/// everything running on the current thread.
+pub struct Pool {
+    conf: PoolConf,
+    // most recently used connections are at the back
+    idle: RefCell<HashMap<String, VecDeque<IdleConn>>>,
+}
+
+thread_local! {
+    static THREAD_LOCAL_POOL: Rc<Pool> = Rc::new(Pool::new(PoolConf::default()));
+}
+
+impl Pool {
+    pub fn new(conf: PoolConf) -> Self {
+        Self {
+            conf,
+            idle: Default::default(),
+        }
+    }
+
+    /// Returns the pool shared by the current thread, with a default configuration
+    pub fn thread_local() -> Rc<Pool> {
+        THREAD_LOCAL_POOL.with(|pool| pool.clone())
+    }
+
+    /// Takes an idle connection to `authority` out of the pool, if there's one
+    /// that hasn't expired and still looks alive.
+    pub fn checkout(&self, authority: &str) -> Option<Transport> {
+        let mut idle = self.idle.borrow_mut();
+        let conns = idle.get_mut(authority)?;
+
+        let mut found = None;
+        while let Some(conn) = conns.pop_back() {
+            if conn.idle_since.elapsed() >= self.conf.idle_timeout {
+                // everything before this one has been idle for even longer
+                debug!(%authority, "dropping expired idle connections");
+                conns.clear();
+                break;
+            }
+            if !net::is_idle_connection_usable(&conn.transport.0) {
+                debug!(%authority, "dropping idle connection closed by peer");
+                continue;
+            }
+            found = Some(conn.transport);
+            break;
+        }
+
+        if conns.is_empty() {
+            idle.remove(authority);
+        }
+        found
+    }
+
+    /// Returns a connection to `authority` to the pool, so it may be re-used by
+    /// a later request. If there are already too many idle connections for that
+    /// authority, the oldest one is closed.
+    pub fn checkin(&self, authority: &str, transport: Transport) {
+        if self.conf.max_idle_per_authority == 0 {
+            return;
+        }
+
+        let mut idle = self.idle.borrow_mut();
+        let conns = idle.entry(authority.to_owned()).or_default();
+        while conns.len() >= self.conf.max_idle_per_authority {
+            conns.pop_front();
+        }
+        conns.push_back(IdleConn {
+            transport,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Returns the number of idle connections to `authority` currently pooled
+    pub fn idle_count(&self, authority: &str) -> usize {
+        self.idle
+            .borrow()
+            .get(authority)
+            .map_or(0, |conns| conns.len())
+    }
+
+    /// Perform an HTTP/1.1 request against `authority`, re-using an idle
+    /// connection if possible, or connecting to `addr` otherwise.
+    ///
+    /// The connection goes back into the pool afterwards, unless the server
+    /// asked for it to be closed or the response body wasn't fully drained.
+    pub async fn request<D>(
+        &self,
+        authority: &str,
+        addr: SocketAddr,
+        conf: &ClientConf,
+        req: Request,
+        body: &mut impl Body,
+        driver: D,
+    ) -> Result<D::Return, Http1ClientError<D::Error>>
+    where
+        D: ClientDriver,
+    {
+        let transport = match self.checkout(authority) {
+            Some(transport) => {
+                debug!(%authority, "re-using idle connection");
+                transport
+            }
+            None => {
+                debug!(%authority, %addr, "making new connection");
+                TcpStream::connect(addr)
+                    .await
+                    .map_err(Http1ClientError::Connect)?
+                    .into_halves()
+            }
+        };
+
+        let (transport, ret) = request(transport, conf, req, body, driver).await?;
+        if let Some(transport) = transport {
+            self.checkin(authority, transport);
+        }
+        Ok(ret)
+    }
+}
@@ -1,14 +1,16 @@
-use std::rc::Rc;
+use std::{rc::Rc, sync::Arc};
 
+use http::header;
 use tracing::debug;
 
 use crate::{
+    auto_headers::AutoHeaders,
     error::ServeError,
-    h1::body::{H1Body, H1BodyKind},
+    h1::body::{content_length, H1Body, H1BodyKind},
     util::{read_and_parse, ReadAndParseError},
-    HeadersExt, Responder, ServeOutcome, ServerDriver,
+    ConnectionInfo, HeadersExt, Responder, ServeOutcome, ServerDriver, ShutdownSignal, StreamInfo,
 };
-use buffet::{ReadOwned, RollMut, WriteOwned};
+use buffet::{Piece, ReadOwned, RollMut, WriteOwned};
 
 use super::encode::H1Encoder;
 
@@ -21,6 +23,21 @@ pub struct ServerConf {
 
     /// Max number of header records
     pub max_header_records: usize,
+
+    /// When triggered, we stop waiting for the next request and close the
+    /// connection. Requests that are being handled run to completion.
+    pub shutdown: ShutdownSignal,
+
+    /// Whether to add a `date` header to final responses that don't have one,
+    /// as origin servers with a clock must, cf. RFC 9110, section 6.6.1
+    pub date_header: bool,
+
+    /// Added as the `server` header to final responses that don't have one
+    pub server_header: Option<Piece>,
+
+    /// Max length of a request body, unless the driver says otherwise, cf.
+    /// [crate::ServerDriver::max_request_body_len]
+    pub max_request_body_len: Option<u64>,
 }
 
 impl Default for ServerConf {
@@ -29,32 +46,74 @@ impl Default for ServerConf {
             max_http_header_len: 64 * 1024,
             max_header_record_len: 4 * 1024,
             max_header_records: 128,
+            shutdown: Default::default(),
+            date_header: true,
+            server_header: None,
+            max_request_body_len: None,
         }
     }
 }
 
+/// Serves HTTP/1.1 requests on a connection, one after the other, until
+/// either side closes it.
+///
+/// The driver's connection hooks are called before the first request, and
+/// once serving is done, whether it succeeded or not.
 pub async fn serve<OurDriver, OurReadOwned, OurWriteOwned>(
-    (mut transport_r, mut transport_w): (OurReadOwned, OurWriteOwned),
+    transport: (OurReadOwned, OurWriteOwned),
     conf: Rc<ServerConf>,
-    mut client_buf: RollMut,
+    client_buf: RollMut,
     driver: OurDriver,
+    conn: ConnectionInfo,
 ) -> Result<ServeOutcome, ServeError<OurDriver::Error>>
 where
     OurDriver: ServerDriver<H1Encoder<OurWriteOwned>>,
     OurReadOwned: ReadOwned,
     OurWriteOwned: WriteOwned,
 {
+    let conn = Arc::new(conn);
+    driver.on_connection_open(&conn);
+    let res = serve_requests(transport, &conf, client_buf, &driver, &conn).await;
+    driver.on_connection_close(&conn, res.as_ref().copied());
+    res
+}
+
+async fn serve_requests<OurDriver, OurReadOwned, OurWriteOwned>(
+    (mut transport_r, mut transport_w): (OurReadOwned, OurWriteOwned),
+    conf: &ServerConf,
+    mut client_buf: RollMut,
+    driver: &OurDriver,
+    conn: &Arc<ConnectionInfo>,
+) -> Result<ServeOutcome, ServeError<OurDriver::Error>>
+where
+    OurDriver: ServerDriver<H1Encoder<OurWriteOwned>>,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    let auto_headers = AutoHeaders {
+        date: conf.date_header,
+        server: conf.server_header.clone(),
+    };
+
+    let mut request_index = 0;
     loop {
-        let req;
-        (client_buf, req) = match read_and_parse(
+        let read_fut = read_and_parse(
             "Http1Request",
             super::parse::request,
             &mut transport_r,
             client_buf,
             conf.max_http_header_len,
-        )
-        .await
-        {
+        );
+        let read_res = tokio::select! {
+            res = read_fut => res,
+            _ = conf.shutdown.wait() => {
+                debug!("server is shutting down, closing connection between requests");
+                return Ok(ServeOutcome::ServerShutDown);
+            }
+        };
+
+        let mut req;
+        (client_buf, req) = match read_res {
             Ok(t) => match t {
                 Some(t) => t,
                 None => {
@@ -80,10 +139,55 @@ where
             },
         };
         debug!("got request {req:?}");
+        req.extensions.insert(conn.clone());
+        req.extensions.insert(StreamInfo::H1 { request_index });
+        request_index += 1;
 
         let chunked = req.headers.is_chunked_transfer_encoding();
         let connection_close = req.headers.is_connection_close();
-        let content_len = req.headers.content_length().unwrap_or_default();
+        let has_transfer_encoding = req.headers.contains_key(header::TRANSFER_ENCODING);
+
+        // anything ambiguous about where the body ends could be used to
+        // smuggle a request past a proxy, cf.
+        // https://httpwg.org/specs/rfc9112.html#message.body.length
+        let framing = match content_length(&req.headers) {
+            Ok(Some(_)) if has_transfer_encoding => {
+                Err("both transfer-encoding and content-length")
+            }
+            Ok(_) if has_transfer_encoding && !chunked => {
+                Err("chunked isn't the final transfer coding")
+            }
+            Ok(len) => Ok(len.unwrap_or_default()),
+            Err(e) => Err(e),
+        };
+        let content_len = match framing {
+            Ok(len) => len,
+            Err(reason) => {
+                debug!(%reason, "can't tell where the request body ends, replying with 400 and hanging up");
+                let reply =
+                    b"HTTP/1.1 400 Bad Request\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+                transport_w
+                    .write_all_owned(reply)
+                    .await
+                    .map_err(ServeError::DownstreamWrite)?;
+
+                return Ok(ServeOutcome::RequestBodyLengthUnknownOnHttp1Conn);
+            }
+        };
+
+        let max_body_len = driver.max_request_body_len(&req, conf.max_request_body_len);
+        if let Some(limit) = max_body_len {
+            if !chunked && content_len > limit {
+                debug!("request body of {content_len} bytes is over the limit of {limit} bytes, replying with 413 and hanging up");
+                let reply = b"HTTP/1.1 413 Content Too Large\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+                transport_w
+                    .write_all_owned(reply)
+                    .await
+                    .map_err(ServeError::DownstreamWrite)?;
+
+                return Ok(ServeOutcome::RequestBodyTooLargeOnHttp1Conn);
+            }
+        }
 
         let mut req_body = H1Body::new(
             transport_r,
@@ -93,9 +197,12 @@ where
             } else {
                 H1BodyKind::ContentLength(content_len)
             },
-        );
+        )
+        .with_max_len(max_body_len);
 
-        let responder = Responder::new(H1Encoder::new(transport_w));
+        let mut encoder = H1Encoder::new(transport_w);
+        encoder.auto_headers = auto_headers.clone();
+        let responder = Responder::for_request(encoder, &req);
 
         let resp = driver
             .handle(req, &mut req_body, responder)
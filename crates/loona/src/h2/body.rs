@@ -1,8 +1,6 @@
-use core::fmt;
-
 use tokio::sync::mpsc;
 
-use crate::{error::NeverError, Body, BodyChunk, Headers};
+use crate::{Body, BodyChunk, Headers};
 use buffet::Piece;
 
 use super::types::H2StreamError;
@@ -21,7 +19,9 @@ pub(crate) enum ChunkPosition {
 }
 
 pub(crate) struct StreamIncoming {
-    tx: mpsc::Sender<IncomingMessageResult>,
+    // `None` if nobody will ever read the body (e.g. we already answered the
+    // request with an error), in which case chunks are silently dropped.
+    tx: Option<mpsc::Sender<IncomingMessageResult>>,
 
     // total bytes received, which we keep track of, because if the client
     // announces a content-length and sends fewer or more bytes, we will
@@ -29,6 +29,9 @@ pub(crate) struct StreamIncoming {
     pub(crate) total_received: u64,
     pub(crate) content_length: Option<u64>,
 
+    // past this many bytes, the handler gets an error and the stream is reset
+    pub(crate) max_len: Option<u64>,
+
     // incoming capacity (that we decide, we get to tell
     // the peer how much we can handle with window updates)
     pub(crate) capacity: i64,
@@ -39,18 +42,35 @@ pub(crate) struct StreamIncoming {
 pub enum StreamIncomingError {
     #[error("stream reset")]
     StreamReset,
+
+    #[error("body is larger than the limit of {limit} bytes")]
+    BodyTooLarge { limit: u64 },
 }
 
 impl StreamIncoming {
     pub(crate) fn new(
         initial_window_size: u32,
         content_length: Option<u64>,
+        max_len: Option<u64>,
         tx: mpsc::Sender<IncomingMessageResult>,
     ) -> Self {
         Self {
-            tx,
+            tx: Some(tx),
             total_received: 0,
             content_length,
+            max_len,
+            capacity: initial_window_size as i64,
+        }
+    }
+
+    /// Accepts (and drops) whatever the peer sends on a stream we've already
+    /// responded to, so that it can finish sending its request body.
+    pub(crate) fn discarding(initial_window_size: u32) -> Self {
+        Self {
+            tx: None,
+            total_received: 0,
+            content_length: None,
+            max_len: None,
             capacity: initial_window_size as i64,
         }
     }
@@ -83,12 +103,18 @@ impl StreamIncoming {
             }
         }
 
-        if self
-            .tx
-            .send(Ok(IncomingMessage::Piece(chunk)))
-            .await
-            .is_err()
-        {
+        if let Some(limit) = self.max_len {
+            if self.total_received > limit {
+                self.send_error(StreamIncomingError::BodyTooLarge { limit })
+                    .await;
+                return Err(H2StreamError::BodyTooLarge { limit });
+            }
+        }
+
+        let Some(tx) = &self.tx else {
+            return Ok(());
+        };
+        if tx.send(Ok(IncomingMessage::Piece(chunk))).await.is_err() {
             // the stream is being ignored, so let's reset it
             return Err(H2StreamError::Cancel);
         }
@@ -105,10 +131,11 @@ impl StreamIncoming {
             }
         }
 
-        let _ = self
-            .tx
-            .send(Ok(IncomingMessage::Trailers(Box::new(trailers))))
-            .await;
+        if let Some(tx) = &self.tx {
+            let _ = tx
+                .send(Ok(IncomingMessage::Trailers(Box::new(trailers))))
+                .await;
+        }
 
         // TODO: keep track of what we've sent, panic if we're not in the right state.
 
@@ -116,7 +143,9 @@ impl StreamIncoming {
     }
 
     pub(crate) async fn send_error(&mut self, err: StreamIncomingError) {
-        let _ = self.tx.send(Err(err)).await;
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Err(err)).await;
+        }
     }
 }
 
@@ -134,6 +163,9 @@ pub(crate) struct H2Body {
 pub(crate) enum H2BodyError {
     #[error("Stream reset")]
     StreamReset,
+
+    #[error("body is larger than the limit of {limit} bytes")]
+    BodyTooLarge { limit: u64 },
 }
 
 impl AsRef<dyn std::error::Error> for H2BodyError {
@@ -167,6 +199,9 @@ impl Body for H2Body {
                         }
                     }
                     Err(StreamIncomingError::StreamReset) => return Err(H2BodyError::StreamReset),
+                    Err(StreamIncomingError::BodyTooLarge { limit }) => {
+                        return Err(H2BodyError::BodyTooLarge { limit })
+                    }
                 },
                 None => {
                     self.eof = true;
@@ -177,57 +212,3 @@ impl Body for H2Body {
         Ok(chunk)
     }
 }
-
-pub(crate) struct SinglePieceBody {
-    content_len: u64,
-    piece: Option<Piece>,
-}
-
-impl fmt::Debug for SinglePieceBody {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut debug_struct = f.debug_struct("SinglePieceBody");
-        debug_struct.field("content_len", &self.content_len);
-
-        if let Some(piece) = &self.piece {
-            match std::str::from_utf8(piece.as_ref()) {
-                Ok(utf8_str) => debug_struct.field("piece", &utf8_str),
-                Err(_) => debug_struct.field("piece", &"(non-utf8 string)"),
-            };
-        } else {
-            debug_struct.field("piece", &"(none)");
-        }
-
-        debug_struct.finish()
-    }
-}
-
-impl SinglePieceBody {
-    pub(crate) fn new(piece: Piece) -> Self {
-        let content_len = piece.len() as u64;
-        Self {
-            content_len,
-            piece: Some(piece),
-        }
-    }
-}
-
-impl Body for SinglePieceBody {
-    type Error = NeverError;
-
-    fn content_len(&self) -> Option<u64> {
-        Some(self.content_len)
-    }
-
-    fn eof(&self) -> bool {
-        self.piece.is_none()
-    }
-
-    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
-        tracing::trace!( has_piece = %self.piece.is_some(), "SinglePieceBody::next_chunk");
-        if let Some(piece) = self.piece.take() {
-            Ok(BodyChunk::Chunk(piece))
-        } else {
-            Ok(BodyChunk::Done { trailers: None })
-        }
-    }
-}
@@ -0,0 +1,1696 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    future::Future,
+    rc::Rc,
+    sync::atomic::AtomicU32,
+};
+
+use b_x::BX;
+use buffet::{Piece, PieceList, ReadOwned, Roll, RollMut, WriteOwned};
+use http::{header, HeaderName, StatusCode, Version};
+use loona_h2::{
+    self as parse, enumflags2::BitFlags, nom::Finish, ContinuationFlags, DataFlags, ErrorCode,
+    Frame, FrameType, GoAway, HeadersFlags, KnownErrorCode, PingFlags, PrioritySpec, RstStream,
+    Setting, SettingPairs, Settings, SettingsFlags, StreamId, WindowUpdate,
+};
+use parse::IntoPiece;
+use smallvec::{smallvec, SmallVec};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, trace};
+
+use crate::{
+    h1::parse::is_token,
+    h2::{
+        body::{ChunkPosition, H2Body, IncomingMessageResult, StreamIncoming, StreamIncomingError},
+        server::{deframe_loop, MAX_WINDOW_SIZE},
+        types::{
+            encode_trailers, ConnState, H2ConnectionError, H2Event, H2EventPayload, H2StreamError,
+            HeadersOutgoing, StreamState,
+        },
+    },
+    util::ReadAndParseError,
+    Body, BodyChunk, ClientDriver, Headers, HeadersExt, InvalidHeaderValue, Method, Request,
+    Response,
+};
+
+/// The connection-level window is always 65,535 bytes initially, regardless
+/// of SETTINGS_INITIAL_WINDOW_SIZE, cf. RFC 9113, section 6.9.2
+const INITIAL_CONNECTION_WINDOW_SIZE: i64 = (1 << 16) - 1;
+
+/// Stream identifiers are 31-bit, cf. RFC 9113, section 5.1.1
+const MAX_STREAM_ID: u32 = (1 << 31) - 1;
+
+/// HTTP/2 client configuration
+pub struct ClientConf {
+    /// The stream window we advertise, ie. how many bytes of response body the
+    /// server may send on a stream before we give it more credit.
+    pub initial_window_size: u32,
+}
+
+impl Default for ClientConf {
+    fn default() -> Self {
+        Self {
+            initial_window_size: Settings::default().initial_window_size,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum H2ClientError<DriverError> {
+    #[error("An error occurred with the client driver: {0}")]
+    DriverError(#[source] DriverError),
+
+    #[error("Could not read the request body: {0}")]
+    RequestBody(#[source] BX),
+
+    #[error("The connection was closed before the response was received")]
+    ConnectionClosed,
+
+    /// The request was not processed by the server, it's safe to retry it on
+    /// another connection.
+    #[error("The server sent GOAWAY (last stream {last_stream_id}, error code {error_code:?}) without processing this request")]
+    GoAway {
+        last_stream_id: StreamId,
+        error_code: ErrorCode,
+    },
+
+    #[error("The stream was reset with error code {error_code:?}")]
+    StreamReset { error_code: ErrorCode },
+
+    #[error("The server sent a malformed response: {0}")]
+    MalformedResponse(&'static str),
+
+    #[error("This connection ran out of stream IDs")]
+    StreamIdsExhausted,
+
+    #[error("Invalid request header: {0}")]
+    InvalidRequestHeader(#[source] InvalidHeaderValue),
+
+    /// The method isn't a token, cf. RFC 9110, section 9.1
+    #[error("Invalid method: {0:?}")]
+    InvalidMethod(Method),
+}
+
+impl<DriverError> From<H2ClientError<DriverError>> for BX
+where
+    DriverError: std::error::Error + 'static,
+{
+    fn from(e: H2ClientError<DriverError>) -> Self {
+        BX::from_err(e)
+    }
+}
+
+/// Why a stream failed, as seen by the connection task
+#[derive(Debug, Clone, Copy)]
+enum StreamFailure {
+    ConnectionClosed,
+    GoAway {
+        last_stream_id: StreamId,
+        error_code: ErrorCode,
+    },
+    Reset {
+        error_code: ErrorCode,
+    },
+    MalformedResponse(&'static str),
+    StreamIdsExhausted,
+}
+
+impl<DriverError> From<StreamFailure> for H2ClientError<DriverError> {
+    fn from(f: StreamFailure) -> Self {
+        match f {
+            StreamFailure::ConnectionClosed => H2ClientError::ConnectionClosed,
+            StreamFailure::GoAway {
+                last_stream_id,
+                error_code,
+            } => H2ClientError::GoAway {
+                last_stream_id,
+                error_code,
+            },
+            StreamFailure::Reset { error_code } => H2ClientError::StreamReset { error_code },
+            StreamFailure::MalformedResponse(msg) => H2ClientError::MalformedResponse(msg),
+            StreamFailure::StreamIdsExhausted => H2ClientError::StreamIdsExhausted,
+        }
+    }
+}
+
+/// Response headers (interim or final), as handed from the connection task to
+/// the request future.
+struct ResponseHead {
+    res: Response,
+    /// The length of the body, as far as the driver is concerned
+    content_length: Option<u64>,
+    /// Whether END_STREAM was set on the headers, ie. there's no body
+    end_stream: bool,
+}
+
+type ResponseHeadResult = Result<ResponseHead, StreamFailure>;
+
+enum ClientCommand {
+    Open(OpenStream),
+    Cancel(StreamId),
+}
+
+struct OpenStream {
+    req: Request,
+    /// Whether the request has no body at all
+    end_stream: bool,
+    res_tx: mpsc::Sender<ResponseHeadResult>,
+    piece_tx: mpsc::Sender<IncomingMessageResult>,
+    opened_tx: oneshot::Sender<Result<StreamId, StreamFailure>>,
+}
+
+/// A stream for which we haven't received the final response headers yet
+struct PendingResponse {
+    res_tx: mpsc::Sender<ResponseHeadResult>,
+    is_head: bool,
+}
+
+/// A handle to an HTTP/2 connection, used to issue requests. Clones share the
+/// same connection.
+#[derive(Clone)]
+pub struct Client {
+    cmd_tx: mpsc::Sender<ClientCommand>,
+    ev_tx: mpsc::Sender<H2Event>,
+}
+
+/// Set up an HTTP/2 connection over the given transport, with prior
+/// knowledge: no upgrade or ALPN negotiation happens here.
+///
+/// Returns a [Client] to issue requests with, and a future that drives the
+/// connection. That future must be polled (typically, spawned) for requests
+/// to make progress: it resolves once every [Client] clone has been dropped,
+/// or once the server goes away.
+pub fn connect<OurReadOwned, OurWriteOwned>(
+    (transport_r, transport_w): (OurReadOwned, OurWriteOwned),
+    conf: Rc<ClientConf>,
+) -> Result<(Client, impl Future<Output = Result<(), H2ConnectionError>>), buffet::bufpool::Error>
+where
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    let mut state = ConnState::default();
+    state.self_settings.initial_window_size = conf.initial_window_size;
+
+    let mut hpack_dec = loona_hpack::Decoder::new();
+    hpack_dec.set_max_allowed_table_size(Settings::default().header_table_size.try_into().unwrap());
+
+    let (cmd_tx, cmd_rx) = mpsc::channel::<ClientCommand>(32);
+    let (ev_tx, ev_rx) = mpsc::channel::<H2Event>(32);
+
+    let server_buf = RollMut::alloc()?;
+    let cx = ClientContext {
+        state,
+        hpack_dec,
+        hpack_enc: loona_hpack::Encoder::new(),
+        out_scratch: RollMut::alloc()?,
+        transport_w,
+        next_stream_id: StreamId(1),
+        responses: Default::default(),
+        waiting: Default::default(),
+        goaway_recv: None,
+        cmd_rx,
+        ev_rx,
+    };
+
+    Ok((Client { cmd_tx, ev_tx }, cx.work(server_buf, transport_r)))
+}
+
+impl Client {
+    /// Perform a request over this connection. Any number of requests may be
+    /// in flight at once, each with its own driver: they're multiplexed as
+    /// separate streams (up to the server's SETTINGS_MAX_CONCURRENT_STREAMS,
+    /// past which they wait for a stream to close).
+    pub async fn request<D>(
+        &self,
+        mut req: Request,
+        body: &mut impl Body,
+        driver: D,
+    ) -> Result<D::Return, H2ClientError<D::Error>>
+    where
+        D: ClientDriver,
+    {
+        if !is_token(req.method.as_str().as_bytes()) {
+            return Err(H2ClientError::InvalidMethod(req.method));
+        }
+        req.headers
+            .validate_values()
+            .map_err(H2ClientError::InvalidRequestHeader)?;
+
+        let end_stream = match body.content_len() {
+            Some(0) => true,
+            Some(len) => {
+                req.headers
+                    .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
+                false
+            }
+            None => false,
+        };
+
+        let (res_tx, mut res_rx) = mpsc::channel::<ResponseHeadResult>(4);
+        let (piece_tx, piece_rx) = mpsc::channel::<IncomingMessageResult>(1);
+        let (opened_tx, opened_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(ClientCommand::Open(OpenStream {
+                req,
+                end_stream,
+                res_tx,
+                piece_tx,
+                opened_tx,
+            }))
+            .await
+            .map_err(|_| H2ClientError::ConnectionClosed)?;
+        let stream_id = opened_rx
+            .await
+            .map_err(|_| H2ClientError::ConnectionClosed)??;
+        debug!(%stream_id, "opened stream");
+
+        // if we bail out (or get dropped) before the stream is done, reset it
+        // so the server doesn't wait on us forever.
+        let mut guard = CancelOnDrop {
+            cmd_tx: self.cmd_tx.clone(),
+            stream_id,
+            armed: true,
+        };
+
+        let send_body_fut = async {
+            if end_stream {
+                return Ok::<_, H2ClientError<D::Error>>(());
+            }
+
+            loop {
+                match body
+                    .next_chunk()
+                    .await
+                    .map_err(|e| H2ClientError::RequestBody(BX::from_err(e)))?
+                {
+                    BodyChunk::Chunk(chunk) => {
+                        if chunk.is_empty() {
+                            continue;
+                        }
+                        self.send_event(stream_id, H2EventPayload::BodyChunk(chunk))
+                            .await?;
+                    }
+                    BodyChunk::Done { trailers } => {
+                        let payload = match trailers {
+                            Some(trailers) => {
+                                trailers
+                                    .validate_values()
+                                    .map_err(H2ClientError::InvalidRequestHeader)?;
+                                H2EventPayload::Trailers(trailers)
+                            }
+                            None => H2EventPayload::BodyEnd,
+                        };
+                        self.send_event(stream_id, payload).await?;
+                        debug!("done writing request body");
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        let recv_res_fut = async move {
+            let mut driver = driver;
+
+            let head = loop {
+                let head = res_rx
+                    .recv()
+                    .await
+                    .ok_or(H2ClientError::ConnectionClosed)??;
+                if head.res.status.is_informational() {
+                    driver
+                        .on_informational_response(head.res)
+                        .await
+                        .map_err(H2ClientError::DriverError)?;
+                    continue;
+                }
+                break head;
+            };
+            debug!("client received response");
+            head.res.debug_print();
+
+            let mut res_body = H2Body {
+                content_length: head.content_length,
+                eof: head.end_stream,
+                rx: piece_rx,
+            };
+            let ret = driver
+                .on_final_response(head.res, &mut res_body)
+                .await
+                .map_err(H2ClientError::DriverError)?;
+
+            Ok((ret, res_body.eof))
+        };
+
+        let ((), (ret, res_body_drained)) = tokio::try_join!(send_body_fut, recv_res_fut)?;
+        if res_body_drained {
+            guard.armed = false;
+        }
+
+        Ok(ret)
+    }
+
+    async fn send_event<DriverError>(
+        &self,
+        stream_id: StreamId,
+        payload: H2EventPayload,
+    ) -> Result<(), H2ClientError<DriverError>> {
+        self.ev_tx
+            .send(H2Event { stream_id, payload })
+            .await
+            .map_err(|_| H2ClientError::ConnectionClosed)
+    }
+}
+
+struct CancelOnDrop {
+    cmd_tx: mpsc::Sender<ClientCommand>,
+    stream_id: StreamId,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.armed {
+            // if this fails, the connection is gone (or very busy) and the
+            // stream will get reset once nobody reads from it anyway.
+            _ = self.cmd_tx.try_send(ClientCommand::Cancel(self.stream_id));
+        }
+    }
+}
+
+#[derive(Debug)]
+enum LoopOutcome {
+    ServerHungUp,
+    ClientsDropped,
+    GoAwayDrained,
+}
+
+/// Reads and processes h2 frames from the server, and requests from [Client]
+/// handles.
+struct ClientContext<OurWriteOwned>
+where
+    OurWriteOwned: WriteOwned,
+{
+    state: ConnState,
+
+    hpack_dec: loona_hpack::Decoder<'static>,
+    hpack_enc: loona_hpack::Encoder<'static>,
+    out_scratch: RollMut,
+
+    transport_w: OurWriteOwned,
+
+    next_stream_id: StreamId,
+
+    /// Streams we haven't received final response headers for yet
+    responses: HashMap<StreamId, PendingResponse>,
+
+    /// Requests waiting for the number of concurrent streams to go down
+    waiting: VecDeque<OpenStream>,
+
+    /// Last stream ID and error code of the GOAWAY frame the server sent us
+    goaway_recv: Option<(StreamId, ErrorCode)>,
+
+    cmd_rx: mpsc::Receiver<ClientCommand>,
+    ev_rx: mpsc::Receiver<H2Event>,
+}
+
+impl<OurWriteOwned> ClientContext<OurWriteOwned>
+where
+    OurWriteOwned: WriteOwned,
+{
+    async fn work(
+        mut self,
+        server_buf: RollMut,
+        transport_r: impl ReadOwned,
+    ) -> Result<(), H2ConnectionError> {
+        let res = self.work_inner(server_buf, transport_r).await;
+
+        // whatever happened, those streams aren't going anywhere anymore
+        let failure = match self.goaway_recv {
+            Some((last_stream_id, error_code)) => StreamFailure::GoAway {
+                last_stream_id,
+                error_code,
+            },
+            None => StreamFailure::ConnectionClosed,
+        };
+        self.fail_all(failure);
+
+        debug!("h2 client connection done");
+        res
+    }
+
+    async fn work_inner(
+        &mut self,
+        server_buf: RollMut,
+        transport_r: impl ReadOwned,
+    ) -> Result<(), H2ConnectionError> {
+        let outcome;
+        let mut goaway_err: Option<H2ConnectionError> = None;
+
+        {
+            let (tx, rx) = mpsc::channel::<(Frame, Roll)>(32);
+            let max_frame_size = Rc::new(AtomicU32::new(self.state.self_settings.max_frame_size));
+
+            let mut deframe_task =
+                std::pin::pin!(deframe_loop(server_buf, transport_r, tx, max_frame_size));
+            let mut process_task = std::pin::pin!(self.process_loop(rx));
+
+            tokio::select! {
+                res = &mut deframe_task => {
+                    debug!(?res, "h2 client deframe task finished");
+
+                    match res {
+                        Err(H2ConnectionError::ReadAndParse(e)) => {
+                            return Err(H2ConnectionError::ReadAndParse(e));
+                        }
+                        Err(e) => {
+                            outcome = LoopOutcome::ServerHungUp;
+                            goaway_err = Some(e);
+                        }
+                        Ok(()) => {
+                            // process whatever frames were read before the server hung up
+                            match (&mut process_task).await {
+                                Ok(o) => outcome = o,
+                                Err(e) => {
+                                    outcome = LoopOutcome::ServerHungUp;
+                                    goaway_err = Some(e);
+                                }
+                            }
+                        }
+                    }
+                }
+                res = &mut process_task => {
+                    debug!(?res, "h2 client process task finished");
+
+                    match res {
+                        Ok(o) => outcome = o,
+                        Err(e) => {
+                            outcome = LoopOutcome::ServerHungUp;
+                            goaway_err = Some(e);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(err) = goaway_err {
+            let error_code = err.as_known_error_code();
+            debug!("Connection error: {err} ({err:?}) (code {error_code:?})");
+            // TODO: don't heap-allocate here
+            let additional_debug_data = format!("{err}").into_bytes();
+            self.write_goaway(error_code, additional_debug_data.into())
+                .await?;
+            return Err(err);
+        }
+
+        match outcome {
+            LoopOutcome::ClientsDropped => {
+                debug!("All client handles were dropped, saying goodbye");
+                self.write_goaway(KnownErrorCode::NoError, Piece::empty())
+                    .await?;
+            }
+            LoopOutcome::ServerHungUp | LoopOutcome::GoAwayDrained => {
+                // nothing to say
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the connection preface, followed by our initial settings.
+    async fn write_preface(&mut self) -> Result<(), H2ConnectionError> {
+        debug!("Sending preface and initial settings");
+        self.transport_w
+            .write_all_owned(parse::PREFACE)
+            .await
+            .map_err(H2ConnectionError::WriteError)?;
+
+        let setting_payload = {
+            let s = &self.state.self_settings;
+            SettingPairs(&[
+                (Setting::EnablePush, 0),
+                (Setting::HeaderTableSize, s.header_table_size),
+                (Setting::InitialWindowSize, s.initial_window_size),
+                (Setting::MaxFrameSize, s.max_frame_size),
+            ])
+            .into_piece(&mut self.out_scratch)
+            .map_err(H2ConnectionError::WriteError)?
+        };
+        let frame = Frame::new(
+            FrameType::Settings(Default::default()),
+            StreamId::CONNECTION,
+        );
+        self.write_frame(frame, PieceList::single(setting_payload))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn process_loop(
+        &mut self,
+        mut rx: mpsc::Receiver<(Frame, Roll)>,
+    ) -> Result<LoopOutcome, H2ConnectionError> {
+        // this happens while we're already reading frames: the server may not
+        // read ours until it's done writing its own settings.
+        self.write_preface().await?;
+
+        loop {
+            tokio::select! {
+                biased;
+
+                maybe_frame = rx.recv() => {
+                    if let Some((frame, payload)) = maybe_frame {
+                        self.process_frame(frame, payload, &mut rx).await?;
+                    } else {
+                        debug!("h2 client process task: server hung up");
+                        return Ok(LoopOutcome::ServerHungUp);
+                    }
+                }
+
+                ev = self.ev_rx.recv() => {
+                    match ev {
+                        Some(ev) => self.handle_event(ev)?,
+                        None => return Ok(LoopOutcome::ClientsDropped),
+                    }
+                }
+
+                cmd = self.cmd_rx.recv() => {
+                    match cmd {
+                        Some(cmd) => self.handle_command(cmd).await?,
+                        None => return Ok(LoopOutcome::ClientsDropped),
+                    }
+                }
+
+                _ = self.state.send_data_maybe.notified() => {
+                    self.send_data_maybe().await?;
+                }
+            }
+
+            self.open_waiting_streams().await?;
+
+            if self.goaway_recv.is_some()
+                && self.state.streams.is_empty()
+                && self.responses.is_empty()
+            {
+                debug!("server sent GOAWAY and all our streams are done");
+                return Ok(LoopOutcome::GoAwayDrained);
+            }
+        }
+    }
+
+    fn handle_event(&mut self, ev: H2Event) -> Result<(), H2ConnectionError> {
+        trace!(?ev, "handling event");
+
+        match ev.payload {
+            H2EventPayload::Headers(_) => {
+                unreachable!("the client never sends response headers")
+            }
+            H2EventPayload::BodyChunk(chunk) => {
+                self.state.queue_body_chunk(ev.stream_id, chunk);
+            }
+            H2EventPayload::BodyEnd => {
+                self.state.queue_body_end(ev.stream_id, None);
+            }
+            H2EventPayload::Trailers(trailers) => {
+                let block = encode_trailers(&mut self.hpack_enc, &mut self.out_scratch, &trailers)?;
+                self.state.queue_body_end(ev.stream_id, Some(block));
+            }
+            H2EventPayload::Reset => {
+                unreachable!("only response encoders reset streams")
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, cmd: ClientCommand) -> Result<(), H2ConnectionError> {
+        match cmd {
+            ClientCommand::Open(open) => {
+                if let Some((last_stream_id, error_code)) = self.goaway_recv {
+                    _ = open.opened_tx.send(Err(StreamFailure::GoAway {
+                        last_stream_id,
+                        error_code,
+                    }));
+                } else {
+                    // actually opened by `open_waiting_streams`, if the server
+                    // lets us have that many streams.
+                    self.waiting.push_back(open);
+                }
+            }
+            ClientCommand::Cancel(stream_id) => {
+                if self.state.streams.contains_key(&stream_id)
+                    || self.responses.contains_key(&stream_id)
+                {
+                    debug!(%stream_id, "request was cancelled, resetting stream");
+                    self.rst(stream_id, H2StreamError::Cancel).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn open_waiting_streams(&mut self) -> Result<(), H2ConnectionError> {
+        let max_concurrent_streams = self
+            .state
+            .peer_settings
+            .max_concurrent_streams
+            .unwrap_or(u32::MAX) as usize;
+
+        while self.state.streams.len() < max_concurrent_streams {
+            let open = match self.waiting.pop_front() {
+                Some(open) => open,
+                None => break,
+            };
+
+            if open.opened_tx.is_closed() {
+                // the request was cancelled while it was waiting
+                continue;
+            }
+            self.open_stream(open).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn open_stream(&mut self, open: OpenStream) -> Result<(), H2ConnectionError> {
+        let OpenStream {
+            req,
+            end_stream,
+            res_tx,
+            piece_tx,
+            opened_tx,
+        } = open;
+
+        let stream_id = self.next_stream_id;
+        if stream_id.0 > MAX_STREAM_ID {
+            _ = opened_tx.send(Err(StreamFailure::StreamIdsExhausted));
+            return Ok(());
+        }
+        self.next_stream_id = StreamId(stream_id.0 + 2);
+
+        let is_head = req.method == Method::Head;
+        let block = self.encode_request_headers(req)?;
+
+        // header blocks are written right away: they're not subject to flow
+        // control, and the HPACK encoder state depends on the server receiving
+        // them in the order we encoded them.
+        let max_fram = self.state.peer_settings.max_frame_size as usize;
+        let mut fragment = block;
+        let mut is_first = true;
+        loop {
+            let rest = if fragment.len() > max_fram {
+                let (written, rest) = fragment.split_at(max_fram);
+                fragment = written;
+                Some(rest)
+            } else {
+                None
+            };
+            let end_headers = rest.is_none();
+
+            let frame_type = if is_first {
+                let mut flags = BitFlags::<HeadersFlags>::default();
+                if end_headers {
+                    flags |= HeadersFlags::EndHeaders;
+                }
+                if end_stream {
+                    flags |= HeadersFlags::EndStream;
+                }
+                FrameType::Headers(flags)
+            } else {
+                let mut flags = BitFlags::<ContinuationFlags>::default();
+                if end_headers {
+                    flags |= ContinuationFlags::EndHeaders;
+                }
+                FrameType::Continuation(flags)
+            };
+            self.write_frame(
+                Frame::new(frame_type, stream_id),
+                PieceList::single(fragment),
+            )
+            .await?;
+
+            match rest {
+                Some(rest) => {
+                    fragment = rest;
+                    is_first = false;
+                }
+                None => break,
+            }
+        }
+
+        let incoming = StreamIncoming::new(
+            self.state.self_settings.initial_window_size,
+            None,
+            None,
+            piece_tx,
+        );
+        let ss = if end_stream {
+            StreamState::HalfClosedLocal { incoming }
+        } else {
+            let mut outgoing = self.state.mk_stream_outgoing();
+            outgoing.headers = HeadersOutgoing::WroteAll;
+            StreamState::Open { incoming, outgoing }
+        };
+        self.state.streams.insert(stream_id, ss);
+        self.responses
+            .insert(stream_id, PendingResponse { res_tx, is_head });
+        debug!(
+            "Just opened stream {stream_id}, now have {} streams",
+            self.state.streams.len()
+        );
+
+        if opened_tx.send(Ok(stream_id)).is_err() {
+            debug!(%stream_id, "request was cancelled right as we opened its stream");
+            self.rst(stream_id, H2StreamError::Cancel).await?;
+        }
+
+        Ok(())
+    }
+
+    fn encode_request_headers(&mut self, req: Request) -> Result<Piece, H2ConnectionError> {
+        let Request {
+            method,
+            uri,
+            mut headers,
+            ..
+        } = req;
+
+        let method = method.into_chunk();
+        let scheme = uri.scheme_str().unwrap_or("http");
+        // cf. RFC 9113, section 8.3.1: clients that generate HTTP/2 requests
+        // directly MUST use the ':authority' pseudo-header field to convey
+        // authority information, instead of the 'host' header field.
+        let host = headers.remove(header::HOST);
+        let authority: Option<&[u8]> = match uri.authority() {
+            Some(authority) => Some(authority.as_str().as_bytes()),
+            None => host.as_deref(),
+        };
+        let path = match uri.path_and_query().map(|pq| pq.as_str()) {
+            Some(pq) if !pq.is_empty() => pq,
+            _ => "/",
+        };
+
+        // TODO: don't allocate so much for headers, cf. the server
+        let mut pairs: Vec<(&[u8], &[u8])> =
+            vec![(b":method", &method[..]), (b":scheme", scheme.as_bytes())];
+        if let Some(authority) = authority {
+            pairs.push((b":authority", authority));
+        }
+        pairs.push((b":path", path.as_bytes()));
+
+        // connection-specific headers are forbidden, cf. RFC 9113, section 8.2.2
+        static KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+        static PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
+
+        for (name, value) in headers.iter() {
+            if name == header::CONNECTION
+                || name == KEEP_ALIVE
+                || name == PROXY_CONNECTION
+                || name == header::TRANSFER_ENCODING
+                || name == header::UPGRADE
+            {
+                continue;
+            }
+
+            if name == header::TE && &value[..] != b"trailers" {
+                continue;
+            }
+
+            pairs.push((name.as_str().as_bytes(), value));
+        }
+
+        assert_eq!(self.out_scratch.len(), 0);
+        self.hpack_enc
+            .encode_into(pairs, &mut self.out_scratch)
+            .map_err(H2ConnectionError::WriteError)?;
+        Ok(self.out_scratch.take_all().into())
+    }
+
+    async fn send_data_maybe(&mut self) -> Result<(), H2ConnectionError> {
+        for (frame, plist) in self.state.take_pending_frames() {
+            debug!(?frame, plist_len = %plist.len(), "writing");
+            self.write_frame(frame, plist).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_frame(
+        &mut self,
+        mut frame: Frame,
+        payload: PieceList,
+    ) -> Result<(), H2ConnectionError> {
+        self.state.on_frame_written(&frame, &payload);
+
+        frame.len = payload
+            .len()
+            .try_into()
+            .map_err(|_| H2ConnectionError::FrameTooLarge {
+                frame_type: frame.frame_type,
+                frame_size: payload.len() as _,
+                max_frame_size: u32::MAX,
+            })?;
+        debug!(?frame, ">");
+        let frame_roll = frame
+            .into_piece(&mut self.out_scratch)
+            .map_err(H2ConnectionError::WriteError)?;
+
+        if payload.is_empty() {
+            trace!("Writing frame without payload");
+            self.transport_w
+                .write_all_owned(frame_roll)
+                .await
+                .map_err(H2ConnectionError::WriteError)?;
+        } else {
+            trace!("Writing frame with payload");
+            self.transport_w
+                .writev_all_owned(payload.preceded_by(frame_roll))
+                .await
+                .map_err(H2ConnectionError::WriteError)?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_goaway(
+        &mut self,
+        error_code: KnownErrorCode,
+        additional_debug_data: Piece,
+    ) -> Result<(), H2ConnectionError> {
+        // we never accept streams from the server, so there's never a
+        // stream it initiated that we processed.
+        let last_stream_id = StreamId::CONNECTION;
+        debug!(%last_stream_id, ?error_code, "Sending GoAway");
+
+        let payload = GoAway {
+            last_stream_id,
+            error_code: error_code.into(),
+            additional_debug_data,
+        }
+        .into_piece(&mut self.out_scratch)
+        .map_err(H2ConnectionError::WriteError)?;
+
+        let frame = Frame::new(FrameType::GoAway, StreamId::CONNECTION);
+        self.write_frame(frame, PieceList::single(payload)).await
+    }
+
+    async fn write_window_update(
+        &mut self,
+        stream_id: StreamId,
+        increment: u32,
+    ) -> Result<(), H2ConnectionError> {
+        let payload = WindowUpdate {
+            reserved: 0,
+            increment,
+        }
+        .into_piece(&mut self.out_scratch)
+        .map_err(H2ConnectionError::WriteError)?;
+
+        let frame = Frame::new(FrameType::WindowUpdate, stream_id);
+        self.write_frame(frame, PieceList::single(payload)).await
+    }
+
+    /// Send a RST_STREAM frame to the server, and let the request know.
+    async fn rst(
+        &mut self,
+        stream_id: StreamId,
+        e: H2StreamError,
+    ) -> Result<(), H2ConnectionError> {
+        let error_code = e.as_known_error_code();
+        debug!(%stream_id, ?error_code, "Sending RstStream because: {e}");
+
+        let payload = RstStream {
+            error_code: error_code.into(),
+        }
+        .into_piece(&mut self.out_scratch)
+        .map_err(H2ConnectionError::WriteError)?;
+
+        let frame = Frame::new(FrameType::RstStream, stream_id);
+        self.write_frame(frame, PieceList::single(payload)).await?;
+
+        let failure = match e {
+            H2StreamError::BadResponse(msg) => StreamFailure::MalformedResponse(msg),
+            _ => StreamFailure::Reset {
+                error_code: error_code.into(),
+            },
+        };
+        self.fail_stream(stream_id, failure);
+
+        Ok(())
+    }
+
+    /// Forget about a stream, letting whoever is waiting on its response
+    /// (headers or body) know that it failed.
+    fn fail_stream(&mut self, stream_id: StreamId, failure: StreamFailure) {
+        self.state.streams_with_pending_data.remove(&stream_id);
+
+        let pending = self.responses.remove(&stream_id);
+        let incoming = match self.state.streams.remove(&stream_id) {
+            Some(
+                StreamState::Open { incoming, .. } | StreamState::HalfClosedLocal { incoming },
+            ) => Some(incoming),
+            _ => None,
+        };
+        debug!(
+            "Closed stream {stream_id} ({failure:?}), now have {} streams",
+            self.state.streams.len()
+        );
+
+        if pending.is_none() && incoming.is_none() {
+            return;
+        }
+
+        // don't hold up the connection if the request isn't reading
+        buffet::spawn(async move {
+            if let Some(pending) = pending {
+                _ = pending.res_tx.send(Err(failure)).await;
+            }
+            if let Some(mut incoming) = incoming {
+                incoming.send_error(StreamIncomingError::StreamReset).await;
+            }
+        });
+    }
+
+    fn fail_all(&mut self, failure: StreamFailure) {
+        let mut ids: Vec<StreamId> = self
+            .state
+            .streams
+            .keys()
+            .chain(self.responses.keys())
+            .copied()
+            .collect();
+        ids.sort();
+        ids.dedup();
+
+        for id in ids {
+            self.fail_stream(id, failure);
+        }
+
+        for open in self.waiting.drain(..) {
+            _ = open.opened_tx.send(Err(failure));
+        }
+    }
+
+    /// Transition a stream after the server sent END_STREAM on it
+    fn on_remote_end_stream(&mut self, stream_id: StreamId) {
+        let mut slot = match self.state.streams.entry(stream_id) {
+            Entry::Occupied(slot) => slot,
+            Entry::Vacant(_) => return,
+        };
+
+        match slot.get_mut() {
+            StreamState::Open { .. } => {
+                let outgoing = match std::mem::take(slot.get_mut()) {
+                    StreamState::Open { outgoing, .. } => outgoing,
+                    _ => unreachable!(),
+                };
+                // this avoid having to re-insert the stream in the map
+                *slot.get_mut() = StreamState::HalfClosedRemote { outgoing };
+            }
+            StreamState::HalfClosedLocal { .. } => {
+                slot.remove();
+                debug!(
+                    "Closed stream {stream_id} (read END_STREAM), now have {} streams",
+                    self.state.streams.len()
+                );
+            }
+            StreamState::HalfClosedRemote { .. } | StreamState::Transition => {
+                unreachable!("received END_STREAM twice")
+            }
+        }
+    }
+
+    /// Whether the given stream is one we haven't opened yet
+    fn is_idle(&self, stream_id: StreamId) -> bool {
+        stream_id.is_server_initiated() || stream_id >= self.next_stream_id
+    }
+
+    async fn process_frame(
+        &mut self,
+        frame: Frame,
+        mut payload: Roll,
+        rx: &mut mpsc::Receiver<(Frame, Roll)>,
+    ) -> Result<(), H2ConnectionError> {
+        match frame.frame_type {
+            FrameType::Data(flags) => {
+                if frame.stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::StreamSpecificFrameToConnection {
+                        frame_type: frame.frame_type,
+                    });
+                }
+
+                // padding counts towards flow control, so use the frame
+                // length, not the payload length
+                self.state.incoming_capacity -= frame.len as i64;
+                if self.state.incoming_capacity < 0 {
+                    return Err(H2ConnectionError::WindowUnderflow {
+                        stream_id: StreamId::CONNECTION,
+                    });
+                }
+
+                self.process_data(frame, flags, payload).await?;
+
+                // give the server more connection-level credit once we've
+                // consumed half of it
+                if self.state.incoming_capacity < INITIAL_CONNECTION_WINDOW_SIZE / 2 {
+                    let increment = INITIAL_CONNECTION_WINDOW_SIZE - self.state.incoming_capacity;
+                    self.state.incoming_capacity = INITIAL_CONNECTION_WINDOW_SIZE;
+                    self.write_window_update(StreamId::CONNECTION, increment as u32)
+                        .await?;
+                }
+            }
+            FrameType::Headers(flags) => {
+                if frame.stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::StreamSpecificFrameToConnection {
+                        frame_type: frame.frame_type,
+                    });
+                }
+
+                if flags.contains(HeadersFlags::Priority) {
+                    let pri_spec;
+                    (payload, pri_spec) = PrioritySpec::parse(payload).finish().map_err(|_| {
+                        H2ConnectionError::ReadAndParse(ReadAndParseError::ParsingError {
+                            parser: "PrioritySpec",
+                        })
+                    })?;
+                    debug!(?pri_spec, "received priority in headers frame, ignoring");
+                }
+
+                let block = read_header_block(flags, frame.stream_id, payload, rx).await?;
+                self.process_header_block(
+                    frame.stream_id,
+                    flags.contains(HeadersFlags::EndStream),
+                    block,
+                )
+                .await?;
+            }
+            FrameType::Priority => {
+                // we never get to pick which stream the server works on first
+                debug!(stream_id = %frame.stream_id, "ignoring priority frame");
+            }
+            FrameType::RstStream => {
+                if frame.stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::StreamSpecificFrameToConnection {
+                        frame_type: frame.frame_type,
+                    });
+                }
+
+                if frame.len != 4 {
+                    self.rst(
+                        frame.stream_id,
+                        H2StreamError::InvalidRstStreamFrameSize {
+                            frame_size: frame.len,
+                        },
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                if self.is_idle(frame.stream_id) {
+                    return Err(H2ConnectionError::RstStreamForUnknownStream {
+                        stream_id: frame.stream_id,
+                    });
+                }
+
+                let (_, rst) = RstStream::parse(payload).finish().map_err(|_| {
+                    H2ConnectionError::ReadAndParse(ReadAndParseError::ParsingError {
+                        parser: "RstStream",
+                    })
+                })?;
+                debug!(stream_id = %frame.stream_id, error_code = ?rst.error_code, "server reset stream");
+                self.fail_stream(
+                    frame.stream_id,
+                    StreamFailure::Reset {
+                        error_code: rst.error_code,
+                    },
+                );
+            }
+            FrameType::Settings(s) => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::SettingsWithNonZeroStreamId {
+                        stream_id: frame.stream_id,
+                    });
+                }
+
+                if payload.len() % 6 != 0 {
+                    return Err(H2ConnectionError::SettingsInvalidLength {
+                        len: payload.len() as _,
+                    });
+                }
+
+                if s.contains(SettingsFlags::Ack) {
+                    debug!("Server has acknowledged our settings, cool");
+                    if !payload.is_empty() {
+                        return Err(H2ConnectionError::SettingsInvalidLength {
+                            len: payload.len() as _,
+                        });
+                    }
+                } else {
+                    let original_initial_window_size = self.state.peer_settings.initial_window_size;
+                    let s = &mut self.state.peer_settings;
+
+                    Settings::parse(&payload[..], |code, value| {
+                        s.apply(code, value)?;
+                        if let Setting::HeaderTableSize = code {
+                            self.hpack_enc.set_max_table_size(value as _);
+                        }
+                        Ok(())
+                    })
+                    .map_err(H2ConnectionError::BadSettingValue)?;
+
+                    let initial_window_size_delta =
+                        (s.initial_window_size as i64) - (original_initial_window_size as i64);
+
+                    let mut maybe_send_data = false;
+                    if initial_window_size_delta != 0 {
+                        // apply that delta to all streams
+                        for (id, stream) in self.state.streams.iter_mut() {
+                            if let Some(outgoing) = stream.outgoing_mut() {
+                                let next_cap = outgoing.capacity + initial_window_size_delta;
+                                if next_cap > MAX_WINDOW_SIZE {
+                                    return Err(
+                                        H2ConnectionError::StreamWindowSizeOverflowDueToSettings {
+                                            stream_id: *id,
+                                        },
+                                    );
+                                }
+                                if next_cap > 0 && outgoing.capacity <= 0 {
+                                    maybe_send_data = true;
+                                }
+                                outgoing.capacity = next_cap;
+                            }
+                        }
+                    }
+
+                    let frame = Frame::new(
+                        FrameType::Settings(SettingsFlags::Ack.into()),
+                        StreamId::CONNECTION,
+                    );
+                    self.write_frame(frame, PieceList::default()).await?;
+                    debug!("Acknowledged server settings");
+
+                    if maybe_send_data {
+                        self.state.send_data_maybe.notify_one();
+                    }
+                }
+            }
+            FrameType::PushPromise => {
+                return Err(H2ConnectionError::ServerSentPushPromise);
+            }
+            FrameType::Ping(flags) => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PingFrameWithNonZeroStreamId {
+                        stream_id: frame.stream_id,
+                    });
+                }
+
+                if frame.len != 8 {
+                    return Err(H2ConnectionError::PingFrameInvalidLength { len: frame.len });
+                }
+
+                if flags.contains(PingFlags::Ack) {
+                    // we never send pings
+                    return Ok(());
+                }
+
+                // send pong frame
+                let flags = PingFlags::Ack.into();
+                let frame = Frame::new(FrameType::Ping(flags), StreamId::CONNECTION)
+                    .with_len(payload.len() as u32);
+                self.write_frame(frame, PieceList::default().followed_by(payload))
+                    .await?;
+            }
+            FrameType::GoAway => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::GoAwayWithNonZeroStreamId {
+                        stream_id: frame.stream_id,
+                    });
+                }
+
+                let (_, goaway) = GoAway::parse(payload).finish().map_err(|_| {
+                    H2ConnectionError::ReadAndParse(ReadAndParseError::ParsingError {
+                        parser: "GoAway",
+                    })
+                })?;
+                debug!(last_stream_id = %goaway.last_stream_id, error_code = ?goaway.error_code, "Received GoAway");
+                self.goaway_recv = Some((goaway.last_stream_id, goaway.error_code));
+
+                // streams past `last_stream_id` weren't processed, and won't
+                // be: let their requests know they can be retried elsewhere.
+                let failure = StreamFailure::GoAway {
+                    last_stream_id: goaway.last_stream_id,
+                    error_code: goaway.error_code,
+                };
+                let mut unprocessed: Vec<StreamId> = self
+                    .state
+                    .streams
+                    .keys()
+                    .chain(self.responses.keys())
+                    .filter(|id| **id > goaway.last_stream_id)
+                    .copied()
+                    .collect();
+                unprocessed.sort();
+                unprocessed.dedup();
+                for id in unprocessed {
+                    self.fail_stream(id, failure);
+                }
+
+                for open in self.waiting.drain(..) {
+                    _ = open.opened_tx.send(Err(failure));
+                }
+            }
+            FrameType::WindowUpdate => {
+                if payload.len() != 4 {
+                    return Err(H2ConnectionError::WindowUpdateInvalidLength {
+                        len: payload.len() as _,
+                    });
+                }
+
+                let (_, update) = WindowUpdate::parse(payload).finish().map_err(|_| {
+                    H2ConnectionError::ReadAndParse(ReadAndParseError::ParsingError {
+                        parser: "WindowUpdate",
+                    })
+                })?;
+                debug!(?update, "Received window update");
+
+                if update.increment == 0 {
+                    return Err(H2ConnectionError::WindowUpdateZeroIncrement);
+                }
+
+                if frame.stream_id == StreamId::CONNECTION {
+                    let new_capacity = self.state.outgoing_capacity + update.increment as i64;
+                    if new_capacity > MAX_WINDOW_SIZE {
+                        return Err(H2ConnectionError::WindowUpdateOverflow);
+                    };
+
+                    debug!(old_capacity = %self.state.outgoing_capacity, %new_capacity, "connection window update");
+                    self.state.outgoing_capacity = new_capacity;
+                    self.state.send_data_maybe.notify_one();
+                } else {
+                    let outgoing = match self
+                        .state
+                        .streams
+                        .get_mut(&frame.stream_id)
+                        .and_then(|ss| ss.outgoing_mut())
+                    {
+                        Some(ss) => ss,
+                        None => {
+                            if self.is_idle(frame.stream_id) {
+                                return Err(
+                                    H2ConnectionError::WindowUpdateForUnknownOrClosedStream {
+                                        stream_id: frame.stream_id,
+                                    },
+                                );
+                            }
+                            // we're done sending on that stream, the server
+                            // may not know it yet.
+                            return Ok(());
+                        }
+                    };
+
+                    let new_capacity = outgoing.capacity + update.increment as i64;
+                    if new_capacity > MAX_WINDOW_SIZE {
+                        self.rst(frame.stream_id, H2StreamError::WindowUpdateOverflow)
+                            .await?;
+                        return Ok(());
+                    }
+
+                    let old_capacity = outgoing.capacity;
+                    debug!(stream_id = %frame.stream_id, %old_capacity, %new_capacity, "stream window update");
+                    outgoing.capacity = new_capacity;
+
+                    if old_capacity <= 0 && new_capacity > 0 {
+                        self.state.streams_with_pending_data.insert(frame.stream_id);
+                        if self.state.outgoing_capacity > 0 {
+                            self.state.send_data_maybe.notify_one();
+                        }
+                    }
+                }
+            }
+            FrameType::Continuation(_flags) => {
+                return Err(H2ConnectionError::UnexpectedContinuationFrame {
+                    stream_id: frame.stream_id,
+                });
+            }
+            FrameType::Unknown(ft) => {
+                trace!(
+                    "ignoring unknown frame with type 0x{:x}, flags 0x{:x}",
+                    ft.ty,
+                    ft.flags
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn process_data(
+        &mut self,
+        frame: Frame,
+        flags: BitFlags<DataFlags>,
+        payload: Roll,
+    ) -> Result<(), H2ConnectionError> {
+        let stream_id = frame.stream_id;
+
+        if self.responses.contains_key(&stream_id) {
+            self.rst(
+                stream_id,
+                H2StreamError::BadResponse("received DATA frame before final response headers"),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let stream_window = self.state.self_settings.initial_window_size as i64;
+        let end_stream = flags.contains(DataFlags::EndStream);
+
+        let ss = match self.state.streams.get_mut(&stream_id) {
+            Some(ss) => ss,
+            None => {
+                if self.is_idle(stream_id) {
+                    return Err(H2ConnectionError::StreamClosed { stream_id });
+                }
+                // probably a stream we reset, the server may not know yet.
+                return Ok(());
+            }
+        };
+
+        let incoming = match ss {
+            StreamState::Open { incoming, .. } | StreamState::HalfClosedLocal { incoming } => {
+                incoming
+            }
+            StreamState::HalfClosedRemote { .. } => {
+                debug!(%stream_id, "Received data for closed stream");
+                self.rst(stream_id, H2StreamError::StreamClosed).await?;
+                return Ok(());
+            }
+            StreamState::Transition => unreachable!(),
+        };
+
+        let next_cap = incoming.capacity - frame.len as i64;
+        if next_cap < 0 {
+            return Err(H2ConnectionError::WindowUnderflow { stream_id });
+        }
+        incoming.capacity = next_cap;
+
+        let which = if end_stream {
+            ChunkPosition::Last
+        } else {
+            ChunkPosition::NotLast
+        };
+        if let Err(e) = incoming.write_chunk(payload.into(), which).await {
+            self.rst(stream_id, e).await?;
+            return Ok(());
+        }
+
+        if end_stream {
+            self.on_remote_end_stream(stream_id);
+        } else if incoming.capacity < stream_window / 2 {
+            // the request has consumed that data, give the server more credit
+            let increment = stream_window - incoming.capacity;
+            incoming.capacity = stream_window;
+            self.write_window_update(stream_id, increment as u32)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_header_block(
+        &mut self,
+        stream_id: StreamId,
+        end_stream: bool,
+        block: Piece,
+    ) -> Result<(), H2ConnectionError> {
+        let mut status: Option<StatusCode> = None;
+        let mut headers = Headers::default();
+
+        {
+            // we must decode the whole block no matter what, to keep the
+            // HPACK decoder's state in sync with the server's encoder.
+            let mut res_error: Option<&'static str> = None;
+            let mut saw_regular_header = false;
+
+            let on_header_pair = |key: std::borrow::Cow<[u8]>, value: std::borrow::Cow<[u8]>| {
+                if res_error.is_some() {
+                    return;
+                }
+
+                if key.first() == Some(&b':') {
+                    if saw_regular_header {
+                        res_error = Some("All pseudo-header fields MUST appear in a field block before all regular field lines (RFC 9113, section 8.3)");
+                        return;
+                    }
+
+                    if &key[..] != b":status" {
+                        res_error = Some("the only pseudo-header defined for responses is ':status' (RFC 9113, section 8.3.2)");
+                        return;
+                    }
+
+                    match StatusCode::from_bytes(&value) {
+                        Ok(code) => {
+                            if status.replace(code).is_some() {
+                                res_error = Some("duplicate ':status' pseudo-header");
+                            }
+                        }
+                        Err(_) => {
+                            res_error = Some("invalid ':status' pseudo-header");
+                        }
+                    }
+                } else {
+                    saw_regular_header = true;
+
+                    if key.iter().any(|b: &u8| b.is_ascii_uppercase()) {
+                        res_error = Some("field names MUST NOT contain uppercase characters (RFC 9113, section 8.2.1)");
+                        return;
+                    }
+
+                    let name = match HeaderName::from_bytes(&key[..]) {
+                        Ok(name) => name,
+                        Err(_) => {
+                            res_error = Some("invalid header name (RFC 9113, section 8.2.1)");
+                            return;
+                        }
+                    };
+                    headers.append(name, Piece::from(value.to_vec()));
+                }
+            };
+
+            self.hpack_dec
+                .decode_with_cb(&block[..], on_header_pair)
+                .map_err(H2ConnectionError::HpackDecodingError)?;
+
+            if let Some(msg) = res_error {
+                if self.state.streams.contains_key(&stream_id) {
+                    self.rst(stream_id, H2StreamError::BadResponse(msg)).await?;
+                }
+                return Ok(());
+            }
+        }
+
+        if let Some(pending) = self.responses.get(&stream_id) {
+            let status = match status {
+                Some(status) => status,
+                None => {
+                    self.rst(
+                        stream_id,
+                        H2StreamError::BadResponse("missing ':status' pseudo-header"),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+
+            if status.is_informational() {
+                if status == StatusCode::SWITCHING_PROTOCOLS {
+                    self.rst(
+                        stream_id,
+                        H2StreamError::BadResponse(
+                            "101 (Switching Protocols) is not supported in HTTP/2 (RFC 9113, section 8.6)",
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                if end_stream {
+                    self.rst(
+                        stream_id,
+                        H2StreamError::BadResponse("interim response headers had END_STREAM set"),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                let head = ResponseHead {
+                    res: Response {
+                        version: Version::HTTP_2,
+                        status,
+                        headers,
+                    },
+                    content_length: None,
+                    end_stream: false,
+                };
+                if pending.res_tx.send(Ok(head)).await.is_err() {
+                    self.rst(stream_id, H2StreamError::Cancel).await?;
+                }
+                return Ok(());
+            }
+
+            let announced_content_length = headers.content_length();
+            if announced_content_length.is_none() && headers.contains_key(header::CONTENT_LENGTH) {
+                self.rst(
+                    stream_id,
+                    H2StreamError::BadResponse(
+                        "content-length header value is not a valid integer",
+                    ),
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let pending = self.responses.remove(&stream_id).unwrap();
+            let empty_body = pending.is_head
+                || matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED);
+
+            if let Some(
+                StreamState::Open { incoming, .. } | StreamState::HalfClosedLocal { incoming },
+            ) = self.state.streams.get_mut(&stream_id)
+            {
+                // content-length describes the representation, not the
+                // message body, for responses that don't have one.
+                if !empty_body {
+                    incoming.content_length = announced_content_length;
+                }
+            }
+
+            let head = ResponseHead {
+                res: Response {
+                    version: Version::HTTP_2,
+                    status,
+                    headers,
+                },
+                content_length: if end_stream || empty_body {
+                    Some(0)
+                } else {
+                    announced_content_length
+                },
+                end_stream,
+            };
+            if pending.res_tx.send(Ok(head)).await.is_err() {
+                self.rst(stream_id, H2StreamError::Cancel).await?;
+                return Ok(());
+            }
+
+            if end_stream {
+                self.on_remote_end_stream(stream_id);
+            }
+            return Ok(());
+        }
+
+        match self.state.streams.get_mut(&stream_id) {
+            Some(
+                StreamState::Open { incoming, .. } | StreamState::HalfClosedLocal { incoming },
+            ) => {
+                debug!("Receiving trailers for stream {stream_id}");
+
+                if !end_stream {
+                    self.rst(stream_id, H2StreamError::TrailersNotEndStream)
+                        .await?;
+                    return Ok(());
+                }
+
+                if status.is_some() {
+                    self.rst(
+                        stream_id,
+                        H2StreamError::BadResponse(
+                            "Pseudo-header fields MUST NOT appear in a trailer section (RFC 9113, section 8.1)",
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                if let Err(e) = incoming.write_trailers(headers).await {
+                    self.rst(stream_id, e).await?;
+                    return Ok(());
+                }
+                self.on_remote_end_stream(stream_id);
+            }
+            Some(StreamState::HalfClosedRemote { .. }) => {
+                return Err(H2ConnectionError::StreamClosed { stream_id });
+            }
+            Some(StreamState::Transition) => unreachable!(),
+            None => {
+                if self.is_idle(stream_id) {
+                    return Err(H2ConnectionError::HeadersForIdleStream { stream_id });
+                }
+                // probably a stream we reset, the server may not know yet.
+                debug!(%stream_id, "ignoring headers for closed stream");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the CONTINUATION frames following a HEADERS frame, if any, and
+/// returns the complete field block.
+async fn read_header_block(
+    flags: BitFlags<HeadersFlags>,
+    stream_id: StreamId,
+    payload: Roll,
+    rx: &mut mpsc::Receiver<(Frame, Roll)>,
+) -> Result<Piece, H2ConnectionError> {
+    if flags.contains(HeadersFlags::EndHeaders) {
+        // good, no continuation frames needed
+        return Ok(payload.into());
+    }
+
+    let mut fragments: SmallVec<[Roll; 2]> = smallvec![payload];
+
+    loop {
+        let (continuation_frame, continuation_payload) = match rx.recv().await {
+            Some(t) => t,
+            None => {
+                return Err(H2ConnectionError::ExpectedContinuationFrame {
+                    stream_id,
+                    frame_type: None,
+                });
+            }
+        };
+
+        if stream_id != continuation_frame.stream_id {
+            return Err(H2ConnectionError::ExpectedContinuationForStream {
+                stream_id,
+                continuation_stream_id: continuation_frame.stream_id,
+            });
+        }
+
+        let cont_flags = match continuation_frame.frame_type {
+            FrameType::Continuation(flags) => flags,
+            other => {
+                return Err(H2ConnectionError::ExpectedContinuationFrame {
+                    stream_id,
+                    frame_type: Some(other),
+                });
+            }
+        };
+
+        fragments.push(continuation_payload);
+
+        if cont_flags.contains(ContinuationFlags::EndHeaders) {
+            break;
+        }
+    }
+
+    // this is a slow path, let's do a little heap allocation.
+    let total_len = fragments.iter().map(|f| f.len()).sum();
+    let mut block = Vec::with_capacity(total_len);
+    for frag in &fragments {
+        block.extend_from_slice(&frag[..]);
+    }
+    Ok(block.into())
+}
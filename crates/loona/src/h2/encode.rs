@@ -4,7 +4,7 @@ use tokio::sync::mpsc;
 use tracing::debug;
 
 use super::types::{H2Event, H2EventPayload};
-use crate::{Encoder, Response};
+use crate::{auto_headers::AutoHeaders, Encoder, HeadersExt, InvalidHeaderValue, Response};
 use loona_h2::StreamId;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -20,14 +20,20 @@ pub struct H2Encoder {
     stream_id: StreamId,
     tx: mpsc::Sender<H2Event>,
     state: EncoderState,
+    auto_headers: AutoHeaders,
 }
 
 impl H2Encoder {
-    pub(crate) fn new(stream_id: StreamId, tx: mpsc::Sender<H2Event>) -> Self {
+    pub(crate) fn new(
+        stream_id: StreamId,
+        tx: mpsc::Sender<H2Event>,
+        auto_headers: AutoHeaders,
+    ) -> Self {
         Self {
             stream_id,
             tx,
             state: EncoderState::ExpectResponseHeaders,
+            auto_headers,
         }
     }
 
@@ -59,6 +65,13 @@ pub enum H2EncoderError {
 
     #[error("Stream reset")]
     StreamReset,
+
+    /// HTTP/2 has no `101 Switching Protocols`, cf. RFC 9113, section 8.6
+    #[error("101 Switching Protocols can't be sent over HTTP/2")]
+    SwitchingProtocols,
+
+    #[error("{0}")]
+    InvalidHeaderValue(#[from] InvalidHeaderValue),
 }
 
 impl AsRef<dyn std::error::Error> for H2EncoderError {
@@ -70,13 +83,7 @@ impl AsRef<dyn std::error::Error> for H2EncoderError {
 impl Encoder for H2Encoder {
     type Error = H2EncoderError;
 
-    async fn write_response(&mut self, res: Response) -> Result<(), Self::Error> {
-        // FIXME: HTTP/2 _does_ support informational responses, cf. https://github.com/bearcove/loona/issues/190
-        assert!(
-            !res.status.is_informational(),
-            "http/2 does not support informational responses"
-        );
-
+    async fn write_response(&mut self, mut res: Response) -> Result<(), Self::Error> {
         if self.state != EncoderState::ExpectResponseHeaders {
             return Err(H2EncoderError::WrongState {
                 expected: EncoderState::ExpectResponseHeaders,
@@ -84,6 +91,21 @@ impl Encoder for H2Encoder {
             });
         }
 
+        if res.status == StatusCode::SWITCHING_PROTOCOLS {
+            return Err(H2EncoderError::SwitchingProtocols);
+        }
+
+        // HPACK would carry these just fine, but they make the response
+        // malformed, cf. RFC 9113, section 8.2.1
+        res.headers.validate_values()?;
+
+        if res.status.is_informational() {
+            // its own header block, the final response follows
+            self.send(H2EventPayload::Headers(res)).await?;
+            return Ok(());
+        }
+
+        self.auto_headers.apply(&mut res.headers);
         self.send(H2EventPayload::Headers(res)).await?;
         self.state = EncoderState::ExpectResponseBody;
 
@@ -118,16 +140,24 @@ impl Encoder for H2Encoder {
         Ok(())
     }
 
-    // TODO: handle trailers
-    async fn write_trailers(&mut self, _trailers: Box<crate::Headers>) -> Result<(), Self::Error> {
-        if self.state != EncoderState::ResponseDone {
+    /// Trailers get their own HEADERS frame, however the body was sent
+    fn can_write_trailers(&self) -> bool {
+        self.state == EncoderState::ExpectResponseBody
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<crate::Headers>) -> Result<(), Self::Error> {
+        if self.state != EncoderState::ExpectResponseBody {
             return Err(H2EncoderError::WrongState {
-                expected: EncoderState::ResponseDone,
+                expected: EncoderState::ExpectResponseBody,
                 actual: self.state,
             });
         }
 
-        todo!("write trailers")
+        trailers.validate_values()?;
+        self.send(H2EventPayload::Trailers(trailers)).await?;
+        self.state = EncoderState::ResponseDone;
+
+        Ok(())
     }
 }
 
@@ -145,8 +175,9 @@ impl Drop for H2Encoder {
                 evs.push(self.event(H2EventPayload::BodyEnd));
             }
             EncoderState::ExpectResponseBody => {
-                // TODO: this should probably be RST_STREAM instead
-                evs.push(self.event(H2EventPayload::BodyEnd));
+                // ending the stream normally would make a truncated body look
+                // complete
+                evs.push(self.event(H2EventPayload::Reset));
             }
             EncoderState::ResponseDone => {
                 // ah, good.
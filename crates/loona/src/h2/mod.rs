@@ -4,8 +4,11 @@
 mod server;
 pub use server::*;
 
+mod client;
+pub use client::*;
+
 mod body;
 mod encode;
-pub use encode::H2EncoderError;
+pub use encode::{H2Encoder, H2EncoderError};
 
 pub(crate) mod types;
@@ -1,9 +1,12 @@
 use std::{
     borrow::Cow,
-    collections::{hash_map::Entry, HashSet},
+    collections::hash_map::Entry,
     io::Write,
     rc::Rc,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
 };
 
 use buffet::{Piece, PieceList, PieceStr, ReadOwned, Roll, RollMut, WriteOwned};
@@ -15,8 +18,8 @@ use http::{
 };
 use loona_h2::{
     self as parse, enumflags2::BitFlags, nom::Finish, ContinuationFlags, DataFlags, Frame,
-    FrameType, HeadersFlags, PingFlags, PrioritySpec, Setting, SettingPairs, Settings,
-    SettingsFlags, StreamId, WindowUpdate,
+    FrameType, HeadersFlags, KnownErrorCode, PingFlags, PrioritySpec, Setting, SettingPairs,
+    Settings, SettingsFlags, StreamId, WindowUpdate,
 };
 use parse::IntoPiece;
 use smallvec::{smallvec, SmallVec};
@@ -24,59 +27,105 @@ use tokio::sync::mpsc;
 use tracing::{debug, trace};
 
 use crate::{
+    auto_headers::AutoHeaders,
+    body::Full,
     error::ServeError,
+    h1::parse::is_token,
     h2::{
         body::{H2Body, IncomingMessageResult, StreamIncoming, StreamIncomingError},
         encode::H2Encoder,
         types::{
-            BodyOutgoing, ConnState, H2ConnectionError, H2Event, H2EventPayload, H2RequestError,
-            H2StreamError, HeadersOrTrailers, HeadersOutgoing, StreamOutgoing, StreamState,
+            encode_trailers, queue_header_block_frames, BodyOutgoing, ConnState, H2ConnectionError,
+            H2Event, H2EventPayload, H2RequestError, H2StreamError, HeadersOrTrailers,
+            HeadersOutgoing, StreamOutgoing, StreamState,
         },
     },
     util::{read_and_parse, ReadAndParseError},
-    Headers, Method, Request, Responder, ResponderOrBodyError, ServeOutcome, ServerDriver,
+    ConnectionInfo, Headers, Method, Request, Responder, ResponderOrBodyError, ServeOutcome,
+    ServerDriver, ShutdownSignal, StreamInfo,
 };
 
-use super::{
-    body::{ChunkPosition, SinglePieceBody},
-    types::H2ErrorLevel,
-};
+use super::{body::ChunkPosition, types::H2ErrorLevel};
 
 pub const MAX_WINDOW_SIZE: i64 = u32::MAX as i64;
 
 /// HTTP/2 server configuration
 pub struct ServerConf {
     pub max_streams: Option<u32>,
+
+    /// When triggered, we send a GOAWAY frame, stop accepting new streams, and
+    /// close the connection once the streams in flight are done.
+    pub shutdown: ShutdownSignal,
+
+    /// Whether to add a `date` header to final responses that don't have one,
+    /// as origin servers with a clock must, cf. RFC 9110, section 6.6.1
+    pub date_header: bool,
+
+    /// Added as the `server` header to final responses that don't have one
+    pub server_header: Option<Piece>,
+
+    /// Max length of a request body, unless the driver says otherwise, cf.
+    /// [crate::ServerDriver::max_request_body_len]
+    pub max_request_body_len: Option<u64>,
 }
 
 impl Default for ServerConf {
     fn default() -> Self {
         Self {
             max_streams: Some(32),
+            shutdown: Default::default(),
+            date_header: true,
+            server_header: None,
+            max_request_body_len: None,
         }
     }
 }
 
+/// Serves HTTP/2 requests on a connection, until the client goes away or
+/// the server shuts down.
+///
+/// The driver's connection hooks are called before the first request, and
+/// once serving is done, whether it succeeded or not.
 pub async fn serve<OurDriver, OurReadOwned, OurWriteOwned>(
     (transport_r, transport_w): (OurReadOwned, OurWriteOwned),
     conf: Rc<ServerConf>,
     client_buf: RollMut,
     driver: Rc<OurDriver>,
-) -> Result<(), ServeError<OurDriver::Error>>
+    conn: ConnectionInfo,
+) -> Result<ServeOutcome, ServeError<OurDriver::Error>>
 where
     OurDriver: ServerDriver<H2Encoder> + 'static,
     OurReadOwned: ReadOwned,
     OurWriteOwned: WriteOwned,
 {
-    let mut state = ConnState::default();
-    state.self_settings.max_concurrent_streams = conf.max_streams;
+    let conn = Arc::new(conn);
+    driver.on_connection_open(&conn);
+
+    let res = async {
+        let mut state = ConnState::default();
+        state.self_settings.max_concurrent_streams = conf.max_streams;
 
-    let mut cx =
-        ServerContext::new(driver.clone(), state, transport_w).map_err(ServeError::Alloc)?;
-    cx.work(client_buf, transport_r).await?;
+        let auto_headers = AutoHeaders {
+            date: conf.date_header,
+            server: conf.server_header.clone(),
+        };
+        let mut cx = ServerContext::new(
+            driver.clone(),
+            state,
+            transport_w,
+            conf.shutdown.clone(),
+            conn.clone(),
+            auto_headers,
+            conf.max_request_body_len,
+        )
+        .map_err(ServeError::Alloc)?;
+        cx.work(client_buf, transport_r).await
+    }
+    .await;
 
     debug!("finished serving");
-    Ok(())
+    driver.on_connection_close(&conn, res.as_ref().copied());
+    res
 }
 
 /// Reads and processes h2 frames from the client.
@@ -95,6 +144,21 @@ where
     /// Whether we've received a GOAWAY frame.
     pub goaway_recv: bool,
 
+    /// Triggers a graceful shutdown
+    shutdown: ShutdownSignal,
+
+    /// Whether we've sent a GOAWAY frame because we're shutting down
+    goaway_sent: bool,
+
+    /// Added to every request's extensions
+    conn: Arc<ConnectionInfo>,
+
+    /// Added to every response
+    auto_headers: AutoHeaders,
+
+    /// Passed to the driver to decide each request's body limit
+    max_request_body_len: Option<u64>,
+
     /// TODO: encapsulate into a framer, don't
     /// allow direct access from context methods
     transport_w: OurWriter,
@@ -112,6 +176,10 @@ where
         driver: Rc<OurDriver>,
         state: ConnState,
         transport_w: OurWriteOwned,
+        shutdown: ShutdownSignal,
+        conn: Arc<ConnectionInfo>,
+        auto_headers: AutoHeaders,
+        max_request_body_len: Option<u64>,
     ) -> Result<Self, buffet::bufpool::Error> {
         let mut hpack_dec = loona_hpack::Decoder::new();
         hpack_dec
@@ -130,6 +198,11 @@ where
             hpack_enc,
             out_scratch: RollMut::alloc()?,
             goaway_recv: false,
+            shutdown,
+            goaway_sent: false,
+            conn,
+            auto_headers,
+            max_request_body_len,
             transport_w,
         })
     }
@@ -196,12 +269,8 @@ where
             // FIXME: the process_task should update this
             let max_frame_size = Rc::new(AtomicU32::new(self.state.self_settings.max_frame_size));
 
-            let mut deframe_task = std::pin::pin!(Self::deframe_loop(
-                client_buf,
-                transport_r,
-                tx,
-                max_frame_size
-            ));
+            let mut deframe_task =
+                std::pin::pin!(deframe_loop(client_buf, transport_r, tx, max_frame_size));
             let mut process_task = std::pin::pin!(self.process_loop(rx));
 
             debug!("Starting both deframe & process tasks");
@@ -257,140 +326,37 @@ where
 
             // TODO: don't heap-allocate here
             let additional_debug_data = format!("{err}").into_bytes();
-
-            // TODO: figure out graceful shutdown: this would involve sending a goaway
-            // before this point, and processing all the connections we've accepted
-            // FIXME: we have a GoAway encoder, why are we doing this manually
-            debug!(last_stream_id = %self.state.last_stream_id, ?error_code, "Sending GoAway");
-            let payload =
-                self.out_scratch
-                    .put_to_roll(8 + additional_debug_data.len(), |mut slice| {
-                        slice.write_u32::<BigEndian>(self.state.last_stream_id.0)?;
-                        slice.write_u32::<BigEndian>(error_code.repr())?;
-                        slice.write_all(additional_debug_data.as_slice())?;
-
-                        Ok(())
-                    })?;
-
-            let frame = Frame::new(FrameType::GoAway, StreamId::CONNECTION);
-            self.write_frame(frame, PieceList::single(payload))
+            self.write_goaway(error_code, &additional_debug_data)
                 .await
                 .map_err(ServeError::H2ConnectionError)?;
+        } else if self.goaway_sent {
+            return Ok(ServeOutcome::ServerShutDown);
         }
 
         Ok(ServeOutcome::SuccessfulHttp2GracefulShutdown)
     }
 
-    async fn deframe_loop(
-        mut client_buf: RollMut,
-        mut transport_r: impl ReadOwned,
-        tx: mpsc::Sender<(Frame, Roll)>,
-        max_frame_size: Rc<AtomicU32>,
+    // FIXME: we have a GoAway encoder, why are we doing this manually
+    async fn write_goaway(
+        &mut self,
+        error_code: KnownErrorCode,
+        additional_debug_data: &[u8],
     ) -> Result<(), H2ConnectionError> {
-        'read_frames: loop {
-            const MAX_FRAME_HEADER_SIZE: usize = 128;
-            let frame;
-            trace!("Reading frame... Buffer length: {}", client_buf.len());
-            let frame_res = read_and_parse(
-                "Http2Frame",
-                Frame::parse,
-                &mut transport_r,
-                client_buf,
-                MAX_FRAME_HEADER_SIZE,
-            )
-            .await;
-
-            let maybe_frame = match frame_res {
-                Ok(inner) => inner,
-                Err(e) => return Err(H2ConnectionError::ReadAndParse(e)),
-            };
-            (client_buf, frame) = match maybe_frame {
-                Some((client_buf, frame)) => (client_buf, frame),
-                None => {
-                    debug!("Peer hung up");
-                    break 'read_frames;
-                }
-            };
-            trace!(
-                "Reading frame... done! New buffer length: {}",
-                client_buf.len()
-            );
-            debug!(?frame, "<");
-
-            let max_frame_size = max_frame_size.load(Ordering::Relaxed);
-            if frame.len > max_frame_size {
-                return Err(H2ConnectionError::FrameTooLarge {
-                    frame_type: frame.frame_type,
-                    frame_size: frame.len,
-                    max_frame_size,
-                });
-            }
-
-            trace!(
-                "Reading payload of size {}... Buffer length: {}",
-                frame.len,
-                client_buf.len()
-            );
-            let mut payload;
-            (client_buf, payload) = match read_and_parse(
-                "FramePayload",
-                nom::bytes::streaming::take(frame.len as usize),
-                &mut transport_r,
-                client_buf,
-                frame.len as usize,
-            )
-            .await
-            .map_err(H2ConnectionError::ReadAndParse)?
-            {
-                Some((client_buf, payload)) => (client_buf, payload),
-                None => {
-                    return Err(H2ConnectionError::IncompleteFrame {
-                        frame_type: frame.frame_type,
-                        frame_size: frame.len,
-                    })
-                }
-            };
-            trace!(
-                "Reading payload... done! New buffer length: {}",
-                client_buf.len()
-            );
-
-            let has_padding = match frame.frame_type {
-                FrameType::Data(flags) => flags.contains(DataFlags::Padded),
-                FrameType::Headers(flags) => flags.contains(HeadersFlags::Padded),
-                _ => false,
-            };
-
-            if has_padding {
-                if payload.is_empty() {
-                    return Err(H2ConnectionError::PaddedFrameEmpty {
-                        frame_type: frame.frame_type,
-                    });
-                }
-
-                let padding_length_roll;
-                (padding_length_roll, payload) = payload.split_at(1);
-                let padding_length = padding_length_roll[0] as usize;
-                if payload.len() < padding_length {
-                    return Err(H2ConnectionError::PaddedFrameTooShort {
-                        frame_type: frame.frame_type,
-                        padding_length,
-                        frame_size: frame.len,
-                    });
-                }
-
-                // padding is on the end of the payload
-                let at = payload.len() - padding_length;
-                (payload, _) = payload.split_at(at);
-            }
+        debug!(last_stream_id = %self.state.last_stream_id, ?error_code, "Sending GoAway");
+        let last_stream_id = self.state.last_stream_id;
+        let payload = self
+            .out_scratch
+            .put_to_roll(8 + additional_debug_data.len(), |mut slice| {
+                slice.write_u32::<BigEndian>(last_stream_id.0)?;
+                slice.write_u32::<BigEndian>(error_code.repr())?;
+                slice.write_all(additional_debug_data)?;
 
-            if tx.send((frame, payload)).await.is_err() {
-                debug!("h2 deframer: receiver dropped, closing connection");
-                return Ok(());
-            }
-        }
+                Ok(())
+            })
+            .map_err(|e| H2ConnectionError::WriteError(std::io::Error::other(e)))?;
 
-        Ok(())
+        let frame = Frame::new(FrameType::GoAway, StreamId::CONNECTION);
+        self.write_frame(frame, PieceList::single(payload)).await
     }
 
     async fn process_loop(
@@ -398,6 +364,11 @@ where
         mut rx: mpsc::Receiver<(Frame, Roll)>,
     ) -> Result<(), H2ConnectionError> {
         loop {
+            if self.goaway_sent && self.state.streams.is_empty() {
+                debug!("h2 process task: all streams are done, shutting down");
+                break;
+            }
+
             tokio::select! {
                 biased;
 
@@ -420,6 +391,13 @@ where
                 _ = self.state.send_data_maybe.notified() => {
                     self.send_data_maybe().await?;
                 }
+
+                _ = self.shutdown.wait(), if !self.goaway_sent => {
+                    // cf. RFC 9113, section 6.8: streams up to the last one we
+                    // accepted are still processed
+                    self.write_goaway(KnownErrorCode::NoError, &[]).await?;
+                    self.goaway_sent = true;
+                }
             }
         }
 
@@ -427,170 +405,12 @@ where
     }
 
     async fn send_data_maybe(&mut self) -> Result<(), H2ConnectionError> {
-        let mut not_pending: HashSet<StreamId> = Default::default();
-
-        // this vec exists for borrow-checker reasons: we can't
-        // borrow self mutably twice in 'each_stream
         // TODO: merge those frames! do a single writev_all call!
-        let mut frames: Vec<(Frame, PieceList)> = vec![];
-
-        let max_fram = self.state.peer_settings.max_frame_size as usize;
-
-        let streams_with_pending_data: HashSet<_> = self
-            .state
-            .streams_with_pending_data
-            .iter()
-            .copied()
-            .collect();
-
-        'each_stream: for id in streams_with_pending_data {
-            if self.state.outgoing_capacity <= 0 {
-                // that's all we can do
-                break 'each_stream;
-            }
-
-            let outgoing = self
-                .state
-                .streams
-                .get_mut(&id)
-                .and_then(|ss| ss.outgoing_mut())
-                .expect("stream should not be in streams_with_pending_data if it's already closed / not in an outgoing state");
-
-            debug!(conn_cap = %self.state.outgoing_capacity, strm_cap = %outgoing.capacity, %max_fram, "ready to write");
-
-            if outgoing.headers.has_more_to_write() {
-                debug!("writing headers...");
-
-                if matches!(&outgoing.headers, HeadersOutgoing::WaitingForHeaders) {
-                    debug!("waiting for headers...");
-
-                    // shouldn't be pending then should it?
-                    not_pending.insert(id);
-                    continue 'each_stream;
-                }
-
-                'queue_header_frames: loop {
-                    debug!("writing headers...");
-
-                    let is_continuation =
-                        matches!(&outgoing.headers, HeadersOutgoing::WroteSome(_));
-                    let piece = outgoing.headers.take_piece();
-                    let piece_len = piece.len();
-
-                    if piece_len > max_fram {
-                        let write_size = max_fram;
-                        let (written, requeued) = piece.split_at(write_size);
-                        debug!(%write_size, requeued_len = %requeued.len(), "splitting headers");
-                        let frame_type = if is_continuation {
-                            FrameType::Continuation(Default::default())
-                        } else {
-                            FrameType::Headers(Default::default())
-                        };
-                        outgoing.headers = HeadersOutgoing::WroteSome(requeued);
-
-                        let frame = Frame::new(frame_type, id);
-                        frames.push((frame, PieceList::single(written)));
-                    } else {
-                        let frame_type = if is_continuation {
-                            FrameType::Continuation(
-                                BitFlags::<ContinuationFlags>::default()
-                                    | ContinuationFlags::EndHeaders,
-                            )
-                        } else {
-                            FrameType::Headers(
-                                BitFlags::<HeadersFlags>::default() | HeadersFlags::EndHeaders,
-                            )
-                        };
-
-                        let frame = Frame::new(frame_type, id);
-                        frames.push((frame, PieceList::single(piece)));
-
-                        break 'queue_header_frames;
-                    }
-                }
-            }
-
-            let capacity = self.state.outgoing_capacity.min(outgoing.capacity) as usize;
-            // bytes written this turn, possibly over multiple frames
-            let mut total_bytes_written = 0;
-
-            if outgoing.body.has_more_to_write() {
-                'queue_body_frames: while total_bytes_written < capacity {
-                    // send as much body data as we can, respecting max frame size and
-                    // connection / stream capacity
-                    let mut plist = PieceList::default();
-                    let mut frame_len = 0;
-
-                    'build_frame: loop {
-                        let piece = match outgoing.body.pop_front() {
-                            None => break 'build_frame,
-                            Some(piece) => piece,
-                        };
-
-                        // do we need to split the piece because we don't have
-                        // enough capacity left / we hit the max frame size?
-                        let piece_len = piece.len();
-                        debug!(%piece_len, "popped a piece");
-
-                        let fram_size_if_full_piece = frame_len + piece_len;
-
-                        let cap_left = capacity - total_bytes_written;
-                        let max_this_fram = max_fram.min(cap_left);
-
-                        if fram_size_if_full_piece > max_this_fram {
-                            // we can't fit this piece in the current frame, so
-                            // we have to split it
-                            let write_size = max_this_fram - frame_len;
-                            let (written, requeued) = piece.split_at(write_size);
-                            frame_len += write_size;
-                            debug!(written_len = %written.len(), requeued_len = %requeued.len(), "splitting piece");
-
-                            plist.push_back(written);
-                            outgoing.body.push_front(requeued);
-
-                            break 'build_frame;
-                        } else {
-                            // we can write the full piece
-                            let write_size = piece_len;
-                            frame_len += write_size;
-
-                            plist.push_back(piece);
-                        }
-                    }
-
-                    let mut flags: BitFlags<DataFlags> = Default::default();
-                    if outgoing.body.might_receive_more() {
-                        if frame_len == 0 {
-                            // the only time we want to send a zero-length frame
-                            // is if we have to send END_STREAM separately from
-                            // the last chunk.
-                            break 'queue_body_frames;
-                        }
-                    } else {
-                        flags |= DataFlags::EndStream;
-                    }
-
-                    let frame = Frame::new(FrameType::Data(flags), id);
-                    debug!(?frame, %frame_len, "queuing");
-                    frames.push((frame, plist));
-                    total_bytes_written += frame_len;
-
-                    if flags.contains(DataFlags::EndStream) {
-                        break 'queue_body_frames;
-                    }
-                }
-            }
-        }
-
-        for (frame, plist) in frames {
+        for (frame, plist) in self.state.take_pending_frames() {
             debug!(?frame, plist_len = %plist.len(), "writing");
             self.write_frame(frame, plist).await?;
         }
 
-        for id in not_pending {
-            self.state.streams_with_pending_data.remove(&id);
-        }
-
         Ok(())
     }
 
@@ -598,6 +418,45 @@ where
         trace!(?ev, "handling event");
 
         match ev.payload {
+            H2EventPayload::Headers(res) if res.status.is_informational() => {
+                if self
+                    .state
+                    .streams
+                    .get_mut(&ev.stream_id)
+                    .and_then(|s| s.outgoing_mut())
+                    .is_none()
+                {
+                    return Ok(());
+                }
+
+                // interim responses are written right away: the final
+                // response can't have been queued yet. they're encoded without
+                // indexing, so it doesn't matter what header blocks were
+                // encoded before them, but haven't been written yet.
+                let mut headers: Vec<(&[u8], &[u8])> = vec![];
+                headers.push((b":status", res.status.as_str().as_bytes()));
+                for (name, value) in res.headers.iter() {
+                    headers.push((name.as_str().as_bytes(), value));
+                }
+
+                assert_eq!(self.out_scratch.len(), 0);
+                self.hpack_enc
+                    .encode_without_indexing_into(headers, &mut self.out_scratch)
+                    .map_err(H2ConnectionError::WriteError)?;
+                let block = self.out_scratch.take_all();
+
+                let mut frames = vec![];
+                queue_header_block_frames(
+                    &mut frames,
+                    ev.stream_id,
+                    block.into(),
+                    false,
+                    self.state.peer_settings.max_frame_size as usize,
+                );
+                for (frame, plist) in frames {
+                    self.write_frame(frame, plist).await?;
+                }
+            }
             H2EventPayload::Headers(res) => {
                 let outgoing = match self
                     .state
@@ -651,60 +510,20 @@ where
                 }
             }
             H2EventPayload::BodyChunk(chunk) => {
-                let outgoing = match self
-                    .state
-                    .streams
-                    .get_mut(&ev.stream_id)
-                    .and_then(|s| s.outgoing_mut())
-                {
-                    None => {
-                        // ignore the event then, but at this point we should
-                        // tell the sender to stop sending chunks, which is not
-                        // possible if they all share the same ev_tx
-                        // TODO: make it possible to propagate errors to the sender
-                        return Ok(());
-                    }
-                    Some(outgoing) => outgoing,
-                };
-
-                // FIXME: this isn't great, because, due to biased polling, body pieces can pile
-                // up. when we've collected enough pieces for max frame size, we
-                // should really send them.
-                outgoing.body.push_back(chunk);
-
-                self.state.streams_with_pending_data.insert(ev.stream_id);
-                if self.state.outgoing_capacity > 0 && outgoing.capacity > 0 {
-                    // worth revisiting then!
-                    self.state.send_data_maybe.notify_one();
-                }
+                self.state.queue_body_chunk(ev.stream_id, chunk);
             }
             H2EventPayload::BodyEnd => {
-                let outgoing = match self
-                    .state
-                    .streams
-                    .get_mut(&ev.stream_id)
-                    .and_then(|s| s.outgoing_mut())
-                {
-                    None => return Ok(()),
-                    Some(outgoing) => outgoing,
-                };
-
-                match &mut outgoing.body {
-                    BodyOutgoing::StillReceiving(pieces) => {
-                        let pieces = std::mem::take(pieces);
-                        if pieces.is_empty() {
-                            // we'll need to send a zero-length data frame
-                            self.state.send_data_maybe.notify_one();
-                        }
-                        outgoing.body = BodyOutgoing::DoneReceiving(pieces);
-                        debug!(stream_id = %ev.stream_id, outgoing_body = ?outgoing.body, "got body end");
-                    }
-                    BodyOutgoing::DoneReceiving(_) => {
-                        unreachable!("got body end twice")
-                    }
-                    BodyOutgoing::DoneSending => {
-                        unreachable!("got body end after we sent everything")
-                    }
+                self.state.queue_body_end(ev.stream_id, None);
+            }
+            H2EventPayload::Trailers(trailers) => {
+                let block = encode_trailers(&mut self.hpack_enc, &mut self.out_scratch, &trailers)?;
+                self.state.queue_body_end(ev.stream_id, Some(block));
+            }
+            H2EventPayload::Reset => {
+                // the peer may have reset the stream already
+                if self.state.streams.contains_key(&ev.stream_id) {
+                    self.rst(ev.stream_id, H2StreamError::ResponseIncomplete)
+                        .await?;
                 }
             }
         }
@@ -717,84 +536,8 @@ where
         mut frame: Frame,
         payload: PieceList,
     ) -> Result<(), H2ConnectionError> {
-        match &frame.frame_type {
-            FrameType::Data(flags) => {
-                let mut ss = match self.state.streams.entry(frame.stream_id) {
-                    std::collections::hash_map::Entry::Occupied(entry) => entry,
-                    std::collections::hash_map::Entry::Vacant(_) => unreachable!(
-                        "writing DATA frame for non-existent stream, this should never happen"
-                    ),
-                };
-
-                // update stream flow control window
-                {
-                    let outgoing = match ss.get_mut().outgoing_mut() {
-                        Some(og) => og,
-                        None => {
-                            unreachable!("writing DATA frame for stream in the wrong state")
-                        }
-                    };
-                    let payload_len: u32 = payload.len().try_into().unwrap();
-                    let next_cap = outgoing.capacity - payload_len as i64;
-
-                    if next_cap < 0 {
-                        unreachable!(
-                            "should never write a frame that makes the stream capacity negative: outgoing.capacity = {}, payload_len = {}",
-                            outgoing.capacity, payload.len()
-                        )
-                    }
-                    outgoing.capacity = next_cap;
-                }
-
-                // now update connection flow control window
-                {
-                    let payload_len: u32 = payload.len().try_into().unwrap();
-                    let next_cap = self.state.outgoing_capacity - payload_len as i64;
-
-                    if next_cap < 0 {
-                        unreachable!(
-                            "should never write a frame that makes the connection capacity negative: outgoing_capacity = {}, payload_len = {}",
-                            self.state.outgoing_capacity, payload.len()
-                        )
-                    }
-                    self.state.outgoing_capacity = next_cap;
-                }
-
-                if flags.contains(DataFlags::EndStream) {
-                    // we won't be sending any more data on this stream
-                    self.state
-                        .streams_with_pending_data
-                        .remove(&frame.stream_id);
-
-                    match ss.get_mut() {
-                        StreamState::Open { .. } => {
-                            let incoming = match std::mem::take(ss.get_mut()) {
-                                StreamState::Open { incoming, .. } => incoming,
-                                _ => unreachable!(),
-                            };
-                            // this avoid having to re-insert the stream in the map
-                            *ss.get_mut() = StreamState::HalfClosedLocal { incoming };
-                        }
-                        _ => {
-                            // transition to closed
-                            ss.remove();
-                            debug!(
-                                "Closed stream {} (wrote data w/EndStream), now have {} streams",
-                                frame.stream_id,
-                                self.state.streams.len()
-                            );
-                        }
-                    }
-                }
-            }
-            FrameType::Settings(_) => {
-                // TODO: keep track of whether our new settings have been
-                // acknowledged
-            }
-            _ => {
-                // muffin.
-            }
-        };
+        // TODO: keep track of whether our new settings have been acknowledged
+        self.state.on_frame_written(&frame, &payload);
 
         // TODO: enforce max_frame_size from the peer settings, not just u32::max
         frame.len = payload
@@ -946,10 +689,14 @@ where
                                     stream_id: frame.stream_id,
                                 });
                             }
+                            std::cmp::Ordering::Greater if self.goaway_sent => {
+                                // we're shutting down, and told the client which
+                                // stream was the last one we'd process
+                                self.rst(frame.stream_id, H2StreamError::RefusedStream)
+                                    .await?;
+                                mode = ReadHeadersMode::Skip;
+                            }
                             std::cmp::Ordering::Greater => {
-                                // TODO: if we're shutting down, ignore streams higher
-                                // than the last one we accepted.
-
                                 let max_concurrent_streams = self
                                     .state
                                     .self_settings
@@ -1016,17 +763,28 @@ where
                             tracing::debug!(?e, %stream_id, "Responding to stream with error");
                             // we need to insert it, otherwise `process_event` will ignore us
                             // sending headers, etc.
-                            self.state.streams.insert(
-                                stream_id,
-                                StreamState::HalfClosedRemote {
-                                    outgoing: self.state.mk_stream_outgoing(),
-                                },
-                            );
+                            let outgoing = self.state.mk_stream_outgoing();
+                            let ss = if flags.contains(HeadersFlags::EndStream) {
+                                StreamState::HalfClosedRemote { outgoing }
+                            } else {
+                                // the peer may still be sending a request body: let it
+                                // finish, and drop it on the floor.
+                                StreamState::Open {
+                                    incoming: StreamIncoming::discarding(
+                                        self.state.self_settings.initial_window_size as _,
+                                    ),
+                                    outgoing,
+                                }
+                            };
+                            self.state.streams.insert(stream_id, ss);
                             // TODO: inserting/removing here is probably unnecessary.
 
                             // respond with status code
-                            let responder =
-                                Responder::new(H2Encoder::new(frame.stream_id, self.ev_tx.clone()));
+                            let responder = Responder::new(H2Encoder::new(
+                                frame.stream_id,
+                                self.ev_tx.clone(),
+                                self.auto_headers.clone(),
+                            ));
                             responder
                                 .write_final_response_with_body(
                                     crate::Response {
@@ -1034,13 +792,13 @@ where
                                         status: e.status,
                                         headers: Default::default(),
                                     },
-                                    &mut SinglePieceBody::new(e.message),
+                                    &mut Full::from(e.message),
                                 )
                                 .await
                                 .map_err(|e| match e {
                                     ResponderOrBodyError::Responder(e) => e,
                                     ResponderOrBodyError::Body(_) => {
-                                        unreachable!("SinglePieceBody's error is Infallible")
+                                        unreachable!("Full's error is Infallible")
                                     }
                                 })?;
 
@@ -1322,6 +1080,7 @@ where
         e: H2StreamError,
     ) -> Result<(), H2ConnectionError> {
         self.state.streams.remove(&stream_id);
+        self.state.streams_with_pending_data.remove(&stream_id);
 
         let error_code = e.as_known_error_code();
         debug!("Sending rst because: {e} (known error code: {error_code:?})");
@@ -1415,9 +1174,30 @@ where
             Data::Multi(fragments)
         };
 
+        let joined: Vec<u8>;
+        let block: &[u8] = match &data {
+            Data::Single(payload) => &payload[..],
+            Data::Multi(fragments) => {
+                // this is a slow path, let's do a little heap allocation. we could
+                // be using `RollMut` for this, but it would probably need to resize
+                // a bunch
+                let total_len = fragments.iter().map(|f| f.len()).sum();
+                let mut payload = Vec::with_capacity(total_len);
+                for frag in fragments {
+                    payload.extend_from_slice(&frag[..]);
+                }
+                joined = payload;
+                &joined[..]
+            }
+        };
+
         if matches!(mode, ReadHeadersMode::Skip) {
-            // that's all we need to do: we're not actually validating the
-            // headers, we already send a RST
+            // we already sent a RST, but the peer's encoder updated its
+            // dynamic table for this field block, so ours must too, cf.
+            // RFC 9113, section 4.3
+            self.hpack_dec
+                .decode_with_cb(block, |_, _| {})
+                .map_err(|e| H2ErrorLevel::Connection(e.into()))?;
             return Ok(());
         }
 
@@ -1478,6 +1258,12 @@ where
                                     return;
                                 }
                             };
+                            if !is_token(value.as_bytes()) {
+                                req_error = Some(H2StreamError::BadRequest(
+                                    "invalid ':method' pseudo-header: methods are tokens, cf. RFC 9110, section 9.1",
+                                ));
+                                return;
+                            }
                             if method.replace(Method::from(value)).is_some() {
                                 req_error = Some(H2StreamError::BadRequest("duplicate ':method' pseudo-header. All HTTP/2 requests MUST include _exactly one_ valid value for the ':method', ':scheme', and ':path' pseudo-header fields, unless they are CONNECT requests (RFC 9114, section 8.3.1)"));
                             }
@@ -1612,26 +1398,9 @@ where
                 }
             };
 
-            match data {
-                Data::Single(payload) => {
-                    self.hpack_dec
-                        .decode_with_cb(&payload[..], on_header_pair)
-                        .map_err(|e| H2ErrorLevel::Connection(e.into()))?;
-                }
-                Data::Multi(fragments) => {
-                    let total_len = fragments.iter().map(|f| f.len()).sum();
-                    // this is a slow path, let's do a little heap allocation. we could
-                    // be using `RollMut` for this, but it would probably need to resize
-                    // a bunch
-                    let mut payload = Vec::with_capacity(total_len);
-                    for frag in &fragments {
-                        payload.extend_from_slice(&frag[..]);
-                    }
-                    self.hpack_dec
-                        .decode_with_cb(&payload[..], on_header_pair)
-                        .map_err(|e| H2ErrorLevel::Connection(e.into()))?;
-                }
-            };
+            self.hpack_dec
+                .decode_with_cb(block, on_header_pair)
+                .map_err(|e| H2ErrorLevel::Connection(e.into()))?;
 
             if let Some(req_error) = req_error {
                 return Err(req_error.into());
@@ -1769,12 +1538,17 @@ where
                     }
                 };
 
-                let req = Request {
+                let mut req = Request {
                     method,
                     uri,
                     version: Version::HTTP_2,
                     headers,
+                    extensions: Default::default(),
                 };
+                req.extensions.insert(self.conn.clone());
+                req.extensions.insert(StreamInfo::H2 {
+                    stream_id: stream_id.0,
+                });
                 let content_length: Option<u64> = match req
                     .headers
                     .get(http::header::CONTENT_LENGTH)
@@ -1799,7 +1573,25 @@ where
                     }
                 };
 
-                let responder = Responder::new(H2Encoder::new(stream_id, self.ev_tx.clone()));
+                let max_body_len = self
+                    .driver
+                    .max_request_body_len(&req, self.max_request_body_len);
+                if let (Some(len), Some(limit)) = (content_length, max_body_len) {
+                    if len > limit {
+                        return Err(H2RequestError {
+                            status: StatusCode::PAYLOAD_TOO_LARGE,
+                            message: format!("request body is larger than {limit} bytes")
+                                .into_bytes()
+                                .into(),
+                        }
+                        .into());
+                    }
+                }
+
+                let responder = Responder::for_request(
+                    H2Encoder::new(stream_id, self.ev_tx.clone(), self.auto_headers.clone()),
+                    &req,
+                );
 
                 let (piece_tx, piece_rx) = mpsc::channel::<IncomingMessageResult>(1); // TODO: is 1 a sensible value here?
 
@@ -1812,6 +1604,7 @@ where
                 let incoming = StreamIncoming::new(
                     self.state.self_settings.initial_window_size as _,
                     content_length,
+                    max_body_len,
                     piece_tx,
                 );
                 let outgoing: StreamOutgoing = self.state.mk_stream_outgoing();
@@ -1885,10 +1678,125 @@ where
     }
 }
 
+/// Reads frames (and their payloads) from the peer, strips padding, and sends
+/// them to the processing task.
+pub(crate) async fn deframe_loop(
+    mut client_buf: RollMut,
+    mut transport_r: impl ReadOwned,
+    tx: mpsc::Sender<(Frame, Roll)>,
+    max_frame_size: Rc<AtomicU32>,
+) -> Result<(), H2ConnectionError> {
+    'read_frames: loop {
+        const MAX_FRAME_HEADER_SIZE: usize = 128;
+        let frame;
+        trace!("Reading frame... Buffer length: {}", client_buf.len());
+        let frame_res = read_and_parse(
+            "Http2Frame",
+            Frame::parse,
+            &mut transport_r,
+            client_buf,
+            MAX_FRAME_HEADER_SIZE,
+        )
+        .await;
+
+        let maybe_frame = match frame_res {
+            Ok(inner) => inner,
+            Err(e) => return Err(H2ConnectionError::ReadAndParse(e)),
+        };
+        (client_buf, frame) = match maybe_frame {
+            Some((client_buf, frame)) => (client_buf, frame),
+            None => {
+                debug!("Peer hung up");
+                break 'read_frames;
+            }
+        };
+        trace!(
+            "Reading frame... done! New buffer length: {}",
+            client_buf.len()
+        );
+        debug!(?frame, "<");
+
+        let max_frame_size = max_frame_size.load(Ordering::Relaxed);
+        if frame.len > max_frame_size {
+            return Err(H2ConnectionError::FrameTooLarge {
+                frame_type: frame.frame_type,
+                frame_size: frame.len,
+                max_frame_size,
+            });
+        }
+
+        trace!(
+            "Reading payload of size {}... Buffer length: {}",
+            frame.len,
+            client_buf.len()
+        );
+        let mut payload;
+        (client_buf, payload) = match read_and_parse(
+            "FramePayload",
+            nom::bytes::streaming::take(frame.len as usize),
+            &mut transport_r,
+            client_buf,
+            frame.len as usize,
+        )
+        .await
+        .map_err(H2ConnectionError::ReadAndParse)?
+        {
+            Some((client_buf, payload)) => (client_buf, payload),
+            None => {
+                return Err(H2ConnectionError::IncompleteFrame {
+                    frame_type: frame.frame_type,
+                    frame_size: frame.len,
+                })
+            }
+        };
+        trace!(
+            "Reading payload... done! New buffer length: {}",
+            client_buf.len()
+        );
+
+        let has_padding = match frame.frame_type {
+            FrameType::Data(flags) => flags.contains(DataFlags::Padded),
+            FrameType::Headers(flags) => flags.contains(HeadersFlags::Padded),
+            _ => false,
+        };
+
+        if has_padding {
+            if payload.is_empty() {
+                return Err(H2ConnectionError::PaddedFrameEmpty {
+                    frame_type: frame.frame_type,
+                });
+            }
+
+            let padding_length_roll;
+            (padding_length_roll, payload) = payload.split_at(1);
+            let padding_length = padding_length_roll[0] as usize;
+            if payload.len() < padding_length {
+                return Err(H2ConnectionError::PaddedFrameTooShort {
+                    frame_type: frame.frame_type,
+                    padding_length,
+                    frame_size: frame.len,
+                });
+            }
+
+            // padding is on the end of the payload
+            let at = payload.len() - padding_length;
+            (payload, _) = payload.split_at(at);
+        }
+
+        if tx.send((frame, payload)).await.is_err() {
+            debug!("h2 deframer: receiver dropped, closing connection");
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
 enum ReadHeadersMode {
     // we're accepting the stream or processing trailers, we want to
     // process the headers we read.
     Process,
-    // we're refusing the stream, we want to skip over the headers we read.
+    // we're refusing the stream: the headers we read only go through the
+    // HPACK decoder, to keep its dynamic table in sync
     Skip,
 }
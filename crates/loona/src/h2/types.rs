@@ -3,15 +3,19 @@ use std::{
     fmt,
 };
 
-use buffet::Piece;
+use buffet::{Piece, PieceList, RollMut};
 use http::StatusCode;
 use loona_hpack::decoder::DecoderError;
 use tokio::sync::Notify;
+use tracing::debug;
 
-use crate::{util::ReadAndParseError, ResponderError, Response};
+use crate::{util::ReadAndParseError, Headers, ResponderError, Response};
 
 use super::{body::StreamIncoming, encode::H2EncoderError};
-use loona_h2::{FrameType, KnownErrorCode, Settings, SettingsError, StreamId};
+use loona_h2::{
+    enumflags2::BitFlags, ContinuationFlags, DataFlags, Frame, FrameType, HeadersFlags,
+    KnownErrorCode, Settings, SettingsError, StreamId,
+};
 
 pub(crate) struct ConnState {
     pub(crate) streams: HashMap<StreamId, StreamState>,
@@ -62,9 +66,404 @@ impl ConnState {
         StreamOutgoing {
             headers: HeadersOutgoing::WaitingForHeaders,
             body: BodyOutgoing::StillReceiving(Default::default()),
+            trailers: None,
             capacity: self.peer_settings.initial_window_size as _,
         }
     }
+
+    /// Queue a body chunk for the given stream, to be sent once flow control
+    /// allows it. Chunks for closed streams are silently dropped.
+    pub(crate) fn queue_body_chunk(&mut self, stream_id: StreamId, chunk: Piece) {
+        let outgoing = match self
+            .streams
+            .get_mut(&stream_id)
+            .and_then(|s| s.outgoing_mut())
+        {
+            None => {
+                // ignore the chunk then, but at this point we should
+                // tell the sender to stop sending chunks, which is not
+                // possible if they all share the same ev_tx
+                // TODO: make it possible to propagate errors to the sender
+                return;
+            }
+            Some(outgoing) => outgoing,
+        };
+
+        // FIXME: this isn't great, because, due to biased polling, body pieces can pile
+        // up. when we've collected enough pieces for max frame size, we
+        // should really send them.
+        outgoing.body.push_back(chunk);
+
+        self.streams_with_pending_data.insert(stream_id);
+        if self.outgoing_capacity > 0 && outgoing.capacity > 0 {
+            // worth revisiting then!
+            self.send_data_maybe.notify_one();
+        }
+    }
+
+    /// Mark the body of the given stream as complete: the next DATA frame we
+    /// send for it will have END_STREAM set. If there's a trailers header
+    /// block, it's sent after the last DATA frame instead, and ends the stream.
+    ///
+    /// Trailers are written whenever the body is done, not in the order they
+    /// were queued in: they must be HPACK-encoded without indexing.
+    pub(crate) fn queue_body_end(&mut self, stream_id: StreamId, trailers: Option<Piece>) {
+        let outgoing = match self
+            .streams
+            .get_mut(&stream_id)
+            .and_then(|s| s.outgoing_mut())
+        {
+            None => return,
+            Some(outgoing) => outgoing,
+        };
+
+        match &mut outgoing.body {
+            BodyOutgoing::StillReceiving(pieces) => {
+                let pieces = std::mem::take(pieces);
+                if pieces.is_empty() {
+                    // we'll need to send a zero-length data frame
+                    self.send_data_maybe.notify_one();
+                }
+                outgoing.body = BodyOutgoing::DoneReceiving(pieces);
+                outgoing.trailers = trailers;
+                debug!(%stream_id, outgoing_body = ?outgoing.body, has_trailers = %outgoing.trailers.is_some(), "got body end");
+            }
+            BodyOutgoing::DoneReceiving(_) => {
+                unreachable!("got body end twice")
+            }
+            BodyOutgoing::DoneSending => {
+                unreachable!("got body end after we sent everything")
+            }
+        }
+    }
+
+    /// Build as many HEADERS/CONTINUATION/DATA frames as the connection and
+    /// stream windows allow. The caller must write all of them, in order,
+    /// calling [ConnState::on_frame_written] for each.
+    pub(crate) fn take_pending_frames(&mut self) -> Vec<(Frame, PieceList)> {
+        let mut not_pending: HashSet<StreamId> = Default::default();
+
+        // TODO: merge those frames! do a single writev_all call!
+        let mut frames: Vec<(Frame, PieceList)> = vec![];
+
+        let max_fram = self.peer_settings.max_frame_size as usize;
+
+        let streams_with_pending_data: HashSet<_> =
+            self.streams_with_pending_data.iter().copied().collect();
+
+        'each_stream: for id in streams_with_pending_data {
+            if self.outgoing_capacity <= 0 {
+                // that's all we can do
+                break 'each_stream;
+            }
+
+            let outgoing = self
+                .streams
+                .get_mut(&id)
+                .and_then(|ss| ss.outgoing_mut())
+                .expect("stream should not be in streams_with_pending_data if it's already closed / not in an outgoing state");
+
+            debug!(conn_cap = %self.outgoing_capacity, strm_cap = %outgoing.capacity, %max_fram, "ready to write");
+
+            if outgoing.headers.has_more_to_write() {
+                debug!("writing headers...");
+
+                if matches!(&outgoing.headers, HeadersOutgoing::WaitingForHeaders) {
+                    debug!("waiting for headers...");
+
+                    // shouldn't be pending then should it?
+                    not_pending.insert(id);
+                    continue 'each_stream;
+                }
+
+                'queue_header_frames: loop {
+                    debug!("writing headers...");
+
+                    let is_continuation =
+                        matches!(&outgoing.headers, HeadersOutgoing::WroteSome(_));
+                    let piece = outgoing.headers.take_piece();
+                    let piece_len = piece.len();
+
+                    if piece_len > max_fram {
+                        let write_size = max_fram;
+                        let (written, requeued) = piece.split_at(write_size);
+                        debug!(%write_size, requeued_len = %requeued.len(), "splitting headers");
+                        let frame_type = if is_continuation {
+                            FrameType::Continuation(Default::default())
+                        } else {
+                            FrameType::Headers(Default::default())
+                        };
+                        outgoing.headers = HeadersOutgoing::WroteSome(requeued);
+
+                        let frame = Frame::new(frame_type, id);
+                        frames.push((frame, PieceList::single(written)));
+                    } else {
+                        let frame_type = if is_continuation {
+                            FrameType::Continuation(
+                                BitFlags::<ContinuationFlags>::default()
+                                    | ContinuationFlags::EndHeaders,
+                            )
+                        } else {
+                            FrameType::Headers(
+                                BitFlags::<HeadersFlags>::default() | HeadersFlags::EndHeaders,
+                            )
+                        };
+
+                        let frame = Frame::new(frame_type, id);
+                        frames.push((frame, PieceList::single(piece)));
+
+                        break 'queue_header_frames;
+                    }
+                }
+            }
+
+            let capacity = self.outgoing_capacity.min(outgoing.capacity) as usize;
+            // bytes written this turn, possibly over multiple frames
+            let mut total_bytes_written = 0;
+
+            if outgoing.body.has_more_to_write() {
+                'queue_body_frames: while total_bytes_written < capacity {
+                    // send as much body data as we can, respecting max frame size and
+                    // connection / stream capacity
+                    let mut plist = PieceList::default();
+                    let mut frame_len = 0;
+
+                    'build_frame: loop {
+                        let piece = match outgoing.body.pop_front() {
+                            None => break 'build_frame,
+                            Some(piece) => piece,
+                        };
+
+                        // do we need to split the piece because we don't have
+                        // enough capacity left / we hit the max frame size?
+                        let piece_len = piece.len();
+                        debug!(%piece_len, "popped a piece");
+
+                        let fram_size_if_full_piece = frame_len + piece_len;
+
+                        let cap_left = capacity - total_bytes_written;
+                        let max_this_fram = max_fram.min(cap_left);
+
+                        if fram_size_if_full_piece > max_this_fram {
+                            // we can't fit this piece in the current frame, so
+                            // we have to split it
+                            let write_size = max_this_fram - frame_len;
+                            let (written, requeued) = piece.split_at(write_size);
+                            frame_len += write_size;
+                            debug!(written_len = %written.len(), requeued_len = %requeued.len(), "splitting piece");
+
+                            plist.push_back(written);
+                            outgoing.body.push_front(requeued);
+
+                            break 'build_frame;
+                        } else {
+                            // we can write the full piece
+                            let write_size = piece_len;
+                            frame_len += write_size;
+
+                            plist.push_back(piece);
+                        }
+                    }
+
+                    let mut flags: BitFlags<DataFlags> = Default::default();
+                    if outgoing.body.might_receive_more() || outgoing.trailers.is_some() {
+                        if frame_len == 0 {
+                            // the only time we want to send a zero-length frame
+                            // is if we have to send END_STREAM separately from
+                            // the last chunk. with trailers, they end the stream.
+                            break 'queue_body_frames;
+                        }
+                    } else {
+                        flags |= DataFlags::EndStream;
+                    }
+
+                    let frame = Frame::new(FrameType::Data(flags), id);
+                    debug!(?frame, %frame_len, "queuing");
+                    frames.push((frame, plist));
+                    total_bytes_written += frame_len;
+
+                    if flags.contains(DataFlags::EndStream) {
+                        break 'queue_body_frames;
+                    }
+                }
+            }
+
+            if !outgoing.body.might_receive_more() {
+                if let Some(trailers) = outgoing.trailers.take() {
+                    debug!(trailers_len = %trailers.len(), "queuing trailers");
+                    queue_header_block_frames(&mut frames, id, trailers, true, max_fram);
+                }
+            }
+        }
+
+        for id in not_pending {
+            self.streams_with_pending_data.remove(&id);
+        }
+
+        frames
+    }
+
+    /// Update flow control windows and stream states for a frame that's about
+    /// to be written to the peer.
+    pub(crate) fn on_frame_written(&mut self, frame: &Frame, payload: &PieceList) {
+        match &frame.frame_type {
+            FrameType::Data(flags) => {
+                self.on_data_written(frame.stream_id, payload);
+                if flags.contains(DataFlags::EndStream) {
+                    self.on_end_stream_written(frame.stream_id);
+                }
+            }
+            FrameType::Headers(flags) if flags.contains(HeadersFlags::EndStream) => {
+                // trailers, or the headers of a request without a body: the
+                // client only starts tracking those streams after writing them.
+                if self.streams.contains_key(&frame.stream_id) {
+                    self.on_end_stream_written(frame.stream_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_data_written(&mut self, stream_id: StreamId, payload: &PieceList) {
+        let payload_len: u32 = payload.len().try_into().unwrap();
+
+        // update stream flow control window
+        {
+            let outgoing = match self.streams.get_mut(&stream_id) {
+                Some(ss) => match ss.outgoing_mut() {
+                    Some(og) => og,
+                    None => {
+                        unreachable!("writing DATA frame for stream in the wrong state")
+                    }
+                },
+                None => {
+                    unreachable!(
+                        "writing DATA frame for non-existent stream, this should never happen"
+                    )
+                }
+            };
+            let next_cap = outgoing.capacity - payload_len as i64;
+
+            if next_cap < 0 {
+                unreachable!(
+                    "should never write a frame that makes the stream capacity negative: outgoing.capacity = {}, payload_len = {}",
+                    outgoing.capacity, payload.len()
+                )
+            }
+            outgoing.capacity = next_cap;
+        }
+
+        // now update connection flow control window
+        {
+            let next_cap = self.outgoing_capacity - payload_len as i64;
+
+            if next_cap < 0 {
+                unreachable!(
+                    "should never write a frame that makes the connection capacity negative: outgoing_capacity = {}, payload_len = {}",
+                    self.outgoing_capacity, payload.len()
+                )
+            }
+            self.outgoing_capacity = next_cap;
+        }
+    }
+
+    fn on_end_stream_written(&mut self, stream_id: StreamId) {
+        // we won't be sending any more data on this stream
+        self.streams_with_pending_data.remove(&stream_id);
+
+        let mut ss = match self.streams.entry(stream_id) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry,
+            std::collections::hash_map::Entry::Vacant(_) => {
+                unreachable!("writing END_STREAM for non-existent stream, this should never happen")
+            }
+        };
+
+        match ss.get_mut() {
+            StreamState::Open { .. } => {
+                let incoming = match std::mem::take(ss.get_mut()) {
+                    StreamState::Open { incoming, .. } => incoming,
+                    _ => unreachable!(),
+                };
+                // this avoid having to re-insert the stream in the map
+                *ss.get_mut() = StreamState::HalfClosedLocal { incoming };
+            }
+            _ => {
+                // transition to closed
+                ss.remove();
+                debug!(
+                    "Closed stream {} (wrote END_STREAM), now have {} streams",
+                    stream_id,
+                    self.streams.len()
+                );
+            }
+        }
+    }
+}
+
+/// HPACK-encode trailers so they can be queued with
+/// [ConnState::queue_body_end]
+pub(crate) fn encode_trailers(
+    hpack_enc: &mut loona_hpack::Encoder<'static>,
+    out_scratch: &mut RollMut,
+    trailers: &Headers,
+) -> Result<Piece, H2ConnectionError> {
+    let pairs = trailers
+        .iter()
+        .map(|(name, value)| (name.as_str().as_bytes(), &value[..]));
+
+    assert_eq!(out_scratch.len(), 0);
+    hpack_enc
+        .encode_without_indexing_into(pairs, out_scratch)
+        .map_err(H2ConnectionError::WriteError)?;
+    Ok(out_scratch.take_all().into())
+}
+
+/// Queue a header block that's ready to be written as a whole: a HEADERS
+/// frame, followed by CONTINUATION frames if it doesn't fit in a single frame.
+pub(crate) fn queue_header_block_frames(
+    frames: &mut Vec<(Frame, PieceList)>,
+    id: StreamId,
+    mut block: Piece,
+    end_stream: bool,
+    max_fram: usize,
+) {
+    let mut is_first = true;
+    loop {
+        let rest = if block.len() > max_fram {
+            let (fragment, rest) = block.split_at(max_fram);
+            block = fragment;
+            Some(rest)
+        } else {
+            None
+        };
+        let end_headers = rest.is_none();
+
+        let frame_type = if is_first {
+            let mut flags = BitFlags::<HeadersFlags>::default();
+            if end_headers {
+                flags |= HeadersFlags::EndHeaders;
+            }
+            if end_stream {
+                flags |= HeadersFlags::EndStream;
+            }
+            FrameType::Headers(flags)
+        } else {
+            let mut flags = BitFlags::<ContinuationFlags>::default();
+            if end_headers {
+                flags |= ContinuationFlags::EndHeaders;
+            }
+            FrameType::Continuation(flags)
+        };
+        frames.push((Frame::new(frame_type, id), PieceList::single(block)));
+
+        match rest {
+            Some(rest) => {
+                block = rest;
+                is_first = false;
+            }
+            None => break,
+        }
+    }
 }
 
 // cf. RFC 9113, 5.1 Stream States:
@@ -149,6 +548,9 @@ pub(crate) struct StreamOutgoing {
     pub(crate) headers: HeadersOutgoing,
     pub(crate) body: BodyOutgoing,
 
+    // HPACK-encoded trailers, sent once the body is done
+    pub(crate) trailers: Option<Piece>,
+
     // window size of the stream, ie. how many bytes
     // we can send to the receiver before waiting.
     pub(crate) capacity: i64,
@@ -369,6 +771,12 @@ pub enum H2ConnectionError {
     #[error("client sent a push promise frame, clients aren't allowed to do that, cf. RFC9113 section 8.4")]
     ClientSentPushPromise,
 
+    #[error("server sent a push promise frame, but we disabled push with SETTINGS_ENABLE_PUSH")]
+    ServerSentPushPromise,
+
+    #[error("received headers for stream {stream_id}, which we never opened")]
+    HeadersForIdleStream { stream_id: StreamId },
+
     #[error("received window update for unknown/closed stream {stream_id}")]
     WindowUpdateForUnknownOrClosedStream { stream_id: StreamId },
 
@@ -489,8 +897,17 @@ pub(crate) enum H2StreamError {
     #[error("bad request: {0}")]
     BadRequest(&'static str),
 
+    #[error("bad response: {0}")]
+    BadResponse(&'static str),
+
     #[error("stream reset")]
     Cancel,
+
+    #[error("the response was dropped before its body was complete")]
+    ResponseIncomplete,
+
+    #[error("request body is larger than the limit of {limit} bytes")]
+    BodyTooLarge { limit: u64 },
 }
 
 impl H2StreamError {
@@ -500,6 +917,8 @@ impl H2StreamError {
 
         match self {
             Cancel => Code::Cancel,
+            ResponseIncomplete => Code::InternalError,
+            BodyTooLarge { .. } => Code::Cancel,
             // stream closed error
             StreamClosed => Code::StreamClosed,
             // stream refused error
@@ -530,6 +949,10 @@ pub(crate) enum H2EventPayload {
     Headers(Response),
     BodyChunk(Piece),
     BodyEnd,
+    /// Ends the body, like [H2EventPayload::BodyEnd], with trailers
+    Trailers(Box<Headers>),
+    /// The response can't be completed, the stream must be reset
+    Reset,
 }
 
 impl fmt::Debug for H2EventPayload {
@@ -538,6 +961,8 @@ impl fmt::Debug for H2EventPayload {
             Self::Headers(_) => f.debug_tuple("Headers").finish(),
             Self::BodyChunk(_) => f.debug_tuple("BodyChunk").finish(),
             Self::BodyEnd => write!(f, "BodyEnd"),
+            Self::Trailers(_) => f.debug_tuple("Trailers").finish(),
+            Self::Reset => write!(f, "Reset"),
         }
     }
 }
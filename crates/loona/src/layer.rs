@@ -0,0 +1,390 @@
+//! Middleware: [Layer]s wrap a [ServerDriver] to see and alter requests and
+//! the responses sent for them, without the wrapped driver knowing.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Instant, SystemTime},
+};
+
+use buffet::Piece;
+use http::header::HeaderName;
+use tracing::debug;
+
+mod compression;
+pub use compression::*;
+
+use crate::{
+    body::FileRegion, error::ServeError, Body, ConnectionInfo, Encoder, ExpectResponseHeaders,
+    Headers, Request, Responder, Response, ResponseDone, ServeOutcome, ServerDriver,
+};
+
+/// Wraps a [ServerDriver], cf. [Layered].
+pub trait Layer {
+    type Interceptor: Interceptor;
+
+    /// Called before the request is handed to the wrapped driver, which sees
+    /// any changes made here. Returns what will see the response go by.
+    fn on_request(&self, req: &mut Request) -> Self::Interceptor;
+
+    /// Wraps `driver` with this layer
+    fn wrap<OurDriver>(self, driver: OurDriver) -> Layered<Self, OurDriver>
+    where
+        Self: Sized,
+    {
+        Layered::new(self, driver)
+    }
+}
+
+/// Sees (and may alter) a single response, as it's written by the wrapped
+/// driver. Every method has a default implementation that does nothing.
+///
+/// The wrapped driver's [Responder] checks what the driver writes, not what
+/// comes out of the interceptor: an interceptor that changes the length of the
+/// body must fix up `content-length` itself.
+pub trait Interceptor {
+    /// Called for every 1xx response
+    fn on_interim_response(&mut self, _res: &mut Response) {}
+
+    /// Called for the final response, before it's written
+    fn on_final_response(&mut self, _res: &mut Response) {}
+
+    /// Called for every body chunk. Returning `None` drops the chunk.
+    fn on_body_chunk(&mut self, chunk: Piece) -> Option<Piece> {
+        Some(chunk)
+    }
+
+    /// Called once the body is done, gives a last chance to write something
+    fn on_body_end(&mut self) -> Option<Piece> {
+        None
+    }
+
+    /// Called with the trailers the wrapped driver sent, if any. Returning
+    /// `Some` sends trailers even if the driver didn't.
+    fn on_trailers(&mut self, trailers: Option<Box<Headers>>) -> Option<Box<Headers>> {
+        trailers
+    }
+
+    /// Called once the response was fully written
+    fn on_response_done(&mut self) {}
+
+    /// Whether `on_body_chunk` and `on_body_end` need to see the body. If
+    /// not, bodies backed by files may go straight from the file to the
+    /// connection, cf. [crate::Body::take_file]. Interceptors that leave the
+    /// body alone should return false.
+    fn intercepts_body(&self) -> bool {
+        true
+    }
+}
+
+/// Runs a [Layer] around a [ServerDriver]. Layers stack:
+/// `Layered<Outer, Layered<Inner, Driver>>` has requests go through `Outer`
+/// first, and responses through `Inner` first.
+pub struct Layered<OurLayer, OurDriver> {
+    pub layer: OurLayer,
+    pub driver: OurDriver,
+}
+
+impl<OurLayer, OurDriver> Layered<OurLayer, OurDriver> {
+    pub fn new(layer: OurLayer, driver: OurDriver) -> Self {
+        Self { layer, driver }
+    }
+}
+
+impl<OurEncoder, OurLayer, OurDriver> ServerDriver<OurEncoder> for Layered<OurLayer, OurDriver>
+where
+    OurEncoder: Encoder,
+    OurLayer: Layer,
+    OurDriver: ServerDriver<LayerEncoder<OurEncoder, OurLayer::Interceptor>>,
+{
+    type Error = OurDriver::Error;
+
+    async fn handle(
+        &self,
+        mut req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> Result<Responder<OurEncoder, ResponseDone>, Self::Error> {
+        let interceptor = self.layer.on_request(&mut req);
+        let respond = respond.map_encoder(|inner| LayerEncoder { inner, interceptor });
+        let respond = self.driver.handle(req, req_body, respond).await?;
+        Ok(Responder::done(respond.into_inner().inner))
+    }
+
+    fn max_request_body_len(&self, req: &Request, default: Option<u64>) -> Option<u64> {
+        self.driver.max_request_body_len(req, default)
+    }
+
+    fn on_connection_open(&self, conn: &ConnectionInfo) {
+        self.driver.on_connection_open(conn)
+    }
+
+    fn on_connection_close(
+        &self,
+        conn: &ConnectionInfo,
+        outcome: Result<ServeOutcome, &ServeError<Self::Error>>,
+    ) {
+        self.driver.on_connection_close(conn, outcome)
+    }
+}
+
+/// The encoder the driver wrapped by a [Layer] writes to: it passes everything
+/// through the layer's [Interceptor].
+pub struct LayerEncoder<OurEncoder, OurInterceptor> {
+    inner: OurEncoder,
+    interceptor: OurInterceptor,
+}
+
+impl<OurEncoder, OurInterceptor> Encoder for LayerEncoder<OurEncoder, OurInterceptor>
+where
+    OurEncoder: Encoder,
+    OurInterceptor: Interceptor,
+{
+    type Error = OurEncoder::Error;
+
+    async fn write_response(&mut self, mut res: Response) -> Result<(), Self::Error> {
+        if res.status.is_informational() {
+            self.interceptor.on_interim_response(&mut res);
+        } else {
+            self.interceptor.on_final_response(&mut res);
+        }
+        self.inner.write_response(res).await
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error> {
+        match self.interceptor.on_body_chunk(chunk) {
+            Some(chunk) => self.inner.write_body_chunk(chunk).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+        self.end_body(None).await
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error> {
+        self.end_body(Some(trailers)).await
+    }
+
+    fn omit_body(&mut self) {
+        self.inner.omit_body()
+    }
+
+    fn can_write_trailers(&self) -> bool {
+        self.inner.can_write_trailers()
+    }
+
+    fn can_write_body_file(&self) -> bool {
+        !self.interceptor.intercepts_body() && self.inner.can_write_body_file()
+    }
+
+    async fn write_body_file(&mut self, region: FileRegion) -> Result<(), Self::Error> {
+        self.inner.write_body_file(region).await
+    }
+}
+
+impl<OurEncoder, OurInterceptor> LayerEncoder<OurEncoder, OurInterceptor>
+where
+    OurEncoder: Encoder,
+    OurInterceptor: Interceptor,
+{
+    async fn end_body(&mut self, trailers: Option<Box<Headers>>) -> Result<(), OurEncoder::Error> {
+        if let Some(chunk) = self.interceptor.on_body_end() {
+            self.inner.write_body_chunk(chunk).await?;
+        }
+        match self.interceptor.on_trailers(trailers) {
+            Some(trailers) => self.inner.write_trailers(trailers).await?,
+            None => self.inner.write_body_end().await?,
+        }
+        self.interceptor.on_response_done();
+        Ok(())
+    }
+}
+
+/// Lets an [Interceptor]-less [Layer] only look at requests
+impl Interceptor for () {
+    fn intercepts_body(&self) -> bool {
+        false
+    }
+}
+
+/// The id [RequestIdLayer] assigned to a request, available in its extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Makes sure every request has an id: the one the client (or a proxy in front
+/// of us) sent, or a freshly generated one. The id is sent back in the response
+/// headers, and is available to handlers as a [RequestId] extension.
+pub struct RequestIdLayer {
+    /// The request and response header holding the id
+    pub header: HeaderName,
+}
+
+impl Default for RequestIdLayer {
+    fn default() -> Self {
+        Self {
+            header: HeaderName::from_static("x-request-id"),
+        }
+    }
+}
+
+impl Layer for RequestIdLayer {
+    type Interceptor = RequestIdInterceptor;
+
+    fn on_request(&self, req: &mut Request) -> RequestIdInterceptor {
+        let id = match req
+            .headers
+            .get(&self.header)
+            .filter(|id| is_valid_request_id(id))
+            .and_then(|id| std::str::from_utf8(&id[..]).ok())
+        {
+            Some(id) => id.to_owned(),
+            // we don't want to log or echo back whatever the client sent
+            None => {
+                let id = generate_request_id();
+                req.headers
+                    .insert(self.header.clone(), id.clone().into_bytes().into());
+                id
+            }
+        };
+        req.extensions.insert(RequestId(id.clone()));
+
+        RequestIdInterceptor {
+            header: self.header.clone(),
+            id,
+        }
+    }
+}
+
+/// Incoming ids longer than this are replaced with a generated one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Whether an id the client sent is one we're willing to pass on: short, and
+/// only made of visible ASCII characters
+fn is_valid_request_id(id: &[u8]) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.iter().all(u8::is_ascii_graphic)
+}
+
+pub struct RequestIdInterceptor {
+    header: HeaderName,
+    id: String,
+}
+
+impl Interceptor for RequestIdInterceptor {
+    fn intercepts_body(&self) -> bool {
+        false
+    }
+
+    fn on_final_response(&mut self, res: &mut Response) {
+        res.headers
+            .insert(self.header.clone(), self.id.clone().into_bytes().into());
+    }
+}
+
+/// Returns an id that's unique within the process, and very likely unique
+/// across processes
+pub(crate) fn generate_request_id() -> String {
+    static PREFIX: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let prefix = PREFIX.get_or_init(|| {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        nanos ^ ((std::process::id() as u64) << 32)
+    });
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{prefix:016x}-{count:08x}")
+}
+
+/// Measures how long requests take: the time until the response headers are
+/// written is sent in a `server-timing` header, and the time until the
+/// response is done is logged.
+#[derive(Default)]
+pub struct TimingLayer;
+
+impl Layer for TimingLayer {
+    type Interceptor = TimingInterceptor;
+
+    fn on_request(&self, req: &mut Request) -> TimingInterceptor {
+        TimingInterceptor {
+            start: Instant::now(),
+            method: req.method.clone(),
+            path: req.uri.path().to_owned(),
+        }
+    }
+}
+
+pub struct TimingInterceptor {
+    start: Instant,
+    method: crate::Method,
+    path: String,
+}
+
+impl Interceptor for TimingInterceptor {
+    fn intercepts_body(&self) -> bool {
+        false
+    }
+
+    fn on_final_response(&mut self, res: &mut Response) {
+        let millis = self.start.elapsed().as_secs_f64() * 1000.0;
+        res.headers.append(
+            HeaderName::from_static("server-timing"),
+            format!("handler;dur={millis:.3}").into_bytes().into(),
+        );
+    }
+
+    fn on_response_done(&mut self) {
+        debug!(
+            method = %self.method,
+            path = %self.path,
+            elapsed = ?self.start.elapsed(),
+            "response done"
+        );
+    }
+}
+
+/// Adds headers to requests before the wrapped driver sees them, and to final
+/// responses. Headers with the same name are replaced.
+#[derive(Default)]
+pub struct InjectHeadersLayer {
+    pub request: Headers,
+    pub response: Headers,
+}
+
+impl Layer for InjectHeadersLayer {
+    type Interceptor = InjectHeadersInterceptor;
+
+    fn on_request(&self, req: &mut Request) -> InjectHeadersInterceptor {
+        insert_all(&mut req.headers, &self.request);
+        InjectHeadersInterceptor {
+            response: self.response.clone(),
+        }
+    }
+}
+
+pub struct InjectHeadersInterceptor {
+    response: Headers,
+}
+
+impl Interceptor for InjectHeadersInterceptor {
+    fn intercepts_body(&self) -> bool {
+        false
+    }
+
+    fn on_final_response(&mut self, res: &mut Response) {
+        insert_all(&mut res.headers, &self.response);
+    }
+}
+
+/// Inserts all of `from` into `into`, keeping multiple values for the same name
+fn insert_all(into: &mut Headers, from: &Headers) {
+    for name in from.keys() {
+        into.remove(name);
+    }
+    for (name, value) in from {
+        into.append(name.clone(), value.clone());
+    }
+}
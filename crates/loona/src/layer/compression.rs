@@ -0,0 +1,338 @@
+//! Response compression, negotiated with `accept-encoding`
+
+use std::io::Write;
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use http::{header, StatusCode};
+
+use super::{Interceptor, Layer};
+use crate::{types::status_means_empty_body, HeadersExt, MediaType, Method, Request, Response};
+
+/// A content coding [CompressionLayer] can compress responses with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ContentCoding {
+    Gzip,
+    /// The zlib format, which is what HTTP calls `deflate`
+    Deflate,
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl ContentCoding {
+    /// The name of the coding, in `accept-encoding` and `content-encoding`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+            #[cfg(feature = "brotli")]
+            ContentCoding::Brotli => "br",
+            #[cfg(feature = "zstd")]
+            ContentCoding::Zstd => "zstd",
+        }
+    }
+
+    fn matches(self, name: &[u8]) -> bool {
+        name.eq_ignore_ascii_case(self.as_str().as_bytes())
+            || (self == ContentCoding::Gzip && name.eq_ignore_ascii_case(b"x-gzip"))
+    }
+}
+
+/// Compresses response bodies with the best coding the client accepts, as
+/// they're written: each body chunk is compressed and flushed on its own, so
+/// streaming responses (like server-sent events) still stream.
+///
+/// Responses are left alone if they already have a `content-encoding`, if
+/// they're shorter than `min_len`, if their `content-type` isn't worth
+/// compressing, if they're partial (206), or if they say `cache-control:
+/// no-transform`.
+pub struct CompressionLayer {
+    /// Codings to offer, in order of preference when the client likes several
+    /// of them as much
+    pub codings: Vec<ContentCoding>,
+
+    /// Responses with a `content-length` under this are sent as-is
+    pub min_len: u64,
+}
+
+impl Default for CompressionLayer {
+    fn default() -> Self {
+        Self {
+            codings: vec![
+                #[cfg(feature = "brotli")]
+                ContentCoding::Brotli,
+                #[cfg(feature = "zstd")]
+                ContentCoding::Zstd,
+                ContentCoding::Gzip,
+                ContentCoding::Deflate,
+            ],
+            min_len: 1024,
+        }
+    }
+}
+
+impl Layer for CompressionLayer {
+    type Interceptor = CompressionInterceptor;
+
+    fn on_request(&self, req: &mut Request) -> CompressionInterceptor {
+        let coding = negotiate(
+            req.headers.weighted_list(header::ACCEPT_ENCODING),
+            &self.codings,
+        );
+        CompressionInterceptor {
+            coding,
+            is_head: req.method == Method::Head,
+            min_len: self.min_len,
+            compressor: None,
+        }
+    }
+}
+
+pub struct CompressionInterceptor {
+    coding: Option<ContentCoding>,
+    is_head: bool,
+    min_len: u64,
+    // only set once we've decided to compress the response body
+    compressor: Option<Compressor>,
+}
+
+impl Interceptor for CompressionInterceptor {
+    fn on_final_response(&mut self, res: &mut Response) {
+        if !may_compress(res) {
+            return;
+        }
+        add_vary_accept_encoding(res);
+
+        let Some(coding) = self.coding else {
+            return;
+        };
+        if res
+            .headers
+            .content_length()
+            .is_some_and(|len| len < self.min_len)
+        {
+            return;
+        }
+        if !self.is_head {
+            match Compressor::new(coding) {
+                Ok(compressor) => self.compressor = Some(compressor),
+                Err(e) => {
+                    tracing::warn!(%e, coding = coding.as_str(), "could not set up compression");
+                    return;
+                }
+            }
+        }
+
+        res.headers.remove(header::CONTENT_LENGTH);
+        // byte ranges of the compressed body aren't something we can serve
+        res.headers.remove(header::ACCEPT_RANGES);
+        res.headers
+            .insert(header::CONTENT_ENCODING, coding.as_str().into());
+        // it's not the same representation anymore, cf. RFC 9110, section 8.8.3
+        if let Some(etag) = res.headers.get(header::ETAG) {
+            if !etag.starts_with(b"W/") {
+                let weak = [&b"W/"[..], &etag[..]].concat();
+                res.headers.insert(header::ETAG, weak.into());
+            }
+        }
+    }
+
+    fn on_body_chunk(&mut self, chunk: buffet::Piece) -> Option<buffet::Piece> {
+        let Some(compressor) = &mut self.compressor else {
+            return Some(chunk);
+        };
+        if chunk.is_empty() {
+            return None;
+        }
+        let out = compressor.compress(&chunk);
+        (!out.is_empty()).then(|| out.into())
+    }
+
+    fn on_body_end(&mut self) -> Option<buffet::Piece> {
+        let out = self.compressor.take()?.finish();
+        (!out.is_empty()).then(|| out.into())
+    }
+
+    // uncompressed file bodies can still be sent as-is
+    fn intercepts_body(&self) -> bool {
+        self.compressor.is_some()
+    }
+}
+
+/// Whether the response is something we could compress at all, given the
+/// right `accept-encoding`
+fn may_compress(res: &Response) -> bool {
+    if status_means_empty_body(res.status) || res.status == StatusCode::PARTIAL_CONTENT {
+        return false;
+    }
+    if res.headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+    let no_transform = res
+        .headers
+        .cache_directives()
+        .any(|(directive, _)| directive.eq_ignore_ascii_case(b"no-transform"));
+    if no_transform {
+        return false;
+    }
+    res.headers.content_type().is_some_and(is_compressible)
+}
+
+/// Text, and the usual text-based formats: images, video, archives and the
+/// like are already compressed.
+fn is_compressible(content_type: MediaType<'_>) -> bool {
+    let subtype = content_type.subtype.to_ascii_lowercase();
+    content_type.ty.eq_ignore_ascii_case(b"text")
+        || subtype.ends_with(b"+json")
+        || subtype.ends_with(b"+xml")
+        || content_type.is("application", "json")
+        || content_type.is("application", "javascript")
+        || content_type.is("application", "xml")
+        || content_type.is("application", "wasm")
+}
+
+fn add_vary_accept_encoding(res: &mut Response) {
+    let already_varies = res
+        .headers
+        .list(header::VARY)
+        .any(|name| name == b"*" || name.eq_ignore_ascii_case(b"accept-encoding"));
+    if !already_varies {
+        res.headers.append(header::VARY, "accept-encoding".into());
+    }
+}
+
+/// Picks the coding with the highest weight in `accept-encoding`, cf. RFC
+/// 9110, section 12.5.3. Ties go to whichever comes first in `codings`.
+fn negotiate<'a>(
+    accept_encoding: impl Iterator<Item = (&'a [u8], u16)>,
+    codings: &[ContentCoding],
+) -> Option<ContentCoding> {
+    let weights: Vec<(&[u8], u16)> = accept_encoding.collect();
+
+    let wildcard = weights
+        .iter()
+        .find(|(name, _)| *name == b"*")
+        .map(|&(_, weight)| weight);
+    let mut best: Option<(ContentCoding, u16)> = None;
+    for &coding in codings {
+        let weight = weights
+            .iter()
+            .find(|(name, _)| coding.matches(name))
+            .map(|&(_, weight)| weight)
+            .or(wildcard)
+            .unwrap_or(0);
+        if weight > 0 && best.map_or(true, |(_, best_weight)| weight > best_weight) {
+            best = Some((coding, weight));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+enum Compressor {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    #[cfg(feature = "brotli")]
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Compressor {
+    fn new(coding: ContentCoding) -> std::io::Result<Self> {
+        let level = flate2::Compression::default();
+        Ok(match coding {
+            ContentCoding::Gzip => Compressor::Gzip(GzEncoder::new(Vec::new(), level)),
+            ContentCoding::Deflate => Compressor::Deflate(ZlibEncoder::new(Vec::new(), level)),
+            // a quality of 4 is a good trade-off for on-the-fly compression
+            #[cfg(feature = "brotli")]
+            ContentCoding::Brotli => Compressor::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                4,
+                22,
+            ))),
+            #[cfg(feature = "zstd")]
+            ContentCoding::Zstd => {
+                Compressor::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?)
+            }
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Compressor::Gzip(w) => w,
+            Compressor::Deflate(w) => w,
+            #[cfg(feature = "brotli")]
+            Compressor::Brotli(w) => w.as_mut(),
+            #[cfg(feature = "zstd")]
+            Compressor::Zstd(w) => w,
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Compressor::Gzip(w) => w.get_mut(),
+            Compressor::Deflate(w) => w.get_mut(),
+            #[cfg(feature = "brotli")]
+            Compressor::Brotli(w) => w.get_mut(),
+            #[cfg(feature = "zstd")]
+            Compressor::Zstd(w) => w.get_mut(),
+        }
+    }
+
+    /// Compresses `chunk` and flushes, returning everything compressed so far
+    fn compress(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let writer = self.writer();
+        writer
+            .write_all(chunk)
+            .and_then(|_| writer.flush())
+            .expect("compressing into a Vec never fails");
+        std::mem::take(self.output())
+    }
+
+    /// Ends the compressed stream, returning what's left of it
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Compressor::Gzip(w) => w.finish(),
+            Compressor::Deflate(w) => w.finish(),
+            #[cfg(feature = "brotli")]
+            Compressor::Brotli(w) => Ok(w.into_inner()),
+            #[cfg(feature = "zstd")]
+            Compressor::Zstd(w) => w.finish(),
+        }
+        .expect("compressing into a Vec never fails")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Headers;
+
+    #[test]
+    fn test_negotiate() {
+        let codings = [ContentCoding::Gzip, ContentCoding::Deflate];
+        for (accept_encoding, expected) in [
+            ("gzip, deflate", Some(ContentCoding::Gzip)),
+            ("deflate, gzip;q=0.5", Some(ContentCoding::Deflate)),
+            ("deflate;q=0.5, GZIP;q=0.8", Some(ContentCoding::Gzip)),
+            ("x-gzip", Some(ContentCoding::Gzip)),
+            ("*", Some(ContentCoding::Gzip)),
+            ("*;q=0.1, gzip;q=0", Some(ContentCoding::Deflate)),
+            ("gzip;q=0, deflate;q=0", None),
+            ("gzip;q=2, deflate;q=0.001", Some(ContentCoding::Deflate)),
+            ("identity", None),
+            ("", None),
+        ] {
+            let mut headers = Headers::default();
+            headers.insert(header::ACCEPT_ENCODING, accept_encoding.into());
+            assert_eq!(
+                negotiate(headers.weighted_list(header::ACCEPT_ENCODING), &codings),
+                expected,
+                "{accept_encoding}"
+            );
+        }
+    }
+}
@@ -1,14 +1,26 @@
+use std::rc::Rc;
+
+mod auto_headers;
 mod types;
 mod util;
 
 pub use types::*;
 
+pub mod body;
 pub mod h1;
 pub mod h2;
+pub mod layer;
+pub mod multipart;
+pub mod proxy;
+pub mod resource;
+pub mod router;
 
 mod responder;
 pub use responder::*;
 
+mod server;
+pub use server::*;
+
 pub use buffet;
 
 /// re-exported so consumers can use whatever forked version we use
@@ -29,4 +41,71 @@ where
         req_body: &mut impl Body,
         respond: Responder<OurEncoder, ExpectResponseHeaders>,
     ) -> Result<Responder<OurEncoder, ResponseDone>, Self::Error>;
+
+    /// Returns how big the body of `req` may be, in bytes, given the limit
+    /// from the server configuration. Called before `handle`: requests that
+    /// announce a larger `content-length` get a 413 response without reaching
+    /// the driver, and streamed bodies error out past the limit.
+    fn max_request_body_len(&self, _req: &Request, default: Option<u64>) -> Option<u64> {
+        default
+    }
+
+    /// Called when we start serving a connection, before any request is
+    /// handled
+    fn on_connection_open(&self, _conn: &ConnectionInfo) {}
+
+    /// Called when we're done serving a connection, with how that went
+    fn on_connection_close(
+        &self,
+        _conn: &ConnectionInfo,
+        _outcome: Result<ServeOutcome, &error::ServeError<Self::Error>>,
+    ) {
+    }
+}
+
+/// Lets a single driver (a [router::Router], for example) be shared by all connections
+impl<OurEncoder, OurDriver> ServerDriver<OurEncoder> for Rc<OurDriver>
+where
+    OurEncoder: Encoder,
+    OurDriver: ServerDriver<OurEncoder>,
+{
+    type Error = OurDriver::Error;
+
+    async fn handle(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> Result<Responder<OurEncoder, ResponseDone>, Self::Error> {
+        self.as_ref().handle(req, req_body, respond).await
+    }
+
+    fn max_request_body_len(&self, req: &Request, default: Option<u64>) -> Option<u64> {
+        self.as_ref().max_request_body_len(req, default)
+    }
+
+    fn on_connection_open(&self, conn: &ConnectionInfo) {
+        self.as_ref().on_connection_open(conn)
+    }
+
+    fn on_connection_close(
+        &self,
+        conn: &ConnectionInfo,
+        outcome: Result<ServeOutcome, &error::ServeError<Self::Error>>,
+    ) {
+        self.as_ref().on_connection_close(conn, outcome)
+    }
+}
+
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait ClientDriver {
+    type Return;
+    type Error: std::error::Error + 'static;
+
+    async fn on_informational_response(&mut self, res: Response) -> Result<(), Self::Error>;
+    async fn on_final_response(
+        self,
+        res: Response,
+        body: &mut impl Body,
+    ) -> Result<Self::Return, Self::Error>;
 }
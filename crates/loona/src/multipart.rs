@@ -0,0 +1,505 @@
+//! Streaming `multipart/form-data` request bodies, cf. RFC 7578 and RFC 2046,
+//! section 5.1.
+//!
+//! [Multipart] wraps any [Body] and yields its parts one at a time: the part
+//! headers come first, then the part itself is read like any other body.
+//! Nothing is buffered beyond what it takes to find the next boundary.
+
+use std::{borrow::Cow, fmt};
+
+use buffet::{Piece, Roll, RollMut};
+use http::header;
+use nom::{
+    branch::alt,
+    bytes::streaming::{tag, take_while},
+    combinator::value,
+    sequence::preceded,
+    IResult,
+};
+
+use crate::{
+    h1::parse::headers_and_crlf, split_params, Body, BodyChunk, Headers, HeadersExt, MediaType,
+    Request,
+};
+
+const CRLF: &[u8] = b"\r\n";
+
+/// Limits enforced while parsing a multipart body
+#[derive(Debug, Clone, Copy)]
+pub struct MultipartConf {
+    /// How many parts a body may have
+    pub max_parts: usize,
+
+    /// How large the headers of a single part may be, in bytes
+    pub max_headers_len: usize,
+}
+
+impl Default for MultipartConf {
+    fn default() -> Self {
+        Self {
+            max_parts: 128,
+            max_headers_len: 8 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum MultipartError<BodyError> {
+    #[error("content-type is not multipart, or has no valid boundary")]
+    NotMultipart,
+
+    #[error("body has more than {max_parts} parts")]
+    TooManyParts { max_parts: usize },
+
+    #[error("part headers are larger than {max_len} bytes")]
+    HeadersTooLarge { max_len: usize },
+
+    #[error("part headers are malformed")]
+    InvalidHeaders,
+
+    #[error("boundary delimiter is malformed")]
+    InvalidDelimiter,
+
+    #[error("body ended before the closing boundary delimiter")]
+    UnexpectedEnd,
+
+    #[error("allocation error: {0}")]
+    Alloc(#[from] buffet::bufpool::Error),
+
+    #[error("body error: {0}")]
+    Body(BodyError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Skipping everything up to the first delimiter
+    Preamble,
+
+    /// Right after a delimiter: either the close delimiter's `--`, or the
+    /// CRLF that precedes part headers
+    Delimiter,
+
+    PartHeaders,
+    PartBody,
+
+    /// Past the close delimiter, the epilogue is ignored
+    Done,
+}
+
+/// A `multipart/*` body, read one [Part] at a time
+pub struct Multipart<B: Body> {
+    body: B,
+    conf: MultipartConf,
+
+    /// `CRLF--boundary`
+    delimiter: Vec<u8>,
+
+    /// Allocated on first read, primed with a CRLF so that a delimiter right
+    /// at the start of the body is found like any other.
+    buf: Option<RollMut>,
+
+    /// What's left of the last chunk read from `body` that didn't fit in
+    /// `buf` yet
+    pending: Option<Piece>,
+
+    state: State,
+    num_parts: usize,
+}
+
+impl<B: Body> fmt::Debug for Multipart<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Multipart")
+            .field("body", &self.body)
+            .field("conf", &self.conf)
+            .field("state", &self.state)
+            .field("num_parts", &self.num_parts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Body> Multipart<B> {
+    /// Reads `body` with the given boundary, as found in the `content-type`
+    /// header. Use [Multipart::from_request] to get it from there.
+    pub fn new(body: B, boundary: impl AsRef<[u8]>) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_ref());
+
+        Self {
+            body,
+            conf: Default::default(),
+            delimiter,
+            buf: None,
+            pending: None,
+            state: State::Preamble,
+            num_parts: 0,
+        }
+    }
+
+    /// Reads `body` with the boundary from the `content-type` header of
+    /// `req`, which must be `multipart/*`.
+    pub fn from_request(req: &Request, body: B) -> Result<Self, MultipartError<B::Error>> {
+        let boundary = req
+            .headers
+            .content_type()
+            .and_then(boundary)
+            .ok_or(MultipartError::NotMultipart)?;
+        Ok(Self::new(body, boundary))
+    }
+
+    pub fn with_conf(mut self, conf: MultipartConf) -> Self {
+        self.conf = conf;
+        self
+    }
+
+    /// Returns the next part, or `None` past the last one. Whatever wasn't
+    /// read of the previous part is skipped.
+    pub async fn next_part(&mut self) -> Result<Option<Part<'_, B>>, MultipartError<B::Error>> {
+        loop {
+            match self.state {
+                State::Preamble => {
+                    if let Some(buf) = self.buf.as_mut() {
+                        match memchr::memmem::find(&buf[..], &self.delimiter) {
+                            Some(i) => {
+                                buf.skip(i + self.delimiter.len());
+                                self.state = State::Delimiter;
+                                continue;
+                            }
+                            None => {
+                                // keep what could be the start of a delimiter
+                                let keep = self.delimiter.len() - 1;
+                                buf.skip(buf.len().saturating_sub(keep));
+                            }
+                        }
+                    }
+                    self.fill().await?;
+                }
+                State::Delimiter => {
+                    let close = self
+                        .parse(after_delimiter, MultipartError::InvalidDelimiter)
+                        .await?;
+                    self.state = if close {
+                        State::Done
+                    } else {
+                        State::PartHeaders
+                    };
+                }
+                State::PartHeaders => {
+                    if self.num_parts == self.conf.max_parts {
+                        return Err(MultipartError::TooManyParts {
+                            max_parts: self.conf.max_parts,
+                        });
+                    }
+                    let headers = self
+                        .parse(headers_and_crlf, MultipartError::InvalidHeaders)
+                        .await?;
+                    self.num_parts += 1;
+                    self.state = State::PartBody;
+                    return Ok(Some(Part {
+                        headers,
+                        multipart: self,
+                    }));
+                }
+                State::PartBody => while self.next_part_chunk().await?.is_some() {},
+                State::Done => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns the next chunk of the current part, or `None` once its
+    /// delimiter was reached.
+    async fn next_part_chunk(&mut self) -> Result<Option<Piece>, MultipartError<B::Error>> {
+        loop {
+            if self.state != State::PartBody {
+                return Ok(None);
+            }
+
+            // we're past the preamble, so `buf` is allocated
+            let buf = self.buf.as_mut().unwrap();
+            let avail = match memchr::memmem::find(&buf[..], &self.delimiter) {
+                Some(0) => {
+                    buf.skip(self.delimiter.len());
+                    self.state = State::Delimiter;
+                    return Ok(None);
+                }
+                Some(i) => i,
+                None => {
+                    // hold back anything that could be the start of a
+                    // delimiter split across chunks
+                    let tail_start = buf.len().saturating_sub(self.delimiter.len() - 1);
+                    match memchr::memchr(b'\r', &buf[tail_start..]) {
+                        Some(i) => tail_start + i,
+                        None => buf.len(),
+                    }
+                }
+            };
+
+            if avail > 0 {
+                return Ok(buf.take_at_most(avail).map(Piece::from));
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Runs `parser` over the buffer, reading more of the body as long as it
+    /// needs more input and the headers limit isn't reached.
+    async fn parse<Output>(
+        &mut self,
+        parser: impl Fn(Roll) -> IResult<Roll, Output>,
+        invalid: MultipartError<B::Error>,
+    ) -> Result<Output, MultipartError<B::Error>> {
+        loop {
+            let buf = self.buf.as_mut().unwrap();
+            match parser(buf.filled()) {
+                Ok((rest, output)) => {
+                    buf.keep(rest);
+                    return Ok(output);
+                }
+                Err(nom::Err::Incomplete(_)) => {
+                    if buf.len() >= self.conf.max_headers_len {
+                        return Err(MultipartError::HeadersTooLarge {
+                            max_len: self.conf.max_headers_len,
+                        });
+                    }
+                    self.fill().await?;
+                }
+                Err(_) => return Err(invalid),
+            }
+        }
+    }
+
+    /// Moves more of the body into `buf`. Running out of body is an error:
+    /// we only ever need more when the close delimiter wasn't seen yet.
+    async fn fill(&mut self) -> Result<(), MultipartError<B::Error>> {
+        let chunk = match self.pending.take() {
+            Some(chunk) => chunk,
+            None => loop {
+                match self.body.next_chunk().await.map_err(MultipartError::Body)? {
+                    BodyChunk::Chunk(chunk) if chunk.is_empty() => continue,
+                    BodyChunk::Chunk(chunk) => break chunk,
+                    BodyChunk::Done { .. } => return Err(MultipartError::UnexpectedEnd),
+                }
+            },
+        };
+
+        let buf = match &mut self.buf {
+            Some(buf) => buf,
+            None => {
+                let mut buf = RollMut::alloc()?;
+                buf.put(CRLF)?;
+                self.buf.insert(buf)
+            }
+        };
+        if buf.cap() == 0 {
+            buf.reserve()?;
+        }
+
+        let n = std::cmp::min(buf.cap(), chunk.len());
+        let (now, later) = chunk.split_at(n);
+        buf.put(&now[..])?;
+        if !later.is_empty() {
+            self.pending = Some(later);
+        }
+        Ok(())
+    }
+}
+
+/// One part of a [Multipart] body. Its contents are read with
+/// [Body::next_chunk], up to the next boundary.
+pub struct Part<'a, B: Body> {
+    pub headers: Headers,
+    multipart: &'a mut Multipart<B>,
+}
+
+impl<B: Body> Part<'_, B> {
+    /// The `name` parameter of the `content-disposition` header: for
+    /// `multipart/form-data`, the name of the form field.
+    pub fn name(&self) -> Option<String> {
+        self.disposition_param("name")
+    }
+
+    /// The `filename` parameter of the `content-disposition` header, for file
+    /// uploads. It comes from the client: don't use it as a path as-is.
+    pub fn filename(&self) -> Option<String> {
+        self.disposition_param("filename")
+    }
+
+    /// The `content-type` header, if any. RFC 7578 says to assume
+    /// `text/plain` otherwise.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    fn disposition_param(&self, name: &str) -> Option<String> {
+        let value = self.headers.get(header::CONTENT_DISPOSITION)?;
+        let param = split_params(value).1.get(name)?;
+        Some(String::from_utf8_lossy(&param).into_owned())
+    }
+}
+
+impl<B: Body> fmt::Debug for Part<'_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Part")
+            .field("name", &self.name())
+            .field("filename", &self.filename())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Body> Body for Part<'_, B> {
+    type Error = MultipartError<B::Error>;
+
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.multipart.state != State::PartBody
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        Ok(match self.multipart.next_part_chunk().await? {
+            Some(chunk) => BodyChunk::Chunk(chunk),
+            None => BodyChunk::Done { trailers: None },
+        })
+    }
+}
+
+/// Returns the `boundary` parameter of a `multipart/*` media type, if it's
+/// a valid one (1 to 70 characters, cf. RFC 2046, section 5.1.1)
+fn boundary(content_type: MediaType<'_>) -> Option<Cow<'_, [u8]>> {
+    if !content_type.ty.eq_ignore_ascii_case(b"multipart") {
+        return None;
+    }
+
+    let boundary = content_type.params().get("boundary")?;
+    (1..=70).contains(&boundary.len()).then_some(boundary)
+}
+
+/// After a delimiter comes either `--` (it was the close delimiter), or
+/// optional whitespace and a CRLF. Returns true for the close delimiter.
+fn after_delimiter(i: Roll) -> IResult<Roll, bool> {
+    alt((
+        value(true, tag(&b"--"[..])),
+        value(
+            false,
+            preceded(take_while(|c| c == b' ' || c == b'\t'), tag(CRLF)),
+        ),
+    ))(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{body::StreamBody, error::NeverError};
+
+    const BODY: &[u8] = b"preamble\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"field\"\r\n\r\n\
+        value\r\n--xyz \r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        line one\r\nline two\r\n--xy\r\n\r\n--xyz--\r\nepilogue";
+
+    fn split_body(
+        chunk_size: usize,
+    ) -> StreamBody<impl futures_util::Stream<Item = Result<Piece, NeverError>>> {
+        let chunks: Vec<Result<Piece, NeverError>> = BODY
+            .chunks(chunk_size)
+            .map(|chunk| Ok(chunk.to_vec().into()))
+            .collect();
+        StreamBody::new(futures_util::stream::iter(chunks))
+    }
+
+    async fn read_parts<B: Body>(
+        multipart: &mut Multipart<B>,
+    ) -> Result<Vec<(Option<String>, Option<String>, Vec<u8>)>, MultipartError<B::Error>> {
+        let mut parts = Vec::new();
+        while let Some(mut part) = multipart.next_part().await? {
+            let (name, filename) = (part.name(), part.filename());
+            let mut contents = Vec::new();
+            while let BodyChunk::Chunk(chunk) = part.next_chunk().await? {
+                contents.extend_from_slice(&chunk);
+            }
+            parts.push((name, filename, contents));
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn test_multipart() {
+        buffet::start(async move {
+            // boundaries must be found wherever the chunks are split
+            for chunk_size in 1..=BODY.len() {
+                let mut multipart = Multipart::new(split_body(chunk_size), "xyz");
+                let parts = read_parts(&mut multipart).await.unwrap();
+                assert_eq!(
+                    parts,
+                    vec![
+                        (Some("field".into()), None, b"value".to_vec()),
+                        (
+                            Some("file".into()),
+                            Some("a \"b\".txt".into()),
+                            b"line one\r\nline two\r\n--xy\r\n".to_vec()
+                        ),
+                    ],
+                    "chunk_size = {chunk_size}"
+                );
+            }
+
+            // parts that aren't read are skipped
+            let mut multipart = Multipart::new(split_body(7), "xyz");
+            let part = multipart.next_part().await.unwrap().unwrap();
+            assert_eq!(part.name().as_deref(), Some("field"));
+            let part = multipart.next_part().await.unwrap().unwrap();
+            assert_eq!(part.content_type(), Some("text/plain"));
+            assert!(multipart.next_part().await.unwrap().is_none());
+
+            let mut multipart = Multipart::new(split_body(16), "xyz").with_conf(MultipartConf {
+                max_parts: 1,
+                ..Default::default()
+            });
+            assert!(matches!(
+                read_parts(&mut multipart).await,
+                Err(MultipartError::TooManyParts { max_parts: 1 })
+            ));
+
+            let mut multipart = Multipart::new(split_body(16), "xyz").with_conf(MultipartConf {
+                max_headers_len: 32,
+                ..Default::default()
+            });
+            assert!(matches!(
+                read_parts(&mut multipart).await,
+                Err(MultipartError::HeadersTooLarge { max_len: 32 })
+            ));
+
+            // no close delimiter
+            let mut multipart = Multipart::new(split_body(16), "nope");
+            assert!(matches!(
+                read_parts(&mut multipart).await,
+                Err(MultipartError::UnexpectedEnd)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_boundary() {
+        for (content_type, expected) in [
+            ("multipart/form-data; boundary=abc", Some("abc")),
+            ("Multipart/Form-Data;boundary=\"a b;c\"", Some("a b;c")),
+            ("multipart/mixed; charset=utf-8; Boundary=abc", Some("abc")),
+            ("multipart/form-data; boundary=\"\"", None),
+            ("multipart/form-data", None),
+            ("text/plain; boundary=abc", None),
+        ] {
+            assert_eq!(
+                MediaType::parse(content_type.as_bytes())
+                    .and_then(boundary)
+                    .as_deref(),
+                expected.map(str::as_bytes),
+                "{content_type}"
+            );
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! A reverse proxy: a [ServerDriver] that forwards requests to an HTTP/1.1
+//! upstream, for both HTTP/1.1 and HTTP/2 frontends.
+
+use std::{
+    cell::Cell,
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    rc::Rc,
+    time::Duration,
+};
+
+use b_x::BX;
+use http::{
+    header::{self, HeaderName},
+    StatusCode, Version,
+};
+use tracing::debug;
+
+use crate::{
+    h1::{self, Http1ClientError, WriteBodyError},
+    Body, BodyChunk, ClientDriver, Encoder, ExpectResponseHeaders, Headers, HeadersExt, Request,
+    Responder, Response, ResponseDone, ServerDriver,
+};
+
+/// A server requests are forwarded to
+pub struct Upstream {
+    /// Where to connect to
+    pub addr: SocketAddr,
+
+    /// Identifies the upstream in the connection pool, e.g. `backend:8080`
+    pub authority: String,
+}
+
+impl Upstream {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            authority: addr.to_string(),
+        }
+    }
+}
+
+pub struct ProxyConf {
+    /// Where requests are forwarded to
+    pub upstream: Upstream,
+
+    /// How requests are sent to the upstream
+    pub client: h1::ClientConf,
+
+    /// How long to wait for the upstream's response headers before giving up
+    /// and responding with 504 (Gateway Timeout)
+    pub upstream_timeout: Duration,
+}
+
+impl ProxyConf {
+    pub fn new(upstream: Upstream) -> Self {
+        Self {
+            upstream,
+            client: Default::default(),
+            upstream_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ProxyError {
+    #[error("Upstream failed after the response headers were relayed: {0}")]
+    Upstream(#[source] BX),
+
+    #[error("Could not read the request body: {0}")]
+    RequestBody(#[source] BX),
+
+    #[error("Could not write the response: {0}")]
+    Downstream(#[source] BX),
+
+    #[error("Upstream switched protocols, which isn't supported")]
+    UnsupportedProtocolSwitch,
+}
+
+/// Forwards every request to the configured upstream, re-using connections
+/// from a [h1::Pool].
+///
+/// Errors that happen before the response headers were relayed are answered
+/// with 502 (Bad Gateway), or 504 (Gateway Timeout) if the upstream took too
+/// long to respond.
+pub struct ProxyDriver {
+    pub conf: Rc<ProxyConf>,
+    pub pool: Rc<h1::Pool>,
+
+    /// The address of the downstream peer, for the `forwarded` and
+    /// `x-forwarded-for` headers. If unset, the peer address from the
+    /// request's [crate::ConnectionInfo] is used.
+    pub client_addr: Option<SocketAddr>,
+}
+
+impl ProxyDriver {
+    /// Builds a driver that uses the thread-local connection pool
+    pub fn new(conf: Rc<ProxyConf>, client_addr: Option<SocketAddr>) -> Self {
+        Self {
+            conf,
+            pool: h1::Pool::thread_local(),
+            client_addr,
+        }
+    }
+
+    fn prepare_request(&self, req: &mut Request) {
+        // HTTP/2 requests don't have a host header, but they have an authority
+        let host = match req.headers.get(header::HOST) {
+            Some(host) => std::str::from_utf8(&host[..]).ok().map(str::to_owned),
+            None => req.uri.authority().map(|a| a.as_str().to_owned()),
+        };
+        let proto = req.uri.scheme_str().unwrap_or("http").to_owned();
+        let client_addr = self
+            .client_addr
+            .or_else(|| req.connection_info().and_then(|conn| conn.peer_addr));
+
+        let accepts_trailers = req.headers.accepts_trailers();
+
+        remove_hop_by_hop_headers(&mut req.headers);
+        req.version = Version::HTTP_11;
+
+        let headers = &mut req.headers;
+        if accepts_trailers {
+            // `te` is hop-by-hop, but we can relay trailers
+            headers.insert(header::TE, "trailers".into());
+        }
+        let mut forwarded = String::new();
+        if let Some(addr) = client_addr {
+            let ip = addr.ip();
+            match ip {
+                IpAddr::V4(ip) => write!(forwarded, "for={ip};").unwrap(),
+                IpAddr::V6(ip) => write!(forwarded, "for=\"[{ip}]\";").unwrap(),
+            }
+            headers.append(
+                HeaderName::from_static("x-forwarded-for"),
+                ip.to_string().into_bytes().into(),
+            );
+        }
+        if let Some(host) = host {
+            forwarded.push_str("host=");
+            push_quoted(&mut forwarded, &host);
+            forwarded.push(';');
+            headers.insert(
+                HeaderName::from_static("x-forwarded-host"),
+                host.into_bytes().into(),
+            );
+        }
+        write!(forwarded, "proto={proto}").unwrap();
+        headers.insert(
+            HeaderName::from_static("x-forwarded-proto"),
+            proto.into_bytes().into(),
+        );
+        headers.append(header::FORWARDED, forwarded.into_bytes().into());
+    }
+}
+
+impl<OurEncoder> ServerDriver<OurEncoder> for ProxyDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = ProxyError;
+
+    async fn handle(
+        &self,
+        mut req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> Result<Responder<OurEncoder, ResponseDone>, ProxyError> {
+        let downstream_version = match req.version {
+            Version::HTTP_2 => Version::HTTP_2,
+            _ => Version::HTTP_11,
+        };
+        let expects_continue = req.headers.expects_100_continue();
+        let accepts_trailers = req.headers.accepts_trailers();
+        self.prepare_request(&mut req);
+
+        let mut respond = Some(respond);
+        let headers_relayed = Cell::new(false);
+        let driver = RelayDriver {
+            respond: &mut respond,
+            headers_relayed: &headers_relayed,
+            downstream_version,
+            expects_continue,
+            accepts_trailers,
+        };
+
+        let upstream = &self.conf.upstream;
+        let res = {
+            let mut request_fut = std::pin::pin!(self.pool.request(
+                &upstream.authority,
+                upstream.addr,
+                &self.conf.client,
+                req,
+                req_body,
+                driver,
+            ));
+            tokio::select! {
+                res = &mut request_fut => Some(res),
+                _ = tokio::time::sleep(self.conf.upstream_timeout) => {
+                    // the timeout only applies until we get response headers
+                    if headers_relayed.get() {
+                        Some(request_fut.await)
+                    } else {
+                        None
+                    }
+                }
+            }
+        };
+
+        let (status, respond) = match (res, respond.take()) {
+            (Some(Ok(respond)), _) => return Ok(respond),
+            (
+                Some(Err(Http1ClientError::WhileWritingRequestBody(
+                    WriteBodyError::InnerBodyError(e),
+                ))),
+                _,
+            ) => return Err(ProxyError::RequestBody(e)),
+            // we've already relayed the response headers, there's no recovering
+            (Some(Err(Http1ClientError::DriverError(e))), None) => return Err(e),
+            (Some(Err(e)), None) => return Err(ProxyError::Upstream(BX::from_err(e))),
+            (Some(Err(e)), Some(respond)) => {
+                debug!("upstream request failed: {e}");
+                (StatusCode::BAD_GATEWAY, respond)
+            }
+            (None, Some(respond)) => {
+                debug!("upstream timed out");
+                (StatusCode::GATEWAY_TIMEOUT, respond)
+            }
+            (None, None) => unreachable!("we don't time out once response headers are relayed"),
+        };
+
+        let res = Response {
+            status,
+            version: downstream_version,
+            ..Default::default()
+        };
+        respond
+            .write_final_response_with_body(res, &mut ())
+            .await
+            .map_err(|e| ProxyError::Downstream(BX::from_err(e)))
+    }
+}
+
+/// Relays the upstream's response to the downstream
+struct RelayDriver<'a, OurEncoder>
+where
+    OurEncoder: Encoder,
+{
+    respond: &'a mut Option<Responder<OurEncoder, ExpectResponseHeaders>>,
+    headers_relayed: &'a Cell<bool>,
+    downstream_version: Version,
+    expects_continue: bool,
+    accepts_trailers: bool,
+}
+
+impl<OurEncoder> ClientDriver for RelayDriver<'_, OurEncoder>
+where
+    OurEncoder: Encoder,
+{
+    type Return = Responder<OurEncoder, ResponseDone>;
+    type Error = ProxyError;
+
+    async fn on_informational_response(&mut self, mut res: Response) -> Result<(), ProxyError> {
+        if res.status == StatusCode::CONTINUE && !self.expects_continue {
+            return Ok(());
+        }
+
+        let Some(respond) = self.respond.as_mut() else {
+            return Ok(());
+        };
+        remove_hop_by_hop_headers(&mut res.headers);
+        res.version = self.downstream_version;
+        respond
+            .write_interim_response(res)
+            .await
+            .map_err(|e| ProxyError::Downstream(BX::from_err(e)))
+    }
+
+    async fn on_final_response(
+        self,
+        mut res: Response,
+        body: &mut impl Body,
+    ) -> Result<Self::Return, ProxyError> {
+        if res.status == StatusCode::SWITCHING_PROTOCOLS {
+            return Err(ProxyError::UnsupportedProtocolSwitch);
+        }
+        let respond = self
+            .respond
+            .take()
+            .expect("there's only one final response");
+        self.headers_relayed.set(true);
+
+        // we re-frame the body for the downstream
+        if res.headers.contains_key(header::TRANSFER_ENCODING) {
+            res.headers.remove(header::CONTENT_LENGTH);
+        }
+        remove_hop_by_hop_headers(&mut res.headers);
+        res.version = self.downstream_version;
+        let announced_trailers = res.headers.announced_trailers();
+
+        let mut respond = respond
+            .write_final_response(res)
+            .await
+            .map_err(|e| ProxyError::Downstream(BX::from_err(e)))?;
+
+        let trailers = loop {
+            match body
+                .next_chunk()
+                .await
+                .map_err(|e| ProxyError::Upstream(BX::from_err(e)))?
+            {
+                BodyChunk::Chunk(chunk) => {
+                    respond
+                        .write_chunk(chunk)
+                        .await
+                        .map_err(|e| ProxyError::Downstream(BX::from_err(e)))?;
+                }
+                BodyChunk::Done { trailers } => break trailers,
+            }
+        };
+
+        let trailers = match trailers {
+            Some(_) if !self.accepts_trailers => {
+                debug!("not relaying trailers to a client that didn't send `te: trailers`");
+                None
+            }
+            trailers => trailers
+                .map(|mut trailers| {
+                    remove_hop_by_hop_headers(&mut trailers);
+                    // the downstream only gets the trailers the upstream announced
+                    let names: Vec<_> = trailers.keys().cloned().collect();
+                    for name in names {
+                        if !announced_trailers.contains(&name) {
+                            debug!(%name, "not relaying trailer that wasn't announced");
+                            trailers.remove(name);
+                        }
+                    }
+                    trailers
+                })
+                .filter(|trailers| !trailers.is_empty()),
+        };
+
+        respond
+            .finish_body(trailers)
+            .await
+            .map_err(|e| ProxyError::Downstream(BX::from_err(e)))
+    }
+}
+
+/// Removes headers that only make sense for a single connection, including
+/// the ones listed in the `connection` header, cf. RFC 9110, section 7.6.1
+pub fn remove_hop_by_hop_headers(headers: &mut Headers) {
+    let listed: Vec<HeaderName> = headers
+        .list(header::CONNECTION)
+        .filter_map(|name| HeaderName::from_bytes(name).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+
+    for name in [
+        header::CONNECTION,
+        HeaderName::from_static("keep-alive"),
+        HeaderName::from_static("proxy-connection"),
+        header::TE,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+}
+
+/// Appends `s` as a quoted-string, cf. RFC 9110, section 5.6.4
+fn push_quoted(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
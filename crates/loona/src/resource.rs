@@ -0,0 +1,532 @@
+//! Serving file-like resources: conditional requests (`if-match`,
+//! `if-none-match`, `if-modified-since`, `if-unmodified-since`) and range
+//! requests (`range`, `if-range`), cf. RFC 9110, sections 13 and 14.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use buffet::Piece;
+use http::{header, HeaderName, StatusCode};
+
+use crate::{
+    auto_headers::{format_http_date, parse_http_date},
+    body::FileBody,
+    layer::generate_request_id,
+    util::trim_ows,
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, Method, Request, Responder,
+    ResponderOrBodyError, Response, ResponseDone,
+};
+
+/// A `range` header asking for more ranges than this is ignored, and the whole
+/// resource is sent instead: many small ranges cost more than they save.
+const MAX_RANGES: usize = 16;
+
+/// Something [serve_resource] can serve
+pub trait Resource {
+    type Body: Body;
+
+    /// Total length, in bytes
+    fn content_len(&self) -> u64;
+
+    /// The entity tag, quotes included: `"xyzzy"`, or `W/"xyzzy"` for a weak
+    /// one
+    fn etag(&self) -> Option<&str> {
+        None
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        None
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        None
+    }
+
+    /// Returns a body for `len` bytes of the resource, starting at `offset`
+    fn read_range(&self, offset: u64, len: u64) -> Self::Body;
+}
+
+/// A file on disk, served with [FileBody]
+pub struct FileResource {
+    file: Rc<File>,
+    len: u64,
+    last_modified: Option<SystemTime>,
+    etag: Option<String>,
+    content_type: Option<String>,
+}
+
+impl FileResource {
+    /// Takes the length and modification time from the file's metadata, and
+    /// derives a strong entity tag from both.
+    pub fn new(file: File) -> std::io::Result<Self> {
+        let meta = file.metadata()?;
+        let last_modified = meta.modified().ok();
+        let etag = last_modified.map(|modified| {
+            let nanos = modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos());
+            format!("\"{nanos:x}-{:x}\"", meta.len())
+        });
+        Ok(Self {
+            file: Rc::new(file),
+            len: meta.len(),
+            last_modified,
+            etag,
+            content_type: None,
+        })
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+}
+
+impl Resource for FileResource {
+    type Body = FileBody;
+
+    fn content_len(&self) -> u64 {
+        self.len
+    }
+
+    fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    fn read_range(&self, offset: u64, len: u64) -> FileBody {
+        FileBody::with_range(self.file.clone(), offset, len)
+    }
+}
+
+/// Responds to a GET or HEAD request for `resource`. Depending on the
+/// request's preconditions and ranges, that's a 200, a 206 (with a
+/// `multipart/byteranges` body if several ranges were asked for), a 304, a
+/// 412 or a 416.
+pub async fn serve_resource<OurEncoder, OurResource>(
+    req: &Request,
+    resource: &OurResource,
+    respond: Responder<OurEncoder, ExpectResponseHeaders>,
+) -> Result<
+    Responder<OurEncoder, ResponseDone>,
+    ResponderOrBodyError<OurEncoder::Error, <OurResource::Body as Body>::Error>,
+>
+where
+    OurEncoder: Encoder,
+    OurResource: Resource,
+{
+    let len = resource.content_len();
+    let etag = resource.etag();
+    let last_modified = resource.last_modified().map(unix_secs);
+
+    let mut res = Response {
+        version: req.version,
+        ..Default::default()
+    };
+    if let Some(etag) = etag {
+        res.headers
+            .insert(header::ETAG, etag.to_owned().into_bytes().into());
+    }
+    if let Some(last_modified) = last_modified {
+        res.headers.insert(
+            header::LAST_MODIFIED,
+            format_http_date(last_modified).to_vec().into(),
+        );
+    }
+
+    match evaluate_preconditions(req, etag, last_modified) {
+        Precondition::Passed => {}
+        Precondition::NotModified => {
+            // no content-length: it would be the one of a 200
+            res.status = StatusCode::NOT_MODIFIED;
+            return respond_without_body(respond, res).await;
+        }
+        Precondition::Failed => {
+            res.status = StatusCode::PRECONDITION_FAILED;
+            res.headers.insert(header::CONTENT_LENGTH, "0".into());
+            return respond_without_body(respond, res).await;
+        }
+    }
+
+    res.headers.insert(header::ACCEPT_RANGES, "bytes".into());
+
+    // range requests are only defined for GET, cf. RFC 9110, section 14.2
+    let ranges = if req.method == Method::Get && if_range_passes(req, etag, last_modified) {
+        req.headers
+            .get(header::RANGE)
+            .and_then(|range| parse_ranges(range, len))
+    } else {
+        None
+    };
+
+    match ranges.as_deref() {
+        None => {
+            insert_content_type(&mut res.headers, resource);
+            respond
+                .write_final_response_with_body(res, &mut resource.read_range(0, len))
+                .await
+        }
+        Some([]) => {
+            res.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            res.headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes */{len}").into_bytes().into(),
+            );
+            res.headers.insert(header::CONTENT_LENGTH, "0".into());
+            respond_without_body(respond, res).await
+        }
+        Some(&[(first, last)]) => {
+            res.status = StatusCode::PARTIAL_CONTENT;
+            insert_content_type(&mut res.headers, resource);
+            res.headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes {first}-{last}/{len}").into_bytes().into(),
+            );
+            respond
+                .write_final_response_with_body(
+                    res,
+                    &mut resource.read_range(first, last - first + 1),
+                )
+                .await
+        }
+        Some(ranges) => {
+            res.status = StatusCode::PARTIAL_CONTENT;
+            let mut body = ByteRangesBody::new(resource, ranges);
+            res.headers.insert(
+                header::CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={}", body.boundary)
+                    .into_bytes()
+                    .into(),
+            );
+            respond.write_final_response_with_body(res, &mut body).await
+        }
+    }
+}
+
+async fn respond_without_body<OurEncoder, BodyError>(
+    respond: Responder<OurEncoder, ExpectResponseHeaders>,
+    res: Response,
+) -> Result<Responder<OurEncoder, ResponseDone>, ResponderOrBodyError<OurEncoder::Error, BodyError>>
+where
+    OurEncoder: Encoder,
+{
+    respond
+        .write_final_response(res)
+        .await
+        .map_err(ResponderOrBodyError::Responder)?
+        .finish_body(None)
+        .await
+        .map_err(ResponderOrBodyError::Responder)
+}
+
+fn insert_content_type(headers: &mut Headers, resource: &impl Resource) {
+    if let Some(content_type) = resource.content_type() {
+        headers.insert(
+            header::CONTENT_TYPE,
+            content_type.to_owned().into_bytes().into(),
+        );
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+enum Precondition {
+    Passed,
+    NotModified,
+    Failed,
+}
+
+/// Evaluates preconditions in the order given by RFC 9110, section 13.2.2
+fn evaluate_preconditions(
+    req: &Request,
+    etag: Option<&str>,
+    last_modified: Option<u64>,
+) -> Precondition {
+    let etag = etag.map(str::as_bytes);
+
+    if req.headers.contains_key(header::IF_MATCH) {
+        if !any_etag_matches(req, header::IF_MATCH, etag, strong_compare) {
+            return Precondition::Failed;
+        }
+    } else if let (Some(since), Some(last_modified)) =
+        (header_date(req, header::IF_UNMODIFIED_SINCE), last_modified)
+    {
+        if last_modified > since {
+            return Precondition::Failed;
+        }
+    }
+
+    let is_get_or_head = matches!(req.method, Method::Get | Method::Head);
+    if req.headers.contains_key(header::IF_NONE_MATCH) {
+        if any_etag_matches(req, header::IF_NONE_MATCH, etag, weak_compare) {
+            return if is_get_or_head {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if let (true, Some(since), Some(last_modified)) = (
+        is_get_or_head,
+        header_date(req, header::IF_MODIFIED_SINCE),
+        last_modified,
+    ) {
+        if last_modified <= since {
+            return Precondition::NotModified;
+        }
+    }
+
+    Precondition::Passed
+}
+
+/// Whether a range request should be honored: always, unless there's an
+/// `if-range` header that doesn't match the current representation
+fn if_range_passes(req: &Request, etag: Option<&str>, last_modified: Option<u64>) -> bool {
+    let Some(if_range) = req.headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    if if_range.starts_with(b"\"") || if_range.starts_with(b"W/") {
+        etag.is_some_and(|etag| strong_compare(etag.as_bytes(), &if_range[..]))
+    } else {
+        parse_http_date(if_range).is_some_and(|date| last_modified == Some(date))
+    }
+}
+
+fn header_date(req: &Request, name: HeaderName) -> Option<u64> {
+    req.headers
+        .get(name)
+        .and_then(|value| parse_http_date(value))
+}
+
+/// Whether any of the entity tags in the (possibly repeated) header matches
+/// `etag`. `*` matches any current representation.
+fn any_etag_matches(
+    req: &Request,
+    name: HeaderName,
+    etag: Option<&[u8]>,
+    compare: fn(&[u8], &[u8]) -> bool,
+) -> bool {
+    req.headers.get_all(name).iter().any(|value| {
+        if trim_ows(value) == b"*" {
+            return true;
+        }
+        etag.is_some_and(|etag| parse_etags(value).any(|candidate| compare(etag, candidate)))
+    })
+}
+
+/// Iterates over a comma-separated list of entity tags, cf. RFC 9110, section
+/// 8.8.3. Stops at the first malformed one.
+fn parse_etags(mut input: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let start = input
+            .iter()
+            .position(|&b| !matches!(b, b' ' | b'\t' | b','))?;
+        input = &input[start..];
+        let opaque_start = if input.starts_with(b"W/") { 2 } else { 0 };
+        if input.get(opaque_start) != Some(&b'"') {
+            return None;
+        }
+        let len = input[opaque_start + 1..].iter().position(|&b| b == b'"')?;
+        let (etag, rest) = input.split_at(opaque_start + len + 2);
+        input = rest;
+        Some(etag)
+    })
+}
+
+/// Both entity tags are strong, and identical
+fn strong_compare(a: &[u8], b: &[u8]) -> bool {
+    !a.starts_with(b"W/") && !b.starts_with(b"W/") && a == b
+}
+
+/// The entity tags are identical, whether they're weak or not
+fn weak_compare(a: &[u8], b: &[u8]) -> bool {
+    a.strip_prefix(b"W/").unwrap_or(a) == b.strip_prefix(b"W/").unwrap_or(b)
+}
+
+/// Parses a `range` header, cf. RFC 9110, section 14.1.2, into inclusive
+/// `(first, last)` byte positions. Returns `None` if the header should be
+/// ignored, and an empty list if none of the ranges are satisfiable.
+fn parse_ranges(value: &[u8], len: u64) -> Option<Vec<(u64, u64)>> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (unit, ranges) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let specs: Vec<&str> = ranges
+        .split(',')
+        .map(|spec| spec.trim_matches([' ', '\t']))
+        .filter(|spec| !spec.is_empty())
+        .collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+
+    let mut satisfiable = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first, last) {
+            ("", suffix_len) => {
+                let suffix_len = parse_pos(suffix_len)?;
+                (suffix_len > 0 && len > 0).then(|| (len - suffix_len.min(len), len - 1))
+            }
+            (first, "") => {
+                let first = parse_pos(first)?;
+                (first < len).then(|| (first, len - 1))
+            }
+            (first, last) => {
+                let (first, last) = (parse_pos(first)?, parse_pos(last)?);
+                if last < first {
+                    return None;
+                }
+                (first < len).then(|| (first, last.min(len - 1)))
+            }
+        };
+        satisfiable.extend(range);
+    }
+    Some(satisfiable)
+}
+
+fn parse_pos(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// A `multipart/byteranges` body, cf. RFC 9110, section 14.6
+struct ByteRangesBody<'a, OurResource: Resource> {
+    resource: &'a OurResource,
+    boundary: String,
+    // the headers of each part, and the range it holds
+    parts: VecDeque<(Piece, u64, u64)>,
+    current: Option<OurResource::Body>,
+    closing: Option<Piece>,
+    content_len: u64,
+}
+
+impl<'a, OurResource: Resource> ByteRangesBody<'a, OurResource> {
+    fn new(resource: &'a OurResource, ranges: &[(u64, u64)]) -> Self {
+        let boundary = format!("loona-{}", generate_request_id());
+        let len = resource.content_len();
+        let content_type = resource
+            .content_type()
+            .map(|content_type| format!("content-type: {content_type}\r\n"))
+            .unwrap_or_default();
+
+        let mut content_len = 0;
+        let parts: VecDeque<_> = ranges
+            .iter()
+            .map(|&(first, last)| {
+                let headers = format!(
+                    "\r\n--{boundary}\r\n{content_type}content-range: bytes {first}-{last}/{len}\r\n\r\n"
+                );
+                content_len += headers.len() as u64 + (last - first + 1);
+                (headers.into_bytes().into(), first, last)
+            })
+            .collect();
+        let closing = format!("\r\n--{boundary}--\r\n");
+        content_len += closing.len() as u64;
+
+        Self {
+            resource,
+            boundary,
+            parts,
+            current: None,
+            closing: Some(closing.into_bytes().into()),
+            content_len,
+        }
+    }
+}
+
+impl<OurResource: Resource> fmt::Debug for ByteRangesBody<'_, OurResource> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ByteRangesBody")
+            .field("boundary", &self.boundary)
+            .field("parts_left", &self.parts.len())
+            .field("content_len", &self.content_len)
+            .finish()
+    }
+}
+
+impl<OurResource: Resource> Body for ByteRangesBody<'_, OurResource> {
+    type Error = <OurResource::Body as Body>::Error;
+
+    fn content_len(&self) -> Option<u64> {
+        Some(self.content_len)
+    }
+
+    fn eof(&self) -> bool {
+        self.current.is_none() && self.parts.is_empty() && self.closing.is_none()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        if let Some(part) = &mut self.current {
+            match part.next_chunk().await? {
+                BodyChunk::Chunk(chunk) => return Ok(BodyChunk::Chunk(chunk)),
+                // trailers of a part have nowhere to go
+                BodyChunk::Done { .. } => self.current = None,
+            }
+        }
+
+        if let Some((headers, first, last)) = self.parts.pop_front() {
+            self.current = Some(self.resource.read_range(first, last - first + 1));
+            return Ok(BodyChunk::Chunk(headers));
+        }
+
+        Ok(match self.closing.take() {
+            Some(closing) => BodyChunk::Chunk(closing),
+            None => BodyChunk::Done { trailers: None },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ranges() {
+        for (input, expected) in [
+            ("bytes=0-499", Some(vec![(0, 499)])),
+            ("bytes=500-", Some(vec![(500, 999)])),
+            ("bytes=-200", Some(vec![(800, 999)])),
+            ("bytes=-5000", Some(vec![(0, 999)])),
+            ("bytes=900-5000", Some(vec![(900, 999)])),
+            ("bytes= 0-0 , -1", Some(vec![(0, 0), (999, 999)])),
+            ("bytes=1000-", Some(vec![])),
+            ("bytes=-0", Some(vec![])),
+            ("bytes=5-1", None),
+            ("bytes=a-b", None),
+            ("bytes=+1-2", None),
+            ("lines=0-1", None),
+            ("bytes=", None),
+        ] {
+            assert_eq!(parse_ranges(input.as_bytes(), 1000), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_etags() {
+        let etags: Vec<&[u8]> = parse_etags(br#""a", W/"b,c" ,"""#).collect();
+        assert_eq!(etags, [&br#""a""#[..], br#"W/"b,c""#, br#""""#]);
+
+        assert!(strong_compare(br#""a""#, br#""a""#));
+        assert!(!strong_compare(br#"W/"a""#, br#""a""#));
+        assert!(weak_compare(br#"W/"a""#, br#""a""#));
+        assert!(!weak_compare(br#""a""#, br#""b""#));
+    }
+}
@@ -1,8 +1,11 @@
 use b_x::BX;
 use buffet::Piece;
-use http::{header, StatusCode};
+use http::{header, HeaderName, StatusCode};
 
-use crate::{Body, BodyChunk, Headers, HeadersExt, Response};
+use crate::{
+    body::FileRegion, types::status_means_empty_body, Body, BodyChunk, Headers, HeadersExt, Method,
+    Request, Response,
+};
 
 pub trait ResponseState {}
 
@@ -12,6 +15,9 @@ impl ResponseState for ExpectResponseHeaders {}
 pub struct ExpectResponseBody {
     pub announced_content_length: Option<u64>,
     pub bytes_written: u64,
+    status: StatusCode,
+    // from the `trailer` header of the response
+    announced_trailers: Vec<HeaderName>,
 }
 impl ResponseState for ExpectResponseBody {}
 
@@ -32,6 +38,21 @@ pub enum ResponderError<EncoderError> {
     )]
     BodyLengthDoesNotMatchAnnouncedContentLength { actual: u64, expected: u64 },
 
+    #[error("responses with status code {status} must not have a body")]
+    ResponseMustNotHaveBody { status: StatusCode },
+
+    #[error("responses with status code {status} must not have trailers")]
+    ResponseMustNotHaveTrailers { status: StatusCode },
+
+    #[error("the client didn't send `te: trailers`, it may not accept trailers")]
+    TrailersNotAcceptedByClient,
+
+    #[error("trailer {name} wasn't announced in the `trailer` response header")]
+    TrailerNotAnnounced { name: HeaderName },
+
+    #[error("trailers can only be sent with chunked transfer-encoding")]
+    TrailersRequireChunkedTransferEncoding,
+
     #[error("encoder error: {0}")]
     EncoderError(#[from] EncoderError),
 }
@@ -61,6 +82,10 @@ where
 {
     encoder: OurEncoder,
     state: OurResponseState,
+    // set when responding to a HEAD request: body chunks are discarded
+    omit_body: bool,
+    // unset if the client didn't send `te: trailers`
+    accepts_trailers: bool,
 }
 
 impl<OurEncoder> Responder<OurEncoder, ExpectResponseHeaders>
@@ -71,6 +96,48 @@ where
         Self {
             encoder,
             state: ExpectResponseHeaders,
+            omit_body: false,
+            accepts_trailers: true,
+        }
+    }
+
+    /// Like [Responder::new], for a response to a request with the given
+    /// method: for `HEAD` requests, the response headers are sent as they
+    /// would be for a `GET` (including `content-length`), but body chunks
+    /// and trailers are discarded.
+    pub fn for_method(mut encoder: OurEncoder, method: &Method) -> Self {
+        let omit_body = *method == Method::Head;
+        if omit_body {
+            encoder.omit_body();
+        }
+        Self {
+            encoder,
+            state: ExpectResponseHeaders,
+            omit_body,
+            accepts_trailers: true,
+        }
+    }
+
+    /// Like [Responder::for_method], and only lets trailers be sent if the
+    /// client sent `te: trailers`.
+    pub fn for_request(encoder: OurEncoder, req: &Request) -> Self {
+        Self {
+            accepts_trailers: req.headers.accepts_trailers(),
+            ..Self::for_method(encoder, &req.method)
+        }
+    }
+
+    /// Swaps out the encoder (e.g. to wrap it), keeping track of whether the
+    /// body is omitted.
+    pub(crate) fn map_encoder<TheirEncoder: Encoder>(
+        self,
+        f: impl FnOnce(OurEncoder) -> TheirEncoder,
+    ) -> Responder<TheirEncoder, ExpectResponseHeaders> {
+        Responder {
+            encoder: f(self.encoder),
+            state: ExpectResponseHeaders,
+            omit_body: self.omit_body,
+            accepts_trailers: self.accepts_trailers,
         }
     }
 
@@ -105,6 +172,8 @@ where
                 },
             );
         }
+        let status = res.status;
+        let announced_trailers = res.headers.announced_trailers();
         self.encoder
             .write_response(res)
             .await
@@ -113,8 +182,12 @@ where
             state: ExpectResponseBody {
                 announced_content_length,
                 bytes_written: 0,
+                status,
+                announced_trailers,
             },
             encoder: self.encoder,
+            omit_body: self.omit_body,
+            accepts_trailers: self.accepts_trailers,
         })
     }
 
@@ -164,6 +237,22 @@ where
             .await
             .map_err(ResponderOrBodyError::Responder)?;
 
+        if this.omit_body {
+            // no need to read a body nobody will see
+            return this
+                .finish_body(None)
+                .await
+                .map_err(ResponderOrBodyError::Responder);
+        }
+
+        if this.encoder.can_write_body_file() {
+            if let Some(region) = body.take_file() {
+                this.write_file(region)
+                    .await
+                    .map_err(ResponderOrBodyError::Responder)?;
+            }
+        }
+
         loop {
             match body
                 .next_chunk()
@@ -190,17 +279,55 @@ impl<E> Responder<E, ExpectResponseBody>
 where
     E: Encoder,
 {
-    /// Send a response body chunk. Errors out if sending more than the
-    /// announced content-length.
+    /// Send a response body chunk. Errors out, without writing anything, if
+    /// the chunk would make the body exceed the announced content-length, or
+    /// if the response status doesn't allow a body (1xx, 204, 205, 304).
+    /// Chunks are discarded if responding to a HEAD request.
     #[inline]
     pub async fn write_chunk(&mut self, chunk: Piece) -> ResponderResult<(), E::Error> {
-        self.state.bytes_written += chunk.len() as u64;
+        if self.omit_body {
+            return Ok(());
+        }
+        self.count_body_bytes(chunk.len() as u64)?;
         self.encoder
             .write_body_chunk(chunk)
             .await
             .map_err(ResponderError::EncoderError)
     }
 
+    /// Send a region of a file as part of the body. Only called if the
+    /// encoder said it could.
+    async fn write_file(&mut self, region: FileRegion) -> ResponderResult<(), E::Error> {
+        self.count_body_bytes(region.len)?;
+        self.encoder
+            .write_body_file(region)
+            .await
+            .map_err(ResponderError::EncoderError)
+    }
+
+    /// Errors out if `len` more bytes of body would go over the announced
+    /// content-length, or if this response must not have a body at all.
+    fn count_body_bytes(&mut self, len: u64) -> ResponderResult<(), E::Error> {
+        if len > 0 && status_means_empty_body(self.state.status) {
+            return Err(ResponderError::ResponseMustNotHaveBody {
+                status: self.state.status,
+            });
+        }
+        let bytes_written = self.state.bytes_written + len;
+        if let Some(announced_content_length) = self.state.announced_content_length {
+            if bytes_written > announced_content_length {
+                return Err(
+                    ResponderError::BodyLengthDoesNotMatchAnnouncedContentLength {
+                        actual: bytes_written,
+                        expected: announced_content_length,
+                    },
+                );
+            }
+        }
+        self.state.bytes_written = bytes_written;
+        Ok(())
+    }
+
     /// Finish the body, with optional trailers, cf. <https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/TE>
     /// Errors out if the sent body doesn't match the announced content-length.
     /// Errors out if trailers that weren't announced are being sent, or if the
@@ -211,6 +338,20 @@ where
         mut self,
         trailers: Option<Box<Headers>>,
     ) -> ResponderResult<Responder<E, ResponseDone>, E::Error> {
+        if self.omit_body {
+            // the announced content-length is the one a GET would have gotten
+            self.encoder
+                .write_body_end()
+                .await
+                .map_err(ResponderError::EncoderError)?;
+            return Ok(Responder {
+                state: ResponseDone,
+                encoder: self.encoder,
+                omit_body: true,
+                accepts_trailers: self.accepts_trailers,
+            });
+        }
+
         if let Some(announced_content_length) = self.state.announced_content_length {
             if self.state.bytes_written != announced_content_length {
                 return Err(
@@ -221,29 +362,58 @@ where
                 );
             }
         }
-        self.encoder
-            .write_body_end()
-            .await
-            .map_err(ResponderError::EncoderError)?;
-
-        if let Some(trailers) = trailers {
-            self.encoder
-                .write_trailers(trailers)
-                .await
-                .map_err(ResponderError::EncoderError)?;
+        if let Some(trailers) = &trailers {
+            self.check_trailers(trailers)?;
+        }
+        match trailers {
+            Some(trailers) => self.encoder.write_trailers(trailers).await,
+            None => self.encoder.write_body_end().await,
         }
+        .map_err(ResponderError::EncoderError)?;
 
         Ok(Responder {
             state: ResponseDone,
             encoder: self.encoder,
+            omit_body: self.omit_body,
+            accepts_trailers: self.accepts_trailers,
         })
     }
+
+    fn check_trailers(&self, trailers: &Headers) -> ResponderResult<(), E::Error> {
+        if status_means_empty_body(self.state.status) {
+            return Err(ResponderError::ResponseMustNotHaveTrailers {
+                status: self.state.status,
+            });
+        }
+        if !self.accepts_trailers {
+            return Err(ResponderError::TrailersNotAcceptedByClient);
+        }
+        if let Some(name) = trailers
+            .keys()
+            .find(|name| !self.state.announced_trailers.contains(name))
+        {
+            return Err(ResponderError::TrailerNotAnnounced { name: name.clone() });
+        }
+        if !self.encoder.can_write_trailers() {
+            return Err(ResponderError::TrailersRequireChunkedTransferEncoding);
+        }
+        Ok(())
+    }
 }
 
 impl<E> Responder<E, ResponseDone>
 where
     E: Encoder,
 {
+    pub(crate) fn done(encoder: E) -> Self {
+        Self {
+            encoder,
+            state: ResponseDone,
+            omit_body: false,
+            accepts_trailers: true,
+        }
+    }
+
     pub fn into_inner(self) -> E {
         self.encoder
     }
@@ -260,7 +430,33 @@ pub trait Encoder {
     /// the responder takes care of that for HTTP/1.1 and HTTP/2
     async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error>;
     async fn write_body_end(&mut self) -> Result<(), Self::Error>;
+    /// Ends the body with trailers: this is called instead of `write_body_end`
     async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error>;
+
+    /// Called before any response is written, if responding to a HEAD request:
+    /// the final response's headers describe the body a GET would have gotten,
+    /// but no body must be framed. The responder then only calls
+    /// `write_body_end`.
+    fn omit_body(&mut self) {}
+
+    /// Whether the body, as framed by the last `write_response`, can end with
+    /// trailers
+    fn can_write_trailers(&self) -> bool {
+        true
+    }
+
+    /// Whether the body, as framed by the last `write_response`, can be sent
+    /// with `write_body_file`
+    fn can_write_body_file(&self) -> bool {
+        false
+    }
+
+    /// Writes a region of a file as part of the body, ideally without copying
+    /// it through userspace. Only called if `can_write_body_file` returned
+    /// true.
+    async fn write_body_file(&mut self, _region: FileRegion) -> Result<(), Self::Error> {
+        unreachable!("write_body_file called, but can_write_body_file returned false")
+    }
 }
 
 #[cfg(test)]
@@ -289,6 +485,29 @@ mod tests {
         }
     }
 
+    /// Like [MockEncoder], for framing that can't carry trailers
+    struct NoTrailersEncoder;
+
+    impl Encoder for NoTrailersEncoder {
+        type Error = BX;
+
+        async fn write_response(&mut self, _: Response) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_chunk(&mut self, _: Piece) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_trailers(&mut self, _: Box<Headers>) -> Result<(), Self::Error> {
+            unreachable!()
+        }
+        fn can_write_trailers(&self) -> bool {
+            false
+        }
+    }
+
     #[tokio::test]
     async fn test_content_length_mismatch() {
         let encoder = MockEncoder;
@@ -319,13 +538,116 @@ mod tests {
                 .write_final_response(res)
                 .await
                 .unwrap();
-            responder.write_chunk(b"12345678901".into()).await.unwrap();
-            let result = responder.finish_body(None).await;
-            assert!(result.is_err());
+            responder.write_chunk(b"12345".into()).await.unwrap();
+            let result = responder.write_chunk(b"678901".into()).await;
             assert!(matches!(
                 result,
-                Err(ResponderError::BodyLengthDoesNotMatchAnnouncedContentLength { .. })
+                Err(
+                    ResponderError::BodyLengthDoesNotMatchAnnouncedContentLength {
+                        actual: 11,
+                        expected: 10
+                    }
+                )
+            ));
+            // the rejected chunk wasn't counted
+            responder.write_chunk(b"67890".into()).await.unwrap();
+            responder.finish_body(None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_not_allowed() {
+        for status in [StatusCode::NO_CONTENT, StatusCode::NOT_MODIFIED] {
+            let res = Response {
+                status,
+                ..Default::default()
+            };
+            let mut responder = Responder::new(MockEncoder)
+                .write_final_response(res)
+                .await
+                .unwrap();
+            let result = responder.write_chunk(b"12345".into()).await;
+            assert!(matches!(
+                result,
+                Err(ResponderError::ResponseMustNotHaveBody { .. })
             ));
         }
     }
+
+    #[tokio::test]
+    async fn test_trailer_checks() {
+        let mut trailers = Headers::default();
+        trailers.insert("x-checksum", "abc123".into());
+
+        let req = |te: Option<&'static str>| {
+            let mut req = Request::default();
+            if let Some(te) = te {
+                req.headers.insert(header::TE, te.into());
+            }
+            req
+        };
+        let res = |status: StatusCode, trailer: Option<&'static str>| {
+            let mut res = Response {
+                status,
+                ..Default::default()
+            };
+            if let Some(trailer) = trailer {
+                res.headers.insert(header::TRAILER, trailer.into());
+            }
+            res
+        };
+
+        for (te, res, ok) in [
+            (
+                Some("trailers"),
+                res(StatusCode::OK, Some("x-checksum")),
+                true,
+            ),
+            (
+                Some("gzip;q=0.5, Trailers"),
+                res(StatusCode::OK, Some("x-foo, X-Checksum")),
+                true,
+            ),
+            (None, res(StatusCode::OK, Some("x-checksum")), false),
+            (Some("trailers"), res(StatusCode::OK, None), false),
+            (Some("trailers"), res(StatusCode::OK, Some("x-foo")), false),
+            (
+                Some("trailers"),
+                res(StatusCode::NO_CONTENT, Some("x-checksum")),
+                false,
+            ),
+        ] {
+            let result = Responder::for_request(MockEncoder, &req(te))
+                .write_final_response(res)
+                .await
+                .unwrap()
+                .finish_body(Some(Box::new(trailers.clone())))
+                .await;
+            assert_eq!(result.is_ok(), ok, "te {te:?}");
+        }
+
+        let result = Responder::for_request(NoTrailersEncoder, &req(Some("trailers")))
+            .write_final_response(res(StatusCode::OK, Some("x-checksum")))
+            .await
+            .unwrap()
+            .finish_body(Some(Box::new(trailers)))
+            .await;
+        assert!(matches!(
+            result,
+            Err(ResponderError::TrailersRequireChunkedTransferEncoding)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_head_discards_body() {
+        let mut res = Response::default();
+        res.headers.insert(header::CONTENT_LENGTH, "10".into());
+        let mut responder = Responder::for_method(MockEncoder, &Method::Head)
+            .write_final_response(res)
+            .await
+            .unwrap();
+        responder.write_chunk(b"12345".into()).await.unwrap();
+        // the content-length describes what a GET would have gotten
+        responder.finish_body(None).await.unwrap();
+    }
 }
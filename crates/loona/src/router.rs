@@ -0,0 +1,747 @@
+//! Dispatching requests to drivers based on their method, path and host.
+
+use std::{any::Any, fmt, rc::Rc};
+
+use b_x::BX;
+use buffet::Piece;
+use futures_util::future::LocalBoxFuture;
+use http::{header, StatusCode};
+
+use crate::{
+    body::FileRegion, Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, Method, Request,
+    Responder, Response, ResponseDone, ServerDriver,
+};
+
+/// A [ServerDriver] that hands each request to the driver of the route it
+/// matches, or responds with 404 (Not Found) if no route matches its path, and
+/// with 405 (Method Not Allowed) if routes match its path, but not its method.
+///
+/// Path patterns are matched segment by segment:
+///
+///   * `/users` only matches `/users`
+///   * `/users/:id` matches `/users/42`, with the `id` parameter set to `42`
+///   * `/static/*path` matches `/static/css/site.css`, with the `path`
+///     parameter set to `css/site.css`. Wildcards must come last, and also
+///     match an empty rest of the path.
+///
+/// When several routes match, the most specific one wins: static segments beat
+/// parameters, which beat wildcards. Path parameters are available to handlers
+/// as a [PathParams] extension.
+///
+/// Route drivers must implement [ServerDriver] for [BoxedEncoder], which any
+/// driver generic over the encoder does. A `Router` is usually built once, and
+/// shared between connections with an [Rc].
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    mounts: Vec<(Vec<Segment>, Router)>,
+    hosts: Vec<(HostPattern, Router)>,
+}
+
+struct Route {
+    method: Method,
+    pattern: Vec<Segment>,
+    handler: Rc<dyn ErasedHandler>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl Segment {
+    fn specificity(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+enum HostPattern {
+    /// `example.org`
+    Exact(String),
+    /// `*.example.org`, stored as `.example.org`
+    Subdomains(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Self {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix('*') {
+            Some(suffix) => {
+                assert!(
+                    suffix.starts_with('.'),
+                    "host wildcards must look like `*.example.org`, got {pattern:?}"
+                );
+                HostPattern::Subdomains(suffix.to_owned())
+            }
+            None => HostPattern::Exact(pattern),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => host.eq_ignore_ascii_case(exact),
+            HostPattern::Subdomains(suffix) => {
+                host.len() > suffix.len()
+                    && host.is_char_boundary(host.len() - suffix.len())
+                    && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+            }
+        }
+    }
+}
+
+/// The parameters extracted from the path by a [Router], e.g. `id` for a route
+/// like `/users/:id`. Values are as they appear in the path: they're not
+/// percent-decoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathParams(pub Vec<(String, String)>);
+
+impl PathParams {
+    /// Returns the value of the parameter named `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Path parameters, by name
+type Params = Vec<(String, String)>;
+
+enum Match<'r> {
+    Found(&'r Rc<dyn ErasedHandler>, Params),
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Routes `method` requests whose path matches `pattern` to `handler`.
+    ///
+    /// Panics if the pattern is invalid: if it doesn't start with `/`, or has
+    /// a wildcard that isn't the last segment.
+    pub fn route<OurDriver>(&mut self, method: Method, pattern: &str, handler: OurDriver)
+    where
+        OurDriver: ServerDriver<BoxedEncoder> + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern: parse_pattern(pattern, true),
+            handler: Rc::new(handler),
+        });
+    }
+
+    /// Routes requests whose path starts with `prefix` to `router`, which
+    /// matches the rest of the path. `prefix` may have parameters, but no
+    /// wildcard. Routes of this router take precedence.
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        self.mounts.push((parse_pattern(prefix, false), router));
+    }
+
+    /// Routes requests for `host` to `router`, whatever their path. `host`
+    /// is either a name like `example.org`, or `*.example.org`, which matches
+    /// any subdomain of `example.org`. The host comes from the `host` header,
+    /// or the `:authority` pseudo-header for HTTP/2, and the port is ignored.
+    pub fn host(&mut self, host: &str, router: Router) {
+        self.hosts.push((HostPattern::parse(host), router));
+    }
+
+    /// Finds the route for `method`, falling back to the GET route for HEAD
+    /// requests: the server leaves out the body, cf. RFC 9110, section 9.3.2
+    fn find<'r>(
+        &'r self,
+        host: Option<&str>,
+        method: &Method,
+        segments: &[&str],
+        params: &[(String, String)],
+    ) -> Match<'r> {
+        match self.find_method(host, method, segments, params) {
+            Match::MethodNotAllowed(mut allowed) => {
+                if let Some(i) = allowed.iter().position(|m| *m == Method::Get) {
+                    if *method == Method::Head {
+                        return self.find_method(host, &Method::Get, segments, params);
+                    }
+                    if !allowed.contains(&Method::Head) {
+                        allowed.insert(i + 1, Method::Head);
+                    }
+                }
+                Match::MethodNotAllowed(allowed)
+            }
+            m => m,
+        }
+    }
+
+    /// Finds the route for exactly `method`
+    fn find_method<'r>(
+        &'r self,
+        host: Option<&str>,
+        method: &Method,
+        segments: &[&str],
+        params: &[(String, String)],
+    ) -> Match<'r> {
+        if let Some(host) = host {
+            if let Some((_, router)) = self.hosts.iter().find(|(p, _)| p.matches(host)) {
+                return router.find_method(None, method, segments, params);
+            }
+        }
+
+        let mut allowed: Vec<Method> = Vec::new();
+        let mut best: Option<(Vec<u8>, &Route, Params)> = None;
+        for route in &self.routes {
+            let mut route_params = params.to_vec();
+            if !match_segments(&route.pattern, segments, &mut route_params, true) {
+                continue;
+            }
+            if route.method != *method {
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method.clone());
+                }
+                continue;
+            }
+            let specificity: Vec<u8> = route.pattern.iter().map(Segment::specificity).collect();
+            if best.as_ref().map_or(true, |(s, _, _)| specificity < *s) {
+                best = Some((specificity, route, route_params));
+            }
+        }
+        if let Some((_, route, params)) = best {
+            return Match::Found(&route.handler, params);
+        }
+
+        for (prefix, router) in &self.mounts {
+            let mut mount_params = params.to_vec();
+            if !match_segments(prefix, segments, &mut mount_params, false) {
+                continue;
+            }
+            match router.find_method(host, method, &segments[prefix.len()..], &mount_params) {
+                Match::Found(handler, params) => return Match::Found(handler, params),
+                Match::MethodNotAllowed(methods) => {
+                    for m in methods {
+                        if !allowed.contains(&m) {
+                            allowed.push(m);
+                        }
+                    }
+                }
+                Match::NotFound => {}
+            }
+        }
+
+        if allowed.is_empty() {
+            Match::NotFound
+        } else {
+            Match::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+/// Parses a path pattern into segments
+fn parse_pattern(pattern: &str, allow_wildcard: bool) -> Vec<Segment> {
+    let Some(rest) = pattern.strip_prefix('/') else {
+        panic!("path patterns must start with `/`, got {pattern:?}");
+    };
+    let segments: Vec<Segment> = if rest.is_empty() {
+        vec![]
+    } else {
+        rest.split('/')
+            .map(|s| {
+                if let Some(name) = s.strip_prefix(':') {
+                    Segment::Param(name.to_owned())
+                } else if let Some(name) = s.strip_prefix('*') {
+                    Segment::Wildcard(name.to_owned())
+                } else {
+                    Segment::Static(s.to_owned())
+                }
+            })
+            .collect()
+    };
+    for (i, segment) in segments.iter().enumerate() {
+        if let Segment::Wildcard(_) = segment {
+            assert!(
+                allow_wildcard && i == segments.len() - 1,
+                "wildcards are only allowed as the last segment of a route, got {pattern:?}"
+            );
+        }
+    }
+    segments
+}
+
+/// Matches the start of `segments` against `pattern` (or all of it, if
+/// `exact` is set), adding path parameters to `params`.
+fn match_segments(
+    pattern: &[Segment],
+    segments: &[&str],
+    params: &mut Params,
+    exact: bool,
+) -> bool {
+    for (i, p) in pattern.iter().enumerate() {
+        match p {
+            Segment::Wildcard(name) => {
+                params.push((name.clone(), segments[i.min(segments.len())..].join("/")));
+                return true;
+            }
+            _ if i >= segments.len() => return false,
+            Segment::Static(s) => {
+                if s != segments[i] {
+                    return false;
+                }
+            }
+            Segment::Param(name) => {
+                if segments[i].is_empty() {
+                    return false;
+                }
+                params.push((name.clone(), segments[i].to_owned()));
+            }
+        }
+    }
+    !exact || pattern.len() == segments.len()
+}
+
+/// Splits a request path into segments, `/` having none
+fn path_segments(path: &str) -> Vec<&str> {
+    match path.strip_prefix('/') {
+        Some("") | None => vec![],
+        Some(rest) => rest.split('/').collect(),
+    }
+}
+
+/// Returns the host the request is for, without the port
+fn request_host(req: &Request) -> Option<&str> {
+    let host = match req.headers.get(header::HOST) {
+        Some(host) => std::str::from_utf8(&host[..]).ok()?,
+        None => req.uri.authority()?.as_str(),
+    };
+    if host.starts_with('[') {
+        // IPv6 literal, e.g. `[::1]:8080`
+        return host.split_inclusive(']').next();
+    }
+    Some(host.split(':').next().unwrap_or(host))
+}
+
+impl<OurEncoder> ServerDriver<OurEncoder> for Router
+where
+    OurEncoder: Encoder + 'static,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        mut req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> Result<Responder<OurEncoder, ResponseDone>, BX> {
+        let path = req.uri.path().to_owned();
+        let segments = path_segments(&path);
+
+        let status = match self.find(request_host(&req), &req.method, &segments, &[]) {
+            Match::Found(handler, params) => {
+                req.extensions.insert(PathParams(params));
+                let respond = respond.map_encoder(|e| BoxedEncoder(Box::new(e)));
+                let respond = handler.handle(req, BoxedBody(req_body), respond).await?;
+                let encoder = respond
+                    .into_inner()
+                    .0
+                    .into_any()
+                    .downcast::<OurEncoder>()
+                    .expect("handlers can't swap encoders");
+                return Ok(Responder::done(*encoder));
+            }
+            Match::MethodNotAllowed(methods) => {
+                let allow = methods
+                    .iter()
+                    .map(|m| m.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let mut res = Response {
+                    status: StatusCode::METHOD_NOT_ALLOWED,
+                    version: req.version,
+                    ..Default::default()
+                };
+                res.headers.insert(header::ALLOW, allow.into_bytes().into());
+                res
+            }
+            Match::NotFound => Response {
+                status: StatusCode::NOT_FOUND,
+                version: req.version,
+                ..Default::default()
+            },
+        };
+        respond
+            .write_final_response_with_body(status, &mut ())
+            .await
+            .map_err(BX::from_err)
+    }
+}
+
+/// Type-erased [Encoder], which is what [Router] route drivers write to
+pub struct BoxedEncoder(Box<dyn ErasedEncoder>);
+
+trait ErasedEncoder {
+    fn write_response(&mut self, res: Response) -> LocalBoxFuture<'_, Result<(), BX>>;
+    fn write_body_chunk(&mut self, chunk: Piece) -> LocalBoxFuture<'_, Result<(), BX>>;
+    fn write_body_end(&mut self) -> LocalBoxFuture<'_, Result<(), BX>>;
+    fn write_trailers(&mut self, trailers: Box<Headers>) -> LocalBoxFuture<'_, Result<(), BX>>;
+    fn omit_body(&mut self);
+    fn can_write_trailers(&self) -> bool;
+    fn can_write_body_file(&self) -> bool;
+    fn write_body_file(&mut self, region: FileRegion) -> LocalBoxFuture<'_, Result<(), BX>>;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<OurEncoder> ErasedEncoder for OurEncoder
+where
+    OurEncoder: Encoder + 'static,
+{
+    fn write_response(&mut self, res: Response) -> LocalBoxFuture<'_, Result<(), BX>> {
+        Box::pin(async move {
+            Encoder::write_response(self, res)
+                .await
+                .map_err(BX::from_err)
+        })
+    }
+
+    fn write_body_chunk(&mut self, chunk: Piece) -> LocalBoxFuture<'_, Result<(), BX>> {
+        Box::pin(async move {
+            Encoder::write_body_chunk(self, chunk)
+                .await
+                .map_err(BX::from_err)
+        })
+    }
+
+    fn write_body_end(&mut self) -> LocalBoxFuture<'_, Result<(), BX>> {
+        Box::pin(async move { Encoder::write_body_end(self).await.map_err(BX::from_err) })
+    }
+
+    fn write_trailers(&mut self, trailers: Box<Headers>) -> LocalBoxFuture<'_, Result<(), BX>> {
+        Box::pin(async move {
+            Encoder::write_trailers(self, trailers)
+                .await
+                .map_err(BX::from_err)
+        })
+    }
+
+    fn omit_body(&mut self) {
+        Encoder::omit_body(self)
+    }
+
+    fn can_write_trailers(&self) -> bool {
+        Encoder::can_write_trailers(self)
+    }
+
+    fn can_write_body_file(&self) -> bool {
+        Encoder::can_write_body_file(self)
+    }
+
+    fn write_body_file(&mut self, region: FileRegion) -> LocalBoxFuture<'_, Result<(), BX>> {
+        Box::pin(async move {
+            Encoder::write_body_file(self, region)
+                .await
+                .map_err(BX::from_err)
+        })
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Encoder for BoxedEncoder {
+    type Error = BX;
+
+    async fn write_response(&mut self, res: Response) -> Result<(), BX> {
+        self.0.write_response(res).await
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), BX> {
+        self.0.write_body_chunk(chunk).await
+    }
+
+    async fn write_body_end(&mut self) -> Result<(), BX> {
+        self.0.write_body_end().await
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), BX> {
+        self.0.write_trailers(trailers).await
+    }
+
+    fn omit_body(&mut self) {
+        self.0.omit_body()
+    }
+
+    fn can_write_trailers(&self) -> bool {
+        self.0.can_write_trailers()
+    }
+
+    fn can_write_body_file(&self) -> bool {
+        self.0.can_write_body_file()
+    }
+
+    async fn write_body_file(&mut self, region: FileRegion) -> Result<(), BX> {
+        self.0.write_body_file(region).await
+    }
+}
+
+/// Type-erased [Body], which is what [Router] route drivers read requests
+/// bodies from
+pub struct BoxedBody<'a>(&'a mut dyn ErasedBody);
+
+impl fmt::Debug for BoxedBody<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoxedBody").finish_non_exhaustive()
+    }
+}
+
+trait ErasedBody {
+    fn content_len(&self) -> Option<u64>;
+    fn eof(&self) -> bool;
+    fn next_chunk(&mut self) -> LocalBoxFuture<'_, Result<BodyChunk, BX>>;
+    fn take_file(&mut self) -> Option<FileRegion>;
+}
+
+impl<OurBody> ErasedBody for OurBody
+where
+    OurBody: Body,
+{
+    fn content_len(&self) -> Option<u64> {
+        Body::content_len(self)
+    }
+
+    fn eof(&self) -> bool {
+        Body::eof(self)
+    }
+
+    fn next_chunk(&mut self) -> LocalBoxFuture<'_, Result<BodyChunk, BX>> {
+        Box::pin(async move { Body::next_chunk(self).await.map_err(BX::from_err) })
+    }
+
+    fn take_file(&mut self) -> Option<FileRegion> {
+        Body::take_file(self)
+    }
+}
+
+impl Body for BoxedBody<'_> {
+    type Error = BX;
+
+    fn content_len(&self) -> Option<u64> {
+        self.0.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.0.eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, BX> {
+        self.0.next_chunk().await
+    }
+
+    fn take_file(&mut self) -> Option<FileRegion> {
+        self.0.take_file()
+    }
+}
+
+/// Object-safe version of [ServerDriver] for [BoxedEncoder]
+trait ErasedHandler {
+    fn handle<'a>(
+        &'a self,
+        req: Request,
+        req_body: BoxedBody<'a>,
+        respond: Responder<BoxedEncoder, ExpectResponseHeaders>,
+    ) -> LocalBoxFuture<'a, Result<Responder<BoxedEncoder, ResponseDone>, BX>>;
+}
+
+impl<OurDriver> ErasedHandler for OurDriver
+where
+    OurDriver: ServerDriver<BoxedEncoder>,
+{
+    fn handle<'a>(
+        &'a self,
+        req: Request,
+        mut req_body: BoxedBody<'a>,
+        respond: Responder<BoxedEncoder, ExpectResponseHeaders>,
+    ) -> LocalBoxFuture<'a, Result<Responder<BoxedEncoder, ResponseDone>, BX>> {
+        Box::pin(async move {
+            ServerDriver::handle(self, req, &mut req_body, respond)
+                .await
+                .map_err(BX::from_err)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Routes are told apart by their pattern, so this is never called
+    struct Noop;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for Noop
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            _respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> Result<Responder<OurEncoder, ResponseDone>, BX> {
+            unreachable!()
+        }
+    }
+
+    fn pattern_string(pattern: &[Segment]) -> String {
+        if pattern.is_empty() {
+            return "/".to_owned();
+        }
+        pattern
+            .iter()
+            .map(|segment| match segment {
+                Segment::Static(s) => format!("/{s}"),
+                Segment::Param(name) => format!("/:{name}"),
+                Segment::Wildcard(name) => format!("/*{name}"),
+            })
+            .collect()
+    }
+
+    /// Returns the method and pattern of the route `handler` belongs to
+    fn route_of(router: &Router, handler: &Rc<dyn ErasedHandler>) -> Option<String> {
+        for route in &router.routes {
+            if std::ptr::eq(&route.handler, handler) {
+                return Some(format!(
+                    "{} {}",
+                    route.method,
+                    pattern_string(&route.pattern)
+                ));
+            }
+        }
+        let mounted = router.mounts.iter().map(|(_, r)| r);
+        mounted
+            .chain(router.hosts.iter().map(|(_, r)| r))
+            .find_map(|router| route_of(router, handler))
+    }
+
+    /// Describes what `router` does with a request, e.g. `GET /users/:id
+    /// id=42`, `404`, or `405 GET, DELETE`
+    fn resolve(router: &Router, host: Option<&str>, method: Method, path: &str) -> String {
+        match router.find(host, &method, &path_segments(path), &[]) {
+            Match::Found(handler, params) => {
+                let mut found = route_of(router, handler).unwrap();
+                for (k, v) in params {
+                    found.push_str(&format!(" {k}={v}"));
+                }
+                found
+            }
+            Match::MethodNotAllowed(methods) => {
+                let methods: Vec<_> = methods.iter().map(|m| m.to_string()).collect();
+                format!("405 {}", methods.join(", "))
+            }
+            Match::NotFound => "404".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let mut api = Router::new();
+        api.route(Method::Get, "/items/:id", Noop);
+        api.route(Method::Put, "/items/:id", Noop);
+
+        let mut subdomains = Router::new();
+        subdomains.route(Method::Get, "/", Noop);
+
+        let mut router = Router::new();
+        router.route(Method::Get, "/", Noop);
+        router.route(Method::Get, "/users", Noop);
+        router.route(Method::Head, "/users", Noop);
+        router.route(Method::Get, "/users/:id", Noop);
+        router.route(Method::Delete, "/users/:id", Noop);
+        router.route(Method::Get, "/users/me", Noop);
+        router.route(Method::Get, "/static/*path", Noop);
+        router.route(Method::Post, "/api/v2/items/:id", Noop);
+        router.mount("/api/:version", api);
+        router.host("*.example.org", subdomains);
+
+        for (host, method, path, expected) in [
+            (None, Method::Get, "/", "GET /"),
+            (None, Method::Get, "/users", "GET /users"),
+            (None, Method::Get, "/users/42", "GET /users/:id id=42"),
+            (None, Method::Delete, "/users/42", "DELETE /users/:id id=42"),
+            // static segments beat parameters
+            (None, Method::Get, "/users/me", "GET /users/me"),
+            (None, Method::Get, "/users/", "404"),
+            (
+                None,
+                Method::Get,
+                "/static/css/site.css",
+                "GET /static/*path path=css/site.css",
+            ),
+            (None, Method::Get, "/static", "GET /static/*path path="),
+            (
+                None,
+                Method::Get,
+                "/api/v1/items/7",
+                "GET /items/:id version=v1 id=7",
+            ),
+            (None, Method::Get, "/api/v1/nope", "404"),
+            (None, Method::Get, "/nope", "404"),
+            (None, Method::Get, "/users/42/nope", "404"),
+            (None, Method::Post, "/users/42", "405 GET, HEAD, DELETE"),
+            // GET routes serve HEAD requests too, across mounts...
+            (None, Method::Head, "/users/42", "GET /users/:id id=42"),
+            (
+                None,
+                Method::Head,
+                "/api/v2/items/7",
+                "GET /items/:id version=v2 id=7",
+            ),
+            (None, Method::Head, "/nope", "404"),
+            // ...unless there's a HEAD route
+            (None, Method::Head, "/users", "HEAD /users"),
+            (None, Method::Post, "/users", "405 GET, HEAD"),
+            // allowed methods are gathered across mounts
+            (
+                None,
+                Method::Delete,
+                "/api/v2/items/7",
+                "405 POST, GET, HEAD, PUT",
+            ),
+            (Some("www.example.org"), Method::Get, "/", "GET /"),
+            (Some("WWW.Example.org"), Method::Get, "/", "GET /"),
+            (Some("example.org"), Method::Get, "/users", "GET /users"),
+            (Some("www.example.org"), Method::Get, "/users", "404"),
+        ] {
+            assert_eq!(
+                resolve(&router, host, method.clone(), path),
+                expected,
+                "{host:?} {method} {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_request_host() {
+        for (uri, host_header, expected) in [
+            ("http://example.org:8080/", None, Some("example.org")),
+            ("/", Some("example.org:8080"), Some("example.org")),
+            ("http://ignored/", Some("example.org"), Some("example.org")),
+            ("/", Some("[::1]:8080"), Some("[::1]")),
+            ("/", None, None),
+        ] {
+            let mut req = Request {
+                uri: uri.parse().unwrap(),
+                ..Default::default()
+            };
+            if let Some(host) = host_header {
+                req.headers.insert(header::HOST, host.into());
+            }
+            assert_eq!(request_host(&req), expected, "{uri} {host_header:?}");
+        }
+    }
+}
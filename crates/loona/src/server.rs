@@ -0,0 +1,458 @@
+//! Accepting connections and serving them over HTTP/1.1 or HTTP/2
+
+use std::{cell::Cell, net::SocketAddr, rc::Rc, time::Duration};
+
+use buffet::{
+    net::{TcpListener, TcpStream, TcpWriteHalf},
+    IntoHalves, ReadOwned, RollMut, WriteOwned,
+};
+use loona_h2::{Frame, FrameType, KnownErrorCode, StreamId};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::{
+    h1::{self, encode::H1Encoder},
+    h2::{self, H2Encoder},
+    ConnectionInfo, ServerDriver,
+};
+
+/// How long the accept loop waits after its first error, doubling with each
+/// consecutive one
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+
+/// How long the accept loop waits at most after an error
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// How long we spend letting a client know we're refusing its connection
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many connections we refuse at once, cf. [refuse_connection]. Past
+/// that, new connections are closed right away.
+const MAX_REFUSALS: usize = 64;
+
+/// A signal that can be triggered once, and that any number of tasks can wait
+/// on. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct ShutdownSignal(Rc<ShutdownState>);
+
+#[derive(Default)]
+struct ShutdownState {
+    triggered: Cell<bool>,
+    notify: Notify,
+}
+
+impl ShutdownSignal {
+    /// Wakes up everyone waiting on this signal, now and in the future
+    pub fn trigger(&self) {
+        self.0.triggered.set(true);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.triggered.get()
+    }
+
+    /// Returns once the signal is triggered
+    pub async fn wait(&self) {
+        loop {
+            // register before checking, so we can't miss a notification
+            let notified = self.0.notify.notified();
+            if self.is_triggered() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Which protocol to speak on a listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// HTTP/1.1 only
+    H1,
+
+    /// HTTP/2 only, with prior knowledge (no upgrade, no ALPN)
+    H2,
+
+    /// HTTP/2 if the client starts with the HTTP/2 connection preface,
+    /// HTTP/1.1 otherwise
+    Auto,
+}
+
+pub struct ServerConf {
+    /// Max number of connections served at once. Connections accepted past
+    /// that are closed immediately, after a 503 (or, for HTTP/2, a GOAWAY
+    /// with REFUSED_STREAM).
+    pub max_connections: usize,
+
+    /// How long to wait for connections to finish once shutdown was requested,
+    /// before dropping them
+    pub shutdown_timeout: Duration,
+
+    /// Configuration for HTTP/1.1 connections (its shutdown signal is
+    /// replaced by the server's)
+    pub h1: h1::ServerConf,
+
+    /// Configuration for HTTP/2 connections (its shutdown signal is replaced
+    /// by the server's)
+    pub h2: h2::ServerConf,
+}
+
+impl Default for ServerConf {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            shutdown_timeout: Duration::from_secs(30),
+            h1: Default::default(),
+            h2: Default::default(),
+        }
+    }
+}
+
+/// Lets you query a [Server], and shut it down, while it runs.
+#[derive(Clone)]
+pub struct ServerHandle {
+    shutdown: ShutdownSignal,
+    live: Rc<LiveConnections>,
+    refusing: Rc<LiveConnections>,
+}
+
+impl ServerHandle {
+    /// Stops accepting connections, and asks live connections to finish what
+    /// they're doing and close.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Returns the number of connections being served right now
+    pub fn live_connections(&self) -> usize {
+        self.live.count.get()
+    }
+}
+
+#[derive(Default)]
+struct LiveConnections {
+    count: Cell<usize>,
+    none_left: Notify,
+}
+
+impl LiveConnections {
+    fn track(self: &Rc<Self>) -> LiveConnectionGuard {
+        self.count.set(self.count.get() + 1);
+        LiveConnectionGuard(self.clone())
+    }
+
+    async fn wait_until_none_left(&self) {
+        loop {
+            let notified = self.none_left.notified();
+            if self.count.get() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+struct LiveConnectionGuard(Rc<LiveConnections>);
+
+impl Drop for LiveConnectionGuard {
+    fn drop(&mut self) {
+        let count = self.0.count.get() - 1;
+        self.0.count.set(count);
+        if count == 0 {
+            self.0.none_left.notify_waiters();
+        }
+    }
+}
+
+/// Accepts connections on any number of listeners, and serves them with
+/// drivers built by `make_driver`, which is passed the peer's address.
+pub struct Server<MakeDriver> {
+    max_connections: usize,
+    shutdown_timeout: Duration,
+    h1_conf: Rc<h1::ServerConf>,
+    h2_conf: Rc<h2::ServerConf>,
+    listeners: Vec<(TcpListener, Protocol)>,
+    make_driver: Rc<MakeDriver>,
+    handle: ServerHandle,
+}
+
+impl<MakeDriver, OurDriver> Server<MakeDriver>
+where
+    MakeDriver: Fn(SocketAddr) -> OurDriver + 'static,
+    OurDriver: ServerDriver<H1Encoder<TcpWriteHalf>> + ServerDriver<H2Encoder> + 'static,
+{
+    pub fn new(mut conf: ServerConf, make_driver: MakeDriver) -> Self {
+        let shutdown = ShutdownSignal::default();
+        conf.h1.shutdown = shutdown.clone();
+        conf.h2.shutdown = shutdown.clone();
+
+        Self {
+            max_connections: conf.max_connections,
+            shutdown_timeout: conf.shutdown_timeout,
+            h1_conf: Rc::new(conf.h1),
+            h2_conf: Rc::new(conf.h2),
+            listeners: Default::default(),
+            make_driver: Rc::new(make_driver),
+            handle: ServerHandle {
+                shutdown,
+                live: Default::default(),
+                refusing: Default::default(),
+            },
+        }
+    }
+
+    /// Serves connections accepted on `ln` once the server runs
+    pub fn add_listener(&mut self, ln: TcpListener, protocol: Protocol) {
+        self.listeners.push((ln, protocol));
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Accepts and serves connections until [ServerHandle::shutdown] is called,
+    /// then waits for live connections to finish, up to the shutdown timeout.
+    pub async fn run(self) {
+        let Self {
+            max_connections,
+            shutdown_timeout,
+            h1_conf,
+            h2_conf,
+            listeners,
+            make_driver,
+            handle,
+        } = self;
+
+        // connections still alive past the shutdown timeout are dropped
+        let force_stop = ShutdownSignal::default();
+
+        let accept_loops = listeners.into_iter().map(|(ln, protocol)| {
+            let h1_conf = h1_conf.clone();
+            let h2_conf = h2_conf.clone();
+            let make_driver = make_driver.clone();
+            let handle = handle.clone();
+            let force_stop = force_stop.clone();
+
+            async move {
+                let mut backoff = Duration::ZERO;
+                loop {
+                    let (stream, addr) = tokio::select! {
+                        res = ln.accept() => match res {
+                            Ok(t) => t,
+                            Err(e) => {
+                                // errors like EMFILE don't go away on their
+                                // own: give connections some time to close
+                                backoff = (backoff * 2).clamp(ACCEPT_BACKOFF_MIN, ACCEPT_BACKOFF_MAX);
+                                warn!(?backoff, "error accepting connection: {e}");
+                                tokio::select! {
+                                    _ = tokio::time::sleep(backoff) => continue,
+                                    _ = handle.shutdown.wait() => break,
+                                }
+                            }
+                        },
+                        _ = handle.shutdown.wait() => break,
+                    };
+                    backoff = Duration::ZERO;
+
+                    if handle.live_connections() >= max_connections {
+                        if handle.refusing.count.get() >= MAX_REFUSALS {
+                            debug!(%addr, "too many connections being refused, closing new one");
+                            continue;
+                        }
+                        debug!(%addr, %max_connections, "too many connections, refusing new one");
+                        let guard = handle.refusing.track();
+                        let force_stop = force_stop.clone();
+                        buffet::spawn(async move {
+                            let _guard = guard;
+                            tokio::select! {
+                                _ = refuse_connection(stream, protocol) => {}
+                                _ = force_stop.wait() => {}
+                            }
+                        });
+                        continue;
+                    }
+
+                    let guard = handle.live.track();
+                    let driver = make_driver(addr);
+                    let mut conn = ConnectionInfo::new();
+                    conn.peer_addr = Some(addr);
+                    conn.local_addr = stream.local_addr().ok();
+                    let h1_conf = h1_conf.clone();
+                    let h2_conf = h2_conf.clone();
+                    let force_stop = force_stop.clone();
+                    buffet::spawn(async move {
+                        let _guard = guard;
+                        tokio::select! {
+                            _ = serve_connection(stream, protocol, h1_conf, h2_conf, driver, conn) => {}
+                            _ = force_stop.wait() => {
+                                debug!(%addr, "shutdown timeout elapsed, dropping connection");
+                            }
+                        }
+                    });
+                }
+            }
+        });
+        futures_util::future::join_all(accept_loops).await;
+
+        debug!(live_connections = %handle.live_connections(), "stopped accepting connections");
+        // no new connections get tracked past this point
+        let none_left = || async {
+            handle.live.wait_until_none_left().await;
+            handle.refusing.wait_until_none_left().await;
+        };
+        if tokio::time::timeout(shutdown_timeout, none_left())
+            .await
+            .is_err()
+        {
+            force_stop.trigger();
+            none_left().await;
+        }
+        debug!("server shut down");
+    }
+}
+
+/// Lets a client we're not going to serve know it's because we're overloaded,
+/// rather than have it guess from the connection closing.
+async fn refuse_connection(stream: TcpStream, protocol: Protocol) {
+    let (mut transport_r, mut transport_w) = stream.into_halves();
+
+    let res = match protocol {
+        // the server preface, then a GOAWAY for all streams
+        Protocol::H2 => {
+            let mut buf = Vec::new();
+            Frame::new(
+                FrameType::Settings(Default::default()),
+                StreamId::CONNECTION,
+            )
+            .write_into(&mut buf)
+            .unwrap();
+            Frame::new(FrameType::GoAway, StreamId::CONNECTION)
+                .with_len(8)
+                .write_into(&mut buf)
+                .unwrap();
+            buf.extend_from_slice(&0u32.to_be_bytes());
+            buf.extend_from_slice(&KnownErrorCode::RefusedStream.repr().to_be_bytes());
+            buf
+        }
+        Protocol::H1 | Protocol::Auto => {
+            b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                .to_vec()
+        }
+    };
+
+    let refuse = async {
+        transport_w.write_all_owned(res).await?;
+        transport_w.shutdown().await?;
+
+        // closing with unread data would reset the connection, and the
+        // client might not get to see the response
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = transport_r.read_owned(buf).await;
+            if res? == 0 {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    match tokio::time::timeout(REFUSE_TIMEOUT, refuse).await {
+        Ok(Err(e)) => debug!("error while refusing connection: {e}"),
+        Err(_) => debug!("timed out refusing connection"),
+        Ok(Ok(())) => {}
+    }
+}
+
+async fn serve_connection<OurDriver>(
+    stream: TcpStream,
+    protocol: Protocol,
+    h1_conf: Rc<h1::ServerConf>,
+    h2_conf: Rc<h2::ServerConf>,
+    driver: OurDriver,
+    conn: ConnectionInfo,
+) where
+    OurDriver: ServerDriver<H1Encoder<TcpWriteHalf>> + ServerDriver<H2Encoder> + 'static,
+{
+    let mut client_buf = match RollMut::alloc() {
+        Ok(buf) => buf,
+        Err(e) => {
+            warn!("could not allocate buffer for connection: {e}");
+            return;
+        }
+    };
+    let (mut transport_r, transport_w) = stream.into_halves();
+
+    let is_h2 = match protocol {
+        Protocol::H1 => false,
+        Protocol::H2 => true,
+        Protocol::Auto => {
+            let is_h2;
+            (client_buf, is_h2) = match sniff_h2_preface(&mut transport_r, client_buf).await {
+                Ok(t) => t,
+                Err(e) => {
+                    debug!("error while sniffing protocol: {e}");
+                    return;
+                }
+            };
+            is_h2
+        }
+    };
+
+    if is_h2 {
+        let res = h2::serve(
+            (transport_r, transport_w),
+            h2_conf,
+            client_buf,
+            Rc::new(driver),
+            conn,
+        )
+        .await;
+        match res {
+            Ok(outcome) => debug!(?outcome, "http/2 connection done"),
+            Err(e) => warn!("http/2 server error: {e}"),
+        }
+    } else {
+        match h1::serve(
+            (transport_r, transport_w),
+            h1_conf,
+            client_buf,
+            driver,
+            conn,
+        )
+        .await
+        {
+            Ok(outcome) => debug!(?outcome, "http/1 connection done"),
+            Err(e) => warn!("http/1 server error: {e}"),
+        }
+    }
+}
+
+/// Reads just enough to tell whether the client started with the HTTP/2
+/// connection preface. What was read is left in the returned buffer.
+async fn sniff_h2_preface(
+    transport_r: &mut impl ReadOwned,
+    mut buf: RollMut,
+) -> std::io::Result<(RollMut, bool)> {
+    let preface = loona_h2::PREFACE;
+    loop {
+        let n = buf.len().min(preface.len());
+        if buf[..n] != preface[..n] {
+            return Ok((buf, false));
+        }
+        if n == preface.len() {
+            return Ok((buf, true));
+        }
+
+        if buf.cap() == 0 {
+            buf.reserve().map_err(std::io::Error::other)?;
+        }
+        let res;
+        let read_limit = preface.len() - buf.len();
+        (res, buf) = buf.read_into(read_limit, transport_r).await;
+        if res? == 0 {
+            // let the HTTP/1.1 server deal with that
+            return Ok((buf, false));
+        }
+    }
+}
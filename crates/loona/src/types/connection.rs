@@ -0,0 +1,62 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What we know about the connection a request was received on. It's passed
+/// to [crate::h1::serve] and [crate::h2::serve], and handlers can get it from
+/// [crate::Request::connection_info].
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Unique within the process, assigned by [ConnectionInfo::new]
+    pub id: u64,
+
+    /// The address of the peer (client, or proxy in front of us)
+    pub peer_addr: Option<SocketAddr>,
+
+    /// The address the peer connected to
+    pub local_addr: Option<SocketAddr>,
+
+    /// Set if the connection is secured with TLS
+    pub tls: Option<TlsInfo>,
+}
+
+impl ConnectionInfo {
+    /// Assigns a fresh connection id, without any addresses or TLS info
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            peer_addr: None,
+            local_addr: None,
+            tls: None,
+        }
+    }
+}
+
+impl Default for ConnectionInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    /// The protocol negotiated with ALPN, e.g. `h2`
+    pub alpn_protocol: Option<Vec<u8>>,
+
+    /// The server name the client asked for with SNI
+    pub server_name: Option<String>,
+}
+
+/// Which stream of its connection a request was received on. Handlers can get
+/// it from [crate::Request::stream_info].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamInfo {
+    /// HTTP/1.1 requests are numbered from 0, in the order they were received
+    H1 { request_index: u64 },
+
+    /// HTTP/2 requests each have their own stream
+    H2 { stream_id: u32 },
+}
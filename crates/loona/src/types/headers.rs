@@ -1,23 +1,64 @@
 //! Types for HTTP headers
 
-use http::{header, HeaderMap};
+use std::borrow::Cow;
+
+use http::{header, HeaderMap, HeaderName};
 
 use buffet::Piece;
 
+use crate::util::trim_ows;
+
 pub type Headers = HeaderMap<Piece>;
 
 pub trait HeadersExt {
     /// Returns the content-length header
     fn content_length(&self) -> Option<u64>;
 
-    /// Returns true if we have a `connection: close` header
+    /// Returns true if the `connection` header lists `close`
     fn is_connection_close(&self) -> bool;
 
-    /// Returns true if we have a `transfer-encoding: chunked` header
+    /// Returns true if `chunked` is the final transfer coding, i.e. the body
+    /// is delimited by chunked framing, cf. RFC 9112, section 6.3
     fn is_chunked_transfer_encoding(&self) -> bool;
 
     /// Returns true if the client expects a `100-continue` response
     fn expects_100_continue(&self) -> bool;
+
+    /// Returns true if the client sent `te: trailers`, i.e. it's willing to
+    /// accept trailer fields in the response
+    fn accepts_trailers(&self) -> bool;
+
+    /// Returns the field names listed in the `trailer` header(s), i.e. the
+    /// trailers the sender announced
+    fn announced_trailers(&self) -> Vec<HeaderName>;
+
+    /// Returns the elements of a list-based field, across all of its lines,
+    /// cf. [list_elements]
+    fn list(&self, name: HeaderName) -> impl Iterator<Item = &[u8]>;
+
+    /// Returns true if a list-based field has an element that is `token`,
+    /// compared case-insensitively and ignoring parameters
+    fn has_token(&self, name: HeaderName, token: &str) -> bool;
+
+    /// Returns the elements of an `accept`-style field (`accept`,
+    /// `accept-encoding`, `accept-language`...), without their parameters,
+    /// along with their weight in thousandths, cf. RFC 9110, section 12.4.2.
+    /// Elements with a malformed weight are skipped.
+    fn weighted_list(&self, name: HeaderName) -> impl Iterator<Item = (&[u8], u16)>;
+
+    /// Returns the `cache-control` directives, along with their argument if
+    /// they have one, cf. RFC 9111, section 5.2
+    fn cache_directives(&self) -> impl Iterator<Item = (&[u8], Option<Cow<'_, [u8]>>)>;
+
+    /// Returns the `content-type` header, if it's a valid media type
+    fn content_type(&self) -> Option<MediaType<'_>>;
+
+    /// Returns the name and value of every cookie in the `cookie` header(s),
+    /// cf. RFC 6265, section 5.4
+    fn cookies(&self) -> impl Iterator<Item = (&[u8], &[u8])>;
+
+    /// Checks that every value can be sent as-is, cf. [is_valid_field_value]
+    fn validate_values(&self) -> Result<(), InvalidHeaderValue>;
 }
 
 impl HeadersExt for HeaderMap<Piece> {
@@ -28,22 +69,304 @@ impl HeadersExt for HeaderMap<Piece> {
     }
 
     fn is_connection_close(&self) -> bool {
-        self.get(header::CONNECTION)
-            .map_or(false, |value| value.eq_ignore_ascii_case(b"close"))
+        self.has_token(header::CONNECTION, "close")
     }
 
     fn is_chunked_transfer_encoding(&self) -> bool {
-        self.get(header::TRANSFER_ENCODING)
-            .map_or(false, |value| value.eq_ignore_ascii_case(b"chunked"))
+        self.list(header::TRANSFER_ENCODING)
+            .last()
+            .is_some_and(|coding| split_params(coding).0.eq_ignore_ascii_case(b"chunked"))
     }
 
     fn expects_100_continue(&self) -> bool {
-        self.get(header::EXPECT)
-            .map_or(false, |value| value.eq_ignore_ascii_case(b"100-continue"))
+        self.has_token(header::EXPECT, "100-continue")
+    }
+
+    fn accepts_trailers(&self) -> bool {
+        // `trailers` doesn't take parameters, but be lenient
+        self.has_token(header::TE, "trailers")
+    }
+
+    fn announced_trailers(&self) -> Vec<HeaderName> {
+        self.list(header::TRAILER)
+            .filter_map(|name| HeaderName::from_bytes(name).ok())
+            .collect()
+    }
+
+    fn list(&self, name: HeaderName) -> impl Iterator<Item = &[u8]> {
+        self.get_all(name)
+            .into_iter()
+            .flat_map(|value| list_elements(value))
+    }
+
+    fn has_token(&self, name: HeaderName, token: &str) -> bool {
+        self.list(name).any(|element| {
+            split_params(element)
+                .0
+                .eq_ignore_ascii_case(token.as_bytes())
+        })
+    }
+
+    fn weighted_list(&self, name: HeaderName) -> impl Iterator<Item = (&[u8], u16)> {
+        self.list(name).filter_map(|element| {
+            let (value, params) = split_params(element);
+            let weight = match params.get("q") {
+                Some(q) => parse_qvalue(&q)?,
+                None => 1000,
+            };
+            Some((value, weight))
+        })
     }
+
+    fn cache_directives(&self) -> impl Iterator<Item = (&[u8], Option<Cow<'_, [u8]>>)> {
+        self.list(header::CACHE_CONTROL)
+            .map(|directive| match memchr::memchr(b'=', directive) {
+                Some(eq) => (
+                    trim_ows(&directive[..eq]),
+                    Some(unquote(trim_ows(&directive[eq + 1..]))),
+                ),
+                None => (directive, None),
+            })
+    }
+
+    fn content_type(&self) -> Option<MediaType<'_>> {
+        self.get(header::CONTENT_TYPE)
+            .and_then(|value| MediaType::parse(value))
+    }
+
+    fn cookies(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        // cookie values can't contain `;`, quoted or not
+        self.get_all(header::COOKIE)
+            .into_iter()
+            .flat_map(|value| value.split(|&b| b == b';'))
+            .filter_map(|pair| {
+                let eq = memchr::memchr(b'=', pair)?;
+                let name = trim_ows(&pair[..eq]);
+                (!name.is_empty()).then(|| (name, trim_ows(&pair[eq + 1..])))
+            })
+    }
+
+    fn validate_values(&self) -> Result<(), InvalidHeaderValue> {
+        match self.iter().find(|(_, value)| !is_valid_field_value(value)) {
+            Some((name, _)) => Err(InvalidHeaderValue { name: name.clone() }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A header value that can't be sent, cf. [is_valid_field_value]
+#[derive(Debug, thiserror::Error)]
+#[error("invalid value for header {name}: CR, LF and NUL aren't allowed, nor leading or trailing whitespace")]
+pub struct InvalidHeaderValue {
+    pub name: HeaderName,
+}
+
+/// Returns true if `value` can be sent as a field value, cf. RFC 9110,
+/// section 5.5. Unlike [http::HeaderValue], [Piece] doesn't check this: a CR
+/// or LF would let whoever controls the value add header lines (or a whole
+/// response) of their own over HTTP/1.1.
+pub fn is_valid_field_value(value: &[u8]) -> bool {
+    let is_whitespace = |b: &u8| matches!(b, b' ' | b'\t');
+    !value.first().is_some_and(is_whitespace)
+        && !value.last().is_some_and(is_whitespace)
+        && memchr::memchr3(b'\r', b'\n', b'\0', value).is_none()
+}
+
+/// Returns the elements of a comma-separated list, as used by most fields,
+/// cf. RFC 9110, section 5.6.1. Elements are trimmed, empty ones are skipped,
+/// and commas within quoted strings don't split anything.
+pub fn list_elements(value: &[u8]) -> ListElements<'_> {
+    ListElements(Split::new(value, b','))
 }
 
-fn from_digits(bytes: &[u8]) -> Option<u64> {
+/// Cf. [list_elements]
+#[derive(Debug, Clone)]
+pub struct ListElements<'a>(Split<'a>);
+
+impl<'a> Iterator for ListElements<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+/// Splits something like `text/html; charset="utf-8"` into what comes before
+/// the first semicolon, and the parameters after it, cf. RFC 9110, section
+/// 5.6.6
+pub fn split_params(value: &[u8]) -> (&[u8], Params<'_>) {
+    let end = find_unquoted(value, b';');
+    let params = value.get(end + 1..).unwrap_or_default();
+    (trim_ows(&value[..end]), Params(Split::new(params, b';')))
+}
+
+/// The parameters of a list element or of a media type, as `(name, value)`
+/// pairs. Quoted values are unquoted, which only allocates if they contain
+/// escapes.
+#[derive(Debug, Clone)]
+pub struct Params<'a>(Split<'a>);
+
+impl<'a> Params<'a> {
+    /// Returns the value of the first parameter named `name`, compared
+    /// case-insensitively
+    pub fn get(self, name: &str) -> Option<Cow<'a, [u8]>> {
+        let mut params = self;
+        params
+            .find(|(param, _)| param.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, value)| value)
+    }
+}
+
+impl<'a> Iterator for Params<'a> {
+    type Item = (&'a [u8], Cow<'a, [u8]>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let param = self.0.next()?;
+        Some(match memchr::memchr(b'=', param) {
+            Some(eq) => (trim_ows(&param[..eq]), unquote(trim_ows(&param[eq + 1..]))),
+            None => (param, Cow::Borrowed(&[][..])),
+        })
+    }
+}
+
+/// Returns the contents of a quoted string, cf. RFC 9110, section 5.6.4, or
+/// `value` itself if it isn't one.
+pub fn unquote(value: &[u8]) -> Cow<'_, [u8]> {
+    let inner = match value {
+        [b'"', inner @ .., b'"'] => inner,
+        _ => return Cow::Borrowed(value),
+    };
+    if memchr::memchr(b'\\', inner).is_none() {
+        return Cow::Borrowed(inner);
+    }
+
+    let mut unescaped = Vec::with_capacity(inner.len());
+    let mut bytes = inner.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'\\' => unescaped.extend(bytes.next()),
+            _ => unescaped.push(b),
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+/// A media type, like `text/html; charset=utf-8`, cf. RFC 9110, section
+/// 8.3.1
+#[derive(Debug, Clone, Copy)]
+pub struct MediaType<'a> {
+    pub ty: &'a [u8],
+    pub subtype: &'a [u8],
+    params: &'a [u8],
+}
+
+impl<'a> MediaType<'a> {
+    pub fn parse(value: &'a [u8]) -> Option<Self> {
+        let end = find_unquoted(value, b';');
+        let essence = trim_ows(&value[..end]);
+        let slash = memchr::memchr(b'/', essence)?;
+        let (ty, subtype) = (&essence[..slash], &essence[slash + 1..]);
+        if ty.is_empty() || subtype.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            ty,
+            subtype,
+            params: value.get(end + 1..).unwrap_or_default(),
+        })
+    }
+
+    /// Compares the type and subtype, case-insensitively
+    pub fn is(&self, ty: &str, subtype: &str) -> bool {
+        self.ty.eq_ignore_ascii_case(ty.as_bytes())
+            && self.subtype.eq_ignore_ascii_case(subtype.as_bytes())
+    }
+
+    pub fn params(&self) -> Params<'a> {
+        Params(Split::new(self.params, b';'))
+    }
+}
+
+/// Splits on a delimiter, except within quoted strings
+#[derive(Debug, Clone)]
+struct Split<'a> {
+    rest: &'a [u8],
+    delimiter: u8,
+}
+
+impl<'a> Split<'a> {
+    fn new(value: &'a [u8], delimiter: u8) -> Self {
+        Self {
+            rest: value,
+            delimiter,
+        }
+    }
+}
+
+impl<'a> Iterator for Split<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.rest.is_empty() {
+            let end = find_unquoted(self.rest, self.delimiter);
+            let element = trim_ows(&self.rest[..end]);
+            self.rest = self.rest.get(end + 1..).unwrap_or_default();
+            if !element.is_empty() {
+                return Some(element);
+            }
+        }
+        None
+    }
+}
+
+/// Returns the position of the first `delimiter` that isn't within a quoted
+/// string, or the length of `value` if there's none
+fn find_unquoted(value: &[u8], delimiter: u8) -> usize {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, &b) in value.iter().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if quoted {
+            match b {
+                b'\\' => escaped = true,
+                b'"' => quoted = false,
+                _ => {}
+            }
+        } else if b == b'"' {
+            quoted = true;
+        } else if b == delimiter {
+            return i;
+        }
+    }
+    value.len()
+}
+
+/// Parses a qvalue into thousandths, cf. RFC 9110, section 12.4.2
+pub(crate) fn parse_qvalue(input: &[u8]) -> Option<u16> {
+    let (int, frac) = match input.iter().position(|&b| b == b'.') {
+        Some(dot) => (&input[..dot], &input[dot + 1..]),
+        None => (input, &b""[..]),
+    };
+    if frac.len() > 3 || !frac.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let int = match int {
+        b"0" => 0,
+        b"1" => 1000,
+        _ => return None,
+    };
+    let frac = frac
+        .iter()
+        .chain(std::iter::repeat(&b'0'))
+        .take(3)
+        .fold(0, |acc, &b| acc * 10 + u16::from(b - b'0'));
+    let weight = int + frac;
+    (weight <= 1000).then_some(weight)
+}
+
+pub(crate) fn from_digits(bytes: &[u8]) -> Option<u64> {
     // cannot use FromStr for u64, since it allows a signed prefix
     let mut result = 0u64;
     const RADIX: u64 = 10;
@@ -69,3 +392,133 @@ fn from_digits(bytes: &[u8]) -> Option<u64> {
 
     Some(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> Headers {
+        let mut headers = Headers::default();
+        for (name, value) in pairs {
+            headers.append(name, (*value).into());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_list_elements() {
+        let elements: Vec<&[u8]> = list_elements(br#" a ,, b;x="1,2" , "c,d",W/"e",	"#).collect();
+        assert_eq!(
+            elements,
+            [&b"a"[..], br#"b;x="1,2""#, br#""c,d""#, br#"W/"e""#]
+        );
+        assert_eq!(list_elements(b" , ,").count(), 0);
+
+        let (value, params) = split_params(br#"form-data; name="a;\"b\""; filename=c.txt; flag"#);
+        assert_eq!(value, b"form-data");
+        let params: Vec<(&[u8], Cow<'_, [u8]>)> = params.collect();
+        assert_eq!(
+            params,
+            [
+                (&b"name"[..], Cow::Borrowed(&br#"a;"b""#[..])),
+                (b"filename", Cow::Borrowed(b"c.txt")),
+                (b"flag", Cow::Borrowed(b"")),
+            ]
+        );
+        assert!(matches!(unquote(br#""plain""#), Cow::Borrowed(b"plain")));
+        assert!(matches!(
+            unquote(br#"unquoted"#),
+            Cow::Borrowed(b"unquoted")
+        ));
+
+        let media_type = MediaType::parse(b"Text/HTML ; Charset=\"utf-8\"").unwrap();
+        assert!(media_type.is("text", "html"));
+        assert_eq!(
+            media_type.params().get("charset").as_deref(),
+            Some(&b"utf-8"[..])
+        );
+        assert!(MediaType::parse(b"text").is_none());
+        assert!(MediaType::parse(b"/html").is_none());
+    }
+
+    #[test]
+    fn test_headers_ext() {
+        let h = headers(&[
+            (header::CONNECTION, "keep-alive, Close"),
+            (header::TRANSFER_ENCODING, "gzip"),
+            (header::TRANSFER_ENCODING, "CHUNKED"),
+            (header::TE, "deflate;q=0.5, trailers"),
+            (header::TRAILER, "x-checksum, , x-timing"),
+            (header::ACCEPT_ENCODING, "gzip;q=0.8, br, zstd;q=bad, *;q=0"),
+            (header::CACHE_CONTROL, "max-age=60, No-Transform"),
+            (header::CACHE_CONTROL, "private=\"set-cookie\""),
+            (header::COOKIE, "a=1; b=\"two\""),
+            (header::COOKIE, "c="),
+        ]);
+        assert!(h.is_connection_close());
+        assert!(h.is_chunked_transfer_encoding());
+        assert!(h.accepts_trailers());
+        assert_eq!(
+            h.announced_trailers(),
+            ["x-checksum", "x-timing"].map(HeaderName::from_static)
+        );
+        let weights: Vec<(&[u8], u16)> = h.weighted_list(header::ACCEPT_ENCODING).collect();
+        assert_eq!(weights, [(&b"gzip"[..], 800), (b"br", 1000), (b"*", 0)]);
+        let directives: Vec<_> = h.cache_directives().collect();
+        assert_eq!(
+            directives,
+            [
+                (&b"max-age"[..], Some(Cow::Borrowed(&b"60"[..]))),
+                (b"No-Transform", None),
+                (b"private", Some(Cow::Borrowed(b"set-cookie"))),
+            ]
+        );
+        let cookies: Vec<(&[u8], &[u8])> = h.cookies().collect();
+        assert_eq!(
+            cookies,
+            [(&b"a"[..], &b"1"[..]), (b"b", b"\"two\""), (b"c", b"")]
+        );
+
+        let h = headers(&[
+            (header::CONNECTION, "keep-alive"),
+            (header::TRANSFER_ENCODING, "chunked, gzip"),
+            (header::TE, "trailersx"),
+        ]);
+        assert!(!h.is_connection_close());
+        assert!(!h.is_chunked_transfer_encoding());
+        assert!(!h.accepts_trailers());
+        assert!(h.content_type().is_none());
+    }
+
+    #[test]
+    fn test_is_valid_field_value() {
+        for value in ["", "text/html", "a  b", "inner\ttab", "caf\u{e9}"] {
+            assert!(is_valid_field_value(value.as_bytes()), "{value:?}");
+        }
+        for value in [
+            "a\r\nset-cookie: x",
+            "a\nb",
+            "a\rb",
+            "a\0b",
+            " a",
+            "a\t",
+            "\tinner\ttab",
+        ] {
+            assert!(!is_valid_field_value(value.as_bytes()), "{value:?}");
+        }
+
+        let h = headers(&[(header::SERVER, "loona"), (header::VARY, "a\r\nb")]);
+        assert_eq!(h.validate_values().unwrap_err().name, header::VARY);
+    }
+
+    #[test]
+    fn test_parse_qvalue() {
+        assert_eq!(parse_qvalue(b"1"), Some(1000));
+        assert_eq!(parse_qvalue(b"1.000"), Some(1000));
+        assert_eq!(parse_qvalue(b"0.5"), Some(500));
+        assert_eq!(parse_qvalue(b"0.125"), Some(125));
+        assert_eq!(parse_qvalue(b"1.5"), None);
+        assert_eq!(parse_qvalue(b"0.1234"), None);
+        assert_eq!(parse_qvalue(b".5"), None);
+    }
+}
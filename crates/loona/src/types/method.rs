@@ -13,6 +13,8 @@ pub enum Method {
     Connect,
     Options,
     Trace,
+    /// cf. <https://www.rfc-editor.org/rfc/rfc5789>
+    Patch,
     Other(PieceStr),
 }
 
@@ -25,7 +27,13 @@ impl fmt::Debug for Method {
 
 impl fmt::Display for Method {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = match self {
+        f.pad(self.as_str())
+    }
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
             Method::Get => "GET",
             Method::Head => "HEAD",
             Method::Post => "POST",
@@ -34,13 +42,11 @@ impl fmt::Display for Method {
             Method::Connect => "CONNECT",
             Method::Options => "OPTIONS",
             Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
             Method::Other(s) => s,
-        };
-        f.pad(s)
+        }
     }
-}
 
-impl Method {
     pub fn into_chunk(self) -> Piece {
         let s = match self {
             Method::Get => "GET",
@@ -51,10 +57,34 @@ impl Method {
             Method::Connect => "CONNECT",
             Method::Options => "OPTIONS",
             Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
             Method::Other(roll) => return roll.into_inner(),
         };
         s.into()
     }
+
+    /// Safe methods are essentially read-only: the client doesn't ask for
+    /// any state to change on the server, cf. RFC 9110, section 9.2.1
+    pub fn is_safe(&self) -> bool {
+        matches!(
+            self,
+            Method::Get | Method::Head | Method::Options | Method::Trace
+        )
+    }
+
+    /// Sending an idempotent request several times has the same effect as
+    /// sending it once, so it may be retried automatically, cf. RFC 9110,
+    /// section 9.2.2
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self, Method::Put | Method::Delete)
+    }
+
+    /// Responses to cacheable methods may be stored, cf. RFC 9110, section
+    /// 9.2.3. For POST, that's only with explicit freshness information, and
+    /// most caches don't bother.
+    pub fn is_cacheable(&self) -> bool {
+        matches!(self, Method::Get | Method::Head | Method::Post)
+    }
 }
 
 impl From<PieceStr> for Method {
@@ -68,7 +98,85 @@ impl From<PieceStr> for Method {
             "CONNECT" => Method::Connect,
             "OPTIONS" => Method::Options,
             "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
             _ => Method::Other(s),
         }
     }
 }
+
+impl From<http::Method> for Method {
+    fn from(method: http::Method) -> Self {
+        match method {
+            http::Method::GET => Method::Get,
+            http::Method::HEAD => Method::Head,
+            http::Method::POST => Method::Post,
+            http::Method::PUT => Method::Put,
+            http::Method::DELETE => Method::Delete,
+            http::Method::CONNECT => Method::Connect,
+            http::Method::OPTIONS => Method::Options,
+            http::Method::TRACE => Method::Trace,
+            http::Method::PATCH => Method::Patch,
+            // `http` already made sure it's a token
+            method => Method::Other(PieceStr::from(method.as_str().to_owned())),
+        }
+    }
+}
+
+/// Fails if an [Method::Other] isn't a valid token
+impl TryFrom<Method> for http::Method {
+    type Error = http::method::InvalidMethod;
+
+    fn try_from(method: Method) -> Result<Self, Self::Error> {
+        Ok(match method {
+            Method::Get => http::Method::GET,
+            Method::Head => http::Method::HEAD,
+            Method::Post => http::Method::POST,
+            Method::Put => http::Method::PUT,
+            Method::Delete => http::Method::DELETE,
+            Method::Connect => http::Method::CONNECT,
+            Method::Options => http::Method::OPTIONS,
+            Method::Trace => http::Method::TRACE,
+            Method::Patch => http::Method::PATCH,
+            Method::Other(s) => http::Method::from_bytes(s.as_bytes())?,
+        })
+    }
+}
+
+impl PartialEq<http::Method> for Method {
+    fn eq(&self, other: &http::Method) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_method_conversions() {
+        for name in [
+            "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+            "PROPFIND",
+        ] {
+            let ours = Method::from(PieceStr::from(name));
+            let theirs = http::Method::from_bytes(name.as_bytes()).unwrap();
+            assert_eq!(ours, theirs);
+            assert_eq!(Method::from(theirs.clone()), ours);
+            assert_eq!(http::Method::try_from(ours).unwrap(), theirs);
+        }
+        assert_eq!(Method::from(PieceStr::from("PATCH")), Method::Patch);
+        assert_ne!(Method::from(PieceStr::from("patch")), Method::Patch);
+
+        let invalid = Method::Other(PieceStr::from("NOT A TOKEN"));
+        assert!(http::Method::try_from(invalid).is_err());
+    }
+
+    #[test]
+    fn test_method_properties() {
+        assert!(Method::Get.is_safe() && Method::Get.is_idempotent());
+        assert!(!Method::Put.is_safe() && Method::Put.is_idempotent());
+        assert!(!Method::Patch.is_idempotent() && !Method::Patch.is_cacheable());
+        assert!(!Method::Post.is_idempotent() && Method::Post.is_cacheable());
+        assert!(!Method::Other(PieceStr::from("PROPFIND")).is_safe());
+    }
+}
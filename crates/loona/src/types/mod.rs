@@ -1,4 +1,7 @@
-use std::fmt::{self, Debug};
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+};
 
 use http::{StatusCode, Uri, Version};
 use tracing::debug;
@@ -11,6 +14,9 @@ pub use headers::*;
 mod method;
 pub use method::*;
 
+mod connection;
+pub use connection::*;
+
 use crate::{error::NeverError, util::ReadAndParseError};
 
 /// An HTTP request
@@ -26,6 +32,10 @@ pub struct Request {
 
     /// Request headers
     pub headers: Headers,
+
+    /// Request-scoped data: servers add the [ConnectionInfo] and [StreamInfo]
+    /// here, middleware may add its own
+    pub extensions: http::Extensions,
 }
 
 impl Default for Request {
@@ -35,10 +45,26 @@ impl Default for Request {
             uri: "/".parse().unwrap(),
             version: Version::HTTP_11,
             headers: Default::default(),
+            extensions: Default::default(),
         }
     }
 }
 
+impl Request {
+    /// Returns the connection this request was received on, if it came from
+    /// [crate::h1::serve] or [crate::h2::serve]
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.extensions
+            .get::<Arc<ConnectionInfo>>()
+            .map(|conn| conn.as_ref())
+    }
+
+    /// Returns the stream of its connection this request was received on
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.extensions.get::<StreamInfo>().copied()
+    }
+}
+
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Request")
@@ -86,15 +112,20 @@ impl Response {
         }
     }
 
-    /// 204 and 304 responses must not have a body
+    /// 1xx, 204, 205 and 304 responses must not have a body
     pub fn means_empty_body(&self) -> bool {
-        matches!(
-            self.status,
-            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
-        )
+        status_means_empty_body(self.status)
     }
 }
 
+pub(crate) fn status_means_empty_body(status: StatusCode) -> bool {
+    status.is_informational()
+        || matches!(
+            status,
+            StatusCode::NO_CONTENT | StatusCode::RESET_CONTENT | StatusCode::NOT_MODIFIED
+        )
+}
+
 /// A body chunk
 pub enum BodyChunk {
     Chunk(Piece),
@@ -160,6 +191,15 @@ pub enum BodyError {
     /// I/O error while writing
     #[error("I/O error while writing: {0}")]
     WriteError(std::io::Error),
+
+    /// The body is larger than the configured limit, cf.
+    /// [crate::ServerDriver::max_request_body_len]
+    #[error("body is larger than the limit of {limit} bytes")]
+    BodyTooLarge { limit: u64 },
+
+    /// A trailer had a value that can't be sent, cf. [is_valid_field_value]
+    #[error("{0}")]
+    InvalidTrailerValue(#[from] InvalidHeaderValue),
 }
 
 impl AsRef<dyn std::error::Error> for BodyError {
@@ -178,6 +218,35 @@ where
     fn content_len(&self) -> Option<u64>;
     fn eof(&self) -> bool;
     async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error>;
+
+    /// If what's left of this body is a region of a file, hands it over: the
+    /// body then acts as if it had been read to the end. This lets encoders
+    /// send it without copying it through userspace. Only ever called before
+    /// the first `next_chunk`.
+    fn take_file(&mut self) -> Option<crate::body::FileRegion> {
+        None
+    }
+}
+
+/// So that adapters can wrap the `&mut impl Body` a driver is handed
+impl<B: Body> Body for &mut B {
+    type Error = B::Error;
+
+    fn content_len(&self) -> Option<u64> {
+        (**self).content_len()
+    }
+
+    fn eof(&self) -> bool {
+        (**self).eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        (**self).next_chunk().await
+    }
+
+    fn take_file(&mut self) -> Option<crate::body::FileRegion> {
+        (**self).take_file()
+    }
 }
 
 impl Body for () {
@@ -216,10 +285,25 @@ pub enum ServeOutcome {
     /// we had to close the entire connection.
     RequestHeadersTooLargeOnHttp1Conn,
 
+    /// HTTP/1.1 only: The request announced a body larger than allowed, we
+    /// replied with 413 and closed the connection without reading it
+    RequestBodyTooLargeOnHttp1Conn,
+
+    /// HTTP/1.1 only: There was no telling where the request body ends: its
+    /// `transfer-encoding` didn't end with `chunked`, it had both
+    /// `transfer-encoding` and `content-length`, or an invalid or conflicting
+    /// `content-length`. We replied with 400 and closed the connection
+    RequestBodyLengthUnknownOnHttp1Conn,
+
     /// HTTP/2 only: Client didn't speak HTTP/2 (missing/invalid request line)
     ClientDidntSpeakHttp2,
 
     /// HTTP/2 only: Client sent a GOAWAY frame, and we've sent a response to
     /// the client
     SuccessfulHttp2GracefulShutdown,
+
+    /// The server is shutting down. For HTTP/1.1, we closed the connection
+    /// between requests. For HTTP/2, we sent a GOAWAY frame and waited for
+    /// streams in flight to finish.
+    ServerShutDown,
 }
@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use nom::IResult;
 use pretty_hex::PrettyHex;
 use tracing::{debug, trace};
@@ -101,3 +103,22 @@ where
         };
     }
 }
+
+/// Trims optional whitespace around a list element, cf. RFC 9110, section 5.6.3
+pub(crate) fn trim_ows(s: &[u8]) -> &[u8] {
+    &s[ows_trimmed_range(s)]
+}
+
+/// The range of `s` that's left once optional whitespace is trimmed, for
+/// callers that slice something other than `s`
+pub(crate) fn ows_trimmed_range(s: &[u8]) -> Range<usize> {
+    let start = s
+        .iter()
+        .position(|&b| b != b' ' && b != b'\t')
+        .unwrap_or(s.len());
+    let end = s
+        .iter()
+        .rposition(|&b| b != b' ' && b != b'\t')
+        .map_or(start, |i| i + 1);
+    start..end
+}
@@ -0,0 +1,307 @@
+//! A [loona::Server] listening on a local port, and clients that talk to it
+//! over HTTP/1.1 and HTTP/2
+
+use std::{collections::VecDeque, net::SocketAddr, rc::Rc, time::Duration};
+
+use b_x::{BxForResults, BX};
+use loona::{
+    body::BodyExt,
+    buffet::{
+        net::{TcpListener, TcpReadHalf, TcpStream, TcpWriteHalf},
+        IntoHalves, PieceList, ReadOwned, RollMut, WriteOwned,
+    },
+    h1::{self, encode::H1Encoder},
+    h2::{self, H2Encoder},
+    Body, BodyChunk, ClientDriver, ConnectionInfo, Encoder, ExpectResponseHeaders, Headers, Method,
+    Protocol, Request, Responder, Response, ResponseDone, ServeOutcome, ServerConf, ServerDriver,
+    ServerHandle,
+};
+use tokio::task::JoinHandle;
+
+/// A response, its whole body, and its trailers if any
+pub(crate) type Collected = (Response, Vec<u8>, Option<Box<Headers>>);
+
+/// Collects the whole response body, and its trailers if any
+pub(crate) struct CollectingDriver;
+
+impl ClientDriver for CollectingDriver {
+    type Return = Collected;
+    type Error = BX;
+
+    async fn on_informational_response(&mut self, _res: Response) -> b_x::Result<()> {
+        Ok(())
+    }
+
+    async fn on_final_response(
+        self,
+        res: Response,
+        body: &mut impl Body,
+    ) -> b_x::Result<Self::Return> {
+        let mut res_body = Vec::new();
+        loop {
+            match body.next_chunk().await.bx()? {
+                BodyChunk::Chunk(chunk) => res_body.extend_from_slice(&chunk[..]),
+                BodyChunk::Done { trailers } => return Ok((res, res_body, trailers)),
+            }
+        }
+    }
+}
+
+/// A body of unknown length, sent one chunk at a time
+#[derive(Debug)]
+pub(crate) struct StrChunks(pub(crate) VecDeque<&'static str>);
+
+impl Body for StrChunks {
+    type Error = BX;
+
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    async fn next_chunk(&mut self) -> b_x::Result<BodyChunk> {
+        Ok(match self.0.pop_front() {
+            Some(chunk) => BodyChunk::Chunk(chunk.into()),
+            None => BodyChunk::Done { trailers: None },
+        })
+    }
+}
+
+/// What a [FnDriver] responds with
+#[derive(Default)]
+pub(crate) struct Reply {
+    pub(crate) res: Response,
+    /// Written one piece at a time, with whatever framing `res` announces
+    pub(crate) body: PieceList,
+    pub(crate) trailers: Option<Box<Headers>>,
+}
+
+/// Responds to each request with what `respond` returns for it, given the
+/// whole request body
+pub(crate) struct FnDriver<F> {
+    respond: F,
+    max_request_body_len: fn(&Request, Option<u64>) -> Option<u64>,
+}
+
+impl<F> FnDriver<F>
+where
+    F: Fn(&Request, PieceList) -> Reply,
+{
+    pub(crate) fn new(respond: F) -> Self {
+        Self {
+            respond,
+            max_request_body_len: |_req, default| default,
+        }
+    }
+
+    /// cf. [ServerDriver::max_request_body_len]
+    pub(crate) fn with_max_request_body_len(
+        mut self,
+        max_request_body_len: fn(&Request, Option<u64>) -> Option<u64>,
+    ) -> Self {
+        self.max_request_body_len = max_request_body_len;
+        self
+    }
+}
+
+impl<F, OurEncoder> ServerDriver<OurEncoder> for FnDriver<F>
+where
+    F: Fn(&Request, PieceList) -> Reply,
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        let collected = req_body.collect(u64::MAX).await.map_err(BX::from_err)?;
+        let Reply {
+            res,
+            body,
+            trailers,
+        } = (self.respond)(&req, collected.body);
+
+        let mut respond = respond.write_final_response(res).await.bx()?;
+        for chunk in body.into_vec_deque() {
+            if !chunk.is_empty() {
+                respond.write_chunk(chunk).await.bx()?;
+            }
+        }
+        respond.finish_body(trailers).await.bx()
+    }
+
+    fn max_request_body_len(&self, req: &Request, default: Option<u64>) -> Option<u64> {
+        (self.max_request_body_len)(req, default)
+    }
+}
+
+/// A request for `path` on the test server
+pub(crate) fn request(method: Method, path: &str) -> Request {
+    Request {
+        method,
+        uri: format!("http://127.0.0.1{path}").parse().unwrap(),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn get(path: &str) -> Request {
+    request(Method::Get, path)
+}
+
+/// A [loona::Server] with a single listener on a random local port
+pub(crate) struct TestServer {
+    pub(crate) addr: SocketAddr,
+    pub(crate) handle: ServerHandle,
+    server_fut: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub(crate) async fn start<MakeDriver, OurDriver>(
+        conf: ServerConf,
+        protocol: Protocol,
+        make_driver: MakeDriver,
+    ) -> b_x::Result<Self>
+    where
+        MakeDriver: Fn(SocketAddr) -> OurDriver + 'static,
+        OurDriver: ServerDriver<H1Encoder<TcpWriteHalf>> + ServerDriver<H2Encoder> + 'static,
+    {
+        let ln = TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let addr = ln.local_addr()?;
+        let mut server = loona::Server::new(conf, make_driver);
+        server.add_listener(ln, protocol);
+        let handle = server.handle();
+        let server_fut = loona::buffet::spawn(server.run());
+
+        Ok(Self {
+            addr,
+            handle,
+            server_fut,
+        })
+    }
+
+    /// Opens a new HTTP/1.1 connection
+    pub(crate) async fn h1(&self) -> b_x::Result<H1Conn> {
+        Ok(H1Conn {
+            conf: Default::default(),
+            transport: Some(TcpStream::connect(self.addr).await?.into_halves()),
+        })
+    }
+
+    /// Opens a new HTTP/2 connection, with prior knowledge
+    pub(crate) async fn h2(&self) -> b_x::Result<H2Conn> {
+        let transport = TcpStream::connect(self.addr).await?.into_halves();
+        let (client, conn_fut) = h2::connect(transport, Rc::new(h2::ClientConf::default()))?;
+        Ok(H2Conn {
+            client,
+            conn_fut: loona::buffet::spawn(async move { conn_fut.await.bx() }),
+        })
+    }
+
+    /// Triggers a graceful shutdown, and waits for the server to stop
+    pub(crate) async fn shutdown(self) -> b_x::Result<()> {
+        self.handle.shutdown();
+        self.server_fut.await.bx()
+    }
+}
+
+/// An HTTP/1.1 connection to a [TestServer], reused for as long as the
+/// server keeps it open
+pub(crate) struct H1Conn {
+    conf: h1::ClientConf,
+    transport: Option<(TcpReadHalf, TcpWriteHalf)>,
+}
+
+impl H1Conn {
+    /// Fails if the server closed the connection after a previous response
+    pub(crate) async fn request(
+        &mut self,
+        req: Request,
+        body: &mut impl Body,
+    ) -> b_x::Result<Collected> {
+        let transport = self
+            .transport
+            .take()
+            .ok_or_else(|| BX::from_string("the connection was closed".to_owned()))?;
+        let (transport, res) =
+            h1::request(transport, &self.conf, req, body, CollectingDriver).await?;
+        self.transport = transport;
+        Ok(res)
+    }
+
+    /// Returns false once the server closed the connection
+    pub(crate) fn is_open(&self) -> bool {
+        self.transport.is_some()
+    }
+}
+
+/// An HTTP/2 connection to a [TestServer]
+pub(crate) struct H2Conn {
+    pub(crate) client: h2::Client,
+    conn_fut: JoinHandle<b_x::Result<()>>,
+}
+
+impl H2Conn {
+    pub(crate) async fn request(
+        &self,
+        req: Request,
+        body: &mut impl Body,
+    ) -> b_x::Result<Collected> {
+        self.client.request(req, body, CollectingDriver).await.bx()
+    }
+
+    /// Drops the client, and waits for the connection to wind down
+    pub(crate) async fn close(self) -> b_x::Result<()> {
+        drop(self.client);
+        self.conn_fut.await.bx()?.bx()
+    }
+}
+
+/// Serves a single HTTP/1.1 connection over pipes: sends `input`, then returns
+/// everything the server wrote back and how serving the connection ended
+pub(crate) async fn serve_h1_raw<OurDriver>(
+    driver: OurDriver,
+    input: &'static str,
+) -> b_x::Result<(String, ServeOutcome)>
+where
+    OurDriver: ServerDriver<H1Encoder<loona::buffet::PipeWrite>> + 'static,
+{
+    let (mut client_write, server_read) = loona::buffet::pipe();
+    let (server_write, mut client_read) = loona::buffet::pipe();
+    let serve_fut = loona::buffet::spawn(h1::serve(
+        (server_read, server_write),
+        Default::default(),
+        RollMut::alloc()?,
+        driver,
+        ConnectionInfo::default(),
+    ));
+
+    client_write.write_all_owned(input).await?;
+    let read_output = async {
+        let mut output = Vec::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                return Ok::<_, BX>(output);
+            }
+            output.extend_from_slice(&buf[..n]);
+        }
+    };
+    let (outcome, output) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(serve_fut, read_output)
+    })
+    .await
+    .bx()?;
+    let (outcome, output) = (outcome.bx()?.bx()?, output?);
+    drop(client_write);
+
+    Ok((String::from_utf8(output)?, outcome))
+}
@@ -2,6 +2,7 @@ use std::future::Future;
 
 use b_x::BX;
 
+pub(crate) mod harness;
 pub(crate) mod tracing_common;
 
 pub(crate) fn run(test: impl Future<Output = Result<(), BX>>) {
@@ -120,7 +120,14 @@ pub fn start_server() -> httpwg::Conn<TwoHalves<PipeWrite, PipeRead>> {
         let client_buf = RollMut::alloc()?;
         let driver = Rc::new(TestDriver);
         let io = (server_read, server_write);
-        loona::h2::serve(io, server_conf, client_buf, driver).await?;
+        loona::h2::serve(
+            io,
+            server_conf,
+            client_buf,
+            driver,
+            loona::ConnectionInfo::default(),
+        )
+        .await?;
         tracing::debug!("http/2 server done");
         Ok::<_, BX>(())
     };
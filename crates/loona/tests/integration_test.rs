@@ -2,17 +2,22 @@ mod helpers;
 
 use b_x::{BxForResults, BX};
 use bytes::BytesMut;
+use helpers::harness::{
+    get, request, serve_h1_raw, CollectingDriver, FnDriver, Reply, StrChunks, TestServer,
+};
 use http::{header, StatusCode};
 use httparse::{Status, EMPTY_HEADER};
 use loona::buffet::{IntoHalves, ReadOwned, WriteOwned};
 use loona::{
-    buffet::{PieceCore, RollMut},
-    h1, h2, Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, HeadersExt, Method, Request,
-    Responder, Response, ResponseDone, ServerDriver,
+    buffet::{PieceCore, PieceList, RollMut},
+    h1, h2,
+    proxy::{ProxyConf, ProxyDriver, Upstream},
+    Body, BodyChunk, ClientDriver, ConnectionInfo, Encoder, ExpectResponseHeaders, Headers,
+    HeadersExt, Method, Request, Responder, Response, ResponseDone, ServerDriver,
 };
 use pretty_assertions::assert_eq;
 use pretty_hex::PrettyHex;
-use std::{future::Future, net::SocketAddr, rc::Rc, time::Duration};
+use std::{cell::RefCell, future::Future, net::SocketAddr, rc::Rc, time::Duration};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::process::Command;
@@ -96,6 +101,7 @@ fn serve_api() {
             conf,
             client_buf,
             driver,
+            ConnectionInfo::default(),
         ));
 
         client_write
@@ -193,7 +199,14 @@ fn request_api() {
         let request_fut = loona::buffet::spawn(async {
             #[allow(clippy::let_unit_value)]
             let mut body = ();
-            h1::request((client_read, client_write), req, &mut body, driver).await
+            h1::request(
+                (client_read, client_write),
+                &h1::ClientConf::default(),
+                req,
+                &mut body,
+                driver,
+            )
+            .await
         });
 
         let mut req_buf = BytesMut::new();
@@ -241,6 +254,514 @@ fn request_api() {
     })
 }
 
+#[test]
+fn request_api_expect_continue() {
+    helpers::run(async move {
+        let (mut server_write, client_read) = loona::buffet::pipe();
+        let (client_write, mut server_read) = loona::buffet::pipe();
+
+        let req = Request {
+            method: Method::Post,
+            uri: "/".parse().unwrap(),
+            headers: {
+                let mut headers = Headers::default();
+                headers.insert(header::EXPECT, "100-continue".into());
+                headers
+            },
+            ..Default::default()
+        };
+
+        #[derive(Default)]
+        struct TestDriver {
+            informational: Vec<StatusCode>,
+        }
+
+        impl h1::ClientDriver for TestDriver {
+            type Return = Vec<StatusCode>;
+            type Error = BX;
+
+            async fn on_informational_response(&mut self, res: Response) -> b_x::Result<()> {
+                self.informational.push(res.status);
+                Ok(())
+            }
+
+            async fn on_final_response(
+                self,
+                res: Response,
+                body: &mut impl Body,
+            ) -> b_x::Result<Self::Return> {
+                assert_eq!(res.status, StatusCode::OK);
+                while let BodyChunk::Chunk(_) = body.next_chunk().await.bx()? {}
+                Ok(self.informational)
+            }
+        }
+
+        let request_fut = loona::buffet::spawn(async {
+            let mut body = StrChunks(["hello"].into());
+            h1::request(
+                (client_read, client_write),
+                &h1::ClientConf::default(),
+                req,
+                &mut body,
+                TestDriver::default(),
+            )
+            .await
+        });
+
+        let mut req_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        macro_rules! read_more {
+            () => {
+                let res;
+                (res, buf) = server_read.read_owned(buf).await;
+                let n = res?;
+                assert_ne!(n, 0, "client hung up");
+                req_buf.extend_from_slice(&buf[..n]);
+            };
+        }
+
+        let body_offset = loop {
+            read_more!();
+
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut req = httparse::Request::new(&mut headers[..]);
+            match req.parse(&req_buf[..]).bx()? {
+                Status::Complete(off) => break off,
+                Status::Partial => continue,
+            }
+        };
+        assert_eq!(
+            req_buf.len(),
+            body_offset,
+            "client should hold back the body until it gets a 100 (Continue)"
+        );
+
+        server_write
+            .write_all_owned(
+                "HTTP/1.1 103 Early Hints\r\nlink: </style.css>; rel=preload\r\n\r\nHTTP/1.1 100 Continue\r\n\r\n",
+            )
+            .await?;
+
+        while req_buf.len() < body_offset + "5\r\nhello\r\n0\r\n\r\n".len() {
+            read_more!();
+        }
+        assert_eq!(&req_buf[body_offset..], b"5\r\nhello\r\n0\r\n\r\n");
+
+        server_write
+            .write_all_owned("HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await?;
+
+        let (_transport, informational) = tokio::time::timeout(Duration::from_secs(5), request_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(
+            informational,
+            vec![StatusCode::from_u16(103).unwrap(), StatusCode::CONTINUE]
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn request_api_early_response() {
+    helpers::run(async move {
+        let (mut server_write, client_read) = loona::buffet::pipe();
+        let (client_write, mut server_read) = loona::buffet::pipe();
+
+        let req = Request {
+            method: Method::Post,
+            uri: "/upload".parse().unwrap(),
+            ..Default::default()
+        };
+
+        struct TestDriver;
+
+        impl h1::ClientDriver for TestDriver {
+            type Return = StatusCode;
+            type Error = BX;
+
+            async fn on_informational_response(&mut self, _res: Response) -> b_x::Result<()> {
+                Ok(())
+            }
+
+            async fn on_final_response(
+                self,
+                res: Response,
+                body: &mut impl Body,
+            ) -> b_x::Result<Self::Return> {
+                while let BodyChunk::Chunk(_) = body.next_chunk().await.bx()? {}
+                Ok(res.status)
+            }
+        }
+
+        let request_fut = loona::buffet::spawn(async {
+            let mut body = SampleBody::default();
+            h1::request(
+                (client_read, client_write),
+                &h1::ClientConf::default(),
+                req,
+                &mut body,
+                TestDriver,
+            )
+            .await
+        });
+
+        // read the request headers, then reject the request and hang up
+        let mut req_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = server_read.read_owned(buf).await;
+            let n = res?;
+            req_buf.extend_from_slice(&buf[..n]);
+
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut req = httparse::Request::new(&mut headers[..]);
+            if req.parse(&req_buf[..]).bx()?.is_complete() {
+                break;
+            }
+        }
+
+        server_write
+            .write_all_owned(
+                "HTTP/1.1 413 Payload Too Large\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+            )
+            .await?;
+        drop(server_write);
+        drop(server_read);
+
+        let (transport, status) = tokio::time::timeout(Duration::from_secs(5), request_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(transport.is_none());
+
+        Ok(())
+    })
+}
+
+/// Sends a bodyless request over a pipe, answers it with `raw_res` then hangs
+/// up. Returns the request head as received, the response body, and whether
+/// the client would have re-used the connection.
+async fn h1_client_exchange(
+    conf: h1::ClientConf,
+    req: Request,
+    raw_res: &'static str,
+) -> b_x::Result<(String, Vec<u8>, bool)> {
+    let (mut server_write, client_read) = loona::buffet::pipe();
+    let (client_write, mut server_read) = loona::buffet::pipe();
+
+    struct TestDriver;
+
+    impl h1::ClientDriver for TestDriver {
+        type Return = Vec<u8>;
+        type Error = BX;
+
+        async fn on_informational_response(&mut self, _res: Response) -> b_x::Result<()> {
+            Ok(())
+        }
+
+        async fn on_final_response(
+            self,
+            _res: Response,
+            body: &mut impl Body,
+        ) -> b_x::Result<Self::Return> {
+            let mut res_body = Vec::new();
+            while let BodyChunk::Chunk(chunk) = body.next_chunk().await.bx()? {
+                res_body.extend_from_slice(&chunk[..]);
+            }
+            Ok(res_body)
+        }
+    }
+
+    let request_fut = loona::buffet::spawn(async move {
+        h1::request((client_read, client_write), &conf, req, &mut (), TestDriver).await
+    });
+
+    let mut req_buf = BytesMut::new();
+    let mut buf = vec![0u8; 1024];
+    loop {
+        let res;
+        (res, buf) = server_read.read_owned(buf).await;
+        let n = res?;
+        req_buf.extend_from_slice(&buf[..n]);
+
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut req = httparse::Request::new(&mut headers[..]);
+        if req.parse(&req_buf[..]).bx()?.is_complete() {
+            break;
+        }
+    }
+
+    server_write.write_all_owned(raw_res).await?;
+    drop(server_write);
+
+    let (transport, res_body) = tokio::time::timeout(Duration::from_secs(5), request_fut)
+        .await
+        .bx()?
+        .bx()??;
+    Ok((
+        String::from_utf8(req_buf.to_vec()).bx()?,
+        res_body,
+        transport.is_some(),
+    ))
+}
+
+async fn h1_client_roundtrip(
+    method: Method,
+    raw_res: &'static str,
+) -> b_x::Result<(Vec<u8>, bool)> {
+    let req = Request {
+        method,
+        uri: "/".parse().unwrap(),
+        ..Default::default()
+    };
+    let (_req_head, res_body, reusable) =
+        h1_client_exchange(h1::ClientConf::default(), req, raw_res).await?;
+    Ok((res_body, reusable))
+}
+
+#[test]
+fn request_api_response_framing() {
+    helpers::run(async move {
+        // responses to HEAD don't have a body, whatever content-length says
+        let (body, reusable) = h1_client_roundtrip(
+            Method::Head,
+            "HTTP/1.1 200 OK\r\ncontent-length: 1234\r\n\r\n",
+        )
+        .await?;
+        assert_eq!(body, b"");
+        assert!(reusable);
+
+        // neither do 204 responses
+        let (body, reusable) = h1_client_roundtrip(
+            Method::Get,
+            "HTTP/1.1 204 No Content\r\ncontent-length: 5\r\n\r\nhello",
+        )
+        .await?;
+        assert_eq!(body, b"");
+        assert!(reusable);
+
+        // no content-length and no transfer-encoding: read until close
+        let (body, reusable) =
+            h1_client_roundtrip(Method::Get, "HTTP/1.1 200 OK\r\n\r\nuntil the very end").await?;
+        assert_eq!(body, b"until the very end");
+        assert!(!reusable);
+
+        // transfer-encoding overrides content-length, but that's suspicious
+        let (body, reusable) = h1_client_roundtrip(
+            Method::Get,
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        )
+        .await?;
+        assert_eq!(body, b"hello");
+        assert!(!reusable);
+
+        // a list of identical content-length values is fine
+        let (body, reusable) = h1_client_roundtrip(
+            Method::Get,
+            "HTTP/1.1 200 OK\r\ncontent-length: 5, 5\r\n\r\nhello",
+        )
+        .await?;
+        assert_eq!(body, b"hello");
+        assert!(reusable);
+
+        // conflicting ones aren't
+        let res = h1_client_roundtrip(
+            Method::Get,
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\ncontent-length: 6\r\n\r\nhello",
+        )
+        .await;
+        assert!(res.is_err());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn request_api_request_target() {
+    helpers::run(async move {
+        let raw_res = "HTTP/1.1 204 No Content\r\n\r\n";
+        let uri: http::Uri = "http://user@example.org:8080/search?q=loona".parse().bx()?;
+
+        // origin form, with the host taken from the URI
+        let req = Request {
+            method: Method::Get,
+            uri: uri.clone(),
+            ..Default::default()
+        };
+        let (head, _, _) = h1_client_exchange(h1::ClientConf::default(), req, raw_res).await?;
+        assert!(
+            head.starts_with("GET /search?q=loona HTTP/1.1\r\n"),
+            "unexpected request head: {head:?}"
+        );
+        assert!(head.contains("host: example.org:8080\r\n"));
+
+        // absolute form, and an explicit host header wins
+        let req = Request {
+            method: Method::Get,
+            uri,
+            headers: {
+                let mut headers = Headers::default();
+                headers.insert(header::HOST, "example.com".into());
+                headers
+            },
+            ..Default::default()
+        };
+        let conf = h1::ClientConf {
+            absolute_form: true,
+            ..Default::default()
+        };
+        let (head, _, _) = h1_client_exchange(conf, req, raw_res).await?;
+        assert!(
+            head.starts_with("GET http://example.org:8080/search?q=loona HTTP/1.1\r\n"),
+            "unexpected request head: {head:?}"
+        );
+        assert!(head.contains("host: example.com\r\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn request_api_pool() {
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: Request,
+                req_body: &mut impl Body,
+                respond: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                while let BodyChunk::Chunk(_) = req_body.next_chunk().await.bx()? {}
+                let res = Response::default();
+                respond
+                    .write_final_response_with_body(res, &mut StrChunks(["ok"].into()))
+                    .await
+                    .bx()
+            }
+        }
+
+        let accepted = Rc::new(std::cell::Cell::new(0));
+        let server = TestServer::start(Default::default(), loona::Protocol::H1, {
+            let accepted = accepted.clone();
+            move |_addr| {
+                accepted.set(accepted.get() + 1);
+                TestDriver
+            }
+        })
+        .await?;
+        let addr = server.addr;
+        let authority = addr.to_string();
+
+        let conf = h1::ClientConf::default();
+        let get = |close: bool| Request {
+            method: Method::Get,
+            uri: "/".parse().unwrap(),
+            headers: {
+                let mut headers = Headers::default();
+                if close {
+                    headers.insert(header::CONNECTION, "close".into());
+                }
+                headers
+            },
+            ..Default::default()
+        };
+
+        // sequential requests share a single connection
+        let pool = h1::Pool::new(Default::default());
+        for _ in 0..3 {
+            let (res, body, _) = pool
+                .request(
+                    &authority,
+                    addr,
+                    &conf,
+                    get(false),
+                    &mut (),
+                    CollectingDriver,
+                )
+                .await?;
+            assert_eq!(res.status, StatusCode::OK);
+            assert_eq!(body, b"ok");
+        }
+        assert_eq!(accepted.get(), 1);
+        assert_eq!(pool.idle_count(&authority), 1);
+
+        // the server closes the connection after this one, which we notice
+        // before trying to re-use it
+        pool.request(
+            &authority,
+            addr,
+            &conf,
+            get(true),
+            &mut (),
+            CollectingDriver,
+        )
+        .await?;
+        assert_eq!(pool.idle_count(&authority), 1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pool.request(
+            &authority,
+            addr,
+            &conf,
+            get(false),
+            &mut (),
+            CollectingDriver,
+        )
+        .await?;
+        assert_eq!(accepted.get(), 2);
+
+        // expired connections aren't re-used
+        let pool = h1::Pool::new(h1::PoolConf {
+            idle_timeout: Duration::ZERO,
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            pool.request(
+                &authority,
+                addr,
+                &conf,
+                get(false),
+                &mut (),
+                CollectingDriver,
+            )
+            .await?;
+        }
+        assert_eq!(accepted.get(), 4);
+
+        // neither are connections we're not allowed to keep around
+        let pool = h1::Pool::new(h1::PoolConf {
+            max_idle_per_authority: 0,
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            pool.request(
+                &authority,
+                addr,
+                &conf,
+                get(false),
+                &mut (),
+                CollectingDriver,
+            )
+            .await?;
+        }
+        assert_eq!(accepted.get(), 6);
+        assert_eq!(pool.idle_count(&authority), 0);
+
+        server.shutdown().await
+    })
+}
+
 #[test]
 fn proxy_statuses() {
     #[allow(drop_bounds)]
@@ -729,6 +1250,7 @@ fn curl_echo_body_noproxy(typ: BodyType) {
                                 conf,
                                 RollMut::alloc().unwrap(),
                                 driver,
+                                ConnectionInfo::default(),
                             )
                             .await
                             .unwrap();
@@ -867,6 +1389,7 @@ fn h2_basic_post() {
                                 conf,
                                 RollMut::alloc().unwrap(),
                                 driver,
+                                ConnectionInfo::default(),
                             )
                             .await
                             .unwrap();
@@ -1039,6 +1562,7 @@ fn h2_basic_get() {
                                 conf,
                                 RollMut::alloc().unwrap(),
                                 driver,
+                                ConnectionInfo::default(),
                             )
                             .await
                             .unwrap();
@@ -1071,19 +1595,2362 @@ fn h2_basic_get() {
     });
 }
 
-trait CommandExt {
-    async fn output_assert_success(&mut self) -> std::process::Output;
-}
-
-impl CommandExt for Command {
-    async fn output_assert_success(&mut self) -> std::process::Output {
-        let output = self.output().await.unwrap();
-        if !output.status.success() {
-            // print stderr
-            eprintln!("command failed with status: {:?}", output.status);
-            eprintln!("stderr: {:?}", String::from_utf8_lossy(&output.stderr));
-            panic!("command failed with status: {:?} {:?}", output.status, self);
+#[test]
+fn h2_client_api() {
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            debug!("Got request {req:#?}");
+
+            let res = Response {
+                status: StatusCode::OK,
+                ..Default::default()
+            };
+            let respond = if req.uri.path() == "/echo-body" {
+                respond
+                    .write_final_response_with_body(res, req_body)
+                    .await
+                    .bx()?
+            } else if req.uri.path() == "/echo-trailers" {
+                let trailers = loop {
+                    if let BodyChunk::Done { trailers } = req_body.next_chunk().await.bx()? {
+                        break trailers;
+                    }
+                };
+                let checksum = trailers
+                    .and_then(|trailers| trailers.get("x-checksum").cloned())
+                    .unwrap_or_else(loona::buffet::Piece::empty);
+                respond
+                    .write_final_response_with_body(res, &mut loona::body::Full::from(checksum))
+                    .await
+                    .bx()?
+            } else {
+                respond
+                    .write_final_response_with_body(res, &mut SampleBody::default())
+                    .await
+                    .bx()?
+            };
+            Ok(respond)
         }
-        output
     }
+
+    helpers::run(async move {
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let server_fut = loona::buffet::spawn(async move {
+            h2::serve(
+                (server_read, server_write),
+                Rc::new(h2::ServerConf::default()),
+                RollMut::alloc()?,
+                Rc::new(TestDriver),
+                ConnectionInfo::default(),
+            )
+            .await
+            .bx()
+        });
+
+        let (client, conn_fut) = h2::connect(
+            (client_read, client_write),
+            Rc::new(h2::ClientConf::default()),
+        )?;
+        let conn_fut = loona::buffet::spawn(conn_fut);
+
+        // both requests are in flight at the same time, over the same connection
+        let echo_fut = async {
+            let req = Request {
+                method: Method::Post,
+                uri: "http://localhost/echo-body".parse().unwrap(),
+                ..Default::default()
+            };
+            let mut body = StrChunks(["hello ", "from ", "the client"].into());
+            client.request(req, &mut body, CollectingDriver).await.bx()
+        };
+        let big_fut = async {
+            let req = Request {
+                method: Method::Get,
+                uri: "http://localhost/stream-big-body".parse().unwrap(),
+                ..Default::default()
+            };
+            client.request(req, &mut (), CollectingDriver).await.bx()
+        };
+
+        let trailers_fut = async {
+            let req = Request {
+                method: Method::Post,
+                uri: "http://localhost/echo-trailers".parse().unwrap(),
+                ..Default::default()
+            };
+            let mut trailers = Box::new(Headers::default());
+            trailers.insert("x-checksum", "abc123".into());
+            let mut body = ChunkWithTrailers(Some("checked"), Some(trailers));
+            client.request(req, &mut body, CollectingDriver).await.bx()
+        };
+
+        let ((echo_res, echo_body, _), (big_res, big_body, _), (_, trailers_body, _)) =
+            tokio::time::timeout(Duration::from_secs(5), async {
+                tokio::try_join!(echo_fut, big_fut, trailers_fut)
+            })
+            .await
+            .bx()??;
+
+        assert_eq!(echo_res.status, StatusCode::OK);
+        assert_eq!(
+            String::from_utf8(echo_body).unwrap(),
+            "hello from the client"
+        );
+
+        assert_eq!(big_res.status, StatusCode::OK);
+        let ref_body = "this is a big chunk".repeat(256).repeat(128);
+        assert_eq!(big_body.len(), ref_body.len());
+        assert_eq!(String::from_utf8(big_body).unwrap(), ref_body);
+
+        // request trailers made it to the server
+        assert_eq!(String::from_utf8(trailers_body).unwrap(), "abc123");
+
+        // dropping the last handle closes the connection
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), conn_fut)
+            .await
+            .bx()?
+            .bx()?
+            .bx()?;
+        tokio::time::timeout(Duration::from_secs(5), server_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_client_trailers() {
+    use loona_h2::{Frame, FrameType, HeadersFlags, IntoPiece, StreamId};
+
+    helpers::run(async move {
+        let (mut server_write, client_read) = loona::buffet::pipe();
+        let (client_write, mut server_read) = loona::buffet::pipe();
+
+        let (client, conn_fut) = h2::connect(
+            (client_read, client_write),
+            Rc::new(h2::ClientConf::default()),
+        )?;
+        let conn_fut = loona::buffet::spawn(conn_fut);
+
+        let request_fut = loona::buffet::spawn(async move {
+            let req = Request {
+                method: Method::Get,
+                uri: "http://localhost/".parse().unwrap(),
+                ..Default::default()
+            };
+            client.request(req, &mut (), CollectingDriver).await
+        });
+
+        // read until we've seen the request's HEADERS frame: the preface,
+        // then 9-byte frame headers followed by their payload.
+        let mut client_bytes = Vec::new();
+        let mut buf = vec![0u8; 1024];
+        let mut offset = loona_h2::PREFACE.len();
+        'read: loop {
+            let res;
+            (res, buf) = server_read.read_owned(buf).await;
+            let n = res?;
+            assert_ne!(n, 0, "client hung up before sending its request");
+            client_bytes.extend_from_slice(&buf[..n]);
+
+            while client_bytes.len() >= offset + 9 {
+                let header = &client_bytes[offset..offset + 9];
+                let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                if header[3] == 0x1 {
+                    break 'read;
+                }
+                offset += 9 + len;
+            }
+        }
+        assert_eq!(&client_bytes[..loona_h2::PREFACE.len()], loona_h2::PREFACE);
+
+        // the client keeps writing (settings acks, etc.), don't block it
+        let drain_fut = loona::buffet::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = server_read.read_owned(buf).await;
+                if res? == 0 {
+                    break;
+                }
+            }
+            Ok::<_, BX>(())
+        });
+
+        let mut out_scratch = RollMut::alloc()?;
+        let mut hpack_enc = loona_hpack::Encoder::new();
+        let stream_id = StreamId(1);
+
+        let mut frames: Vec<(Frame, Vec<u8>)> = vec![(
+            Frame::new(
+                FrameType::Settings(Default::default()),
+                StreamId::CONNECTION,
+            ),
+            vec![],
+        )];
+
+        let mut block = Vec::new();
+        hpack_enc.encode_into([(&b":status"[..], &b"200"[..])], &mut block)?;
+        frames.push((
+            Frame::new(
+                FrameType::Headers(HeadersFlags::EndHeaders.into()),
+                stream_id,
+            ),
+            block,
+        ));
+
+        frames.push((
+            Frame::new(FrameType::Data(Default::default()), stream_id),
+            b"hello".to_vec(),
+        ));
+
+        let mut block = Vec::new();
+        hpack_enc.encode_into([(&b"x-checksum"[..], &b"abc123"[..])], &mut block)?;
+        frames.push((
+            Frame::new(
+                FrameType::Headers(HeadersFlags::EndHeaders | HeadersFlags::EndStream),
+                stream_id,
+            ),
+            block,
+        ));
+
+        for (frame, payload) in frames {
+            let frame = frame.with_len(payload.len() as u32);
+            server_write
+                .write_all_owned(frame.into_piece(&mut out_scratch)?)
+                .await?;
+            if !payload.is_empty() {
+                server_write.write_all_owned(payload).await?;
+            }
+        }
+
+        let (res, res_body, trailers) = tokio::time::timeout(Duration::from_secs(5), request_fut)
+            .await
+            .bx()?
+            .bx()?
+            .bx()?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(String::from_utf8(res_body).unwrap(), "hello");
+        let trailers = trailers.expect("response should have trailers");
+        assert_eq!(&trailers.get("x-checksum").unwrap()[..], b"abc123");
+
+        // the request future owned the last client handle
+        drop(server_write);
+        tokio::time::timeout(Duration::from_secs(5), conn_fut)
+            .await
+            .bx()?
+            .bx()?
+            .bx()?;
+        drain_fut.await.bx()??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_refused_stream_keeps_hpack_in_sync() {
+    use loona_h2::{Frame, FrameType, HeadersFlags, IntoPiece, StreamId};
+
+    /// Notified once it starts handling a request
+    struct TestDriver(Rc<tokio::sync::Notify>);
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            _req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            self.0.notify_one();
+            let trailers = loop {
+                if let BodyChunk::Done { trailers } = req_body.next_chunk().await.bx()? {
+                    break trailers;
+                }
+            };
+            let checksum = trailers
+                .and_then(|trailers| trailers.get("x-checksum").cloned())
+                .unwrap_or_else(loona::buffet::Piece::empty);
+            let res = Response {
+                status: StatusCode::OK,
+                ..Default::default()
+            };
+            respond
+                .write_final_response_with_body(res, &mut loona::body::Full::from(checksum))
+                .await
+                .bx()
+        }
+    }
+
+    helpers::run(async move {
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+
+        let conf = Rc::new(h2::ServerConf::default());
+        let handling = Rc::new(tokio::sync::Notify::new());
+        let server_fut = loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            conf.clone(),
+            RollMut::alloc()?,
+            Rc::new(TestDriver(handling.clone())),
+            ConnectionInfo::default(),
+        ));
+
+        // 9-byte frame headers followed by their payload
+        fn parse_frames(bytes: &[u8]) -> Vec<(u8, u32, &[u8])> {
+            let mut frames = Vec::new();
+            let mut offset = 0;
+            while bytes.len() >= offset + 9 {
+                let header = &bytes[offset..offset + 9];
+                let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                if bytes.len() < offset + 9 + len {
+                    break;
+                }
+                let stream_id =
+                    u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & !(1 << 31);
+                frames.push((header[3], stream_id, &bytes[offset + 9..offset + 9 + len]));
+                offset += 9 + len;
+            }
+            frames
+        }
+
+        // the server doesn't read while it's blocked on writing, so read
+        // everything it sends as it comes
+        let (goaway_tx, goaway_rx) = tokio::sync::oneshot::channel();
+        let read_fut = loona::buffet::spawn(async move {
+            let mut goaway_tx = Some(goaway_tx);
+            let mut server_bytes = Vec::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    return Ok::<_, BX>(server_bytes);
+                }
+                server_bytes.extend_from_slice(&buf[..n]);
+                if parse_frames(&server_bytes)
+                    .iter()
+                    .any(|(typ, _, _)| *typ == 0x7)
+                {
+                    if let Some(tx) = goaway_tx.take() {
+                        _ = tx.send(());
+                    }
+                }
+            }
+        });
+
+        let mut out_scratch = RollMut::alloc()?;
+        let mut hpack_enc = loona_hpack::Encoder::new();
+
+        client_write.write_all_owned(loona_h2::PREFACE).await?;
+        let mut frames: Vec<(Frame, Vec<u8>)> = vec![(
+            Frame::new(
+                FrameType::Settings(Default::default()),
+                StreamId::CONNECTION,
+            ),
+            vec![],
+        )];
+
+        // stream 1 is accepted, and waits for its trailers
+        let mut block = Vec::new();
+        hpack_enc.encode_into(
+            [
+                (&b":method"[..], &b"POST"[..]),
+                (&b":scheme"[..], &b"http"[..]),
+                (&b":path"[..], &b"/"[..]),
+                (&b":authority"[..], &b"localhost"[..]),
+            ],
+            &mut block,
+        )?;
+        frames.push((
+            Frame::new(
+                FrameType::Headers(HeadersFlags::EndHeaders.into()),
+                StreamId(1),
+            ),
+            block,
+        ));
+
+        for (frame, payload) in frames.drain(..) {
+            let frame = frame.with_len(payload.len() as u32);
+            client_write
+                .write_all_owned(frame.into_piece(&mut out_scratch)?)
+                .await?;
+            if !payload.is_empty() {
+                client_write.write_all_owned(payload).await?;
+            }
+        }
+
+        // stream 1 must be accepted before we shut down
+        tokio::time::timeout(Duration::from_secs(5), handling.notified())
+            .await
+            .bx()?;
+        conf.shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(5), goaway_rx)
+            .await
+            .bx()?
+            .bx()?;
+
+        // stream 3 is refused, but its field block still inserts into the
+        // dynamic table...
+        let mut block = Vec::new();
+        hpack_enc.encode_into(
+            [
+                (&b":method"[..], &b"GET"[..]),
+                (&b":scheme"[..], &b"http"[..]),
+                (&b":path"[..], &b"/"[..]),
+                (&b":authority"[..], &b"localhost"[..]),
+                (&b"x-checksum"[..], &b"abc123"[..]),
+            ],
+            &mut block,
+        )?;
+        frames.push((
+            Frame::new(
+                FrameType::Headers(HeadersFlags::EndHeaders | HeadersFlags::EndStream),
+                StreamId(3),
+            ),
+            block,
+        ));
+
+        // ...which stream 1's trailers then refer to
+        let mut block = Vec::new();
+        hpack_enc.encode_into([(&b"x-checksum"[..], &b"abc123"[..])], &mut block)?;
+        frames.push((
+            Frame::new(
+                FrameType::Headers(HeadersFlags::EndHeaders | HeadersFlags::EndStream),
+                StreamId(1),
+            ),
+            block,
+        ));
+
+        for (frame, payload) in frames {
+            let frame = frame.with_len(payload.len() as u32);
+            client_write
+                .write_all_owned(frame.into_piece(&mut out_scratch)?)
+                .await?;
+            if !payload.is_empty() {
+                client_write.write_all_owned(payload).await?;
+            }
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), server_fut)
+            .await
+            .bx()?
+            .bx()?
+            .bx()?;
+        drop(client_write);
+        let server_bytes = tokio::time::timeout(Duration::from_secs(5), read_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        let frames = parse_frames(&server_bytes);
+        let rst = frames
+            .iter()
+            .find(|(typ, stream_id, _)| *typ == 0x3 && *stream_id == 3)
+            .expect("stream 3 should be refused");
+        // REFUSED_STREAM
+        assert_eq!(rst.2, &[0, 0, 0, 7]);
+
+        let body: Vec<u8> = frames
+            .iter()
+            .filter(|(typ, stream_id, _)| *typ == 0x0 && *stream_id == 1)
+            .flat_map(|(_, _, payload)| payload.iter().copied())
+            .collect();
+        assert_eq!(String::from_utf8(body).unwrap(), "abc123");
+
+        Ok(())
+    })
+}
+
+trait CommandExt {
+    async fn output_assert_success(&mut self) -> std::process::Output;
+}
+
+impl CommandExt for Command {
+    async fn output_assert_success(&mut self) -> std::process::Output {
+        let output = self.output().await.unwrap();
+        if !output.status.success() {
+            // print stderr
+            eprintln!("command failed with status: {:?}", output.status);
+            eprintln!("stderr: {:?}", String::from_utf8_lossy(&output.stderr));
+            panic!("command failed with status: {:?} {:?}", output.status, self);
+        }
+        output
+    }
+}
+
+/// A single chunk, followed by trailers
+struct ChunkWithTrailers(Option<&'static str>, Option<Box<Headers>>);
+
+impl std::fmt::Debug for ChunkWithTrailers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ChunkWithTrailers").field(&self.0).finish()
+    }
+}
+
+impl Body for ChunkWithTrailers {
+    type Error = BX;
+
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.0.is_none() && self.1.is_none()
+    }
+
+    async fn next_chunk(&mut self) -> b_x::Result<BodyChunk> {
+        Ok(match self.0.take() {
+            Some(chunk) => BodyChunk::Chunk(chunk.into()),
+            None => BodyChunk::Done {
+                trailers: self.1.take(),
+            },
+        })
+    }
+}
+
+/// Starts an upstream that echoes request bodies (and trailers), reports some
+/// of the request headers it saw, and sends hop-by-hop headers of its own.
+async fn start_proxy_upstream() -> b_x::Result<SocketAddr> {
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            mut respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            if req.uri.path() == "/slow" {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            if req.uri.path() == "/early-hints" {
+                let mut headers = Headers::default();
+                headers.insert(header::LINK, "</style.css>; rel=preload".into());
+                respond
+                    .write_interim_response(Response {
+                        status: StatusCode::from_u16(103).unwrap(),
+                        headers,
+                        ..Default::default()
+                    })
+                    .await
+                    .bx()?;
+            }
+
+            let mut headers = Headers::default();
+            for name in [
+                "forwarded",
+                "x-forwarded-for",
+                "x-forwarded-host",
+                "x-hop",
+                "te",
+            ] {
+                let saw = req.headers.get(name).cloned().unwrap_or("none".into());
+                headers.insert(
+                    header::HeaderName::from_bytes(format!("x-saw-{name}").as_bytes()).bx()?,
+                    saw,
+                );
+            }
+            headers.insert(header::CONNECTION, "x-upstream-hop".into());
+            headers.insert("x-upstream-hop", "1".into());
+            headers.insert("keep-alive", "timeout=5".into());
+            // the echoed trailers are the ones the client announced
+            if let Some(trailer) = req.headers.get(header::TRAILER) {
+                headers.insert(header::TRAILER, trailer.clone());
+            }
+
+            let res = Response {
+                status: StatusCode::OK,
+                headers,
+                ..Default::default()
+            };
+            respond
+                .write_final_response_with_body(res, req_body)
+                .await
+                .bx()
+        }
+    }
+
+    let ln = loona::buffet::net::TcpListener::bind("127.0.0.1:0".parse()?).await?;
+    let addr = ln.local_addr()?;
+    loona::buffet::spawn(async move {
+        let conf = Rc::new(h1::ServerConf::default());
+        while let Ok((transport, _)) = ln.accept().await {
+            let conf = conf.clone();
+            loona::buffet::spawn(async move {
+                _ = h1::serve(
+                    transport.into_halves(),
+                    conf,
+                    RollMut::alloc().unwrap(),
+                    TestDriver,
+                    ConnectionInfo::default(),
+                )
+                .await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Sends a single request through a `ProxyDriver`, served over HTTP/1.1
+async fn proxy_h1_exchange(
+    conf: Rc<ProxyConf>,
+    req: Request,
+    body: &mut impl Body,
+) -> b_x::Result<(Response, Vec<u8>, Option<Box<Headers>>)> {
+    let (server_write, client_read) = loona::buffet::pipe();
+    let (client_write, server_read) = loona::buffet::pipe();
+
+    let driver = ProxyDriver {
+        conf,
+        pool: Rc::new(h1::Pool::new(Default::default())),
+        client_addr: Some("192.0.2.1:1234".parse()?),
+    };
+    let serve_fut = loona::buffet::spawn(async move {
+        h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            driver,
+            ConnectionInfo::default(),
+        )
+        .await
+        .bx()
+    });
+
+    let (transport, res) = tokio::time::timeout(
+        Duration::from_secs(5),
+        h1::request(
+            (client_read, client_write),
+            &h1::ClientConf::default(),
+            req,
+            body,
+            CollectingDriver,
+        ),
+    )
+    .await
+    .bx()??;
+    drop(transport);
+    tokio::time::timeout(Duration::from_secs(5), serve_fut)
+        .await
+        .bx()?
+        .bx()??;
+
+    Ok(res)
+}
+
+#[test]
+fn proxy_module_h1() {
+    helpers::run(async move {
+        let upstream_addr = start_proxy_upstream().await?;
+        let conf = Rc::new(ProxyConf {
+            upstream_timeout: Duration::from_millis(250),
+            ..ProxyConf::new(Upstream::new(upstream_addr))
+        });
+
+        // hop-by-hop headers are stripped both ways, forwarding headers are
+        // added, bodies and trailers are relayed
+        let req = Request {
+            method: Method::Post,
+            uri: "/echo".parse().unwrap(),
+            headers: {
+                let mut headers = Headers::default();
+                headers.insert(header::HOST, "example.org".into());
+                headers.insert(header::CONNECTION, "x-hop".into());
+                headers.insert("x-hop", "secret".into());
+                headers.insert(header::TE, "gzip, trailers".into());
+                headers.insert(header::TRAILER, "x-checksum".into());
+                headers
+            },
+            ..Default::default()
+        };
+        let mut body = ChunkWithTrailers(
+            Some("hello"),
+            Some(Box::new({
+                let mut trailers = Headers::default();
+                trailers.insert("x-checksum", "abc123".into());
+                trailers
+            })),
+        );
+        let (res, res_body, trailers) = proxy_h1_exchange(conf.clone(), req, &mut body).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        let saw = |name: &str| String::from_utf8(res.headers.get(name).unwrap().to_vec()).unwrap();
+        assert_eq!(
+            saw("x-saw-forwarded"),
+            r#"for=192.0.2.1;host="example.org";proto=http"#
+        );
+        assert_eq!(saw("x-saw-x-forwarded-for"), "192.0.2.1");
+        assert_eq!(saw("x-saw-x-forwarded-host"), "example.org");
+        assert_eq!(saw("x-saw-x-hop"), "none");
+        // only the part of `te` the proxy itself supports is forwarded
+        assert_eq!(saw("x-saw-te"), "trailers");
+        assert!(!res.headers.contains_key("x-upstream-hop"));
+        assert!(!res.headers.contains_key("keep-alive"));
+        assert_eq!(res_body, b"hello");
+        let trailers = trailers.expect("response should have trailers");
+        assert_eq!(&trailers.get("x-checksum").unwrap()[..], b"abc123");
+
+        // the upstream takes too long to respond
+        let req = Request {
+            method: Method::Get,
+            uri: "/slow".parse().unwrap(),
+            ..Default::default()
+        };
+        let (res, _, _) = proxy_h1_exchange(conf, req, &mut ()).await?;
+        assert_eq!(res.status, StatusCode::GATEWAY_TIMEOUT);
+
+        // the upstream isn't there at all
+        let closed_addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let conf = Rc::new(ProxyConf::new(Upstream::new(closed_addr)));
+        let req = Request {
+            method: Method::Get,
+            uri: "/".parse().unwrap(),
+            ..Default::default()
+        };
+        let (res, _, _) = proxy_h1_exchange(conf, req, &mut ()).await?;
+        assert_eq!(res.status, StatusCode::BAD_GATEWAY);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn proxy_module_h2() {
+    helpers::run(async move {
+        let upstream_addr = start_proxy_upstream().await?;
+        let conf = Rc::new(ProxyConf::new(Upstream::new(upstream_addr)));
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let server_fut = loona::buffet::spawn(async move {
+            h2::serve(
+                (server_read, server_write),
+                Rc::new(h2::ServerConf::default()),
+                RollMut::alloc()?,
+                Rc::new(ProxyDriver::new(conf, None)),
+                ConnectionInfo::default(),
+            )
+            .await
+            .bx()
+        });
+
+        let (client, conn_fut) = h2::connect(
+            (client_read, client_write),
+            Rc::new(h2::ClientConf::default()),
+        )?;
+        let conn_fut = loona::buffet::spawn(conn_fut);
+
+        let req = Request {
+            method: Method::Post,
+            uri: "https://example.org/echo".parse().unwrap(),
+            ..Default::default()
+        };
+        let mut body = StrChunks(["hello ", "over ", "h2"].into());
+        let (res, res_body, _) = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.request(req, &mut body, CollectingDriver),
+        )
+        .await
+        .bx()?
+        .bx()?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            &res.headers.get("x-saw-forwarded").unwrap()[..],
+            br#"host="example.org";proto=https"#
+        );
+        assert!(!res.headers.contains_key(header::CONNECTION));
+        assert_eq!(res_body, b"hello over h2");
+
+        // interim responses and trailers are relayed too
+        struct InterimDriver(Rc<RefCell<Vec<Response>>>);
+
+        impl ClientDriver for InterimDriver {
+            type Return = (Response, Vec<u8>, Option<Box<Headers>>);
+            type Error = BX;
+
+            async fn on_informational_response(&mut self, res: Response) -> b_x::Result<()> {
+                self.0.borrow_mut().push(res);
+                Ok(())
+            }
+
+            async fn on_final_response(
+                self,
+                res: Response,
+                body: &mut impl Body,
+            ) -> b_x::Result<Self::Return> {
+                CollectingDriver.on_final_response(res, body).await
+            }
+        }
+
+        let req = Request {
+            method: Method::Post,
+            uri: "https://example.org/early-hints".parse().unwrap(),
+            headers: {
+                let mut headers = Headers::default();
+                headers.insert(header::TE, "trailers".into());
+                headers.insert(header::TRAILER, "x-checksum".into());
+                headers
+            },
+            ..Default::default()
+        };
+        let mut trailers = Box::new(Headers::default());
+        trailers.insert("x-checksum", "abc123".into());
+        let mut body = ChunkWithTrailers(Some("hello"), Some(trailers));
+        let interim = Rc::new(RefCell::new(Vec::new()));
+        let (res, res_body, trailers) = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.request(req, &mut body, InterimDriver(interim.clone())),
+        )
+        .await
+        .bx()?
+        .bx()?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res_body, b"hello");
+        let interim = interim.take();
+        assert_eq!(interim.len(), 1);
+        assert_eq!(interim[0].status.as_u16(), 103);
+        assert_eq!(
+            &interim[0].headers.get(header::LINK).unwrap()[..],
+            b"</style.css>; rel=preload"
+        );
+        let trailers = trailers.expect("response should have trailers");
+        assert_eq!(&trailers.get("x-checksum").unwrap()[..], b"abc123");
+
+        drop(client);
+        conn_fut.await.bx()?.bx()?;
+        server_fut.await.bx()??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn server_orchestrator() {
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            if req.uri.path() == "/slow" {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            respond
+                .write_final_response_with_body(Response::default(), &mut StrChunks(["ok"].into()))
+                .await
+                .bx()
+        }
+    }
+
+    helpers::run(async move {
+        let ln = loona::buffet::net::TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let addr = ln.local_addr()?;
+
+        let conf = loona::ServerConf {
+            max_connections: 2,
+            shutdown_timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let h2_ln = loona::buffet::net::TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let h2_addr = h2_ln.local_addr()?;
+
+        let mut server = loona::Server::new(conf, |_addr| TestDriver);
+        server.add_listener(ln, loona::Protocol::Auto);
+        server.add_listener(h2_ln, loona::Protocol::H2);
+        let handle = server.handle();
+        let server_fut = loona::buffet::spawn(server.run());
+
+        // the same listener speaks HTTP/1.1...
+        let transport = loona::buffet::net::TcpStream::connect(addr)
+            .await?
+            .into_halves();
+        let (h1_transport, (res, body, _)) = h1::request(
+            transport,
+            &h1::ClientConf::default(),
+            get("/"),
+            &mut (),
+            CollectingDriver,
+        )
+        .await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(body, b"ok");
+        let (mut h1_r, _h1_w) = h1_transport.expect("connection should be reusable");
+
+        // ...and HTTP/2 with prior knowledge
+        let transport = loona::buffet::net::TcpStream::connect(addr)
+            .await?
+            .into_halves();
+        let (client, conn_fut) = h2::connect(transport, Rc::new(h2::ClientConf::default()))?;
+        let conn_fut = loona::buffet::spawn(conn_fut);
+        let (res, body, _) = client
+            .request(get("/"), &mut (), CollectingDriver)
+            .await
+            .bx()?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(body, b"ok");
+        assert_eq!(handle.live_connections(), 2);
+
+        // we're at capacity: the next connection gets a 503, and is closed
+        let transport = loona::buffet::net::TcpStream::connect(addr)
+            .await?
+            .into_halves();
+        let (_, (res, _, _)) = h1::request(
+            transport,
+            &h1::ClientConf::default(),
+            get("/"),
+            &mut (),
+            CollectingDriver,
+        )
+        .await
+        .bx()?;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // HTTP/2 clients get an empty SETTINGS frame, then a GOAWAY with
+        // REFUSED_STREAM
+        let mut stream = TcpStream::connect(h2_addr).await?;
+        let mut refusal = Vec::new();
+        stream.read_to_end(&mut refusal).await?;
+        assert_eq!(
+            refusal,
+            [
+                &[0, 0, 0, 0x4, 0, 0, 0, 0, 0][..],
+                &[0, 0, 8, 0x7, 0, 0, 0, 0, 0],
+                &[0, 0, 0, 0, 0, 0, 0, 0x7],
+            ]
+            .concat()
+        );
+        assert_eq!(handle.live_connections(), 2);
+
+        // refusals last until the client hangs up, but there are only so
+        // many at once: past that, connections are closed right away
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut refused = Vec::new();
+        for _ in 0..64 {
+            let mut stream = TcpStream::connect(addr).await?;
+            let mut output = Vec::new();
+            stream.read_to_end(&mut output).await?;
+            assert!(output.starts_with(b"HTTP/1.1 503 "));
+            refused.push(stream);
+        }
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await?;
+        assert_eq!(output, b"");
+        drop(refused);
+
+        // requests in flight when shutting down still complete
+        let slow_fut = loona::buffet::spawn(async move {
+            client
+                .request(get("/slow"), &mut (), CollectingDriver)
+                .await
+                .bx()
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        handle.shutdown();
+
+        let (res, body, _) = slow_fut.await.bx()??;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(body, b"ok");
+
+        // the idle HTTP/1.1 connection gets closed
+        let (res, _) = h1_r.read_owned(vec![0u8; 16]).await;
+        assert_eq!(res?, 0);
+
+        tokio::time::timeout(Duration::from_secs(5), server_fut)
+            .await
+            .bx()?
+            .bx()?;
+        assert_eq!(handle.live_connections(), 0);
+        conn_fut.await.bx()?.bx()?;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn connection_info_and_hooks() {
+    type Events = Rc<RefCell<Vec<String>>>;
+
+    struct TestDriver {
+        events: Events,
+    }
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let conn = req.connection_info().unwrap();
+            let mut res = Response::default();
+            res.headers
+                .insert("x-conn-id", conn.id.to_string().into_bytes().into());
+            res.headers.insert(
+                "x-peer-addr",
+                format!("{:?}", conn.peer_addr).into_bytes().into(),
+            );
+            res.headers.insert(
+                "x-stream",
+                format!("{:?}", req.stream_info().unwrap())
+                    .into_bytes()
+                    .into(),
+            );
+            respond
+                .write_final_response_with_body(res, &mut ())
+                .await
+                .bx()
+        }
+
+        fn on_connection_open(&self, conn: &ConnectionInfo) {
+            self.events.borrow_mut().push(format!("open {}", conn.id));
+        }
+
+        fn on_connection_close(
+            &self,
+            conn: &ConnectionInfo,
+            outcome: Result<loona::ServeOutcome, &loona::error::ServeError<BX>>,
+        ) {
+            self.events
+                .borrow_mut()
+                .push(format!("close {} {:?}", conn.id, outcome.unwrap()));
+        }
+    }
+
+    fn header(res: &Response, name: &str) -> String {
+        String::from_utf8(res.headers.get(name).unwrap().to_vec()).unwrap()
+    }
+
+    helpers::run(async move {
+        let events: Events = Default::default();
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, {
+            let events = events.clone();
+            move |_addr| TestDriver {
+                events: events.clone(),
+            }
+        })
+        .await?;
+
+        // two requests on the same HTTP/1.1 connection
+        let stream = loona::buffet::net::TcpStream::connect(server.addr).await?;
+        let client_addr = stream.local_addr()?;
+        let transport = stream.into_halves();
+        let conf = h1::ClientConf::default();
+        let (transport, (res1, _, _)) =
+            h1::request(transport, &conf, get("/"), &mut (), CollectingDriver).await?;
+        let mut req = get("/");
+        req.headers.insert(http::header::CONNECTION, "close".into());
+        let (_, (res2, _, _)) = h1::request(
+            transport.expect("connection should be reusable"),
+            &conf,
+            req,
+            &mut (),
+            CollectingDriver,
+        )
+        .await?;
+
+        let h1_id = header(&res1, "x-conn-id");
+        assert_eq!(header(&res2, "x-conn-id"), h1_id);
+        assert_eq!(header(&res1, "x-peer-addr"), format!("Some({client_addr})"));
+        assert_eq!(header(&res1, "x-stream"), "H1 { request_index: 0 }");
+        assert_eq!(header(&res2, "x-stream"), "H1 { request_index: 1 }");
+
+        // one request on an HTTP/2 connection
+        let h2_conn = server.h2().await?;
+        let (res, _, _) = h2_conn.request(get("/"), &mut ()).await?;
+        let h2_id = header(&res, "x-conn-id");
+        assert_ne!(h2_id, h1_id);
+        assert_eq!(header(&res, "x-stream"), "H2 { stream_id: 1 }");
+
+        server.shutdown().await?;
+        h2_conn.close().await?;
+
+        assert_eq!(
+            *events.borrow(),
+            [
+                format!("open {h1_id}"),
+                format!("close {h1_id} ClientRequestedConnectionClose"),
+                format!("open {h2_id}"),
+                format!("close {h2_id} ServerShutDown"),
+            ]
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn layers() {
+    use loona::layer::{
+        InjectHeadersLayer, Interceptor, Layer, RequestId, RequestIdLayer, TimingLayer,
+    };
+
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let mut res = Response::default();
+            let id = req.extensions.get::<RequestId>().unwrap().0.clone();
+            res.headers.insert("x-seen-id", id.into_bytes().into());
+            let injected = req.headers.get("x-injected").unwrap().clone();
+            res.headers.insert("x-seen-injected", injected);
+            respond
+                .write_final_response_with_body(res, &mut StrChunks(["hello ", "world"].into()))
+                .await
+                .bx()
+        }
+    }
+
+    /// Uppercases the body, and counts its bytes in a trailer
+    struct ShoutLayer;
+
+    struct ShoutInterceptor(usize);
+
+    impl Layer for ShoutLayer {
+        type Interceptor = ShoutInterceptor;
+
+        fn on_request(&self, _req: &mut Request) -> ShoutInterceptor {
+            ShoutInterceptor(0)
+        }
+    }
+
+    impl Interceptor for ShoutInterceptor {
+        fn on_body_chunk(&mut self, chunk: loona::buffet::Piece) -> Option<loona::buffet::Piece> {
+            self.0 += chunk.len();
+            Some(chunk.to_ascii_uppercase().into())
+        }
+
+        fn on_body_end(&mut self) -> Option<loona::buffet::Piece> {
+            Some("!".into())
+        }
+
+        fn on_trailers(&mut self, trailers: Option<Box<Headers>>) -> Option<Box<Headers>> {
+            let mut trailers = trailers.unwrap_or_default();
+            trailers.insert("x-body-len", self.0.to_string().into_bytes().into());
+            Some(trailers)
+        }
+    }
+
+    helpers::run(async move {
+        let mut inject = InjectHeadersLayer::default();
+        inject.request.insert("x-injected", "yes".into());
+        inject
+            .response
+            .insert(http::header::X_FRAME_OPTIONS, "DENY".into());
+
+        let server = TestServer::start(Default::default(), loona::Protocol::H1, move |_addr| {
+            RequestIdLayer::default().wrap(
+                TimingLayer.wrap(
+                    InjectHeadersLayer {
+                        request: inject.request.clone(),
+                        response: inject.response.clone(),
+                    }
+                    .wrap(ShoutLayer.wrap(TestDriver)),
+                ),
+            )
+        })
+        .await?;
+
+        let mut conn = server.h1().await?;
+        let mut ids = vec![];
+        // ids that are empty, too long or not visible ASCII get replaced
+        for (sent_id, kept) in [
+            (None, false),
+            (Some("from-client".to_owned()), true),
+            (Some(String::new()), false),
+            (Some("a".repeat(129)), false),
+            (Some("from client".to_owned()), false),
+        ] {
+            let mut req = get("/");
+            if let Some(id) = &sent_id {
+                req.headers
+                    .insert("x-request-id", id.clone().into_bytes().into());
+            }
+            let (res, body, trailers) = conn.request(req, &mut ()).await?;
+
+            let id = res.headers.get("x-request-id").unwrap().to_vec();
+            assert_eq!(&res.headers.get("x-seen-id").unwrap()[..], &id[..]);
+            if let Some(sent_id) = sent_id {
+                assert_eq!(id == sent_id.as_bytes(), kept, "{sent_id:?}");
+            }
+            ids.push(id);
+
+            assert_eq!(&res.headers.get("x-seen-injected").unwrap()[..], b"yes");
+            assert_eq!(&res.headers.get("x-frame-options").unwrap()[..], b"DENY");
+            assert!(res
+                .headers
+                .get("server-timing")
+                .unwrap()
+                .starts_with(b"handler;dur="));
+            assert_eq!(body, b"HELLO WORLD!");
+            assert_eq!(&trailers.unwrap().get("x-body-len").unwrap()[..], b"11");
+        }
+        let num_ids = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), num_ids);
+
+        server.shutdown().await
+    })
+}
+
+#[test]
+fn router() {
+    use loona::router::{PathParams, Router};
+
+    /// Responds with its name, and the path parameters in a header
+    struct Named(&'static str);
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for Named
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let params = req.extensions.get::<PathParams>().unwrap();
+            let params = params
+                .0
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<_>>()
+                .join("&");
+            let mut res = Response::default();
+            res.headers.insert("x-params", params.into_bytes().into());
+            respond
+                .write_final_response_with_body(res, &mut StrChunks([self.0].into()))
+                .await
+                .bx()
+        }
+    }
+
+    // matching itself is covered by the router's unit tests: this checks
+    // requests make it to the right driver, over both protocols
+    let mut api = Router::new();
+    api.route(Method::Get, "/items/:id", Named("item"));
+
+    let mut subdomains = Router::new();
+    subdomains.route(Method::Get, "/", Named("subdomain"));
+
+    let mut router = Router::new();
+    router.route(Method::Get, "/", Named("root"));
+    router.route(Method::Get, "/users/:id", Named("user"));
+    router.route(Method::Delete, "/users/:id", Named("delete user"));
+    router.mount("/api/:version", api);
+    router.host("*.example.org", subdomains);
+    let router = Rc::new(router);
+
+    fn check(
+        (status, body, params): (u16, &str, &str),
+        (res, res_body, _): helpers::harness::Collected,
+    ) -> b_x::Result<()> {
+        assert_eq!(res.status.as_u16(), status);
+        assert_eq!(std::str::from_utf8(&res_body)?, body);
+        if status == 200 {
+            assert_eq!(&res.headers.get("x-params").unwrap()[..], params.as_bytes());
+        }
+        if status == 405 {
+            assert_eq!(&res.headers.get("allow").unwrap()[..], b"GET, HEAD, DELETE");
+        }
+        Ok(())
+    }
+
+    helpers::run(async move {
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, move |_addr| {
+            router.clone()
+        })
+        .await?;
+
+        let mut h1_conn = server.h1().await?;
+        let h2_conn = server.h2().await?;
+        for (method, uri, expected) in [
+            (Method::Get, "http://localhost/", (200, "root", "")),
+            (
+                Method::Get,
+                "http://localhost/users/42",
+                (200, "user", "id=42"),
+            ),
+            (
+                Method::Get,
+                "http://localhost/api/v1/items/7",
+                (200, "item", "version=v1&id=7"),
+            ),
+            (
+                Method::Get,
+                "http://www.example.org/",
+                (200, "subdomain", ""),
+            ),
+            (Method::Get, "http://localhost/nope", (404, "", "")),
+            (Method::Post, "http://localhost/users/42", (405, "", "")),
+        ] {
+            let req = Request {
+                method,
+                uri: uri.parse().unwrap(),
+                ..Default::default()
+            };
+            check(expected, h1_conn.request(req.clone(), &mut ()).await?)?;
+            check(expected, h2_conn.request(req, &mut ()).await?)?;
+        }
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    })
+}
+
+#[test]
+fn date_and_server_headers() {
+    // how dates are formatted is covered by the unit tests
+    const CUSTOM_DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn check(res: &Response, custom: bool) {
+        let date = &res.headers.get(http::header::DATE).unwrap()[..];
+        let server = &res.headers.get(http::header::SERVER).unwrap()[..];
+        if custom {
+            assert_eq!(date, CUSTOM_DATE.as_bytes());
+            assert_eq!(server, b"custom");
+        } else {
+            assert_ne!(date, CUSTOM_DATE.as_bytes());
+            assert_eq!(server, b"loona");
+        }
+    }
+
+    helpers::run(async move {
+        let mut conf = loona::ServerConf::default();
+        conf.h1.server_header = Some("loona".into());
+        conf.h2.server_header = Some("loona".into());
+
+        let server = TestServer::start(conf, loona::Protocol::Auto, |_addr| {
+            FnDriver::new(|req, _| {
+                let mut res = Response::default();
+                if req.uri.path() == "/custom" {
+                    res.headers.insert(http::header::DATE, CUSTOM_DATE.into());
+                    res.headers.insert(http::header::SERVER, "custom".into());
+                }
+                Reply {
+                    res,
+                    ..Default::default()
+                }
+            })
+        })
+        .await?;
+
+        let mut h1_conn = server.h1().await?;
+        let h2_conn = server.h2().await?;
+        for custom in [false, true] {
+            let path = if custom { "/custom" } else { "/" };
+            let (res, _, _) = h1_conn.request(get(path), &mut ()).await?;
+            check(&res, custom);
+            let (res, _, _) = h2_conn.request(get(path), &mut ()).await?;
+            check(&res, custom);
+        }
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    })
+}
+
+#[test]
+fn head_responses() {
+    helpers::run(async move {
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, |_addr| {
+            FnDriver::new(|req, _| {
+                let mut reply = Reply::default();
+                if req.uri.path() == "/chunked" {
+                    reply.body = PieceList::single("hello").followed_by(" world");
+                } else {
+                    reply
+                        .res
+                        .headers
+                        .insert(header::CONTENT_LENGTH, "11".into());
+                    reply.body = PieceList::single("hello world");
+                }
+                reply
+            })
+        })
+        .await?;
+
+        let mut conn = server.h1().await?;
+        // the connection must still be usable after each HEAD response
+        for (method, path, body) in [
+            (Method::Head, "/", ""),
+            (Method::Get, "/", "hello world"),
+            (Method::Head, "/chunked", ""),
+            (Method::Get, "/chunked", "hello world"),
+        ] {
+            let (res, res_body, _) = conn.request(request(method, path), &mut ()).await?;
+            assert_eq!(res.status, StatusCode::OK);
+            if path == "/" {
+                assert_eq!(&res.headers.get(header::CONTENT_LENGTH).unwrap()[..], b"11");
+            }
+            assert_eq!(std::str::from_utf8(&res_body)?, body);
+        }
+        assert!(conn.is_open());
+
+        let h2_conn = server.h2().await?;
+        let (res, res_body, _) = h2_conn.request(request(Method::Head, "/"), &mut ()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(&res.headers.get(header::CONTENT_LENGTH).unwrap()[..], b"11");
+        assert!(res_body.is_empty());
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    })
+}
+
+#[test]
+fn content_length_enforced() {
+    helpers::run(async move {
+        // which errors the responder returns is covered by its unit tests
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, |_addr| {
+            FnDriver::new(|req, _| {
+                let mut res = Response::default();
+                res.headers.insert(header::CONTENT_LENGTH, "11".into());
+                let body = match req.uri.path() {
+                    "/long" => "hello world!",
+                    "/short" => "hello",
+                    _ => "hello world",
+                };
+                Reply {
+                    res,
+                    body: PieceList::single(body),
+                    ..Default::default()
+                }
+            })
+        })
+        .await?;
+
+        for path in ["/long", "/short"] {
+            // the connection is closed before the body is complete
+            let res = server.h1().await?.request(get(path), &mut ()).await;
+            assert!(res.is_err(), "{path}: the body should be incomplete");
+        }
+
+        let h2_conn = server.h2().await?;
+        // the stream is reset rather than ended normally
+        let res = h2_conn.request(get("/short"), &mut ()).await;
+        assert!(res.is_err(), "the body should be incomplete");
+        // other streams are unaffected
+        let (_, res_body, _) = h2_conn.request(get("/"), &mut ()).await?;
+        assert_eq!(&res_body[..], b"hello world");
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    })
+}
+
+#[test]
+fn response_trailers() {
+    helpers::run(async move {
+        // which trailers get refused is covered by the responder's unit tests
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, |_addr| {
+            FnDriver::new(|_req, _| {
+                let mut res = Response::default();
+                res.headers.insert(header::CONTENT_LENGTH, "5".into());
+                res.headers.insert(header::TRAILER, "x-checksum".into());
+                let mut trailers = Box::new(Headers::default());
+                trailers.insert("x-checksum", "abc123".into());
+                Reply {
+                    res,
+                    body: PieceList::single("hello"),
+                    trailers: Some(trailers),
+                }
+            })
+        })
+        .await?;
+
+        let mut req = get("/");
+        req.headers.insert(header::TE, "trailers".into());
+
+        let (res, res_body, trailers) = server.h1().await?.request(req.clone(), &mut ()).await?;
+        // trailers need chunked transfer-encoding
+        assert!(!res.headers.contains_key(header::CONTENT_LENGTH));
+        assert_eq!(
+            &res.headers.get(header::TRANSFER_ENCODING).unwrap()[..],
+            b"chunked"
+        );
+        assert_eq!(&res_body[..], b"hello");
+        let trailers = trailers.expect("response should have trailers");
+        assert_eq!(&trailers.get("x-checksum").unwrap()[..], b"abc123");
+
+        // over HTTP/2, trailers are a HEADERS frame that ends the stream
+        let h2_conn = server.h2().await?;
+        let (res, res_body, trailers) = h2_conn.request(req, &mut ()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(&res_body[..], b"hello");
+        let trailers = trailers.expect("response should have trailers");
+        assert_eq!(&trailers.get("x-checksum").unwrap()[..], b"abc123");
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    })
+}
+
+#[test]
+fn request_body_limits() {
+    use loona::body::Full;
+
+    helpers::run(async move {
+        let mut conf = loona::ServerConf::default();
+        conf.h1.max_request_body_len = Some(10);
+        conf.h2.max_request_body_len = Some(10);
+        let server = TestServer::start(conf, loona::Protocol::Auto, |_addr| {
+            // echoes the request body
+            FnDriver::new(|_req, body| Reply {
+                body,
+                ..Default::default()
+            })
+            .with_max_request_body_len(|req, default| {
+                if req.uri.path() == "/big" {
+                    Some(1024)
+                } else {
+                    default
+                }
+            })
+        })
+        .await?;
+
+        let post = |path: &str| request(Method::Post, path);
+        let twenty_bytes = || Full::from(loona::buffet::Piece::from("0123456789abcdefghij"));
+
+        // the announced length is over the limit: 413, and the connection is
+        // closed
+        let mut conn = server.h1().await?;
+        let (res, _, _) = conn.request(post("/"), &mut twenty_bytes()).await?;
+        assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!conn.is_open());
+
+        // the driver raised the limit for that path
+        let (res, res_body, _) = server
+            .h1()
+            .await?
+            .request(post("/big"), &mut twenty_bytes())
+            .await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(&res_body[..], b"0123456789abcdefghij");
+
+        // a chunked body goes over the limit while it's read
+        let res = server
+            .h1()
+            .await?
+            .request(
+                post("/"),
+                &mut StrChunks(["0123456789", "abcdefghij"].into()),
+            )
+            .await;
+        assert!(res.is_err());
+
+        let h2_conn = server.h2().await?;
+        let (res, _, _) = h2_conn.request(post("/"), &mut twenty_bytes()).await?;
+        assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // the stream is reset once the body goes over the limit
+        let res = h2_conn
+            .request(
+                post("/"),
+                &mut StrChunks(["0123456789", "abcdefghij"].into()),
+            )
+            .await;
+        assert!(res.is_err());
+
+        // the connection is still usable
+        let (res, res_body, _) = h2_conn.request(post("/big"), &mut twenty_bytes()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(&res_body[..], b"0123456789abcdefghij");
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    })
+}
+
+#[test]
+fn file_bodies() {
+    use loona::body::FileBody;
+
+    struct TestDriver {
+        path: std::path::PathBuf,
+    }
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let file = std::fs::File::open(&self.path)?;
+            let mut body = if req.uri.path() == "/range" {
+                FileBody::with_range(file, 1_000, 50_000)
+            } else {
+                FileBody::new(file)?
+            };
+            respond
+                .write_final_response_with_body(Response::default(), &mut body)
+                .await
+                .bx()
+        }
+    }
+
+    let contents: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let path = std::env::temp_dir().join(format!("loona-file-bodies-{}", std::process::id()));
+    std::fs::write(&path, &contents).unwrap();
+
+    let server_path = path.clone();
+    helpers::run(async move {
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, move |_addr| {
+            TestDriver {
+                path: server_path.clone(),
+            }
+        })
+        .await?;
+
+        let mut conn = server.h1().await?;
+        let (res, res_body, _) = conn.request(get("/"), &mut ()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers.content_length(), Some(100_000));
+        assert!(res_body == contents);
+
+        let (res, res_body, _) = conn.request(get("/range"), &mut ()).await?;
+        assert_eq!(res.headers.content_length(), Some(50_000));
+        assert!(res_body[..] == contents[1_000..51_000]);
+
+        let (res, res_body, _) = conn.request(request(Method::Head, "/"), &mut ()).await?;
+        assert_eq!(res.headers.content_length(), Some(100_000));
+        assert!(res_body.is_empty());
+
+        // http/2 reads the file into buffers
+        let h2_conn = server.h2().await?;
+        let (res, res_body, _) = h2_conn.request(get("/range"), &mut ()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(res_body[..] == contents[1_000..51_000]);
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    });
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn resource_requests() {
+    use loona::{
+        buffet::Piece,
+        resource::{serve_resource, FileResource},
+    };
+
+    struct TestDriver {
+        path: std::path::PathBuf,
+    }
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let resource = FileResource::new(std::fs::File::open(&self.path)?)?
+                .with_content_type("text/plain");
+            serve_resource(&req, &resource, respond).await.bx()
+        }
+    }
+
+    let contents: Vec<u8> = (0..1000).map(|i| b'a' + (i % 26) as u8).collect();
+    let path = std::env::temp_dir().join(format!("loona-resource-{}", std::process::id()));
+    std::fs::write(&path, &contents).unwrap();
+
+    let server_path = path.clone();
+    helpers::run(async move {
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, move |_addr| {
+            TestDriver {
+                path: server_path.clone(),
+            }
+        })
+        .await?;
+
+        let get = |headers: Vec<(header::HeaderName, Piece)>| {
+            let mut req = get("/");
+            for (name, value) in headers {
+                req.headers.append(name, value);
+            }
+            req
+        };
+
+        let mut conn = server.h1().await?;
+
+        let (res, body, _) = conn.request(get(vec![]), &mut ()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(&res.headers[header::ACCEPT_RANGES][..], b"bytes");
+        assert_eq!(&res.headers[header::CONTENT_TYPE][..], b"text/plain");
+        assert!(body == contents);
+        let etag = res.headers[header::ETAG].clone();
+        let last_modified = res.headers[header::LAST_MODIFIED].clone();
+
+        let (res, body, _) = conn
+            .request(get(vec![(header::RANGE, "bytes=10-19".into())]), &mut ())
+            .await?;
+        assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&res.headers[header::CONTENT_RANGE][..], b"bytes 10-19/1000");
+        assert!(body[..] == contents[10..20]);
+
+        let (res, body, _) = conn
+            .request(get(vec![(header::RANGE, "bytes=-5".into())]), &mut ())
+            .await?;
+        assert_eq!(
+            &res.headers[header::CONTENT_RANGE][..],
+            b"bytes 995-999/1000"
+        );
+        assert!(body[..] == contents[995..]);
+
+        let (res, body, _) = conn
+            .request(get(vec![(header::RANGE, "bytes=2000-".into())]), &mut ())
+            .await?;
+        assert_eq!(res.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(&res.headers[header::CONTENT_RANGE][..], b"bytes */1000");
+        assert!(body.is_empty());
+
+        // the representation changed since the client got its part
+        let (res, body, _) = conn
+            .request(
+                get(vec![
+                    (header::RANGE, "bytes=10-19".into()),
+                    (header::IF_RANGE, "\"something-else\"".into()),
+                ]),
+                &mut (),
+            )
+            .await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(body.len(), 1000);
+
+        let (res, _, _) = conn
+            .request(
+                get(vec![
+                    (header::RANGE, "bytes=10-19".into()),
+                    (header::IF_RANGE, etag.clone()),
+                ]),
+                &mut (),
+            )
+            .await?;
+        assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+
+        let (res, body, _) = conn
+            .request(get(vec![(header::IF_NONE_MATCH, etag.clone())]), &mut ())
+            .await?;
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(&res.headers[header::ETAG][..], &etag[..]);
+        assert!(body.is_empty());
+
+        let (res, _, _) = conn
+            .request(
+                get(vec![(header::IF_MODIFIED_SINCE, last_modified.clone())]),
+                &mut (),
+            )
+            .await?;
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+
+        let (res, _, _) = conn
+            .request(
+                get(vec![(header::IF_MATCH, "\"nope\", \"nah\"".into())]),
+                &mut (),
+            )
+            .await?;
+        assert_eq!(res.status, StatusCode::PRECONDITION_FAILED);
+
+        let (res, body, _) = conn
+            .request(get(vec![(header::RANGE, "bytes=0-1, 5-6".into())]), &mut ())
+            .await?;
+        assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+        let content_type = std::str::from_utf8(&res.headers[header::CONTENT_TYPE])?.to_owned();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{boundary}\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-1/1000\r\n\r\nab\
+             \r\n--{boundary}\r\ncontent-type: text/plain\r\ncontent-range: bytes 5-6/1000\r\n\r\nfg\
+             \r\n--{boundary}--\r\n"
+        );
+        assert_eq!(std::str::from_utf8(&body)?, expected);
+        drop(conn);
+
+        let h2_conn = server.h2().await?;
+        let (res, body, _) = h2_conn
+            .request(get(vec![(header::RANGE, "bytes=10-19".into())]), &mut ())
+            .await?;
+        assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+        assert!(body[..] == contents[10..20]);
+
+        let (res, _, _) = h2_conn
+            .request(get(vec![(header::IF_NONE_MATCH, etag)]), &mut ())
+            .await?;
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    });
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn compression_layer() {
+    use loona::{
+        body::Full,
+        layer::{CompressionLayer, Layer},
+    };
+    use std::io::Read;
+
+    struct TestDriver;
+
+    fn text() -> String {
+        "the quick brown fox jumps over the lazy dog\n".repeat(100)
+    }
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let (content_type, body) = match req.uri.path() {
+                "/small" => ("text/plain", "tiny".to_owned()),
+                "/image" => ("image/png", text()),
+                _ => ("text/plain; charset=utf-8", text()),
+            };
+            let mut res = Response::default();
+            res.headers
+                .insert(header::CONTENT_TYPE, content_type.into());
+            respond
+                .write_final_response_with_body(
+                    res,
+                    &mut Full::from(loona::buffet::Piece::from(body.into_bytes())),
+                )
+                .await
+                .bx()
+        }
+    }
+
+    helpers::run(async move {
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, |_addr| {
+            CompressionLayer::default().wrap(TestDriver)
+        })
+        .await?;
+
+        let req = |method: Method, path: &str, accept_encoding: &'static str| {
+            let mut req = request(method, path);
+            req.headers
+                .insert(header::ACCEPT_ENCODING, accept_encoding.into());
+            req
+        };
+
+        let mut conn = server.h1().await?;
+        let (res, body, _) = conn
+            .request(req(Method::Get, "/", "deflate;q=0.5, gzip"), &mut ())
+            .await?;
+        assert_eq!(&res.headers[header::CONTENT_ENCODING][..], b"gzip");
+        assert_eq!(&res.headers[header::VARY][..], b"accept-encoding");
+        assert!(res.headers.get(header::CONTENT_LENGTH).is_none());
+        assert!(body.len() < text().len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, text());
+
+        let (res, body, _) = conn
+            .request(req(Method::Head, "/", "gzip"), &mut ())
+            .await?;
+        assert_eq!(&res.headers[header::CONTENT_ENCODING][..], b"gzip");
+        assert!(body.is_empty());
+
+        // too small to bother
+        let (res, body, _) = conn
+            .request(req(Method::Get, "/small", "gzip"), &mut ())
+            .await?;
+        assert!(res.headers.get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(&res.headers[header::VARY][..], b"accept-encoding");
+        assert_eq!(&body[..], b"tiny");
+
+        // already compressed
+        let (res, body, _) = conn
+            .request(req(Method::Get, "/image", "gzip"), &mut ())
+            .await?;
+        assert!(res.headers.get(header::CONTENT_ENCODING).is_none());
+        assert!(res.headers.get(header::VARY).is_none());
+        assert_eq!(body.len(), text().len());
+
+        // nothing the client accepts
+        let (res, body, _) = conn
+            .request(req(Method::Get, "/", "identity"), &mut ())
+            .await?;
+        assert!(res.headers.get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(body.len(), text().len());
+
+        let h2_conn = server.h2().await?;
+        let (res, body, _) = h2_conn
+            .request(req(Method::Get, "/", "deflate"), &mut ())
+            .await?;
+        assert_eq!(&res.headers[header::CONTENT_ENCODING][..], b"deflate");
+        let mut decoded = String::new();
+        flate2::read::ZlibDecoder::new(&body[..]).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, text());
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    });
+}
+
+#[test]
+fn multipart_uploads() {
+    use loona::{body::Full, multipart::Multipart};
+
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            // answers with one `name filename len` line per part
+            let mut summary = String::new();
+            let mut multipart = Multipart::from_request(&req, req_body).bx()?;
+            while let Some(mut part) = multipart.next_part().await.bx()? {
+                let mut len = 0;
+                while let BodyChunk::Chunk(chunk) = part.next_chunk().await.bx()? {
+                    len += chunk.len();
+                }
+                summary += &format!(
+                    "{} {} {len}\n",
+                    part.name().unwrap_or_default(),
+                    part.filename().unwrap_or_default(),
+                );
+            }
+
+            respond
+                .write_final_response_with_body(
+                    Response::default(),
+                    &mut Full::from(loona::buffet::Piece::from(summary.into_bytes())),
+                )
+                .await
+                .bx()
+        }
+    }
+
+    helpers::run(async move {
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, |_addr| {
+            TestDriver
+        })
+        .await?;
+
+        // large enough to span several reads, and several h2 DATA frames
+        let upload = "0123456789abcdef\r\n--boundar".repeat(2048);
+        let mut form = String::new();
+        form += "--boundary\r\n";
+        form += "Content-Disposition: form-data; name=\"title\"\r\n\r\n";
+        form += "hello\r\n";
+        form += "--boundary\r\n";
+        form += "Content-Disposition: form-data; name=\"upload\"; filename=\"a.bin\"\r\n";
+        form += "Content-Type: application/octet-stream\r\n\r\n";
+        form += &upload;
+        form += "\r\n--boundary--\r\n";
+
+        let req = || {
+            let mut req = request(Method::Post, "/upload");
+            req.headers.insert(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary".into(),
+            );
+            req
+        };
+        let body = || Full::from(loona::buffet::Piece::from(form.clone().into_bytes()));
+        let expected = format!("title  5\nupload a.bin {}\n", upload.len());
+
+        let (res, res_body, _) = server.h1().await?.request(req(), &mut body()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(String::from_utf8(res_body)?, expected);
+
+        let h2_conn = server.h2().await?;
+        let (res, res_body, _) = h2_conn.request(req(), &mut body()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(String::from_utf8(res_body)?, expected);
+
+        // not multipart
+        let (res, _, _) = h2_conn
+            .request(request(Method::Post, "/upload"), &mut ())
+            .await?;
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    });
+}
+
+#[test]
+fn h1_list_based_framing() {
+    use loona::body::{BodyExt, Full};
+
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            _req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let collected = req_body.collect(1024).await.map_err(BX::from_err)?;
+            respond
+                .write_final_response_with_body(Response::default(), &mut Full::new(collected.body))
+                .await
+                .bx()
+        }
+    }
+
+    helpers::run(async move {
+        // `close` is found anywhere in the list, in any case
+        let (output, outcome) = serve_h1_raw(
+            TestDriver,
+            "GET / HTTP/1.1\r\nconnection: keep-alive, Close\r\n\r\n",
+        )
+        .await?;
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{output}");
+        assert_eq!(outcome, loona::ServeOutcome::ClientRequestedConnectionClose);
+
+        // chunked is the final coding, so it frames the body
+        let (output, outcome) = serve_h1_raw(
+            TestDriver,
+            "POST / HTTP/1.1\r\ntransfer-encoding: identity, Chunked\r\nconnection: close\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        )
+        .await?;
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{output}");
+        assert!(output.ends_with("hello"), "{output}");
+        assert_eq!(outcome, loona::ServeOutcome::ClientRequestedConnectionClose);
+
+        // chunked isn't the final coding: there's no telling where the body ends
+        for transfer_encoding in ["gzip", "chunked, gzip"] {
+            let input =
+                format!("POST / HTTP/1.1\r\ntransfer-encoding: {transfer_encoding}\r\n\r\nhello");
+            let (output, outcome) = serve_h1_raw(TestDriver, input.leak()).await?;
+            assert!(
+                output.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{output}"
+            );
+            assert_eq!(
+                outcome,
+                loona::ServeOutcome::RequestBodyLengthUnknownOnHttp1Conn
+            );
+        }
+
+        // identical content-length values are fine
+        let (output, outcome) = serve_h1_raw(
+            TestDriver,
+            "POST / HTTP/1.1\r\ncontent-length: 5, 5\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+        )
+        .await?;
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{output}");
+        assert!(output.ends_with("hello"), "{output}");
+        assert_eq!(outcome, loona::ServeOutcome::ClientRequestedConnectionClose);
+
+        // anything else could be used to smuggle a request
+        for framing in [
+            "content-length: 5, 50",
+            "content-length: 5\r\ncontent-length: 50",
+            "content-length: +5",
+            "content-length: 5\r\ntransfer-encoding: chunked",
+            "transfer-encoding: chunked\r\ncontent-length: 0",
+        ] {
+            let input = format!("POST / HTTP/1.1\r\n{framing}\r\n\r\nhello");
+            let (output, outcome) = serve_h1_raw(TestDriver, input.leak()).await?;
+            assert!(
+                output.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{framing}: {output}"
+            );
+            assert_eq!(
+                outcome,
+                loona::ServeOutcome::RequestBodyLengthUnknownOnHttp1Conn
+            );
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn invalid_header_values() {
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let mut res = Response::default();
+            res.headers.insert(header::CONTENT_LENGTH, "0".into());
+            if let Some(value) = req.headers.get("x-echo") {
+                res.headers.insert("x-echo", value.clone());
+            }
+            if req.uri.path() == "/split" {
+                res.headers
+                    .insert("x-split", "a\r\nset-cookie: evil=1".into());
+            }
+            respond
+                .write_final_response(res)
+                .await
+                .bx()?
+                .finish_body(None)
+                .await
+                .bx()
+        }
+    }
+
+    helpers::run(async move {
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, |_addr| {
+            TestDriver
+        })
+        .await?;
+
+        // whitespace around parsed values is dropped, so they can be sent back
+        let mut stream = TcpStream::connect(server.addr).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nx-echo:  hi \t\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut output = String::new();
+        stream.read_to_string(&mut output).await?;
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{output}");
+        assert!(output.contains("\r\nx-echo: hi\r\n"), "{output}");
+
+        // the response is never written
+        let mut stream = TcpStream::connect(server.addr).await?;
+        stream.write_all(b"GET /split HTTP/1.1\r\n\r\n").await?;
+        let mut output = String::new();
+        stream.read_to_string(&mut output).await?;
+        assert!(!output.contains("set-cookie"), "{output}");
+
+        let split = || {
+            let mut req = get("/split");
+            req.headers.insert("x-split", "a\r\nx-injected: 1".into());
+            req
+        };
+
+        let transport = loona::buffet::net::TcpStream::connect(server.addr)
+            .await?
+            .into_halves();
+        let res = h1::request(
+            transport,
+            &h1::ClientConf::default(),
+            split(),
+            &mut (),
+            CollectingDriver,
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(h1::Http1ClientError::EncodeRequestHeaders(_))
+        ));
+
+        let h2_conn = server.h2().await?;
+        let res = h2_conn
+            .client
+            .request(split(), &mut (), CollectingDriver)
+            .await;
+        assert!(matches!(
+            res,
+            Err(h2::H2ClientError::InvalidRequestHeader(_))
+        ));
+
+        // the server answers with a 500 instead
+        let (res, _, _) = h2_conn.request(get("/split"), &mut ()).await?;
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(res.headers.get("x-split").is_none());
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    });
+}
+
+#[test]
+fn patch_and_extension_methods() {
+    use loona::body::Full;
+
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let variant = format!("{:?}", matches!(req.method, Method::Patch));
+            respond
+                .write_final_response_with_body(
+                    Response::default(),
+                    &mut Full::from(loona::buffet::Piece::from(
+                        format!("{} {variant}", req.method).into_bytes(),
+                    )),
+                )
+                .await
+                .bx()
+        }
+    }
+
+    helpers::run(async move {
+        let server = TestServer::start(Default::default(), loona::Protocol::Auto, |_addr| {
+            TestDriver
+        })
+        .await?;
+
+        let other = |name: &'static str| Method::Other(name.into());
+
+        let mut conn = server.h1().await?;
+        let (res, body, _) = conn.request(request(Method::Patch, "/"), &mut ()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(String::from_utf8(body)?, "PATCH true");
+        let (_, body, _) = conn
+            .request(request(other("PROPFIND"), "/"), &mut ())
+            .await?;
+        assert_eq!(String::from_utf8(body)?, "PROPFIND false");
+
+        let h2_conn = server.h2().await?;
+        let (res, body, _) = h2_conn
+            .request(request(Method::Patch, "/"), &mut ())
+            .await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(String::from_utf8(body)?, "PATCH true");
+
+        // anything but a token could be used to inject a request, so neither
+        // client sends it
+        for method in [
+            "GE T",
+            "GET / HTTP/1.1\r\nx-injected: 1\r\n\r\nGET",
+            "(GET)",
+            "",
+        ] {
+            let transport = loona::buffet::net::TcpStream::connect(server.addr)
+                .await?
+                .into_halves();
+            let res = h1::request(
+                transport,
+                &h1::ClientConf::default(),
+                request(other(method), "/"),
+                &mut (),
+                CollectingDriver,
+            )
+            .await;
+            assert!(
+                matches!(
+                    res,
+                    Err(h1::Http1ClientError::EncodeRequestHeaders(
+                        h1::encode::H1EncoderError::InvalidMethod(_)
+                    ))
+                ),
+                "{method:?}"
+            );
+
+            let res = h2_conn
+                .client
+                .request(request(other(method), "/"), &mut (), CollectingDriver)
+                .await;
+            assert!(
+                matches!(res, Err(h2::H2ClientError::InvalidMethod(_))),
+                "{method:?}"
+            );
+        }
+
+        server.shutdown().await?;
+        h2_conn.close().await
+    });
 }